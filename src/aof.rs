@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::thread;
+
+use crate::enums::val_type::ValueType;
+use crate::structs::client_stream::ClientStream;
+use crate::structs::connection::Connection;
+use crate::structs::keyspace::Entry;
+use crate::structs::request::Request;
+use crate::structs::runner::Runner;
+use crate::structs::stream::Stream;
+use crate::types::{DbType, RedisGlobalType};
+use crate::utils::encode_resp_command;
+
+// Elements per RPUSH/SADD/HSET in a rewritten file, as Redis does
+const AOF_REWRITE_ITEMS_PER_CMD: usize = 64;
+
+/// Replays the AOF through the command runner without replies or
+/// propagation, then opens it for appending. A truncated final command is
+/// dropped from the file with a warning.
+pub fn load_aof(db: &DbType, global_state: &RedisGlobalType) {
+    let path = global_state.lock().unwrap().aof_path();
+    let contents = fs::read(&path).unwrap_or_default();
+
+    global_state.lock().unwrap().loading = true;
+    let mut sink = ClientStream::detached();
+    let mut connection = Connection::new(sink.outbox());
+    let mut offset = 0;
+    let mut commands = 0;
+
+    while let Ok(Some((request, consumed))) = Request::try_parse(&contents[offset..]) {
+        let mut runner = Runner::new(request.args);
+        runner.run(&mut sink, db, global_state, &mut connection, &0, true);
+        sink.discard();
+        offset += consumed;
+        commands += 1;
+    }
+    {
+        // Replayed writes are already on disk
+        let mut global = global_state.lock().unwrap();
+        global.loading = false;
+        global.dirty = 0;
+    }
+
+    if offset < contents.len() {
+        eprintln!(
+            "AOF {} ends with a truncated command, dropping the last {} bytes",
+            path,
+            contents.len() - offset
+        );
+        if let Err(e) = OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_len(offset as u64))
+        {
+            eprintln!("Failed to truncate AOF: {e}");
+        }
+    }
+    if commands > 0 {
+        eprintln!("Replayed {} commands from {}", commands, path);
+    }
+
+    match open_for_append(&path) {
+        Ok(file) => global_state.lock().unwrap().aof = Some(file),
+        Err(e) => eprintln!("Failed to open AOF {}: {e}", path),
+    }
+}
+
+/// Writes the smallest command sequence rebuilding the current dataset on a
+/// background thread, then swaps it in with the writes that arrived
+/// meanwhile. Returns false when a rewrite is already running.
+pub fn spawn_aof_rewrite(db: &DbType, global_state: &RedisGlobalType) -> bool {
+    let (snapshot, path) = {
+        let db = db.lock_all();
+        let mut global = global_state.lock().unwrap();
+        if global.aof_rewrite_buffer.is_some() {
+            return false;
+        }
+        // Writes from here on are part of the new file's tail, not the snapshot
+        global.aof_rewrite_buffer = Some(Vec::new());
+        (db.snapshot(), global.aof_path())
+    };
+
+    let global_state = Arc::clone(global_state);
+    thread::spawn(move || {
+        let tmp_path = format!("{}.rewrite-{}", path, std::process::id());
+        let written = fs::write(&tmp_path, encode_aof(&snapshot));
+
+        let mut global = global_state.lock().unwrap();
+        let tail = global.aof_rewrite_buffer.take().unwrap_or_default();
+        let result = written
+            .and_then(|_| open_for_append(&tmp_path))
+            .and_then(|mut file| file.write_all(&tail).map(|_| file))
+            .and_then(|file| fs::rename(&tmp_path, &path).map(|_| file));
+
+        match result {
+            Ok(file) => {
+                if global.appendonly {
+                    global.aof = Some(file);
+                }
+                eprintln!("Background AOF rewrite finished successfully");
+            }
+            Err(e) => {
+                let _ = fs::remove_file(&tmp_path);
+                eprintln!("Background AOF rewrite failed: {e}");
+            }
+        }
+    });
+    true
+}
+
+fn open_for_append(path: &str) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn encode_aof(db: &HashMap<String, Entry>) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut skipped = 0;
+
+    for (key, entry) in db {
+        if entry.is_expired() {
+            continue;
+        }
+        let (value, expire_at) = (&entry.value, entry.expire_at);
+
+        match value {
+            ValueType::String(_) | ValueType::Int(_) => {
+                let value = value.string_bytes().unwrap_or_default();
+                out.extend(encode_resp_command(&[
+                    b"SET".as_slice(),
+                    key.as_bytes(),
+                    &value,
+                ]));
+            }
+            ValueType::List(items) => {
+                push_batched(&mut out, "RPUSH", key, items.iter().cloned());
+            }
+            ValueType::Set(members) => {
+                push_batched(&mut out, "SADD", key, members.iter().map(string_bytes));
+            }
+            ValueType::Hash(fields) => {
+                let pairs = fields
+                    .iter()
+                    .flat_map(|(field, value)| [field.clone().into_bytes(), string_bytes(value)]);
+                push_batched(&mut out, "HSET", key, pairs);
+            }
+            ValueType::ZSet(zset) => {
+                for (member, score) in zset.iter() {
+                    out.extend(encode_resp_command(&[
+                        "ZADD",
+                        key,
+                        &score.to_string(),
+                        member,
+                    ]));
+                }
+            }
+            ValueType::Stream(stream) => encode_stream(&mut out, key, stream),
+            // No command recreates a vector set, so it can't be replayed
+            ValueType::VectorSet(_) => {
+                skipped += 1;
+                continue;
+            }
+        }
+
+        // Every type shares the absolute expiry, so replay keeps the deadline
+        if let Some(expire_at) = expire_at {
+            out.extend(encode_resp_command(&[
+                "PEXPIREAT",
+                key,
+                &expire_at.to_string(),
+            ]));
+        }
+    }
+
+    if skipped > 0 {
+        eprintln!(
+            "AOF rewrite skipped {} vector set key(s), they won't survive a replay",
+            skipped
+        );
+    }
+    out
+}
+
+fn push_batched(out: &mut Vec<u8>, command: &str, key: &str, items: impl Iterator<Item = Vec<u8>>) {
+    let items: Vec<Vec<u8>> = items.collect();
+    for chunk in items.chunks(AOF_REWRITE_ITEMS_PER_CMD) {
+        let mut args = vec![command.as_bytes(), key.as_bytes()];
+        args.extend(chunk.iter().map(Vec::as_slice));
+        out.extend(encode_resp_command(&args));
+    }
+}
+
+/// A set member or hash value, which are always strings.
+fn string_bytes(value: &ValueType) -> Vec<u8> {
+    value.string_bytes().unwrap_or_default().into_owned()
+}
+
+fn encode_stream(out: &mut Vec<u8>, key: &str, stream: &Stream) {
+    for ((ms, seq), key_val) in &stream.entries {
+        let id = format!("{}-{}", ms, seq);
+        let mut args = vec!["XADD".to_string(), key.to_string(), id];
+        for (field, value) in key_val {
+            args.push(field.clone());
+            args.push(value.clone());
+        }
+        out.extend(encode_resp_command(&args));
+    }
+
+    for (name, group) in &stream.groups {
+        let (ms, seq) = group.last_delivered_id;
+        let mut args = vec![
+            "XGROUP".to_string(),
+            "CREATE".to_string(),
+            key.to_string(),
+            name.clone(),
+            format!("{ms}-{seq}"),
+            "MKSTREAM".to_string(),
+        ];
+        if let Some(entries_read) = group.entries_read {
+            args.push("ENTRIESREAD".to_string());
+            args.push(entries_read.to_string());
+        }
+        out.extend(encode_resp_command(&args));
+
+        for consumer in group.consumers.keys() {
+            out.extend(encode_resp_command(&[
+                "XGROUP",
+                "CREATECONSUMER",
+                key,
+                name,
+                consumer,
+            ]));
+        }
+        for (&(ms, seq), pending) in &group.pending {
+            out.extend(encode_resp_command(&[
+                "XCLAIM",
+                key,
+                name,
+                &pending.consumer,
+                "0",
+                &format!("{ms}-{seq}"),
+                "TIME",
+                &pending.delivery_time.to_string(),
+                "RETRYCOUNT",
+                &pending.delivery_count.to_string(),
+                "FORCE",
+                "JUSTID",
+            ]));
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+
+use crate::structs::global::RedisGlobal;
+use crate::utils::split_args;
+
+// Immutable directives RedisGlobal::init reads as command-line flags:
+// (name, argument count)
+const FLAG_DIRECTIVES: &[(&str, usize)] = &[
+    ("port", 1),
+    ("bind", usize::MAX),
+    ("unixsocket", 1),
+    ("unixsocketperm", 1),
+    ("appendfilename", 1),
+    ("repl-backlog-size", 1),
+    ("io-threads", 1),
+    ("rename-command", 2),
+    ("replicaof", 2),
+];
+
+/// A parsed config file. Immutable directives become flags placed ahead of
+/// the real command line, so the command line wins; the rest are CONFIG
+/// parameters applied through CONFIG SET once the server state exists.
+pub struct ConfigFile {
+    pub flags: Vec<String>,
+    // (line number, parameter, value)
+    pub settings: Vec<(usize, String, String)>,
+}
+
+/// Reads a redis.conf-style file. Unknown directives only warn; malformed
+/// lines fail with their line number.
+pub fn read_config_file(path: &str) -> Result<ConfigFile, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Can't open config file '{}': {}", path, e))?;
+    let mut file = ConfigFile {
+        flags: Vec::new(),
+        settings: Vec::new(),
+    };
+    // save lines add up, as in Redis, and become a single setting
+    let mut save: Option<(usize, Vec<String>)> = None;
+
+    for (index, line) in contents.lines().enumerate() {
+        let line_number = index + 1;
+        let fail = |reason: &str| {
+            format!(
+                "Bad config file line {}: {}: '{}'",
+                line_number, reason, line
+            )
+        };
+        let args = split_config_line(line).map_err(|reason| fail(&reason))?;
+        let Some((directive, values)) = args.split_first() else {
+            continue;
+        };
+        let directive = directive.to_ascii_lowercase();
+
+        if let Some(&(_, count)) = FLAG_DIRECTIVES.iter().find(|(name, _)| *name == directive) {
+            if values.is_empty() || (count != usize::MAX && values.len() != count) {
+                return Err(fail("wrong number of arguments"));
+            }
+            if directive == "replicaof" {
+                file.flags
+                    .extend(["--replicaof".to_string(), values.join(" ")]);
+            } else {
+                file.flags.push(format!("--{}", directive));
+                file.flags.extend_from_slice(values);
+            }
+        } else if directive == "save" {
+            if values.is_empty() {
+                return Err(fail("wrong number of arguments"));
+            }
+            let (_, params) = save.get_or_insert_with(|| (line_number, Vec::new()));
+            if values.iter().all(|value| value.is_empty()) {
+                params.clear();
+            } else {
+                params.extend_from_slice(values);
+            }
+        } else if directive == "client-output-buffer-limit" {
+            if values.len() != 4 {
+                return Err(fail("wrong number of arguments"));
+            }
+            file.settings
+                .push((line_number, directive, values.join(" ")));
+        } else if RedisGlobal::is_config_param(&directive) {
+            if values.len() != 1 {
+                return Err(fail("wrong number of arguments"));
+            }
+            file.settings
+                .push((line_number, directive, values[0].clone()));
+        } else {
+            eprintln!(
+                "Warning: unknown directive '{}' at line {} of {}",
+                directive, line_number, path
+            );
+        }
+    }
+    if let Some((line_number, params)) = save {
+        file.settings
+            .push((line_number, "save".to_string(), params.join(" ")));
+    }
+    Ok(file)
+}
+
+/// Splits a config line into arguments the way Redis does; comments and
+/// blank lines give no arguments.
+pub fn split_config_line(line: &str) -> Result<Vec<String>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(Vec::new());
+    }
+    split_args(line)
+}
+
+/// CONFIG REWRITE: updates the file's directives to the current values and
+/// appends parameters changed at runtime that the file doesn't mention.
+/// Comments, unknown lines and their order are kept.
+pub fn rewrite_config_file(path: &str, global: &RedisGlobal) -> io::Result<()> {
+    let contents = fs::read_to_string(path)?;
+    let mut written = HashSet::new();
+    let mut lines = Vec::new();
+
+    for line in contents.lines() {
+        let directive = split_config_line(line)
+            .ok()
+            .and_then(|args| args.first().map(|arg| arg.to_ascii_lowercase()));
+        match directive.and_then(|name| Some((global.get_config(&name)?, name))) {
+            // Repeated directives such as save collapse into the first one
+            Some((value, name)) => {
+                if written.insert(name.clone()) {
+                    lines.push(format_directive(&name, &value));
+                }
+            }
+            None => lines.push(line.to_string()),
+        }
+    }
+    for name in &global.changed_configs {
+        if !written.contains(name) {
+            if let Some(value) = global.get_config(name) {
+                lines.push(format_directive(name, &value));
+            }
+        }
+    }
+
+    let tmp_path = format!("{}.tmp-{}", path, std::process::id());
+    fs::write(&tmp_path, lines.join("\n") + "\n")?;
+    fs::rename(&tmp_path, path)
+}
+
+fn format_directive(name: &str, value: &str) -> String {
+    // Space-separated lists are written as separate arguments
+    if matches!(name, "save" | "bind" | "client-output-buffer-limit") && !value.is_empty() {
+        return format!("{} {}", name, value);
+    }
+    let needs_quotes = value.is_empty()
+        || value
+            .chars()
+            .any(|c| c.is_whitespace() || c == '"' || c == '\'' || c == '\\');
+    if !needs_quotes {
+        return format!("{} {}", name, value);
+    }
+    let escaped: String = value
+        .chars()
+        .map(|c| match c {
+            '"' => "\\\"".to_string(),
+            '\\' => "\\\\".to_string(),
+            '\n' => "\\n".to_string(),
+            '\r' => "\\r".to_string(),
+            '\t' => "\\t".to_string(),
+            c => c.to_string(),
+        })
+        .collect();
+    format!("{} \"{}\"", name, escaped)
+}
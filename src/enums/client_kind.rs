@@ -0,0 +1,29 @@
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClientKind {
+    Normal,
+    // A replica that completed PSYNC with us
+    Replica,
+    // Our link to the master we replicate from
+    Master,
+}
+
+impl ClientKind {
+    /// The CLIENT LIST flag.
+    pub fn flag(&self) -> &'static str {
+        match self {
+            ClientKind::Normal => "N",
+            ClientKind::Replica => "S",
+            ClientKind::Master => "M",
+        }
+    }
+
+    /// Parses a CLIENT LIST TYPE argument.
+    pub fn from_type_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "normal" => Some(ClientKind::Normal),
+            "replica" | "slave" => Some(ClientKind::Replica),
+            "master" => Some(ClientKind::Master),
+            _ => None,
+        }
+    }
+}
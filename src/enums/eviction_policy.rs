@@ -0,0 +1,31 @@
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EvictionPolicy {
+    // Refuse writes once maxmemory is reached
+    NoEviction,
+    AllKeysRandom,
+    AllKeysLru,
+    // Only keys with a TTL are candidates
+    VolatileLru,
+}
+
+impl EvictionPolicy {
+    /// The maxmemory-policy name.
+    pub fn name(&self) -> &'static str {
+        match self {
+            EvictionPolicy::NoEviction => "noeviction",
+            EvictionPolicy::AllKeysRandom => "allkeys-random",
+            EvictionPolicy::AllKeysLru => "allkeys-lru",
+            EvictionPolicy::VolatileLru => "volatile-lru",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "noeviction" => Some(EvictionPolicy::NoEviction),
+            "allkeys-random" => Some(EvictionPolicy::AllKeysRandom),
+            "allkeys-lru" => Some(EvictionPolicy::AllKeysLru),
+            "volatile-lru" => Some(EvictionPolicy::VolatileLru),
+            _ => None,
+        }
+    }
+}
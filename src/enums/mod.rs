@@ -1,3 +1,6 @@
 pub mod add_stream_entries_result;
-pub mod transaction_result;
+pub mod client_kind;
+pub mod eviction_policy;
+pub mod psync_result;
+pub mod rdb_error;
 pub mod val_type;
@@ -0,0 +1,4 @@
+pub enum PsyncResult {
+    FullResync { replid: String, offset: usize },
+    Continue,
+}
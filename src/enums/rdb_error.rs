@@ -0,0 +1,34 @@
+use std::fmt;
+
+/// Why an RDB file couldn't be loaded. Shown as-is when startup aborts.
+#[derive(Debug)]
+pub enum RdbError {
+    Io {
+        path: String,
+        message: String,
+    },
+    Checksum {
+        path: String,
+    },
+    Corrupt {
+        path: String,
+        offset: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for RdbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RdbError::Io { path, message } => write!(f, "Failed to read {}: {}", path, message),
+            RdbError::Checksum { path } => write!(f, "Wrong signature or checksum in {}", path),
+            RdbError::Corrupt {
+                path,
+                offset,
+                message,
+            } => write!(f, "Bad RDB file {} at byte {}: {}", path, offset, message),
+        }
+    }
+}
+
+impl std::error::Error for RdbError {}
@@ -1,4 +0,0 @@
-pub enum TransactionResult {
-    Some(String),
-    Err(String),
-}
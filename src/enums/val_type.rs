@@ -1,11 +1,18 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::fmt;
 
 use crate::structs::{stream::Stream, zset::ZSet};
 
+#[derive(Clone)]
 pub enum ValueType {
-    String(String),
+    // Strings are binary safe, so they are kept as bytes
+    String(Vec<u8>),
+    // A string value that is the canonical form of an i64, kept as a number
+    // so counters skip the parse and format on each INCR
+    Int(i64),
     Stream(Stream),
-    List(Vec<String>),
+    List(Vec<Vec<u8>>),
     ZSet(ZSet),
     Set(Vec<ValueType>),
     Hash(HashMap<String, ValueType>),
@@ -13,9 +20,30 @@ pub enum ValueType {
 }
 
 impl ValueType {
+    /// A string value, stored as `Int` when reading it back as a number
+    /// gives the same text.
+    pub fn string(value: Vec<u8>) -> Self {
+        let number = std::str::from_utf8(&value)
+            .ok()
+            .and_then(|text| text.parse::<i64>().ok());
+        match number {
+            Some(n) if n.to_string().as_bytes() == value => ValueType::Int(n),
+            _ => ValueType::String(value),
+        }
+    }
+
+    /// The bytes of a string value, None for other types.
+    pub fn string_bytes(&self) -> Option<Cow<'_, [u8]>> {
+        match self {
+            ValueType::String(s) => Some(Cow::Borrowed(s)),
+            ValueType::Int(n) => Some(Cow::Owned(n.to_string().into_bytes())),
+            _ => None,
+        }
+    }
+
     pub fn type_name(&self) -> &'static str {
         match self {
-            ValueType::String(_) => "string",
+            ValueType::String(_) | ValueType::Int(_) => "string",
             ValueType::List(_) => "list",
             ValueType::Set(_) => "set",
             ValueType::ZSet(_) => "zset",
@@ -24,32 +52,128 @@ impl ValueType {
             ValueType::VectorSet(_) => "vectorset",
         }
     }
+
+    /// The OBJECT ENCODING Redis would pick for a value of this size.
+    pub fn encoding(&self) -> &'static str {
+        const LISTPACK_MAX_ENTRIES: usize = 128;
+        match self {
+            ValueType::Int(_) => "int",
+            ValueType::String(s) if s.len() <= 44 => "embstr",
+            ValueType::String(_) => "raw",
+            ValueType::List(items) if items.len() <= LISTPACK_MAX_ENTRIES => "listpack",
+            ValueType::List(_) => "quicklist",
+            ValueType::Set(members)
+                if members.len() <= 512
+                    && members
+                        .iter()
+                        .all(|member| member.to_string().parse::<i64>().is_ok()) =>
+            {
+                "intset"
+            }
+            ValueType::Set(members) if members.len() <= LISTPACK_MAX_ENTRIES => "listpack",
+            ValueType::Set(_) => "hashtable",
+            ValueType::Hash(fields) if fields.len() <= LISTPACK_MAX_ENTRIES => "listpack",
+            ValueType::Hash(_) => "hashtable",
+            ValueType::ZSet(zset) if zset.zcard() <= LISTPACK_MAX_ENTRIES => "listpack",
+            ValueType::ZSet(_) => "skiplist",
+            ValueType::Stream(_) => "stream",
+            ValueType::VectorSet(_) => "vectorset",
+        }
+    }
+
+    /// Number of elements held; a string counts as one.
+    pub fn element_count(&self) -> usize {
+        match self {
+            ValueType::String(_) | ValueType::Int(_) => 1,
+            ValueType::List(items) => items.len(),
+            ValueType::Set(members) => members.len(),
+            ValueType::Hash(fields) => fields.len(),
+            ValueType::ZSet(zset) => zset.zcard(),
+            ValueType::Stream(stream) => stream.entries.len(),
+            ValueType::VectorSet(vectors) => vectors.len(),
+        }
+    }
+
+    /// Rough heap footprint: payload bytes plus a fixed overhead per element.
+    /// Collections are measured on a few elements and scaled up, as MEMORY
+    /// USAGE does, so this stays cheap enough to run after every write.
+    pub fn estimated_size(&self) -> usize {
+        const ELEMENT_OVERHEAD: usize = 16;
+        match self {
+            ValueType::String(s) => s.len(),
+            ValueType::Int(_) => 8,
+            ValueType::List(items) => scaled(
+                items.len(),
+                items.iter().map(|item| item.len() + ELEMENT_OVERHEAD),
+            ),
+            ValueType::Set(members) => scaled(
+                members.len(),
+                members
+                    .iter()
+                    .map(|member| member.estimated_size() + ELEMENT_OVERHEAD),
+            ),
+            ValueType::Hash(fields) => scaled(
+                fields.len(),
+                fields
+                    .iter()
+                    .map(|(field, value)| field.len() + value.estimated_size() + ELEMENT_OVERHEAD),
+            ),
+            ValueType::ZSet(zset) => scaled(
+                zset.zcard(),
+                zset.iter()
+                    .map(|(member, _)| member.len() + 8 + ELEMENT_OVERHEAD),
+            ),
+            ValueType::Stream(stream) => scaled(
+                stream.entries.len(),
+                stream.entries.values().map(|key_val| {
+                    let fields: usize = key_val
+                        .iter()
+                        .map(|(field, value)| field.len() + value.len())
+                        .sum();
+                    fields + 16 + ELEMENT_OVERHEAD
+                }),
+            ),
+            ValueType::VectorSet(vectors) => scaled(
+                vectors.len(),
+                vectors
+                    .iter()
+                    .map(|vector| vector.len() * 4 + ELEMENT_OVERHEAD),
+            ),
+        }
+    }
+}
+
+/// Total of `count` element sizes, extrapolated from the first few.
+fn scaled(count: usize, sizes: impl Iterator<Item = usize>) -> usize {
+    const SAMPLES: usize = 5;
+    let (measured, total) = sizes.take(SAMPLES).fold((0, 0), |(measured, total), size| {
+        (measured + 1, total + size)
+    });
+    (total * count).checked_div(measured).unwrap_or(0)
 }
 
-impl ToString for ValueType {
-    fn to_string(&self) -> String {
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            ValueType::String(s) => s.clone(),
+            ValueType::String(s) => write!(f, "{}", String::from_utf8_lossy(s)),
+            ValueType::Int(n) => write!(f, "{}", n),
             ValueType::List(list) => {
-                let items: Vec<String> = list.iter().map(|v| v.to_string()).collect();
-                format!("[{}]", items.join(", "))
+                let items: Vec<Cow<str>> =
+                    list.iter().map(|v| String::from_utf8_lossy(v)).collect();
+                write!(f, "[{}]", items.join(", "))
             }
             ValueType::Set(set) => {
                 let items: Vec<String> = set.iter().map(|v| v.to_string()).collect();
-                format!("{{{}}}", items.join(", "))
-            }
-            ValueType::ZSet(zset) => {
-                // TODO: FINISH TOSTRING FOR THIS
-                format!("")
+                write!(f, "{{{}}}", items.join(", "))
             }
+            // TODO: FINISH TOSTRING FOR THIS
+            ValueType::ZSet(_) => Ok(()),
             ValueType::Hash(hash) => {
-                let items: Vec<String> = hash
-                    .iter()
-                    .map(|(k, v)| format!("{}: {}", k, v.to_string()))
-                    .collect();
-                format!("{{{}}}", items.join(", "))
+                let items: Vec<String> =
+                    hash.iter().map(|(k, v)| format!("{}: {}", k, v)).collect();
+                write!(f, "{{{}}}", items.join(", "))
             }
-            ValueType::Stream(stream) => stream.to_string(),
+            ValueType::Stream(stream) => write!(f, "{}", stream),
             ValueType::VectorSet(vectors) => {
                 let items: Vec<String> = vectors
                     .iter()
@@ -58,7 +182,7 @@ impl ToString for ValueType {
                         format!("[{}]", nums.join(", "))
                     })
                     .collect();
-                format!("[{}]", items.join(", "))
+                write!(f, "[{}]", items.join(", "))
             }
         }
     }
@@ -0,0 +1,48 @@
+//! Error replies worded exactly like Redis's, since clients and test suites
+//! match on them. Each message starts with its error code (ERR, WRONGTYPE,
+//! ...) and is written as is by `write_error`.
+
+use crate::utils::escape_repr;
+
+pub const SYNTAX: &str = "ERR syntax error";
+pub const NOT_AN_INTEGER: &str = "ERR value is not an integer or out of range";
+pub const NOT_A_FLOAT: &str = "ERR value is not a valid float";
+pub const INVALID_STREAM_ID: &str = "ERR Invalid stream ID specified as stream command argument";
+pub const NO_SUCH_KEY: &str = "ERR no such key";
+pub const WRONGTYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+pub const NOAUTH: &str = "NOAUTH Authentication required.";
+
+/// `command` is lowercased, as Redis names commands in its errors.
+/// Subcommands are given as "container|subcommand".
+pub fn wrong_arity(command: &str) -> String {
+    format!(
+        "ERR wrong number of arguments for '{}' command",
+        command.to_ascii_lowercase()
+    )
+}
+
+/// Arguments are quoted until 128 bytes are shown.
+pub fn unknown_command(command: &str, args: &[String]) -> String {
+    let mut shown = String::new();
+    for arg in args {
+        if shown.len() >= 128 {
+            break;
+        }
+        let arg: String = escape_repr(arg).chars().take(128 - shown.len()).collect();
+        shown.push_str(&format!("'{}' ", arg));
+    }
+    let command: String = escape_repr(command).chars().take(128).collect();
+    format!(
+        "ERR unknown command '{}', with args beginning with: {}",
+        command, shown
+    )
+}
+
+pub fn unknown_subcommand(command: &str, subcommand: &str) -> String {
+    let subcommand: String = escape_repr(subcommand).chars().take(128).collect();
+    format!(
+        "ERR unknown subcommand '{}'. Try {} HELP.",
+        subcommand,
+        command.to_ascii_uppercase()
+    )
+}
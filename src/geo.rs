@@ -80,12 +80,20 @@ pub fn decode(geo_code: u64) -> (f64, f64) {
     convert_grid_numbers_to_coordinates(grid_latitude_number, grid_longitude_number)
 }
 
+/// Formats a coordinate the way Redis replies with it: 17 decimal places with
+/// trailing zeros trimmed.
+pub fn format_coordinate(value: f64) -> String {
+    let formatted = format!("{:.17}", value);
+    let trimmed = formatted.trim_end_matches('0').trim_end_matches('.');
+    trimmed.to_string()
+}
+
 pub fn validate_longitude(lon: f64) -> bool {
-    lon >= -180.0 && lon <= 180.0
+    (-180.0..=180.0).contains(&lon)
 }
 
 pub fn validate_latitude(lat: f64) -> bool {
-    lat >= -85.05112878 && lat <= 85.05112878
+    (-85.05112878..=85.05112878).contains(&lat)
 }
 
 pub fn geo_distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
@@ -120,3 +128,82 @@ pub fn haversine(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
         + lat1.to_radians().cos() * lat2.to_radians().cos() * (d_lon / 2.0).sin().powi(2);
     2.0 * EARTH_RADIUS_METERS * a.sqrt().asin()
 }
+
+pub fn unit_to_meters(unit: &str) -> Option<f64> {
+    match unit.to_ascii_lowercase().as_str() {
+        "m" => Some(1.0),
+        "km" => Some(1000.0),
+        "mi" => Some(1609.34),
+        "ft" => Some(0.3048),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum GeoShape {
+    Radius(f64),   // meters
+    Box(f64, f64), // width, height in meters
+}
+
+#[derive(Debug)]
+pub struct GeoMatch {
+    pub member: String,
+    pub distance: f64, // meters
+    pub hash: u64,
+    pub longitude: f64,
+    pub latitude: f64,
+}
+
+/// Finds the members inside `shape` around (lon, lat). A bounding box prefilter
+/// rejects most members cheaply before the exact distance check. `limit` stops the
+/// scan early (COUNT ANY); results are unsorted.
+pub fn search<'a>(
+    members: impl Iterator<Item = (&'a String, &'a f64)>,
+    lon: f64,
+    lat: f64,
+    shape: GeoShape,
+    limit: Option<usize>,
+) -> Vec<GeoMatch> {
+    let reach = match shape {
+        GeoShape::Radius(radius) => radius,
+        GeoShape::Box(width, height) => (width / 2.0).hypot(height / 2.0),
+    };
+    let (min_lat, max_lat, min_lon, max_lon) = bounding_box(lat, lon, reach);
+    let half_lon_span = (max_lon - min_lon) / 2.0;
+
+    let mut matches = Vec::new();
+    for (member, score) in members {
+        if limit.is_some_and(|limit| matches.len() >= limit) {
+            break;
+        }
+
+        let hash = *score as u64;
+        let (plat, plon) = decode(hash);
+
+        // Compare longitudes on the circle so boxes crossing ±180 still match
+        let lon_delta = ((plon - lon + 540.0) % 360.0) - 180.0;
+        if plat < min_lat || plat > max_lat || lon_delta.abs() > half_lon_span {
+            continue;
+        }
+
+        let distance = haversine(lat, lon, plat, plon);
+        let inside = match shape {
+            GeoShape::Radius(radius) => distance <= radius,
+            GeoShape::Box(width, height) => {
+                let lat_distance = EARTH_RADIUS_METERS * (plat - lat).to_radians().abs();
+                let lon_distance = haversine(plat, plon, plat, lon);
+                lat_distance <= height / 2.0 && lon_distance <= width / 2.0
+            }
+        };
+        if inside {
+            matches.push(GeoMatch {
+                member: member.clone(),
+                distance,
+                hash,
+                longitude: plon,
+                latitude: plat,
+            });
+        }
+    }
+    matches
+}
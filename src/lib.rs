@@ -1,6 +1,10 @@
+pub mod aof;
+pub mod config_file;
 pub mod enums;
+pub mod error;
 pub mod geo;
 pub mod rdb;
+pub mod replication;
 pub mod structs;
 pub mod types;
 pub mod utils;
@@ -1,18 +1,48 @@
-use std::collections::HashMap;
-use std::io::{self, Read};
-use std::net::{Shutdown, TcpListener, TcpStream};
-use std::sync::mpsc::TryRecvError;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixListener;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use std::{env, thread};
 
+use codecrafters_redis::aof::load_aof;
+use codecrafters_redis::rdb::save::spawn_bgsave;
 use codecrafters_redis::rdb::start_up::start_up;
+use codecrafters_redis::replication::spawn_master_link;
+use codecrafters_redis::structs::client_stream::ClientStream;
 use codecrafters_redis::structs::connection::Connection;
-use codecrafters_redis::structs::global::RedisGlobal;
-use codecrafters_redis::structs::request::Request;
+use codecrafters_redis::structs::global::{RedisGlobal, PROTECTED_MODE_DENIED};
+use codecrafters_redis::structs::keyspace::Keyspace;
+use codecrafters_redis::structs::outbox::{outbox, Outbox, OutboxReceiver};
+use codecrafters_redis::structs::replica::{record_replica_ack, remove_replica, replica_link};
+use codecrafters_redis::structs::request::{Request, RequestBuffer};
 use codecrafters_redis::structs::runner::Runner;
-use codecrafters_redis::types::{DbConfigType, DbType, RedisGlobalType};
-use codecrafters_redis::utils::{update_replica_offsets, write_array};
+use codecrafters_redis::structs::socket::Socket;
+use codecrafters_redis::structs::transaction::Transaction;
+use codecrafters_redis::structs::worker_pool::WorkerPool;
+use codecrafters_redis::types::{DbType, RedisGlobalType};
+use codecrafters_redis::utils::{active_expire_cycle, ping_replicas, write_error};
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+
+// Replies pending past this many bytes are written without waiting for the
+// rest of the pipelined batch
+const REPLY_FLUSH_THRESHOLD: usize = 64 * 1024;
+
+// Threads running commands: the --io-threads workers plus one per blocked
+// command, which gives its worker back while it waits
+const MAX_COMMAND_THREADS: usize = 65536;
+
+// How often an idle connection wakes up to check the idle timeout
+const IDLE_CHECK_PERIOD: Duration = Duration::from_secs(1);
+
+// Active expiration runs ten times a second, for at most a quarter of it
+const ACTIVE_EXPIRE_PERIOD: Duration = Duration::from_millis(100);
+const ACTIVE_EXPIRE_BUDGET: Duration = Duration::from_millis(25);
 
 fn main() {
     println!("Logs from your program will appear here!");
@@ -20,240 +50,556 @@ fn main() {
 
     let global_state = Arc::new(Mutex::new(RedisGlobal::init(env::args())));
 
-    let port = {
+    let (port, bind, unixsocket, unixsocketperm) = {
         let global = global_state.lock().unwrap();
-        global.port.clone()
+        (
+            global.port.clone(),
+            global.bind.clone(),
+            global.unixsocket.clone(),
+            global.unixsocketperm,
+        )
     };
 
-    let bind_addr = format!("127.0.0.1:{port}");
-    let listener = TcpListener::bind(&bind_addr).expect(&format!("Failed to bind to {bind_addr}"));
-    println!("Listening on {bind_addr}");
-
-    let db = Arc::new(Mutex::new(HashMap::new()));
-    let db_config = Arc::new(Mutex::new(HashMap::new()));
-
-    start_up(
-        Arc::clone(&db),
-        Arc::clone(&db_config),
-        Arc::clone(&global_state),
-    );
-    spawn_cleanup_thread(Arc::clone(&db), Arc::clone(&db_config));
-    spawn_replica_handler_thread(
-        Arc::clone(&db),
-        Arc::clone(&db_config),
-        Arc::clone(&global_state),
-    );
+    let mut listeners = Vec::new();
+    for ip in bind {
+        let bind_addr = SocketAddr::new(ip, port.parse().unwrap_or(6379));
+        match TcpListener::bind(bind_addr) {
+            Ok(listener) => {
+                println!("Listening on {bind_addr}");
+                listeners.push(listener);
+            }
+            Err(e) => eprintln!("Failed to bind to {bind_addr}: {e}"),
+        }
+    }
+    if listeners.is_empty() {
+        eprintln!("Failed listening on any address, exiting");
+        std::process::exit(1);
+    }
+    let unix_listener = unixsocket
+        .as_deref()
+        .map(|path| bind_unix_socket(path, unixsocketperm));
+
+    let db = Arc::new(Keyspace::new());
+
+    if let Err(e) = start_up(Arc::clone(&db), Arc::clone(&global_state)) {
+        eprintln!("{e}");
+        // Nothing was loaded, so the dataset is simply empty
+        if !global_state.lock().unwrap().ignore_rdb_errors {
+            std::process::exit(1);
+        }
+        eprintln!("Starting with an empty dataset (--ignore-rdb-errors)");
+    }
+    if global_state.lock().unwrap().appendonly {
+        load_aof(&db, &global_state);
+    }
+    spawn_cleanup_thread(Arc::clone(&db), Arc::clone(&global_state));
+    spawn_save_cron_thread(Arc::clone(&db), Arc::clone(&global_state));
+    spawn_replica_handler_thread(Arc::clone(&db), Arc::clone(&global_state));
 
     let duration = start.elapsed();
     eprintln!("initialization took {:?}", duration);
 
-    listen_for_clients(listener, db, db_config, global_state);
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .max_blocking_threads(MAX_COMMAND_THREADS)
+        .build()
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to start the event loop: {e}");
+            std::process::exit(1);
+        });
+    let pool = Arc::new(WorkerPool::new(global_state.lock().unwrap().io_threads));
+    runtime.block_on(async {
+        // Every listener feeds the same handling; SHUTDOWN ends the first one
+        let listener = listeners.remove(0);
+        for extra in listeners {
+            tokio::spawn(listen_for_clients(
+                extra,
+                Arc::clone(&db),
+                Arc::clone(&global_state),
+                Arc::clone(&pool),
+            ));
+        }
+        if let Some(listener) = unix_listener {
+            tokio::spawn(listen_for_clients(
+                listener,
+                Arc::clone(&db),
+                Arc::clone(&global_state),
+                Arc::clone(&pool),
+            ));
+        }
+        listen_for_clients(listener, db, Arc::clone(&global_state), pool).await;
+    });
+    // Workers parked in blocking commands would otherwise hold up the exit
+    runtime.shutdown_background();
+
+    if let Some(aof) = &global_state.lock().unwrap().aof {
+        let _ = aof.sync_all();
+    }
+    if let Some(path) = &unixsocket {
+        let _ = fs::remove_file(path);
+    }
+    eprintln!("Redis is now ready to exit, bye bye...");
 }
 
-pub fn spawn_replica_handler_thread(
-    db: DbType,
-    db_config: DbConfigType,
-    global_state: RedisGlobalType,
-) {
+/// Listens on the unix socket at `path`, replacing a stale socket file left
+/// by an earlier run. Failing to bind is fatal, as for the TCP listeners.
+fn bind_unix_socket(path: &str, perm: Option<u32>) -> UnixListener {
+    let _ = fs::remove_file(path);
+    let listener = UnixListener::bind(path).unwrap_or_else(|e| {
+        eprintln!("Failed opening unix socket {path}: {e}");
+        std::process::exit(1);
+    });
+    if let Some(perm) = perm {
+        if let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(perm)) {
+            eprintln!("Failed to set permissions on unix socket {path}: {e}");
+        }
+    }
+    println!("Listening on unix socket {path}");
+    listener
+}
+
+pub fn spawn_replica_handler_thread(db: DbType, global_state: RedisGlobalType) {
     let is_master = {
         let global_guard = global_state.lock().unwrap();
         global_guard.is_master()
     };
 
-    if is_master {
-        thread::spawn(move || loop {
-            thread::sleep(Duration::from_secs(1));
-            update_replica_offsets(&global_state);
-        });
-    } else {
-        thread::spawn(move || {
-            let master_stream_arc = {
-                let global_guard = global_state.lock().unwrap();
-                match &global_guard.master_stream {
-                    Some(stream_arc) => Arc::clone(stream_arc),
-                    None => {
-                        eprintln!("No master stream found; aborting replication thread");
-                        return;
-                    }
-                }
-            };
-
-            let mut connection_info = Connection::default();
-            let mut local_offset = 0;
-            let mut read_buffer: Vec<u8> = Vec::new();
-
-            loop {
-                let mut temp = [0u8; 1024];
-                let mut stream_guard = master_stream_arc.lock().unwrap();
-                let bytes_read = match stream_guard.read(&mut temp) {
-                    Ok(0) => {
-                        eprintln!("Master closed connection");
-                        break;
-                    }
-                    Ok(n) => n,
-                    Err(e) => {
-                        eprintln!("Read error from master: {e}");
-                        break;
-                    }
-                };
-
-                read_buffer.extend_from_slice(&temp[..bytes_read]);
-
-                while let Some((request, consumed)) = Request::try_parse(&read_buffer) {
-                    local_offset += consumed;
-
-                    let mut runner = Runner::new(request.args);
-                    runner.run(
-                        &mut stream_guard,
-                        &db,
-                        &db_config,
-                        &global_state,
-                        &mut connection_info,
-                        &local_offset,
-                        true,
-                    );
-                    read_buffer.drain(..consumed);
-                }
-            }
-
-            eprintln!("Replication thread exiting; consider retrying sync with master");
-        });
+    if !is_master {
+        spawn_master_link(db, Arc::clone(&global_state));
     }
+
+    // Runs regardless of the starting role, since REPLICAOF NO ONE can
+    // promote this server later
+    thread::spawn(move || loop {
+        let period = {
+            let global_guard = global_state.lock().unwrap();
+            global_guard.repl_ping_replica_period
+        };
+        thread::sleep(Duration::from_secs(period));
+        ping_replicas(&global_state);
+    });
 }
 
-fn spawn_cleanup_thread(db: DbType, db_config: DbConfigType) {
+/// Starts a BGSAVE whenever one of the configured save points is reached.
+fn spawn_save_cron_thread(db: DbType, global_state: RedisGlobalType) {
     thread::spawn(move || loop {
         thread::sleep(Duration::from_secs(1));
 
-        let expired_keys: Vec<String> = {
-            let config = db_config.lock().unwrap();
-            config
-                .iter()
-                .filter_map(|(key, cfg)| {
-                    if cfg.is_expired() {
-                        Some(key.clone())
-                    } else {
-                        None
-                    }
-                })
-                .collect()
-        };
+        let should_save = global_state.lock().unwrap().save_point_reached();
+        if should_save && spawn_bgsave(&db, &global_state) {
+            eprintln!("Save point reached, background saving started");
+        }
+    });
+}
+
+/// Actively expires keys on the master. Replicas never expire on their own;
+/// they wait for the DEL the master propagates.
+fn spawn_cleanup_thread(db: DbType, global_state: RedisGlobalType) {
+    thread::spawn(move || {
+        let mut cursor = 0;
+        loop {
+            thread::sleep(ACTIVE_EXPIRE_PERIOD);
 
-        if !expired_keys.is_empty() {
-            let mut db = db.lock().unwrap();
-            let mut config = db_config.lock().unwrap();
-            for key in expired_keys {
-                db.remove(&key);
-                config.remove(&key);
-                println!("Expired key removed: {}", key);
+            let should_expire = {
+                let global = global_state.lock().unwrap();
+                global.is_master() && global.active_expire
+            };
+            if should_expire {
+                active_expire_cycle(&db, &global_state, &mut cursor, ACTIVE_EXPIRE_BUDGET);
             }
         }
     });
 }
 
-fn listen_for_clients(
-    listener: TcpListener,
+/// A TCP or unix listener on the event loop. Clients from either are
+/// handled the same way.
+enum Listener {
+    Tcp(tokio::net::TcpListener),
+    Unix(tokio::net::UnixListener),
+}
+
+impl TryFrom<TcpListener> for Listener {
+    type Error = io::Error;
+
+    fn try_from(listener: TcpListener) -> io::Result<Self> {
+        listener.set_nonblocking(true)?;
+        tokio::net::TcpListener::from_std(listener).map(Listener::Tcp)
+    }
+}
+
+impl TryFrom<UnixListener> for Listener {
+    type Error = io::Error;
+
+    fn try_from(listener: UnixListener) -> io::Result<Self> {
+        listener.set_nonblocking(true)?;
+        tokio::net::UnixListener::from_std(listener).map(Listener::Unix)
+    }
+}
+
+impl Listener {
+    async fn accept(&self) -> io::Result<Socket> {
+        match self {
+            Listener::Tcp(listener) => Ok(Socket::from(listener.accept().await?.0.into_std()?)),
+            Listener::Unix(listener) => Ok(Socket::from(listener.accept().await?.0.into_std()?)),
+        }
+    }
+}
+
+async fn listen_for_clients(
+    listener: impl TryInto<Listener, Error = io::Error>,
     db: DbType,
-    db_config: DbConfigType,
     global_state: RedisGlobalType,
+    pool: Arc<WorkerPool>,
 ) {
-    for stream in listener.incoming() {
-        let db = Arc::clone(&db);
-        let db_config = Arc::clone(&db_config);
-        let global_state = Arc::clone(&global_state);
-        match stream {
-            Ok(stream) => {
-                thread::spawn(move || {
-                    handle_connection(stream, db, db_config, global_state);
-                });
-            }
+    let listener = match listener.try_into() {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Failed to listen on the event loop: {e}");
+            return;
+        }
+    };
+    let mut shutdown = global_state.lock().unwrap().shutdown.subscribe();
+    loop {
+        let accepted = tokio::select! {
+            _ = shutdown.wait_for(|&down| down) => break,
+            accepted = async {
+                // While the worker queue is full, new clients wait in the listen backlog
+                pool.until_queue_has_room().await;
+                listener.accept().await
+            } => accepted,
+        };
+        match accepted {
+            Ok(socket) => accept_client(socket, &db, &global_state, &pool),
             Err(e) => eprintln!("accept error: {e}"),
         }
     }
 }
 
-fn handle_connection(
-    mut stream: TcpStream,
-    db: DbType,
-    db_config: DbConfigType,
-    global_state: RedisGlobalType,
+/// A client's socket registered with the event loop. The connection's task
+/// reads from it and its writer task writes to it.
+struct ClientSocket(AsyncFd<Socket>);
+
+impl ClientSocket {
+    /// Waits for input and appends it to `buffer`. Ok(0) means the client
+    /// closed its side.
+    async fn read_into(&self, buffer: &mut RequestBuffer) -> io::Result<usize> {
+        self.0
+            .async_io(Interest::READABLE, |mut socket| {
+                // Redis's IO buffer size
+                let mut temp = [0u8; 16 * 1024];
+                let n = socket.read(&mut temp)?;
+                buffer.extend(&temp[..n]);
+                Ok(n)
+            })
+            .await
+    }
+
+    async fn write_all(&self, mut bytes: &[u8]) -> io::Result<()> {
+        while !bytes.is_empty() {
+            let n = self
+                .0
+                .async_io(Interest::WRITABLE, |mut socket| socket.write(bytes))
+                .await?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            bytes = &bytes[n..];
+        }
+        Ok(())
+    }
+}
+
+/// A connection's state, handed to a worker thread for each batch of
+/// requests and back.
+struct Session {
+    client_stream: ClientStream,
+    connection: Connection,
+    local_offset: usize,
+}
+
+/// Serves a new connection on the event loop, unless maxclients or protected
+/// mode turns it away. Everything written to it goes out through one writer
+/// task, in order.
+fn accept_client(
+    socket: Socket,
+    db: &DbType,
+    global_state: &RedisGlobalType,
+    pool: &Arc<WorkerPool>,
 ) {
-    let mut connection_info = Connection::default();
-    let mut local_offset = 0;
-    let mut read_buffer: Vec<u8> = Vec::new();
+    let client_socket = match AsyncFd::new(socket.share()) {
+        Ok(fd) => Arc::new(ClientSocket(fd)),
+        Err(e) => {
+            eprintln!("Failed to register client socket: {e}");
+            return;
+        }
+    };
+    let (outbox, frames) = outbox();
+    tokio::spawn(write_frames(Arc::clone(&client_socket), frames));
 
-    stream
-        .set_read_timeout(Some(Duration::from_millis(100)))
-        .unwrap_or(());
+    let peer = socket.peer_ip();
+    let rejection = {
+        let mut global = global_state.lock().unwrap();
+        if !global.accepts_new_client() {
+            global.stat_rejected_conn += 1;
+            Some("ERR max number of clients reached")
+        } else if peer.is_some_and(|ip| global.denies_in_protected_mode(ip)) {
+            Some(PROTECTED_MODE_DENIED)
+        } else {
+            None
+        }
+    };
+    if let Some(reply) = rejection {
+        // The writer closes the connection once the outbox is dropped
+        let _ = outbox.send(format!("-{}\r\n", reply).into());
+        return;
+    }
 
-    loop {
-        if connection_info.is_slave_established {
+    tokio::spawn(handle_connection(
+        client_socket,
+        socket,
+        outbox,
+        Arc::clone(db),
+        Arc::clone(global_state),
+        Arc::clone(pool),
+    ));
+}
+
+/// Writes a connection's frames in order, closing its side of the socket
+/// once every outbox is dropped or a write fails.
+async fn write_frames(client_socket: Arc<ClientSocket>, mut frames: OutboxReceiver) {
+    while let Some(frame) = frames.recv().await {
+        if client_socket.write_all(&frame).await.is_err() {
             break;
         }
+    }
+    let _ = client_socket.0.get_ref().shutdown(Shutdown::Write);
+}
 
-        // Check for pub/sub mode (any active subscriptions)
-        let pubsub_channels = !connection_info.subscribed_channels.is_empty();
-
-        if pubsub_channels {
-            let channel_names: Vec<String> = connection_info
-                .subscribed_channels
-                .keys()
-                .cloned()
-                .collect();
-
-            for channel in &channel_names {
-                if let Some(receiver) = connection_info.subscribed_channels.get(channel) {
-                    match receiver.try_recv() {
-                        Ok(msg) => {
-                            // RESP:  ["message", channel, message]
-                            let _ = write_array(
-                                &mut stream,
-                                &[Some("message"), Some(channel), Some(&msg)],
-                            );
-                        }
-                        Err(TryRecvError::Empty) => {} // No message right now
-                        Err(TryRecvError::Disconnected) => {}
+/// Waits for requests and runs each pipelined batch once `pool` has a worker
+/// for it, so an idle connection holds no thread. The socket is still read while a
+/// batch runs, to notice a blocked client hanging up.
+///
+/// The socket closes once the connection's state is dropped and its writer
+/// has sent everything queued, including the reply to a QUIT.
+async fn handle_connection(
+    reader: Arc<ClientSocket>,
+    socket: Socket,
+    outbox: Outbox,
+    db: DbType,
+    global_state: RedisGlobalType,
+    pool: Arc<WorkerPool>,
+) {
+    let connection_info = Connection::new(outbox.clone());
+    connection_info.info.lock().unwrap().attach(&socket);
+    let hung_up = Arc::new(AtomicBool::new(false));
+    let client_stream = ClientStream::for_connection(socket, outbox, Arc::clone(&hung_up));
+    {
+        let mut global = global_state.lock().unwrap();
+        global.stat_numconnections += 1;
+        global
+            .clients
+            .insert(connection_info.id, Arc::clone(&connection_info.info));
+    }
+    let mut session = Session {
+        client_stream,
+        connection: connection_info,
+        local_offset: 0,
+    };
+    let mut read_buffer = RequestBuffer::new();
+
+    loop {
+        // Requests read while the previous batch ran go first
+        read_buffer.max_bulk_len = global_state.lock().unwrap().proto_max_bulk_len;
+        let (requests, protocol_error) = take_requests(&mut read_buffer);
+        if requests.is_empty() && protocol_error.is_none() {
+            // Wake up now and then to check the idle timeout
+            match tokio::time::timeout(IDLE_CHECK_PERIOD, reader.read_into(&mut read_buffer)).await
+            {
+                Ok(Ok(0)) => break,
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => {
+                    eprintln!("read error from api handler: {e}");
+                    break;
+                }
+                Err(_) => {
+                    let timeout = global_state.lock().unwrap().timeout;
+                    if session.connection.is_idle_past(timeout) {
+                        eprintln!("Closing idle client {}", session.connection.id);
+                        break;
                     }
                 }
             }
+            continue;
         }
 
-        let mut temp = [0u8; 1024];
-        match stream.read(&mut temp) {
-            Ok(0) => {
-                let _ = stream.shutdown(Shutdown::Both);
-                break;
+        session.connection.worker = Some(pool.acquire().await);
+        let job = tokio::task::spawn_blocking({
+            let db = Arc::clone(&db);
+            let global_state = Arc::clone(&global_state);
+            move || {
+                // A panicking command still leaves the session for teardown
+                let leftover = panic::catch_unwind(AssertUnwindSafe(|| {
+                    run_batch(&mut session, requests, protocol_error, &db, &global_state)
+                }));
+                (session, leftover)
             }
-            Ok(n) => {
-                read_buffer.extend_from_slice(&temp[..n]);
+        });
+        tokio::pin!(job);
+        let finished = loop {
+            tokio::select! {
+                finished = &mut job => break finished,
+                read = reader.read_into(&mut read_buffer), if !hung_up.load(Ordering::Relaxed) => {
+                    if !matches!(read, Ok(n) if n > 0) {
+                        hung_up.store(true, Ordering::Relaxed);
+                    }
+                }
             }
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                continue;
+        };
+        let leftover;
+        (session, leftover) = match finished {
+            Ok(finished) => finished,
+            // Only when the runtime is shutting down, taking the session with it
+            Err(e) => {
+                eprintln!("Command worker failed: {e}");
+                return;
             }
-            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
-                continue;
+        };
+        session.connection.worker = None;
+        let Ok(leftover) = leftover else {
+            eprintln!("Command panicked, closing client {}", session.connection.id);
+            break;
+        };
+
+        if session.connection.is_slave_established {
+            if let Some(replica_port) = &session.connection.slave_port {
+                serve_replica_link(
+                    &reader,
+                    leftover,
+                    &mut read_buffer,
+                    replica_port,
+                    &global_state,
+                )
+                .await;
             }
+            break;
+        }
+        if session.connection.is_killed()
+            || session.client_stream.is_closing()
+            || hung_up.load(Ordering::Relaxed)
+        {
+            break;
+        }
+    }
+
+    // Drop this connection's subscriptions, watches and pending transaction so
+    // no server-side state outlives it
+    let connection_info = &mut session.connection;
+    let mut global = global_state.lock().unwrap();
+    for channel in &connection_info.subscribed_channels {
+        global.unsubscribe(channel, connection_info.id);
+    }
+    for pattern in &connection_info.subscribed_patterns {
+        global.punsubscribe(pattern, connection_info.id);
+    }
+    connection_info.unwatch_all(&mut global);
+    connection_info.transaction = Transaction::new();
+    global.clients.remove(&connection_info.id);
+}
+
+/// The complete requests in `read_buffer`, and the protocol error that cut
+/// them short, if any.
+fn take_requests(read_buffer: &mut RequestBuffer) -> (Vec<(Request, usize)>, Option<String>) {
+    let mut requests = Vec::new();
+    loop {
+        match read_buffer.next_request() {
+            Ok(Some(parsed)) => requests.push(parsed),
+            Ok(None) => return (requests, None),
+            Err(e) => return (requests, Some(e)),
+        }
+    }
+}
+
+/// Runs a batch of pipelined requests on the calling worker thread and
+/// flushes their replies. Returns the arguments of the requests left unrun
+/// because the connection turned into a replica link or was closed.
+fn run_batch(
+    session: &mut Session,
+    requests: Vec<(Request, usize)>,
+    protocol_error: Option<String>,
+    db: &DbType,
+    global_state: &RedisGlobalType,
+) -> Vec<Vec<Vec<u8>>> {
+    let mut requests = requests.into_iter();
+    let mut stopped = false;
+    for (request, consumed) in requests.by_ref() {
+        session.local_offset += consumed;
+
+        let mut runner = Runner::new(request.args);
+        runner.run(
+            &mut session.client_stream,
+            db,
+            global_state,
+            &mut session.connection,
+            &session.local_offset,
+            false,
+        );
+        if session.client_stream.pending() >= REPLY_FLUSH_THRESHOLD {
+            let _ = session.client_stream.flush();
+        }
+
+        // Whatever follows PSYNC belongs to the replication link
+        if session.connection.is_slave_established
+            || session.connection.is_killed()
+            || session.client_stream.is_closing()
+        {
+            stopped = true;
+            break;
+        }
+    }
+    if let Some(e) = protocol_error.filter(|_| !stopped) {
+        // The stream can't be resynchronized after a protocol error
+        write_error(&mut session.client_stream, &format!("ERR {}", e));
+        let _ = session.client_stream.shutdown(Shutdown::Both);
+    }
+    // The whole batch of replies goes out in one write
+    let _ = session.client_stream.flush();
+    requests.map(|(request, _)| request.args).collect()
+}
+
+/// Serves a replica's connection after PSYNC: reads `REPLCONF ACK <offset>`
+/// replies until the link drops or its writer fails, starting with whatever
+/// the connection had already buffered, then removes the replica.
+async fn serve_replica_link(
+    reader: &ClientSocket,
+    leftover: Vec<Vec<Vec<u8>>>,
+    read_buffer: &mut RequestBuffer,
+    replica_port: &str,
+    global_state: &RedisGlobalType,
+) {
+    let Some((link, outbox)) = replica_link(global_state, replica_port) else {
+        return;
+    };
+    for args in &leftover {
+        record_replica_ack(global_state, replica_port, args);
+    }
+
+    loop {
+        while let Ok(Some((request, _))) = read_buffer.next_request() {
+            record_replica_ack(global_state, replica_port, &request.args);
+        }
+        let read = tokio::select! {
+            read = reader.read_into(read_buffer) => read,
+            _ = outbox.closed() => break,
+        };
+        match read {
+            Ok(0) => break,
+            Ok(_) => {}
             Err(e) => {
-                eprintln!("read error from api handler: {e}");
+                eprintln!("Error reading from replica {}: {:?}", replica_port, e);
                 break;
             }
-        };
-
-        while let Some((request, consumed)) = Request::try_parse(&read_buffer) {
-            local_offset += consumed;
-
-            let mut runner = Runner::new(request.args);
-            runner.run(
-                &mut stream,
-                &db,
-                &db_config,
-                &global_state,
-                &mut connection_info,
-                &local_offset,
-                false,
-            );
-
-            read_buffer.drain(..consumed);
         }
     }
+
+    remove_replica(global_state, replica_port, &link);
 }
@@ -1,2 +1,4 @@
+pub mod crc;
+pub mod save;
 pub mod start_up;
 pub mod structs;
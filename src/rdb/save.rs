@@ -0,0 +1,410 @@
+use std::fs;
+use std::io;
+use std::thread;
+
+use crate::{
+    enums::val_type::ValueType,
+    rdb::crc::crc64,
+    structs::{consumer_group::now_ms, keyspace::Entry, stream::Stream},
+    types::{DbType, RedisGlobalType},
+};
+
+const RDB_VERSION: &[u8] = b"REDIS0011";
+pub const REDIS_VER: &str = "7.2.0";
+
+pub const RDB_TYPE_STRING: u8 = 0;
+pub const RDB_TYPE_LIST: u8 = 1;
+pub const RDB_TYPE_SET: u8 = 2;
+pub const RDB_TYPE_HASH: u8 = 4;
+pub const RDB_TYPE_ZSET: u8 = 3;
+pub const RDB_TYPE_ZSET_2: u8 = 5;
+pub const RDB_TYPE_STREAM_LISTPACKS: u8 = 15;
+pub const RDB_TYPE_STREAM_LISTPACKS_2: u8 = 19;
+pub const RDB_TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+// Compact encodings real Redis writes; only the loader reads these
+pub const RDB_TYPE_HASH_ZIPMAP: u8 = 9;
+pub const RDB_TYPE_LIST_ZIPLIST: u8 = 10;
+pub const RDB_TYPE_SET_INTSET: u8 = 11;
+pub const RDB_TYPE_ZSET_ZIPLIST: u8 = 12;
+pub const RDB_TYPE_HASH_ZIPLIST: u8 = 13;
+pub const RDB_TYPE_LIST_QUICKLIST: u8 = 14;
+pub const RDB_TYPE_HASH_LISTPACK: u8 = 16;
+pub const RDB_TYPE_ZSET_LISTPACK: u8 = 17;
+pub const RDB_TYPE_LIST_QUICKLIST_2: u8 = 18;
+pub const RDB_TYPE_SET_LISTPACK: u8 = 20;
+
+pub const STREAM_ITEM_FLAG_DELETED: i64 = 1;
+pub const STREAM_ITEM_FLAG_SAMEFIELDS: i64 = 2;
+
+/// Serializes the live dataset into an RDB image. Expired keys are left out,
+/// as are vector sets, which have no RDB encoding here.
+pub fn encode_rdb<'a>(db: impl Iterator<Item = (&'a String, &'a Entry)>) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(RDB_VERSION);
+
+    encode_aux(&mut out, "redis-ver", REDIS_VER);
+    encode_aux(&mut out, "redis-bits", &(usize::BITS).to_string());
+    encode_aux(&mut out, "ctime", &(now_ms() / 1000).to_string());
+    encode_aux(&mut out, "used-mem", &used_memory_rss().to_string());
+    encode_aux(&mut out, "aof-base", "0");
+
+    let entries: Vec<(&String, &ValueType, Option<u64>)> = db
+        .filter_map(|(key, entry)| {
+            if entry.is_expired() {
+                return None;
+            }
+            match entry.value {
+                ValueType::VectorSet(_) => None,
+                _ => Some((key, &entry.value, entry.expire_at)),
+            }
+        })
+        .collect();
+
+    if !entries.is_empty() {
+        // Database selector, then the resizedb hint
+        out.extend_from_slice(&[0xFE, 0x00, 0xFB]);
+        encode_len(&mut out, entries.len() as u64);
+        let expires = entries.iter().filter(|entry| entry.2.is_some()).count();
+        encode_len(&mut out, expires as u64);
+
+        for (key, value, expire_at) in entries {
+            if let Some(expire_at) = expire_at {
+                out.push(0xFC);
+                out.extend_from_slice(&expire_at.to_le_bytes());
+            }
+            encode_entry(&mut out, key, value);
+        }
+    }
+
+    // End of file, then the checksum of everything before it
+    out.push(0xFF);
+    let checksum = crc64(0, &out);
+    out.extend_from_slice(&checksum.to_le_bytes());
+    out
+}
+
+/// Writes the dataset to `path` through a temp file, so a crash mid-write
+/// never leaves a truncated dump behind.
+pub fn save_rdb<'a>(
+    db: impl Iterator<Item = (&'a String, &'a Entry)>,
+    path: &str,
+) -> io::Result<()> {
+    let tmp_path = format!("{}.tmp-{}", path, std::process::id());
+    fs::write(&tmp_path, encode_rdb(db))?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Snapshots the dataset and writes it on a background thread. Returns false
+/// when another background save is still running.
+pub fn spawn_bgsave(db: &DbType, global_state: &RedisGlobalType) -> bool {
+    let (snapshot, path) = {
+        let db = db.lock_all();
+        let mut global = global_state.lock().unwrap();
+        if global.rdb_bgsave_in_progress {
+            return false;
+        }
+        global.rdb_bgsave_in_progress = true;
+        // Only the changes the snapshot holds are cleared once it's on disk
+        global.dirty_before_bgsave = global.dirty;
+        let path = format!("{}/{}", global.dir_path, global.dbfilename);
+        (db.snapshot(), path)
+    };
+
+    let global_state = global_state.clone();
+    thread::spawn(move || {
+        let result = save_rdb(snapshot.iter(), &path);
+        match &result {
+            Ok(()) => eprintln!("Background saving terminated with success"),
+            Err(e) => eprintln!("Background saving error: {}", e),
+        }
+        global_state.lock().unwrap().finish_bgsave(result.is_ok());
+    });
+    true
+}
+
+/// Bytes `value` takes up in an RDB file, leaving out its key.
+pub fn serialized_length(value: &ValueType) -> usize {
+    let mut out = Vec::new();
+    encode_entry(&mut out, "", value);
+    // Less the type byte and the empty key's length byte
+    out.len().saturating_sub(2)
+}
+
+fn encode_entry(out: &mut Vec<u8>, key: &str, value: &ValueType) {
+    match value {
+        ValueType::String(s) => {
+            out.push(RDB_TYPE_STRING);
+            encode_string(out, key);
+            encode_bytes(out, s);
+        }
+        ValueType::Int(n) => {
+            out.push(RDB_TYPE_STRING);
+            encode_string(out, key);
+            encode_int_string(out, *n);
+        }
+        ValueType::List(items) => {
+            out.push(RDB_TYPE_LIST);
+            encode_string(out, key);
+            encode_len(out, items.len() as u64);
+            for item in items {
+                encode_bytes(out, item);
+            }
+        }
+        ValueType::Set(members) => {
+            out.push(RDB_TYPE_SET);
+            encode_string(out, key);
+            encode_len(out, members.len() as u64);
+            for member in members {
+                encode_bytes(out, &member.string_bytes().unwrap_or_default());
+            }
+        }
+        ValueType::Hash(fields) => {
+            out.push(RDB_TYPE_HASH);
+            encode_string(out, key);
+            encode_len(out, fields.len() as u64);
+            for (field, value) in fields {
+                encode_string(out, field);
+                encode_bytes(out, &value.string_bytes().unwrap_or_default());
+            }
+        }
+        ValueType::ZSet(zset) => {
+            out.push(RDB_TYPE_ZSET_2);
+            encode_string(out, key);
+            let members: Vec<(&String, &f64)> = zset.iter().collect();
+            encode_len(out, members.len() as u64);
+            for (member, score) in members {
+                encode_string(out, member);
+                out.extend_from_slice(&score.to_le_bytes());
+            }
+        }
+        ValueType::Stream(stream) => {
+            out.push(RDB_TYPE_STREAM_LISTPACKS_3);
+            encode_string(out, key);
+            encode_stream(out, stream);
+        }
+        ValueType::VectorSet(_) => {}
+    }
+}
+
+/// Stream layout of RDB_TYPE_STREAM_LISTPACKS_3. Every entry gets its own
+/// listpack node whose master entry carries the entry's fields.
+fn encode_stream(out: &mut Vec<u8>, stream: &Stream) {
+    encode_len(out, stream.entries.len() as u64);
+    for (&(ms, seq), key_val) in &stream.entries {
+        encode_bytes(out, &encode_stream_id(ms, seq));
+
+        let mut lp = Listpack::new();
+        lp.push_int(1); // valid entries
+        lp.push_int(0); // deleted entries
+        lp.push_int(key_val.len() as i64);
+        for (field, _) in key_val {
+            lp.push_str(field.as_bytes());
+        }
+        lp.push_int(0); // master entry terminator
+
+        lp.push_int(STREAM_ITEM_FLAG_SAMEFIELDS);
+        lp.push_int(0); // ms delta from the master id
+        lp.push_int(0); // seq delta from the master id
+        for (_, value) in key_val {
+            lp.push_str(value.as_bytes());
+        }
+        lp.push_int(key_val.len() as i64 + 3);
+        encode_bytes(out, &lp.finish());
+    }
+
+    encode_len(out, stream.entries.len() as u64);
+    let (last_ms, last_seq) = stream.last_id.unwrap_or((0, 0));
+    encode_len(out, last_ms);
+    encode_len(out, last_seq);
+    let (first_ms, first_seq) = stream.entries.keys().next().copied().unwrap_or((0, 0));
+    encode_len(out, first_ms);
+    encode_len(out, first_seq);
+    encode_len(out, stream.max_deleted_id.0);
+    encode_len(out, stream.max_deleted_id.1);
+    encode_len(out, stream.entries_added);
+
+    encode_len(out, stream.groups.len() as u64);
+    for (name, group) in &stream.groups {
+        encode_string(out, name);
+        encode_len(out, group.last_delivered_id.0);
+        encode_len(out, group.last_delivered_id.1);
+        // An unknown counter is stored as -1, like Redis does
+        encode_len(out, group.entries_read.unwrap_or(u64::MAX));
+
+        encode_len(out, group.pending.len() as u64);
+        for (&(ms, seq), pending) in &group.pending {
+            out.extend_from_slice(&encode_stream_id(ms, seq));
+            out.extend_from_slice(&pending.delivery_time.to_le_bytes());
+            encode_len(out, pending.delivery_count);
+        }
+
+        encode_len(out, group.consumers.len() as u64);
+        for (consumer_name, consumer) in &group.consumers {
+            encode_string(out, consumer_name);
+            // seen-time, then active-time
+            out.extend_from_slice(&consumer.seen_time.to_le_bytes());
+            out.extend_from_slice(&consumer.seen_time.to_le_bytes());
+            let owned: Vec<&(u64, u64)> = group
+                .pending
+                .iter()
+                .filter(|(_, pending)| &pending.consumer == consumer_name)
+                .map(|(id, _)| id)
+                .collect();
+            encode_len(out, owned.len() as u64);
+            for &&(ms, seq) in &owned {
+                out.extend_from_slice(&encode_stream_id(ms, seq));
+            }
+        }
+    }
+}
+
+fn encode_stream_id(ms: u64, seq: u64) -> [u8; 16] {
+    let mut id = [0u8; 16];
+    id[..8].copy_from_slice(&ms.to_be_bytes());
+    id[8..].copy_from_slice(&seq.to_be_bytes());
+    id
+}
+
+/// Length encoding mirroring `parse_len`.
+fn encode_len(out: &mut Vec<u8>, len: u64) {
+    if len < 1 << 6 {
+        out.push(len as u8);
+    } else if len < 1 << 14 {
+        out.push(0b0100_0000 | (len >> 8) as u8);
+        out.push(len as u8);
+    } else if len <= u32::MAX as u64 {
+        out.push(0x80);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        out.push(0x81);
+        out.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+fn encode_aux(out: &mut Vec<u8>, key: &str, value: &str) {
+    out.push(0xFA);
+    encode_string(out, key);
+    encode_string(out, value);
+}
+
+/// Resident set size from /proc, or 0 where that isn't available. There is
+/// no allocator accounting to report instead.
+/// Resident set size of the process, from /proc.
+pub fn used_memory_rss() -> u64 {
+    fs::read_to_string("/proc/self/statm")
+        .ok()
+        .and_then(|statm| statm.split_whitespace().nth(1)?.parse::<u64>().ok())
+        .map_or(0, |pages| pages * 4096)
+}
+
+fn encode_string(out: &mut Vec<u8>, s: &str) {
+    encode_bytes(out, s.as_bytes());
+}
+
+/// A number saved as a string, in the integer encodings Redis uses when it
+/// fits 32 bits.
+fn encode_int_string(out: &mut Vec<u8>, n: i64) {
+    if let Ok(n) = i8::try_from(n) {
+        out.push(0xC0);
+        out.extend_from_slice(&n.to_le_bytes());
+    } else if let Ok(n) = i16::try_from(n) {
+        out.push(0xC1);
+        out.extend_from_slice(&n.to_le_bytes());
+    } else if let Ok(n) = i32::try_from(n) {
+        out.push(0xC2);
+        out.extend_from_slice(&n.to_le_bytes());
+    } else {
+        encode_string(out, &n.to_string());
+    }
+}
+
+fn encode_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    encode_len(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+/// Minimal listpack builder, enough for stream nodes.
+struct Listpack {
+    body: Vec<u8>,
+    count: usize,
+}
+
+impl Listpack {
+    fn new() -> Self {
+        Listpack {
+            body: Vec::new(),
+            count: 0,
+        }
+    }
+
+    fn push_int(&mut self, value: i64) {
+        let mut element = Vec::new();
+        if (0..=127).contains(&value) {
+            element.push(value as u8);
+        } else if (-4096..4096).contains(&value) {
+            let v = (value as u16) & 0x1FFF;
+            element.push(0xC0 | (v >> 8) as u8);
+            element.push(v as u8);
+        } else if i16::try_from(value).is_ok() {
+            element.push(0xF1);
+            element.extend_from_slice(&(value as i16).to_le_bytes());
+        } else if (-(1 << 23)..(1 << 23)).contains(&value) {
+            element.push(0xF2);
+            element.extend_from_slice(&(value as i32).to_le_bytes()[..3]);
+        } else if i32::try_from(value).is_ok() {
+            element.push(0xF3);
+            element.extend_from_slice(&(value as i32).to_le_bytes());
+        } else {
+            element.push(0xF4);
+            element.extend_from_slice(&value.to_le_bytes());
+        }
+        self.push_element(element);
+    }
+
+    fn push_str(&mut self, s: &[u8]) {
+        let mut element = Vec::new();
+        if s.len() < 1 << 6 {
+            element.push(0x80 | s.len() as u8);
+        } else if s.len() < 1 << 12 {
+            element.push(0xE0 | (s.len() >> 8) as u8);
+            element.push(s.len() as u8);
+        } else {
+            element.push(0xF0);
+            element.extend_from_slice(&(s.len() as u32).to_le_bytes());
+        }
+        element.extend_from_slice(s);
+        self.push_element(element);
+    }
+
+    fn push_element(&mut self, element: Vec<u8>) {
+        self.body.extend_from_slice(&element);
+        // Back-length: element size in 7-bit groups, most significant first,
+        // with the continuation bit on every byte but the first
+        let mut groups = Vec::new();
+        let mut rest = element.len();
+        loop {
+            groups.push((rest & 127) as u8);
+            rest >>= 7;
+            if rest == 0 {
+                break;
+            }
+        }
+        groups.reverse();
+        for byte in groups.iter_mut().skip(1) {
+            *byte |= 128;
+        }
+        self.body.extend_from_slice(&groups);
+        self.count += 1;
+    }
+
+    fn finish(self) -> Vec<u8> {
+        let total = 6 + self.body.len() + 1;
+        let mut out = Vec::with_capacity(total);
+        out.extend_from_slice(&(total as u32).to_le_bytes());
+        out.extend_from_slice(&(self.count.min(u16::MAX as usize) as u16).to_le_bytes());
+        out.extend_from_slice(&self.body);
+        out.push(0xFF);
+        out
+    }
+}
@@ -2,98 +2,195 @@ use memmap2::Mmap;
 use std::fs::File;
 
 use crate::{
-    enums::val_type::ValueType,
-    rdb::structs::header_metadata::HeaderMetadata,
-    structs::config::Config,
-    types::{DbConfigType, DbType, RedisGlobalType},
-    utils::{parse_expiry, parse_key_value, parse_len, parse_value_by_type},
+    enums::{rdb_error::RdbError, val_type::ValueType},
+    rdb::{crc::crc64, structs::header_metadata::HeaderMetadata},
+    structs::keyspace::Entry,
+    types::{DbType, RedisGlobalType},
+    utils::{parse_bytes, parse_expiry, parse_key_value, parse_len, parse_value_by_type},
 };
 
-pub fn start_up(db: DbType, db_config: DbConfigType, global_state: RedisGlobalType) {
-    let global = global_state.lock().unwrap();
-    let db_path = format!("{}/{}", global.dir_path, global.dbfilename);
+struct LoadedKey {
+    db_number: usize,
+    key: String,
+    value: ValueType,
+    expire_at: Option<u64>,
+}
+
+/// Loads the RDB file into the dataset. A missing file is not an error; a
+/// bad signature, checksum or record is, and nothing gets loaded.
+pub fn start_up(db: DbType, global_state: RedisGlobalType) -> Result<(), RdbError> {
+    let (db_path, skip_checksum) = {
+        let global = global_state.lock().unwrap();
+        (
+            format!("{}/{}", global.dir_path, global.dbfilename),
+            global.skip_rdb_checksum,
+        )
+    };
     let file = match File::open(&db_path) {
         Ok(f) => f,
-        Err(_) => return,
+        Err(_) => return Ok(()),
     };
 
     // Memory-map the file for efficient access
-    let file_map: Mmap = unsafe { Mmap::map(&file).expect("Failed to mmap dump.rdb") };
-
-    // Parse the header metadata and get the initial offset
-    let (_header_metadata, mut offset) = HeaderMetadata::from_bytes(&file_map[..]);
+    let file_map: Mmap = unsafe { Mmap::map(&file) }.map_err(|e| RdbError::Io {
+        path: db_path.clone(),
+        message: e.to_string(),
+    })?;
 
-    if file_map.get(offset) == Some(&0xFF) {
-        return;
+    if !skip_checksum && !has_valid_checksum(&file_map) {
+        return Err(RdbError::Checksum { path: db_path });
     }
 
-    loop {
-        // Database indicator
-        match file_map.get(offset) {
-            Some(&0xFE) => offset += 1,
-            _ => panic!("Expected database indicator (0xFE)"),
+    // On failure `offset` is left at the start of the offending record
+    let mut offset = 0;
+    let keys = HeaderMetadata::from_bytes(&file_map[..])
+        .and_then(|(_header_metadata, header_len)| {
+            offset = header_len;
+            parse_records(&file_map, &mut offset)
+        })
+        .map_err(|message| RdbError::Corrupt {
+            path: db_path.clone(),
+            offset,
+            message,
+        })?;
+
+    let mut loaded = 0;
+    let mut expired = 0;
+    let mut other_dbs = 0;
+    let mut db_guard = db.lock_all();
+
+    for LoadedKey {
+        db_number,
+        key,
+        value,
+        expire_at,
+    } in keys
+    {
+        // There is only one keyspace here, so only database 0 is kept
+        if db_number != 0 {
+            other_dbs += 1;
+            continue;
         }
 
-        // Database number (skip)
-        offset += 1;
-
-        // Resizedb field indicator
-        match file_map.get(offset) {
-            Some(&0xFB) => offset += 1,
-            _ => panic!("Expected resizedb field indicator (0xFB)"),
+        let mut entry = Entry::new(value);
+        entry.expire_at = expire_at;
+        // Keys already past their expiry are dropped, as Redis does
+        if entry.is_expired() {
+            expired += 1;
+            continue;
         }
 
-        // Hash table size
-        let (ht_size, used1) = parse_len(&file_map[offset..]);
-        offset += used1;
-
-        // Hash table expires size (skip)
-        let (_ht_expires_size, used2) = parse_len(&file_map[offset..]);
-        offset += used2;
-
-        let mut local_offset = offset;
-
-        for _ in 0..ht_size {
-            let (expiry, is_millis, exp_used) = match parse_expiry(&file_map[local_offset..]) {
-                Some((exp, millis, used)) => (Some(exp), millis, used),
-                None => (None, false, 0),
-            };
-            local_offset += exp_used;
+        db_guard.insert_entry(key, entry);
+        loaded += 1;
+    }
 
-            // Parse key and value
-            let (key, key_used, value_type) = parse_key_value(&file_map[local_offset..]);
-            local_offset += key_used;
+    eprintln!(
+        "Loaded {} keys from {} ({} already expired)",
+        loaded, db_path, expired
+    );
+    if other_dbs > 0 {
+        eprintln!("Ignored {} keys in databases other than 0", other_dbs);
+    }
+    Ok(())
+}
 
-            let (value, value_used) = parse_value_by_type(value_type, &file_map[local_offset..]);
-            local_offset += value_used;
+/// Walks the opcodes after the header up to the EOF marker. `offset` only
+/// moves past a record once it has been read completely.
+fn parse_records(bytes: &[u8], offset: &mut usize) -> Result<Vec<LoadedKey>, String> {
+    let mut keys = Vec::new();
+    let mut db_number = 0;
+    let mut expire_at = None;
 
-            // Insert into DB
-            {
-                let mut db_guard = db.lock().unwrap();
-                db_guard.insert(key.clone(), ValueType::String(value));
+    loop {
+        let Some(&opcode) = bytes.get(*offset) else {
+            return Err("unexpected end of file before the EOF marker".to_string());
+        };
+        let body = &bytes[*offset + 1..];
+
+        let used = match opcode {
+            // EOF, followed by the checksum
+            0xFF => return Ok(keys),
+            // SELECTDB
+            0xFE => {
+                let (number, used) = parse_len(body)?;
+                db_number = number;
+                used
             }
-
-            // Insert config (expiry)
-            let mut config = Config::default();
-            if let Some(expiry_ts) = expiry {
-                let expire_at = if is_millis {
-                    expiry_ts
-                } else {
-                    // Convert seconds to milliseconds
-                    expiry_ts * 1000
-                };
-                config.expire_at = Some(expire_at);
+            // RESIZEDB: hash table sizes, only a hint
+            0xFB => {
+                let (_, used1) = parse_len(body)?;
+                let (_, used2) = parse_len(&body[used1..])?;
+                used1 + used2
             }
-            {
-                let mut config_guard = db_config.lock().unwrap();
-                config_guard.insert(key, config);
+            // AUX fields may also appear between databases
+            0xFA => {
+                let (_, used1) = parse_bytes(body)?;
+                let (_, used2) = parse_bytes(&body[used1..])?;
+                used1 + used2
             }
-        }
-
-        offset = local_offset;
+            // Eviction hints (LRU idle time, LFU counter) carry nothing we use
+            0xF8 => parse_len(body)?.1,
+            0xF9 => {
+                if body.is_empty() {
+                    return Err("unexpected end of file".to_string());
+                }
+                1
+            }
+            // FUNCTION2: a library's source code, which we can't run
+            0xF5 => parse_bytes(body)?.1,
+            0xF6 | 0xF7 => {
+                return Err(format!("unsupported opcode {:#x}", opcode));
+            }
+            0xFC | 0xFD => {
+                let (ts, is_millis, used) = parse_expiry(&bytes[*offset..])?.unwrap();
+                // Convert seconds to milliseconds
+                expire_at = Some(if is_millis { ts } else { ts * 1000 });
+                *offset += used;
+                continue;
+            }
+            _ => {
+                let (key, key_used, value_type) = parse_key_value(&bytes[*offset..])?;
+                let (value, value_used) =
+                    parse_value_by_type(value_type, &bytes[*offset + key_used..])?;
+                match value {
+                    Some(value) => keys.push(LoadedKey {
+                        db_number,
+                        key,
+                        value,
+                        expire_at,
+                    }),
+                    None => eprintln!(
+                        "Skipped key '{}' with unsupported encoding {:#x}",
+                        key, value_type
+                    ),
+                }
+                expire_at = None;
+                *offset += key_used + value_used;
+                continue;
+            }
+        };
+        *offset += 1 + used;
+    }
+}
 
-        if file_map.get(offset) == Some(&0xFF) {
-            break;
-        }
+/// Checks the REDIS magic and, from RDB version 5 on, the CRC64 trailer. A
+/// zero trailer means the writer had checksumming disabled.
+fn has_valid_checksum(bytes: &[u8]) -> bool {
+    if bytes.len() < 9 || &bytes[..5] != b"REDIS" {
+        return false;
+    }
+    let version: u32 = match std::str::from_utf8(&bytes[5..9]).map(str::parse) {
+        Ok(Ok(version)) => version,
+        _ => return false,
+    };
+    if version < 5 {
+        return true;
+    }
+    if bytes.len() < 18 {
+        return false;
     }
+
+    let (body, trailer) = bytes.split_at(bytes.len() - 8);
+    let expected = u64::from_le_bytes(trailer.try_into().unwrap());
+    expected == 0 || crc64(0, body) == expected
 }
@@ -10,32 +10,34 @@ pub struct HeaderMetadata {
 }
 
 impl HeaderMetadata {
-    pub fn from_bytes(bytes: &[u8]) -> (Self, usize) {
+    /// Reads the magic, version and any leading aux fields. Returns the
+    /// offset of the first opcode after them.
+    pub fn from_bytes(bytes: &[u8]) -> Result<(Self, usize), String> {
+        if bytes.len() < 9 || &bytes[0..5] != b"REDIS" {
+            return Err("missing REDIS signature".to_string());
+        }
         let magic_string = String::from_utf8_lossy(&bytes[0..5]).to_string();
         let version_number_string = String::from_utf8_lossy(&bytes[5..9]).to_string();
         let mut metadata_map = HashMap::new();
 
         let mut idx = 9;
 
-        while bytes[idx] != 0xFE && bytes[idx] != 0xFF {
-            if bytes[idx] == 0xFA {
-                idx += 1
-            }
-
-            let (key, offset) = parse_string(&bytes[idx..]);
+        while bytes.get(idx) == Some(&0xFA) {
+            idx += 1;
+            let (key, offset) = parse_string(&bytes[idx..])?;
             idx += offset;
-            let (value, offset) = parse_string(&bytes[idx..]);
+            let (value, offset) = parse_string(&bytes[idx..])?;
             idx += offset;
             metadata_map.insert(key, value);
         }
 
-        (
+        Ok((
             HeaderMetadata {
                 magic_string,
                 version_number_string,
                 metadata_map,
             },
             idx,
-        )
+        ))
     }
 }
@@ -0,0 +1,241 @@
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::enums::{client_kind::ClientKind, psync_result::PsyncResult};
+use crate::rdb::start_up::start_up;
+use crate::structs::client_stream::ClientStream;
+use crate::structs::connection::Connection;
+use crate::structs::global::generate_replid;
+use crate::structs::outbox::Outbox;
+use crate::structs::request::RequestBuffer;
+use crate::structs::runner::Runner;
+use crate::structs::socket::Socket;
+use crate::types::{DbType, RedisGlobalType};
+use crate::utils::{encode_resp_command, sync_with_master};
+
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(100);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(5);
+const ACK_PERIOD: Duration = Duration::from_secs(1);
+
+/// Runs the replica side of replication: applies the master's stream and
+/// reconnects when the link drops. The thread belongs to the current
+/// `master_link_generation` and exits once REPLICAOF moves on from it.
+pub fn spawn_master_link(db: DbType, global_state: RedisGlobalType) {
+    let (generation, mut master_stream_arc) = {
+        let global = global_state.lock().unwrap();
+        (global.master_link_generation, global.master_stream.clone())
+    };
+
+    thread::spawn(move || {
+        loop {
+            if let Some(stream_arc) = &master_stream_arc {
+                stream_from_master(stream_arc, &db, &global_state, generation);
+            }
+
+            master_stream_arc = reconnect_to_master(&db, &global_state, generation);
+            if master_stream_arc.is_none() {
+                break;
+            }
+        }
+
+        eprintln!("Replication thread exiting");
+    });
+}
+
+fn is_current_link(global_state: &RedisGlobalType, generation: u64) -> bool {
+    let global = global_state.lock().unwrap();
+    global.master_link_generation == generation && global.master_address.is_some()
+}
+
+/// Applies the master's command stream until the link drops.
+fn stream_from_master(
+    master_stream_arc: &Arc<Mutex<TcpStream>>,
+    db: &DbType,
+    global_state: &RedisGlobalType,
+    generation: u64,
+) {
+    // Read from a clone so the shared handle stays free for REPLICAOF to shut down
+    let (mut reader, mut master_writer) = match master_stream_arc.lock().unwrap().try_clone() {
+        Ok(stream) => {
+            let reader = Socket::from(stream);
+            let writer = ClientStream::new(reader.share());
+            (reader, writer)
+        }
+        Err(e) => {
+            eprintln!("Failed to clone master stream: {e}");
+            return;
+        }
+    };
+    let mut connection_info = Connection::new(master_writer.outbox());
+    {
+        let mut info = connection_info.info.lock().unwrap();
+        info.kind = ClientKind::Master;
+        info.attach(&reader);
+    }
+    global_state
+        .lock()
+        .unwrap()
+        .clients
+        .insert(connection_info.id, Arc::clone(&connection_info.info));
+    spawn_ack_sender(master_writer.outbox(), Arc::clone(global_state), generation);
+    // Resume from the offset the master's snapshot corresponds to
+    let mut local_offset = global_state.lock().unwrap().offset_replica_sync;
+    let mut read_buffer = RequestBuffer::new();
+
+    'link: loop {
+        let mut temp = [0u8; 16 * 1024];
+        let bytes_read = match reader.read(&mut temp) {
+            Ok(0) => {
+                eprintln!("Master closed connection");
+                break;
+            }
+            Ok(n) => n,
+            Err(e) => {
+                eprintln!("Read error from master: {e}");
+                break;
+            }
+        };
+
+        if !is_current_link(global_state, generation) {
+            break;
+        }
+
+        read_buffer.extend(&temp[..bytes_read]);
+
+        loop {
+            let (request, consumed) = match read_buffer.next_request() {
+                Ok(Some(parsed)) => parsed,
+                Ok(None) => break,
+                Err(e) => {
+                    // The stream can't be followed past this, so drop the link
+                    // and start over from a full resync
+                    eprintln!("Protocol error from master: {e}");
+                    global_state.lock().unwrap().master_replid = generate_replid();
+                    let _ = master_stream_arc.lock().unwrap().shutdown(Shutdown::Both);
+                    break 'link;
+                }
+            };
+            // The master only ever hears back from us through ACKs
+            let is_getack = request.args.len() >= 2
+                && request.args[0].eq_ignore_ascii_case(b"REPLCONF")
+                && request.args[1].eq_ignore_ascii_case(b"GETACK");
+
+            let mut runner = Runner::new(request.args);
+            runner.run(
+                &mut master_writer,
+                db,
+                global_state,
+                &mut connection_info,
+                &local_offset,
+                true,
+            );
+            if is_getack {
+                let _ = master_writer.flush();
+            } else {
+                master_writer.discard();
+            }
+
+            // A GETACK reports the offset of everything applied before it
+            local_offset += consumed;
+            global_state.lock().unwrap().offset_replica_sync = local_offset;
+        }
+    }
+
+    global_state
+        .lock()
+        .unwrap()
+        .clients
+        .remove(&connection_info.id);
+}
+
+/// Reports the applied offset every second, so the master can tell the link
+/// is healthy without waiting for a GETACK. Stops once the link's writer is gone.
+fn spawn_ack_sender(outbox: Outbox, global_state: RedisGlobalType, generation: u64) {
+    thread::spawn(move || loop {
+        thread::sleep(ACK_PERIOD);
+        if !is_current_link(&global_state, generation) {
+            break;
+        }
+
+        let offset = global_state.lock().unwrap().offset_replica_sync;
+        let ack = encode_resp_command(&["REPLCONF", "ACK", &offset.to_string()]);
+        if outbox.send(ack.into()).is_err() {
+            break;
+        }
+    });
+}
+
+/// Redoes the handshake with capped exponential backoff, asking to resume
+/// from where the old link stopped. On a full resync the dataset is reloaded
+/// from the fresh RDB. Swaps in the new master stream and returns it, or None
+/// once this link was superseded or the server is no longer a replica.
+fn reconnect_to_master(
+    db: &DbType,
+    global_state: &RedisGlobalType,
+    generation: u64,
+) -> Option<Arc<Mutex<TcpStream>>> {
+    let mut backoff = RECONNECT_BACKOFF_MIN;
+
+    loop {
+        let (host, master_port, listening_port, rdb_path, replid, offset) = {
+            let global = global_state.lock().unwrap();
+            if global.master_link_generation != generation {
+                return None;
+            }
+            let (host, master_port) = global.master_address.clone()?;
+            (
+                host,
+                master_port,
+                global.port.clone(),
+                format!("{}/{}", global.dir_path, global.dbfilename),
+                global.master_replid.clone(),
+                global.offset_replica_sync,
+            )
+        };
+
+        eprintln!("Reconnecting to master {host}:{master_port} in {backoff:?}");
+        thread::sleep(backoff);
+        if !is_current_link(global_state, generation) {
+            return None;
+        }
+
+        let resume = Some((replid.as_str(), offset));
+        match sync_with_master(&host, &master_port, &listening_port, &rdb_path, resume) {
+            Ok((stream, result)) => {
+                if !is_current_link(global_state, generation) {
+                    return None;
+                }
+
+                if let PsyncResult::FullResync { replid, offset } = result {
+                    db.lock_all().clear();
+                    if let Err(e) = start_up(Arc::clone(db), Arc::clone(global_state)) {
+                        // Forget the old history so the retry is a full resync
+                        eprintln!("Failed to load RDB from master: {e}");
+                        global_state.lock().unwrap().master_replid = generate_replid();
+                        backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+                        continue;
+                    }
+
+                    let mut global = global_state.lock().unwrap();
+                    global.master_replid = replid;
+                    global.offset_replica_sync = offset;
+                    eprintln!("Full resync with master {host}:{master_port}");
+                } else {
+                    eprintln!("Resumed replication from master {host}:{master_port}");
+                }
+
+                let stream_arc = Arc::new(Mutex::new(stream));
+                let mut global = global_state.lock().unwrap();
+                global.master_stream = Some(Arc::clone(&stream_arc));
+                return Some(stream_arc);
+            }
+            Err(e) => {
+                eprintln!("Failed to sync with master: {e}");
+                backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+            }
+        }
+    }
+}
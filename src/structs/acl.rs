@@ -0,0 +1,356 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::structs::command_spec::{all_categories, CommandSpec};
+use crate::utils::is_matched;
+
+/// One ACL user. Command rules keep the order they were given in and the
+/// last one matching a command decides, which is what applying them in turn
+/// amounts to.
+#[derive(Debug, Clone)]
+pub struct AclUser {
+    pub enabled: bool,
+    pub nopass: bool,
+    // SHA-256 hex digests
+    pub passwords: BTreeSet<String>,
+    // (allowed, "@category" | "command" | "command|subcommand")
+    pub command_rules: Vec<(bool, String)>,
+    pub key_patterns: Vec<String>,
+}
+
+impl AclUser {
+    /// A fresh user: off, without passwords, commands or keys.
+    pub fn new() -> Self {
+        AclUser {
+            enabled: false,
+            nopass: false,
+            passwords: BTreeSet::new(),
+            command_rules: vec![(false, "@all".to_string())],
+            key_patterns: Vec::new(),
+        }
+    }
+
+    /// Applies one ACL SETUSER rule.
+    pub fn apply_rule(&mut self, rule: &str) -> Result<(), String> {
+        match rule.to_ascii_lowercase().as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allkeys" => self.key_patterns = vec!["*".to_string()],
+            "resetkeys" => self.key_patterns.clear(),
+            "allcommands" => self.command_rules = vec![(true, "@all".to_string())],
+            "nocommands" => self.command_rules = vec![(false, "@all".to_string())],
+            "reset" => *self = AclUser::new(),
+            _ => return self.apply_prefixed_rule(rule),
+        }
+        Ok(())
+    }
+
+    fn apply_prefixed_rule(&mut self, rule: &str) -> Result<(), String> {
+        let mut chars = rule.chars();
+        let (Some(prefix), rest) = (chars.next(), chars.as_str()) else {
+            return Err("Syntax error".to_string());
+        };
+        match prefix {
+            '>' => {
+                self.nopass = false;
+                self.passwords.insert(sha256_hex(rest.as_bytes()));
+            }
+            '#' => {
+                let is_hash = rest.len() == 64
+                    && rest
+                        .chars()
+                        .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c));
+                if !is_hash {
+                    return Err("The password hash must be exactly 64 characters and contain only lowercase hexadecimal characters".to_string());
+                }
+                self.nopass = false;
+                self.passwords.insert(rest.to_string());
+            }
+            '<' | '!' => {
+                let hash = if prefix == '<' {
+                    sha256_hex(rest.as_bytes())
+                } else {
+                    rest.to_string()
+                };
+                if !self.passwords.remove(&hash) {
+                    return Err(
+                        "The password you are trying to remove from the user does not exist"
+                            .to_string(),
+                    );
+                }
+            }
+            '~' => self.key_patterns.push(rest.to_string()),
+            '+' | '-' => {
+                let name = rest.to_ascii_lowercase();
+                if !is_known_rule_target(&name) {
+                    return Err("Unknown command or category name in ACL".to_string());
+                }
+                // @all overrides everything before it
+                if name == "@all" {
+                    self.command_rules.clear();
+                }
+                self.command_rules.push((prefix == '+', name));
+            }
+            _ => return Err("Syntax error".to_string()),
+        }
+        Ok(())
+    }
+
+    pub fn check_password(&self, password: &str) -> bool {
+        self.nopass || self.passwords.contains(&sha256_hex(password.as_bytes()))
+    }
+
+    fn allows_command(&self, spec: &CommandSpec, args: &[String]) -> bool {
+        let categories = spec.categories();
+        let subcommand = args.first().map(|arg| arg.to_ascii_lowercase());
+        let matches = |rule: &str| {
+            if rule == "@all" {
+                return true;
+            }
+            if rule.starts_with('@') {
+                return categories.iter().any(|category| category == rule);
+            }
+            match rule.split_once('|') {
+                Some((command, sub)) => command == spec.name && subcommand.as_deref() == Some(sub),
+                None => rule == spec.name,
+            }
+        };
+        self.command_rules
+            .iter()
+            .rev()
+            .find(|(_, rule)| matches(rule))
+            .is_some_and(|(allowed, _)| *allowed)
+    }
+
+    fn allows_key(&self, key: &str) -> bool {
+        self.key_patterns
+            .iter()
+            .any(|pattern| is_matched(pattern, key))
+    }
+
+    pub fn describe_commands(&self) -> String {
+        self.command_rules
+            .iter()
+            .map(|(allowed, rule)| format!("{}{}", if *allowed { '+' } else { '-' }, rule))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    pub fn describe_keys(&self) -> String {
+        self.key_patterns
+            .iter()
+            .map(|pattern| format!("~{}", pattern))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// The ACL LIST line for this user.
+    pub fn describe(&self, name: &str) -> String {
+        let mut parts = vec![
+            format!("user {}", name),
+            if self.enabled { "on" } else { "off" }.to_string(),
+        ];
+        if self.nopass {
+            parts.push("nopass".to_string());
+        }
+        parts.extend(self.passwords.iter().map(|hash| format!("#{}", hash)));
+        if !self.key_patterns.is_empty() {
+            parts.push(self.describe_keys());
+        }
+        parts.push(self.describe_commands());
+        parts.join(" ")
+    }
+}
+
+impl Default for AclUser {
+    fn default() -> Self {
+        AclUser::new()
+    }
+}
+
+fn is_known_rule_target(name: &str) -> bool {
+    match name.strip_prefix('@') {
+        Some(category) => category == "all" || all_categories().contains(category),
+        None => CommandSpec::lookup(name.split('|').next().unwrap_or(name)).is_some(),
+    }
+}
+
+/// The users known to the server. `default` always exists; requirepass is a
+/// shorthand for its password.
+#[derive(Debug)]
+pub struct Acl {
+    pub users: BTreeMap<String, AclUser>,
+}
+
+impl Acl {
+    pub fn new(requirepass: Option<&str>) -> Self {
+        let mut default = AclUser::new();
+        default.enabled = true;
+        default.key_patterns = vec!["*".to_string()];
+        default.command_rules = vec![(true, "@all".to_string())];
+
+        let mut acl = Acl {
+            users: BTreeMap::from([("default".to_string(), default)]),
+        };
+        acl.set_requirepass(requirepass);
+        acl
+    }
+
+    pub fn default_user(&self) -> &AclUser {
+        &self.users["default"]
+    }
+
+    pub fn set_requirepass(&mut self, requirepass: Option<&str>) {
+        let default = self.users.get_mut("default").expect("default user exists");
+        default.passwords.clear();
+        match requirepass {
+            Some(password) => {
+                default.nopass = false;
+                default.passwords.insert(sha256_hex(password.as_bytes()));
+            }
+            None => default.nopass = true,
+        }
+    }
+
+    /// Whether new connections are logged in as `default` without AUTH.
+    pub fn default_is_open(&self) -> bool {
+        let default = self.default_user();
+        default.enabled && default.nopass
+    }
+
+    pub fn authenticate(&self, username: &str, password: &str) -> bool {
+        self.users
+            .get(username)
+            .is_some_and(|user| user.enabled && user.check_password(password))
+    }
+
+    /// Applies `rules` to `name`, creating the user if needed. Nothing changes
+    /// unless every rule is valid; the error names the offending rule.
+    pub fn set_user(&mut self, name: &str, rules: &[String]) -> Result<(), String> {
+        let mut user = self.users.get(name).cloned().unwrap_or_else(AclUser::new);
+        for rule in rules {
+            user.apply_rule(rule)
+                .map_err(|e| format!("Error in ACL SETUSER modifier '{}': {}", rule, e))?;
+        }
+        self.users.insert(name.to_string(), user);
+        Ok(())
+    }
+
+    /// Checks that `username` may run `spec` with `args`, returning the
+    /// NOPERM message otherwise.
+    pub fn check(&self, username: &str, spec: &CommandSpec, args: &[String]) -> Result<(), String> {
+        if spec.flags.contains(&"no-auth") {
+            return Ok(());
+        }
+        let Some(user) = self.users.get(username) else {
+            return Err(format!("User {} no longer exists", username));
+        };
+        if !user.allows_command(spec, args) {
+            return Err(format!(
+                "User {} has no permissions to run the '{}' command",
+                username, spec.name
+            ));
+        }
+        if spec.keys(args).iter().any(|key| !user.allows_key(key)) {
+            return Err("No permissions to access a key".to_string());
+        }
+        Ok(())
+    }
+}
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// Lowercase hex SHA-256, the form ACL passwords are stored and shown in.
+fn sha256_hex(data: &[u8]) -> String {
+    let mut state: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(SHA256_K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (slot, value) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *slot = slot.wrapping_add(value);
+        }
+    }
+
+    state.iter().map(|word| format!("{:08x}", word)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sha256_hex_matches_the_fips_180_2_vectors() {
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            sha256_hex(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        // Two blocks once padded
+        assert_eq!(
+            sha256_hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+}
@@ -0,0 +1,109 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+
+// A popped element and the list it came from
+type Popped = (String, Vec<u8>);
+
+struct Waiter {
+    id: u64,
+    keys: Vec<String>,
+    sender: Sender<Popped>,
+}
+
+/// A BLPOP waiting on its keys. The element handed to it arrives on
+/// `receiver`.
+pub struct Registration {
+    id: u64,
+    keys: Vec<String>,
+    pub receiver: Receiver<Popped>,
+}
+
+/// Clients blocked in BLPOP, queued per key in the order they blocked, so a
+/// push serves the longest-waiting one first.
+///
+/// Registering and serving both happen with the list's shard locked, so an
+/// element can't be pushed between a BLPOP finding its lists empty and
+/// registering. The registry's own lock is taken after the shards.
+pub struct BlockedClients {
+    waiters: Mutex<HashMap<String, VecDeque<Waiter>>>,
+    next_id: AtomicU64,
+}
+
+impl BlockedClients {
+    pub fn new() -> Self {
+        BlockedClients {
+            waiters: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Queues a waiter behind the ones already blocked on each of `keys`.
+    pub fn register(&self, keys: &[String]) -> Registration {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = mpsc::channel();
+        let mut waiters = self.waiters.lock().unwrap();
+        for key in keys {
+            waiters.entry(key.clone()).or_default().push_back(Waiter {
+                id,
+                keys: keys.to_vec(),
+                sender: sender.clone(),
+            });
+        }
+        Registration {
+            id,
+            keys: keys.to_vec(),
+            receiver,
+        }
+    }
+
+    /// Takes the waiter out of every queue. Returns the element it was
+    /// handed in the meantime, if any, since nobody else will pop it now.
+    pub fn unregister(&self, registration: &Registration) -> Option<Popped> {
+        let mut waiters = self.waiters.lock().unwrap();
+        remove_waiter(&mut waiters, registration.id, &registration.keys);
+        registration.receiver.try_recv().ok()
+    }
+
+    /// Hands elements from the front of `list` to the clients blocked on
+    /// `key`, oldest first, until either runs out. Returns how many were
+    /// handed over, each of which the caller propagates as an LPOP.
+    pub fn serve(&self, key: &str, list: &mut Vec<Vec<u8>>) -> usize {
+        let mut waiters = self.waiters.lock().unwrap();
+        let mut served = 0;
+        while !list.is_empty() {
+            let Some(waiter) = waiters.get_mut(key).and_then(|queue| queue.pop_front()) else {
+                break;
+            };
+            remove_waiter(&mut waiters, waiter.id, &waiter.keys);
+            // A waiter that went away without unregistering leaves the element be
+            if waiter
+                .sender
+                .send((key.to_string(), list[0].clone()))
+                .is_ok()
+            {
+                list.remove(0);
+                served += 1;
+            }
+        }
+        served
+    }
+}
+
+impl Default for BlockedClients {
+    fn default() -> Self {
+        BlockedClients::new()
+    }
+}
+
+fn remove_waiter(waiters: &mut HashMap<String, VecDeque<Waiter>>, id: u64, keys: &[String]) {
+    for key in keys {
+        if let Some(queue) = waiters.get_mut(key) {
+            queue.retain(|waiter| waiter.id != id);
+            if queue.is_empty() {
+                waiters.remove(key);
+            }
+        }
+    }
+}
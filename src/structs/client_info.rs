@@ -0,0 +1,90 @@
+use std::net::Shutdown;
+use std::time::{Duration, Instant};
+
+use crate::enums::client_kind::ClientKind;
+use crate::structs::socket::Socket;
+
+/// What CLIENT LIST reports about a connection. The connection's thread keeps
+/// it current; the registry in `RedisGlobal` holds a handle for listing.
+#[derive(Debug)]
+pub struct ClientInfo {
+    pub addr: String,
+    pub laddr: String,
+    pub name: Option<String>,
+    // ACL user the connection is authenticated as
+    pub user: String,
+    pub kind: ClientKind,
+    pub created_at: Instant,
+    pub last_interaction: Instant,
+    pub last_command: String,
+    // Commands queued since MULTI, None outside a transaction
+    pub multi: Option<usize>,
+    // Waiting in BLPOP, XREAD BLOCK, XREADGROUP BLOCK or WAIT
+    pub blocked: bool,
+    // Total time spent blocked, kept out of SLOWLOG durations
+    pub blocked_time: Duration,
+    // Set by CLIENT KILL; the connection's thread stops once it sees it
+    pub killed: bool,
+    // Handle for shutting the socket down from another thread
+    pub socket: Option<Socket>,
+}
+
+impl ClientInfo {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        ClientInfo {
+            addr: String::new(),
+            laddr: String::new(),
+            name: None,
+            user: "default".to_string(),
+            kind: ClientKind::Normal,
+            created_at: now,
+            last_interaction: now,
+            last_command: "NULL".to_string(),
+            multi: None,
+            blocked: false,
+            blocked_time: Duration::ZERO,
+            killed: false,
+            socket: None,
+        }
+    }
+
+    /// Records the connection's addresses and keeps a handle to its socket.
+    pub fn attach(&mut self, socket: &Socket) {
+        self.addr = socket.peer_name();
+        self.laddr = socket.local_name();
+        self.socket = Some(socket.share());
+    }
+
+    /// Flags the connection as killed and shuts its socket down, which wakes
+    /// a thread blocked reading from it.
+    pub fn kill(&mut self) {
+        self.killed = true;
+        if let Some(socket) = &self.socket {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// One CLIENT LIST line, without the trailing newline.
+    pub fn describe(&self, id: u64) -> String {
+        format!(
+            "id={} addr={} laddr={} name={} age={} idle={} flags={} db=0 multi={} cmd={} user={}",
+            id,
+            self.addr,
+            self.laddr,
+            self.name.as_deref().unwrap_or(""),
+            self.created_at.elapsed().as_secs(),
+            self.last_interaction.elapsed().as_secs(),
+            self.kind.flag(),
+            self.multi.map_or(-1, |queued| queued as i64),
+            self.last_command,
+            self.user,
+        )
+    }
+}
+
+impl Default for ClientInfo {
+    fn default() -> Self {
+        ClientInfo::new()
+    }
+}
@@ -0,0 +1,195 @@
+use std::{
+    io::{self, Write},
+    mem,
+    net::Shutdown,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use bytes::Bytes;
+
+use crate::structs::outbox::{outbox, Outbox, OutboxReceiver};
+use crate::structs::socket::Socket;
+
+/// Write side of a client connection.
+///
+/// Replies written by the thread running the connection's commands are
+/// buffered and flushed as one chunk, once per batch of pipelined commands.
+/// Other threads (pub/sub deliveries) push complete frames through the
+/// outbox, so the two never interleave mid-reply.
+///
+/// A client served by the event loop flushes into the outbox too, and its
+/// writer task sends everything in order. The link to our own master writes
+/// to its socket directly, with a writer thread for the outbox.
+///
+/// A detached stream has no socket behind it and drops everything written to
+/// it; AOF replay runs commands through one.
+pub struct ClientStream {
+    sink: Sink,
+    outbox: Outbox,
+    buffer: Vec<u8>,
+}
+
+enum Sink {
+    Socket(Arc<Mutex<Socket>>),
+    Connection {
+        // For handing over to a replica link; not written to directly
+        socket: Socket,
+        // Set by the event loop once the client's side of the socket closed
+        hung_up: Arc<AtomicBool>,
+        // QUIT was seen; the connection closes once its replies are out
+        closing: bool,
+    },
+    Detached,
+}
+
+impl ClientStream {
+    pub fn new(stream: Socket) -> Self {
+        let (tx, rx) = outbox();
+
+        let stream_arc = Arc::new(Mutex::new(stream));
+        spawn_client_stream_writer(Arc::clone(&stream_arc), rx);
+
+        ClientStream {
+            sink: Sink::Socket(stream_arc),
+            outbox: tx,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// A stream for a connection whose writer task drains `outbox`.
+    pub fn for_connection(socket: Socket, outbox: Outbox, hung_up: Arc<AtomicBool>) -> Self {
+        ClientStream {
+            sink: Sink::Connection {
+                socket,
+                hung_up,
+                closing: false,
+            },
+            outbox,
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn detached() -> Self {
+        let (tx, _) = outbox();
+        ClientStream {
+            sink: Sink::Detached,
+            outbox: tx,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Sender for frames produced outside the connection's thread.
+    pub fn outbox(&self) -> Outbox {
+        self.outbox.clone()
+    }
+
+    /// Another handle on the connection's socket.
+    pub fn share_socket(&self) -> io::Result<Socket> {
+        match &self.sink {
+            Sink::Socket(stream) => Ok(stream.lock().unwrap().share()),
+            Sink::Connection { socket, .. } => Ok(socket.share()),
+            Sink::Detached => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+
+    /// Bytes of replies buffered since the last flush.
+    pub fn pending(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Drops whatever replies were buffered since the last flush.
+    pub fn discard(&mut self) {
+        self.buffer.clear();
+    }
+
+    /// Sends `frame` after whatever replies are buffered, without copying it
+    /// into the buffer.
+    pub fn write_frame(&mut self, frame: Bytes) -> io::Result<()> {
+        self.flush()?;
+        match &self.sink {
+            Sink::Socket(stream) => stream.lock().unwrap().write_all(&frame),
+            Sink::Connection { .. } => self
+                .outbox
+                .send(frame)
+                .map_err(|_| io::ErrorKind::BrokenPipe.into()),
+            Sink::Detached => Ok(()),
+        }
+    }
+
+    /// Whether the client hung up. A detached stream never does.
+    pub fn peer_closed(&self) -> bool {
+        match &self.sink {
+            Sink::Socket(stream) => stream.lock().unwrap().peer_closed(),
+            Sink::Connection { hung_up, .. } => hung_up.load(Ordering::Relaxed),
+            Sink::Detached => false,
+        }
+    }
+
+    /// Whether `shutdown` was called on an event loop connection, which its
+    /// task closes after sending what was flushed.
+    pub fn is_closing(&self) -> bool {
+        matches!(self.sink, Sink::Connection { closing: true, .. })
+    }
+
+    pub fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
+        let _ = self.flush();
+        match &mut self.sink {
+            Sink::Socket(stream) => stream.lock().unwrap().shutdown(how),
+            Sink::Connection { closing, .. } => {
+                *closing = true;
+                Ok(())
+            }
+            Sink::Detached => Ok(()),
+        }
+    }
+}
+
+impl Write for ClientStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        match &self.sink {
+            Sink::Socket(stream) => {
+                let result = stream.lock().unwrap().write_all(&self.buffer);
+                self.buffer.clear();
+                result
+            }
+            Sink::Connection { .. } => self
+                .outbox
+                .send(mem::take(&mut self.buffer).into())
+                .map_err(|_| io::ErrorKind::BrokenPipe.into()),
+            Sink::Detached => {
+                self.buffer.clear();
+                Ok(())
+            }
+        }
+    }
+}
+
+fn spawn_client_stream_writer(stream: Arc<Mutex<Socket>>, mut receiver: OutboxReceiver) {
+    thread::spawn(move || {
+        while let Some(frame) = receiver.blocking_recv() {
+            let mut stream_guard = match stream.lock() {
+                Ok(guard) => guard,
+                Err(_) => {
+                    eprintln!("Failed to lock client stream");
+                    break;
+                }
+            };
+            if let Err(e) = stream_guard.write_all(&frame) {
+                eprintln!("Failed to write to client: {:?}", e);
+                break;
+            }
+        }
+    });
+}
@@ -0,0 +1,265 @@
+use std::collections::BTreeSet;
+
+/// Static description of a command, as reported by COMMAND and used to
+/// validate arity and classify writes.
+#[derive(Debug)]
+pub struct CommandSpec {
+    pub name: &'static str,
+    // Redis convention: counts the command name, negative means "at least"
+    pub arity: i64,
+    pub flags: &'static [&'static str],
+    pub first_key: i64,
+    pub last_key: i64,
+    pub step: i64,
+    pub group: &'static str,
+    pub summary: &'static str,
+}
+
+const fn spec(
+    name: &'static str,
+    arity: i64,
+    flags: &'static [&'static str],
+    (first_key, last_key, step): (i64, i64, i64),
+    group: &'static str,
+    summary: &'static str,
+) -> CommandSpec {
+    CommandSpec {
+        name,
+        arity,
+        flags,
+        first_key,
+        last_key,
+        step,
+        group,
+        summary,
+    }
+}
+
+const NO_KEYS: (i64, i64, i64) = (0, 0, 0);
+const ONE_KEY: (i64, i64, i64) = (1, 1, 1);
+
+#[rustfmt::skip]
+pub static COMMAND_TABLE: &[CommandSpec] = &[
+    // connection
+    spec("auth", -2, &["noscript", "loading", "stale", "fast", "no-auth"], NO_KEYS, "connection", "Authenticates the connection."),
+    spec("client", -2, &["noscript", "loading", "stale"], NO_KEYS, "connection", "A container for client connection commands."),
+    spec("hello", -1, &["noscript", "loading", "stale", "fast", "no-auth"], NO_KEYS, "connection", "Handshakes with the Redis server."),
+    spec("echo", 2, &["fast"], NO_KEYS, "connection", "Returns the given string."),
+    spec("ping", -1, &["fast"], NO_KEYS, "connection", "Returns the server's liveliness response."),
+    spec("quit", -1, &["noscript", "loading", "stale", "fast", "no-auth"], NO_KEYS, "connection", "Closes the connection."),
+    spec("reset", 1, &["noscript", "loading", "stale", "fast", "no-auth"], NO_KEYS, "connection", "Resets the connection."),
+    // server
+    spec("acl", -2, &["admin", "noscript", "loading", "stale"], NO_KEYS, "server", "A container for Access List Control commands."),
+    spec("bgrewriteaof", 1, &["admin", "noscript"], NO_KEYS, "server", "Asynchronously rewrites the append-only file to disk."),
+    spec("bgsave", -1, &["admin", "noscript"], NO_KEYS, "server", "Asynchronously saves the database(s) to disk."),
+    spec("command", -1, &["loading", "stale"], NO_KEYS, "server", "Returns detailed information about all commands."),
+    spec("config", -2, &["admin", "noscript", "loading", "stale"], NO_KEYS, "server", "A container for server configuration commands."),
+    spec("debug", -2, &["admin", "noscript", "loading", "stale"], NO_KEYS, "server", "A container for debugging commands."),
+    spec("info", -1, &["loading", "stale"], NO_KEYS, "server", "Returns information and statistics about the server."),
+    spec("lastsave", 1, &["loading", "stale", "fast"], NO_KEYS, "server", "Returns the Unix timestamp of the last successful save to disk."),
+    spec("psync", -3, &["admin", "noscript"], NO_KEYS, "server", "An internal command used in replication."),
+    spec("replconf", -1, &["admin", "noscript", "loading", "stale"], NO_KEYS, "server", "An internal command for configuring the replication stream."),
+    spec("replicaof", 3, &["admin", "noscript", "stale"], NO_KEYS, "server", "Configures a server as replica of another, or promotes it to a master."),
+    spec("lolwut", -1, &["readonly", "fast"], NO_KEYS, "server", "Displays computer art and the Redis version."),
+    spec("save", 1, &["admin", "noscript"], NO_KEYS, "server", "Synchronously saves the database(s) to disk."),
+    spec("shutdown", -1, &["admin", "noscript", "loading", "stale"], NO_KEYS, "server", "Synchronously saves the database(s) to disk and shuts down the Redis server."),
+    spec("slowlog", -2, &["admin", "loading", "stale"], NO_KEYS, "server", "A container for slow log commands."),
+    spec("time", 1, &["loading", "stale", "fast"], NO_KEYS, "server", "Returns the server time."),
+    spec("slaveof", 3, &["admin", "noscript", "stale"], NO_KEYS, "server", "Sets a Redis server as a replica of another, or promotes it to being a master."),
+    // generic
+    spec("del", -2, &["write"], (1, -1, 1), "generic", "Deletes one or more keys."),
+    spec("keys", 2, &["readonly"], NO_KEYS, "generic", "Returns all key names that match a pattern."),
+    spec("object", -2, &["readonly"], (2, 2, 1), "generic", "A container for object introspection commands."),
+    spec("pexpireat", 3, &["write", "fast"], ONE_KEY, "generic", "Sets the expiration time of a key to a Unix milliseconds timestamp."),
+    spec("type", 2, &["readonly", "fast"], ONE_KEY, "generic", "Determines the type of value stored at a key."),
+    spec("wait", 3, &["noscript"], NO_KEYS, "generic", "Blocks until the asynchronous replication of all preceding write commands sent by the connection is completed."),
+    // string
+    spec("get", 2, &["readonly", "fast"], ONE_KEY, "string", "Returns the string value of a key."),
+    spec("incr", 2, &["write", "denyoom", "fast"], ONE_KEY, "string", "Increments the integer value of a key by one."),
+    spec("set", -3, &["write", "denyoom"], ONE_KEY, "string", "Sets the string value of a key, ignoring its type."),
+    // list
+    spec("blpop", -3, &["write", "blocking"], (1, -2, 1), "list", "Removes and returns the first element in a list. Blocks until an element is available otherwise."),
+    spec("llen", 2, &["readonly", "fast"], ONE_KEY, "list", "Returns the length of a list."),
+    spec("lpop", -2, &["write", "fast"], ONE_KEY, "list", "Returns the first elements in a list after removing it."),
+    spec("lpush", -3, &["write", "denyoom", "fast"], ONE_KEY, "list", "Prepends one or more elements to a list."),
+    spec("lrange", 4, &["readonly"], ONE_KEY, "list", "Returns a range of elements from a list."),
+    spec("rpush", -3, &["write", "denyoom", "fast"], ONE_KEY, "list", "Appends one or more elements to a list."),
+    // set
+    spec("sadd", -3, &["write", "denyoom", "fast"], ONE_KEY, "set", "Adds one or more members to a set. Creates the key if it doesn't exist."),
+    // hash
+    spec("hset", -4, &["write", "denyoom", "fast"], ONE_KEY, "hash", "Creates or modifies the value of a field in a hash."),
+    // sorted-set
+    spec("zadd", -4, &["write", "denyoom", "fast"], ONE_KEY, "sorted-set", "Adds one or more members to a sorted set, or updates their scores."),
+    spec("zcard", 2, &["readonly", "fast"], ONE_KEY, "sorted-set", "Returns the number of members in a sorted set."),
+    spec("zrange", -4, &["readonly"], ONE_KEY, "sorted-set", "Returns members in a sorted set within a range of indexes."),
+    spec("zrank", -3, &["readonly", "fast"], ONE_KEY, "sorted-set", "Returns the index of a member in a sorted set ordered by ascending scores."),
+    spec("zrem", -3, &["write", "fast"], ONE_KEY, "sorted-set", "Removes one or more members from a sorted set."),
+    spec("zscore", 3, &["readonly", "fast"], ONE_KEY, "sorted-set", "Returns the score of a member in a sorted set."),
+    // geo
+    spec("geoadd", -5, &["write", "denyoom"], ONE_KEY, "geo", "Adds one or more members to a geospatial index."),
+    spec("geodist", -4, &["readonly"], ONE_KEY, "geo", "Returns the distance between two members of a geospatial index."),
+    spec("geopos", -2, &["readonly"], ONE_KEY, "geo", "Returns the longitude and latitude of members from a geospatial index."),
+    spec("georadius", -6, &["write", "denyoom"], ONE_KEY, "geo", "Queries a geospatial index for members within a distance from a coordinate, optionally stores the result."),
+    spec("georadius_ro", -6, &["readonly"], ONE_KEY, "geo", "Returns members from a geospatial index that are within a distance from a coordinate."),
+    spec("georadiusbymember", -5, &["write", "denyoom"], ONE_KEY, "geo", "Queries a geospatial index for members within a distance from a member, optionally stores the result."),
+    spec("georadiusbymember_ro", -5, &["readonly"], ONE_KEY, "geo", "Returns members from a geospatial index that are within a distance from a member."),
+    spec("geosearch", -7, &["readonly"], ONE_KEY, "geo", "Queries a geospatial index for members inside an area of a box or a circle."),
+    // stream
+    spec("xack", -4, &["write", "fast"], ONE_KEY, "stream", "Returns the number of messages that were successfully acknowledged by the consumer group member of a stream."),
+    spec("xadd", -5, &["write", "denyoom", "fast"], ONE_KEY, "stream", "Appends a new message to a stream. Creates the key if it doesn't exist."),
+    spec("xautoclaim", -6, &["write", "fast"], ONE_KEY, "stream", "Changes, or acquires, ownership of messages in a consumer group, as if the messages were delivered to as consumer group member."),
+    spec("xclaim", -6, &["write", "fast"], ONE_KEY, "stream", "Changes, or acquires, ownership of a message in a consumer group, as if the message was delivered a consumer group member."),
+    spec("xgroup", -2, &["write", "denyoom"], (2, 2, 1), "stream", "A container for consumer groups commands."),
+    spec("xpending", -3, &["readonly"], ONE_KEY, "stream", "Returns the information and entries from a stream consumer group's pending entries list."),
+    spec("xrange", -4, &["readonly"], ONE_KEY, "stream", "Returns the messages from a stream within a range of IDs."),
+    spec("xread", -4, &["readonly", "blocking", "movablekeys"], NO_KEYS, "stream", "Returns messages from multiple streams with IDs greater than the ones requested. Blocks until a message is available otherwise."),
+    spec("xreadgroup", -7, &["write", "blocking", "movablekeys"], NO_KEYS, "stream", "Returns new or historical messages from a stream for a consumer in a group. Blocks until a message is available otherwise."),
+    // pubsub
+    spec("psubscribe", -2, &["pubsub", "noscript", "loading", "stale"], NO_KEYS, "pubsub", "Listens for messages published to channels that match one or more patterns."),
+    spec("publish", 3, &["pubsub", "loading", "stale", "fast"], NO_KEYS, "pubsub", "Posts a message to a channel."),
+    spec("pubsub", -2, &["pubsub", "loading", "stale"], NO_KEYS, "pubsub", "A container for Pub/Sub commands."),
+    spec("punsubscribe", -1, &["pubsub", "noscript", "loading", "stale"], NO_KEYS, "pubsub", "Stops listening to messages published to channels that match one or more patterns."),
+    spec("subscribe", -2, &["pubsub", "noscript", "loading", "stale"], NO_KEYS, "pubsub", "Listens for messages published to channels."),
+    spec("unsubscribe", -1, &["pubsub", "noscript", "loading", "stale"], NO_KEYS, "pubsub", "Stops listening to messages posted to channels."),
+    // transactions
+    spec("discard", 1, &["noscript", "loading", "stale", "fast"], NO_KEYS, "transactions", "Discards a transaction."),
+    spec("exec", 1, &["noscript", "loading", "stale"], NO_KEYS, "transactions", "Executes all commands in a transaction."),
+    spec("multi", 1, &["noscript", "loading", "stale", "fast"], NO_KEYS, "transactions", "Starts a transaction."),
+    spec("unwatch", 1, &["noscript", "loading", "stale", "fast"], NO_KEYS, "transactions", "Forgets about watched keys of a transaction."),
+    spec("watch", -2, &["noscript", "loading", "stale", "fast"], (1, -1, 1), "transactions", "Monitors changes to keys to determine the execution of a transaction."),
+];
+
+impl CommandSpec {
+    /// Looks a command up by its lowercase name.
+    pub fn lookup(name: &str) -> Option<&'static CommandSpec> {
+        COMMAND_TABLE.iter().find(|spec| spec.name == name)
+    }
+
+    pub fn is_write(&self) -> bool {
+        self.flags.contains(&"write")
+    }
+
+    /// Whether `argc` arguments after the command name satisfy the arity.
+    pub fn accepts(&self, argc: usize) -> bool {
+        let total = argc as i64 + 1;
+        if self.arity < 0 {
+            total >= -self.arity
+        } else {
+            total == self.arity
+        }
+    }
+
+    /// Keys among `args` (the arguments after the command name), for ACL key
+    /// patterns.
+    pub fn keys<'a>(&self, args: &'a [String]) -> Vec<&'a String> {
+        if self.flags.contains(&"movablekeys") {
+            // XREAD / XREADGROUP: the first half of what follows STREAMS
+            let Some(start) = args
+                .iter()
+                .position(|arg| arg.eq_ignore_ascii_case("streams"))
+            else {
+                return Vec::new();
+            };
+            let streams = &args[start + 1..];
+            return streams[..streams.len() / 2].iter().collect();
+        }
+        if self.first_key == 0 {
+            return Vec::new();
+        }
+        // Positions count the command name; negative ones are from the end
+        let last = if self.last_key < 0 {
+            args.len() as i64 + self.last_key
+        } else {
+            self.last_key - 1
+        };
+        (self.first_key - 1..=last)
+            .step_by(self.step as usize)
+            .filter_map(|i| args.get(i as usize))
+            .collect()
+    }
+
+    /// ACL categories derived from the flags and the command group.
+    pub fn categories(&self) -> Vec<String> {
+        let mut categories: Vec<String> = Vec::new();
+        for (flag, category) in [
+            ("write", "@write"),
+            ("readonly", "@read"),
+            ("admin", "@admin"),
+            ("pubsub", "@pubsub"),
+            ("blocking", "@blocking"),
+        ] {
+            if self.flags.contains(&flag) {
+                categories.push(category.to_string());
+            }
+        }
+        let group = match self.group {
+            "generic" => "keyspace",
+            "sorted-set" => "sortedset",
+            "transactions" => "transaction",
+            group => group,
+        };
+        if group != "server" && !categories.contains(&format!("@{}", group)) {
+            categories.push(format!("@{}", group));
+        }
+        if self.flags.contains(&"admin") {
+            categories.push("@dangerous".to_string());
+        }
+        categories.push(
+            if self.flags.contains(&"fast") {
+                "@fast"
+            } else {
+                "@slow"
+            }
+            .to_string(),
+        );
+        categories
+    }
+
+    /// The COMMAND / COMMAND INFO entry for this command.
+    pub fn encode_info(&self) -> String {
+        let mut resp = format!(
+            "*10\r\n${}\r\n{}\r\n:{}\r\n",
+            self.name.len(),
+            self.name,
+            self.arity
+        );
+        resp.push_str(&format!("*{}\r\n", self.flags.len()));
+        for flag in self.flags {
+            resp.push_str(&format!("+{}\r\n", flag));
+        }
+        resp.push_str(&format!(
+            ":{}\r\n:{}\r\n:{}\r\n",
+            self.first_key, self.last_key, self.step
+        ));
+        let categories = self.categories();
+        resp.push_str(&format!("*{}\r\n", categories.len()));
+        for category in &categories {
+            resp.push_str(&format!("+{}\r\n", category));
+        }
+        // Tips, key specifications and subcommands are not tracked
+        resp.push_str("*0\r\n*0\r\n*0\r\n");
+        resp
+    }
+
+    /// The COMMAND DOCS name/doc pair for this command.
+    pub fn encode_docs(&self) -> String {
+        format!(
+            "${}\r\n{}\r\n*4\r\n$7\r\nsummary\r\n${}\r\n{}\r\n$5\r\ngroup\r\n${}\r\n{}\r\n",
+            self.name.len(),
+            self.name,
+            self.summary.len(),
+            self.summary,
+            self.group.len(),
+            self.group
+        )
+    }
+}
+
+/// Every ACL category some command belongs to, without the leading '@'.
+pub fn all_categories() -> BTreeSet<String> {
+    COMMAND_TABLE
+        .iter()
+        .flat_map(|spec| spec.categories())
+        .map(|category| category[1..].to_string())
+        .collect()
+}
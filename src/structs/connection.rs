@@ -1,31 +1,129 @@
-use rand::{distr::Alphanumeric, rng, Rng};
-use std::{collections::HashMap, sync::mpsc::Receiver};
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
 
+use crate::enums::client_kind::ClientKind;
+use crate::structs::client_info::ClientInfo;
+use crate::structs::global::{RedisGlobal, Subscriber};
+use crate::structs::outbox::Outbox;
 use crate::structs::transaction::Transaction;
+use crate::structs::worker_pool::WorkerSlot;
+
+/// Keeps the connection marked as blocked until dropped, and its worker
+/// handed back meanwhile.
+pub struct BlockedGuard(Arc<Mutex<ClientInfo>>, Instant, Option<Arc<WorkerSlot>>);
+
+impl Drop for BlockedGuard {
+    fn drop(&mut self) {
+        {
+            let mut info = self.0.lock().unwrap();
+            info.blocked = false;
+            info.blocked_time += self.1.elapsed();
+            // Waking up counts as activity for the idle timeout
+            info.last_interaction = Instant::now();
+        }
+        if let Some(worker) = &self.2 {
+            worker.reacquire();
+        }
+    }
+}
+
+// CLIENT ID values, handed out in accept order and never reused
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
 
 pub struct Connection {
-    pub id: String,
+    pub id: u64,
+    // Shared with the CLIENT LIST registry once registered
+    pub info: Arc<Mutex<ClientInfo>>,
     pub slave_port: Option<String>,
     pub is_slave_established: bool,
+    // Cleared while requirepass is set until the client AUTHs
+    pub authenticated: bool,
+    // RESP version negotiated with HELLO, 2 until then
+    pub protocol: u8,
     pub transaction: Transaction,
-    pub subscribed_channels: HashMap<String, Receiver<String>>,
+    // Key -> version seen at WATCH time
+    pub watched_keys: HashMap<String, u64>,
+    pub subscribed_channels: HashSet<String>,
+    pub subscribed_patterns: HashSet<String>,
+    // Frames produced by other threads go through the client's writer thread
+    pub outbox: Outbox,
+    // Held while a batch of the client's requests runs on the worker pool
+    pub worker: Option<Arc<WorkerSlot>>,
+    // Only ever cloned as a Weak into the pub/sub registry
+    liveness: Arc<()>,
 }
 
-impl Default for Connection {
-    fn default() -> Self {
-        // Generate a random alphanumeric id of length 16 for each connection
-        let id: String = rng()
-            .sample_iter(&Alphanumeric)
-            .take(16)
-            .map(char::from)
-            .collect();
-
+impl Connection {
+    pub fn new(outbox: Outbox) -> Self {
         Connection {
-            id,
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            info: Arc::new(Mutex::new(ClientInfo::new())),
             slave_port: None,
             is_slave_established: false,
+            authenticated: false,
+            protocol: 2,
             transaction: Transaction::new(),
-            subscribed_channels: HashMap::new(),
+            watched_keys: HashMap::new(),
+            subscribed_channels: HashSet::new(),
+            subscribed_patterns: HashSet::new(),
+            outbox,
+            worker: None,
+            liveness: Arc::new(()),
+        }
+    }
+
+    pub fn subscriber(&self) -> Subscriber {
+        Subscriber {
+            sender: self.outbox.clone(),
+            alive: Arc::downgrade(&self.liveness),
+            protocol: self.protocol,
+        }
+    }
+
+    /// Releases every WATCH registration held by this connection.
+    pub fn unwatch_all(&mut self, global: &mut RedisGlobal) {
+        for key in self.watched_keys.keys() {
+            global.unwatch_key(key);
+        }
+        self.watched_keys.clear();
+    }
+
+    /// Marks the connection as blocked, for INFO's blocked_clients. Replies
+    /// are otherwise flushed once per batch of pipelined commands, so the
+    /// ones ahead of the blocking command are sent now. The worker is free
+    /// for other clients until the guard is dropped.
+    pub fn block(&self, stream: &mut impl Write) -> BlockedGuard {
+        let _ = stream.flush();
+        self.info.lock().unwrap().blocked = true;
+        if let Some(worker) = &self.worker {
+            worker.release();
+        }
+        BlockedGuard(Arc::clone(&self.info), Instant::now(), self.worker.clone())
+    }
+
+    /// Whether CLIENT KILL has targeted this connection.
+    pub fn is_killed(&self) -> bool {
+        self.info.lock().unwrap().killed
+    }
+
+    /// Whether the connection sat idle past `timeout` seconds (0 disables).
+    /// Replicas and subscribers are expected to stay quiet, so they never do.
+    pub fn is_idle_past(&self, timeout: u64) -> bool {
+        if timeout == 0 || self.is_slave_established || self.subscription_count() > 0 {
+            return false;
         }
+        let info = self.info.lock().unwrap();
+        info.kind == ClientKind::Normal && info.last_interaction.elapsed().as_secs() > timeout
+    }
+
+    pub fn subscription_count(&self) -> usize {
+        self.subscribed_channels.len() + self.subscribed_patterns.len()
     }
 }
@@ -0,0 +1,95 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone)]
+pub struct ConsumerGroup {
+    pub last_delivered_id: (u64, u64),
+    pub entries_read: Option<u64>,
+    pub consumers: HashMap<String, Consumer>,
+    pub pending: BTreeMap<(u64, u64), PendingEntry>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Consumer {
+    pub seen_time: u64, // epoch in ms
+}
+
+#[derive(Debug, Clone)]
+pub struct PendingEntry {
+    pub consumer: String,
+    pub delivery_time: u64, // epoch in ms
+    pub delivery_count: u64,
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+impl ConsumerGroup {
+    pub fn new(last_delivered_id: (u64, u64), entries_read: Option<u64>) -> Self {
+        ConsumerGroup {
+            last_delivered_id,
+            entries_read,
+            consumers: HashMap::new(),
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Returns true when the consumer did not exist yet.
+    pub fn create_consumer(&mut self, name: &str) -> bool {
+        if self.consumers.contains_key(name) {
+            return false;
+        }
+        self.consumers.insert(
+            name.to_string(),
+            Consumer {
+                seen_time: now_ms(),
+            },
+        );
+        true
+    }
+
+    /// Removes the consumer and its pending entries, returning how many were pending.
+    pub fn delete_consumer(&mut self, name: &str) -> usize {
+        if self.consumers.remove(name).is_none() {
+            return 0;
+        }
+        let before = self.pending.len();
+        self.pending.retain(|_, entry| entry.consumer != name);
+        before - self.pending.len()
+    }
+
+    /// Moves a pending entry to `consumer`, creating it when `force` is set.
+    /// Returns false when the entry is not pending and wasn't forced in.
+    pub fn claim(
+        &mut self,
+        id: (u64, u64),
+        consumer: &str,
+        delivery_time: u64,
+        retry_count: Option<u64>,
+        increment: bool,
+        force: bool,
+    ) -> bool {
+        self.create_consumer(consumer);
+        let pending = match self.pending.get_mut(&id) {
+            Some(pending) => pending,
+            None if force => self.pending.entry(id).or_insert(PendingEntry {
+                consumer: consumer.to_string(),
+                delivery_time,
+                delivery_count: 0,
+            }),
+            None => return false,
+        };
+        pending.consumer = consumer.to_string();
+        pending.delivery_time = delivery_time;
+        if let Some(count) = retry_count {
+            pending.delivery_count = count;
+        } else if increment {
+            pending.delivery_count += 1;
+        }
+        true
+    }
+}
@@ -0,0 +1,268 @@
+use crate::error;
+use crate::geo::{unit_to_meters, GeoShape};
+
+#[derive(Debug)]
+pub enum GeoCenter {
+    Member(String),
+    LonLat(f64, f64),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum GeoOrder {
+    Asc,
+    Desc,
+}
+
+#[derive(Debug)]
+pub struct GeoSearchConfig {
+    pub center: Option<GeoCenter>,
+    pub shape: Option<GeoShape>,
+    pub unit: String,
+    pub order: Option<GeoOrder>,
+    pub count: Option<usize>,
+    pub any: bool,
+    pub with_coord: bool,
+    pub with_dist: bool,
+    pub with_hash: bool,
+}
+
+impl GeoSearchConfig {
+    /// Parses the GEOSEARCH option list (everything after the key).
+    pub fn from_args(args: &[String]) -> (Self, Option<String>) {
+        let mut config = GeoSearchConfig {
+            center: None,
+            shape: None,
+            unit: "m".to_string(),
+            order: None,
+            count: None,
+            any: false,
+            with_coord: false,
+            with_dist: false,
+            with_hash: false,
+        };
+
+        let mut i = 0;
+        let mut err: Option<String> = None;
+
+        while i < args.len() {
+            let arg = args[i].to_ascii_lowercase();
+            let remaining = args.len() - i - 1;
+            match arg.as_str() {
+                "frommember" if remaining >= 1 => {
+                    if config.center.is_some() {
+                        err = Some(
+                            "exactly one of FROMMEMBER or FROMLONLAT can be specified for GEOSEARCH"
+                                .to_string(),
+                        );
+                        break;
+                    }
+                    config.center = Some(GeoCenter::Member(args[i + 1].clone()));
+                    i += 2;
+                }
+                "fromlonlat" if remaining >= 2 => {
+                    if config.center.is_some() {
+                        err = Some(
+                            "exactly one of FROMMEMBER or FROMLONLAT can be specified for GEOSEARCH"
+                                .to_string(),
+                        );
+                        break;
+                    }
+                    match (args[i + 1].parse::<f64>(), args[i + 2].parse::<f64>()) {
+                        (Ok(lon), Ok(lat)) => config.center = Some(GeoCenter::LonLat(lon, lat)),
+                        _ => {
+                            err = Some(error::NOT_A_FLOAT.to_string());
+                            break;
+                        }
+                    }
+                    i += 3;
+                }
+                "byradius" if remaining >= 2 => {
+                    if config.shape.is_some() {
+                        err = Some(
+                            "exactly one of BYRADIUS and BYBOX arguments must be provided for GEOSEARCH command"
+                                .to_string(),
+                        );
+                        break;
+                    }
+                    let (radius, unit) = (&args[i + 1], &args[i + 2]);
+                    match (radius.parse::<f64>(), unit_to_meters(unit)) {
+                        (Ok(radius), Some(factor)) if radius >= 0.0 => {
+                            config.shape = Some(GeoShape::Radius(radius * factor));
+                            config.unit = unit.to_ascii_lowercase();
+                        }
+                        (Ok(radius), _) if radius < 0.0 => {
+                            err = Some("ERR radius cannot be negative".to_string());
+                            break;
+                        }
+                        (Ok(_), None) => {
+                            err = Some(
+                                "unsupported unit provided. please use M, KM, FT, MI".to_string(),
+                            );
+                            break;
+                        }
+                        _ => {
+                            err = Some("ERR need numeric radius".to_string());
+                            break;
+                        }
+                    }
+                    i += 3;
+                }
+                "bybox" if remaining >= 3 => {
+                    if config.shape.is_some() {
+                        err = Some(
+                            "exactly one of BYRADIUS and BYBOX arguments must be provided for GEOSEARCH command"
+                                .to_string(),
+                        );
+                        break;
+                    }
+                    let unit = &args[i + 3];
+                    match (
+                        args[i + 1].parse::<f64>(),
+                        args[i + 2].parse::<f64>(),
+                        unit_to_meters(unit),
+                    ) {
+                        (Ok(width), Ok(height), Some(factor)) if width >= 0.0 && height >= 0.0 => {
+                            config.shape = Some(GeoShape::Box(width * factor, height * factor));
+                            config.unit = unit.to_ascii_lowercase();
+                        }
+                        (Ok(_), Ok(_), None) => {
+                            err = Some(
+                                "unsupported unit provided. please use M, KM, FT, MI".to_string(),
+                            );
+                            break;
+                        }
+                        (Ok(_), Ok(_), Some(_)) => {
+                            err = Some("ERR height or width cannot be negative".to_string());
+                            break;
+                        }
+                        _ => {
+                            err = Some("ERR need numeric width and height".to_string());
+                            break;
+                        }
+                    }
+                    i += 4;
+                }
+                "asc" => {
+                    config.order = Some(GeoOrder::Asc);
+                    i += 1;
+                }
+                "desc" => {
+                    config.order = Some(GeoOrder::Desc);
+                    i += 1;
+                }
+                "count" if remaining >= 1 => {
+                    match args[i + 1].parse::<i64>() {
+                        Ok(n) if n > 0 => config.count = Some(n as usize),
+                        Ok(_) => {
+                            err = Some("ERR COUNT must be > 0".to_string());
+                            break;
+                        }
+                        Err(_) => {
+                            err = Some(error::NOT_AN_INTEGER.to_string());
+                            break;
+                        }
+                    }
+                    i += 2;
+                    if i < args.len() && args[i].eq_ignore_ascii_case("any") {
+                        config.any = true;
+                        i += 1;
+                    }
+                }
+                "withcoord" => {
+                    config.with_coord = true;
+                    i += 1;
+                }
+                "withdist" => {
+                    config.with_dist = true;
+                    i += 1;
+                }
+                "withhash" => {
+                    config.with_hash = true;
+                    i += 1;
+                }
+                _ => {
+                    err = Some(error::SYNTAX.to_string());
+                    break;
+                }
+            }
+        }
+
+        if err.is_none() {
+            if config.center.is_none() {
+                err = Some(
+                    "exactly one of FROMMEMBER or FROMLONLAT can be specified for GEOSEARCH"
+                        .to_string(),
+                );
+            } else if config.shape.is_none() {
+                err = Some(
+                    "exactly one of BYRADIUS and BYBOX arguments must be provided for GEOSEARCH command"
+                        .to_string(),
+                );
+            }
+        }
+
+        // COUNT without an explicit order still returns the closest matches
+        if config.count.is_some() && config.order.is_none() && !config.any {
+            config.order = Some(GeoOrder::Asc);
+        }
+
+        (config, err)
+    }
+}
+
+/// A GEORADIUS-family command read as GEOSEARCH options, with its STORE or
+/// STOREDIST target pulled out.
+#[derive(Debug)]
+pub struct GeoRadiusOptions {
+    pub key: String,
+    pub config: GeoSearchConfig,
+    // Destination key, and whether distances are stored rather than hashes
+    pub store: Option<(String, bool)>,
+}
+
+impl GeoRadiusOptions {
+    /// Parses the arguments of `command`, which is GEORADIUS or
+    /// GEORADIUSBYMEMBER, either of them possibly in its _RO form.
+    pub fn from_args(command: &str, args: &[String]) -> Result<Self, String> {
+        let by_member = command.starts_with("georadiusbymember");
+        let read_only = command.ends_with("_ro");
+        let fixed = if by_member { 4 } else { 5 };
+        if args.len() < fixed {
+            return Err(error::wrong_arity(command));
+        }
+
+        let mut search_args: Vec<String> = if by_member {
+            vec!["FROMMEMBER".to_string(), args[1].clone()]
+        } else {
+            vec!["FROMLONLAT".to_string(), args[1].clone(), args[2].clone()]
+        };
+        search_args.push("BYRADIUS".to_string());
+        search_args.extend_from_slice(&args[fixed - 2..fixed]);
+
+        let mut store = None;
+        let mut idx = fixed;
+        while idx < args.len() {
+            let opt = args[idx].to_ascii_lowercase();
+            if !read_only && (opt == "store" || opt == "storedist") && idx + 1 < args.len() {
+                store = Some((args[idx + 1].clone(), opt == "storedist"));
+                idx += 2;
+            } else {
+                search_args.push(args[idx].clone());
+                idx += 1;
+            }
+        }
+
+        let (config, err) = GeoSearchConfig::from_args(&search_args);
+        if let Some(e) = err {
+            return Err(e);
+        }
+        if store.is_some() && (config.with_coord || config.with_dist || config.with_hash) {
+            return Err("ERR STORE option in GEORADIUS is not compatible with WITHDIST, WITHHASH and WITHCOORD options".to_string());
+        }
+        Ok(GeoRadiusOptions {
+            key: args[0].clone(),
+            config,
+            store,
+        })
+    }
+}
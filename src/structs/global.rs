@@ -1,26 +1,195 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     env::Args,
-    net::TcpStream,
-    sync::{mpsc::Sender, Arc, Mutex},
+    fs::{self, File},
+    io::Write,
+    net::{IpAddr, Ipv4Addr, Shutdown, TcpStream},
+    path::Path,
+    sync::{Arc, Condvar, Mutex, Weak},
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 
-use crate::structs::replica::ReplicaState;
-use crate::utils::sync_with_master;
+use bytes::Bytes;
+use rand::Rng;
+use tokio::sync::watch;
+
+use crate::config_file::read_config_file;
+use crate::enums::client_kind::ClientKind;
+use crate::enums::eviction_policy::EvictionPolicy;
+use crate::enums::psync_result::PsyncResult;
+use crate::structs::acl::Acl;
+use crate::structs::client_info::ClientInfo;
+use crate::structs::command_spec::CommandSpec;
+use crate::structs::outbox::Outbox;
+use crate::structs::repl_backlog::{ReplBacklog, DEFAULT_REPL_BACKLOG_SIZE};
+use crate::structs::replica::{
+    OutputBufferLimit, ReplicaState, DEFAULT_REPLICA_OUTPUT_BUFFER_LIMIT,
+};
+use crate::structs::slowlog::Slowlog;
+use crate::structs::worker_pool::DEFAULT_IO_THREADS;
+use crate::utils::{encode_resp_command, is_matched, sync_with_master};
+
+/// A pub/sub receiver. `alive` is tied to the owning connection, so a client
+/// that went away stops counting before its cleanup has run.
+#[derive(Debug, Clone)]
+pub struct Subscriber {
+    pub sender: Outbox,
+    pub alive: Weak<()>,
+    pub protocol: u8,
+}
+
+impl Subscriber {
+    pub fn is_alive(&self) -> bool {
+        self.alive.strong_count() > 0
+    }
+
+    /// Sends a message frame, as a push frame to RESP3 clients.
+    pub fn deliver(&self, frame: &Bytes) -> bool {
+        let frame = if self.protocol == 3 {
+            let mut push = frame.to_vec();
+            push[0] = b'>';
+            Bytes::from(push)
+        } else {
+            frame.clone()
+        };
+        self.sender.send(frame).is_ok()
+    }
+}
+
+pub const DEFAULT_REPL_PING_REPLICA_PERIOD: u64 = 10;
+pub const DEFAULT_MIN_REPLICAS_MAX_LAG: u64 = 10;
+pub const DEFAULT_MAXCLIENTS: usize = 10000;
+pub const DEFAULT_PROTO_MAX_BULK_LEN: u64 = 512 * 1024 * 1024;
+
+pub const PROTECTED_MODE_DENIED: &str = "DENIED Redis is running in protected mode because protected mode is enabled and no password is set for the default user. In this mode connections are only accepted from the loopback interface. If you want to connect from external computers to Redis you may adopt one of the following solutions: 1) Just disable protected mode sending the command 'CONFIG SET protected-mode no' from the loopback interface by connecting to Redis from the same host the server is running, however MAKE SURE Redis is not publicly accessible from internet if you do so. Use CONFIG REWRITE to make this change permanent. 2) Alternatively you can just disable the protected mode by editing the Redis configuration file, and setting the protected mode option to 'no', and then restarting the server. 3) If you started the server manually just for testing, restart it with the '--protected-mode no' option. 4) Set up an authentication password for the default user. NOTE: You only need to do one of the above things in order for the server to start accepting connections from the outside.";
+
+/// Parameters known to CONFIG GET, in the order they are listed.
+const CONFIG_PARAMS: &[&str] = &[
+    "dir",
+    "dbfilename",
+    "bind",
+    "port",
+    "protected-mode",
+    "loglevel",
+    "unixsocket",
+    "unixsocketperm",
+    "timeout",
+    "maxclients",
+    "io-threads",
+    "proto-max-bulk-len",
+    "save",
+    "appendonly",
+    "appendfilename",
+    "maxmemory",
+    "maxmemory-policy",
+    "replica-read-only",
+    "requirepass",
+    "repl-backlog-size",
+    "repl-ping-replica-period",
+    "client-output-buffer-limit",
+    "min-replicas-to-write",
+    "min-replicas-max-lag",
+    "slowlog-log-slower-than",
+    "slowlog-max-len",
+];
 
 #[derive(Debug)]
 pub struct RedisGlobal {
     pub port: String,
+    // Absolute path of the config file given at startup, for CONFIG REWRITE
+    pub config_file: Option<String>,
+    // Parameters changed by CONFIG SET since startup
+    pub changed_configs: BTreeSet<String>,
+    // Recorded for CONFIG only; logging isn't levelled
+    pub loglevel: String,
+    // Addresses a listener is opened on, all sharing the port
+    pub bind: Vec<IpAddr>,
+    // Extra listener on a unix domain socket, with the file's mode bits
+    pub unixsocket: Option<String>,
+    pub unixsocketperm: Option<u32>,
+    // Without a password, only loopback peers are served when bound elsewhere
+    pub protected_mode: bool,
     pub master_address: Option<(String, String)>,
     pub master_stream: Option<Arc<Mutex<TcpStream>>>,
+    // Bumped by every REPLICAOF so a superseded replication thread winds down
+    pub master_link_generation: u64,
     pub replica_caps: HashMap<String, Vec<String>>,
     pub replica_states: HashMap<String, ReplicaState>,
+    // Notified with this state's lock released whenever a replica acks, for WAIT
+    pub replica_acked: Arc<Condvar>,
     pub master_replid: String,
-    pub master_repl_offset: usize,
     pub dir_path: String,
     pub dbfilename: String,
+    // Load RDB files even when their CRC64 trailer doesn't match
+    pub skip_rdb_checksum: bool,
+    // Start with an empty dataset when the RDB file can't be loaded, instead
+    // of exiting
+    pub ignore_rdb_errors: bool,
+    // Bytes of replication stream sent to replicas (master) or applied from
+    // the master (replica)
     pub offset_replica_sync: usize,
-    pub channel_map: HashMap<String, HashMap<String, Sender<String>>>,
+    pub repl_backlog: ReplBacklog,
+    // Seconds between PINGs sent down the replication stream
+    pub repl_ping_replica_period: u64,
+    // How much propagated stream may queue up for a replica before it's dropped
+    pub replica_output_buffer_limit: OutputBufferLimit,
+    // Writes are refused unless this many replicas acked within the lag (0 disables)
+    pub min_replicas_to_write: usize,
+    pub min_replicas_max_lag: u64,
+    pub rdb_bgsave_in_progress: bool,
+    // Unix time in seconds of the last successful save
+    pub rdb_last_save_time: u64,
+    pub rdb_last_bgsave_status: bool,
+    // Writes since the last successful save
+    pub dirty: u64,
+    pub dirty_before_bgsave: u64,
+    // Automatic save points: BGSAVE after `seconds` once `changes` writes piled up
+    pub save_params: Vec<(u64, u64)>,
+    // Append-only file, opened once the startup replay is done
+    pub appendonly: bool,
+    pub appendfilename: String,
+    pub aof: Option<File>,
+    // Writes arriving during BGREWRITEAOF, appended to the new file on swap
+    pub aof_rewrite_buffer: Option<Vec<u8>>,
+    // Set while the AOF is replayed so replayed writes aren't fed back out
+    pub loading: bool,
+    // Cleared by DEBUG SET-ACTIVE-EXPIRE 0 to pause the cleanup thread
+    pub active_expire: bool,
+    // Normal client connections allowed at once; replicas don't count
+    pub maxclients: usize,
+    // Connections running commands at once; the rest wait their turn
+    pub io_threads: usize,
+    // rename-command: original name -> the name clients must use, "" disables it
+    pub renamed_commands: HashMap<String, String>,
+    // Seconds a client may sit idle before it is disconnected (0 disables)
+    pub timeout: u64,
+    // Longest bulk string a request may carry
+    pub proto_max_bulk_len: u64,
+    // Set by SHUTDOWN, which wakes the accept loops to stop
+    pub shutdown: watch::Sender<bool>,
+    // Keys are evicted by maxmemory_policy once the dataset outgrows this (0 disables)
+    pub maxmemory: u64,
+    pub maxmemory_policy: EvictionPolicy,
+    pub replica_read_only: bool,
+    // Password of the default user, kept for CONFIG GET
+    pub requirepass: Option<String>,
+    pub acl: Acl,
+    // INFO stats; everything but start_time is cleared by CONFIG RESETSTAT
+    pub start_time: Instant,
+    pub stat_numcommands: u64,
+    pub stat_numconnections: u64,
+    pub stat_keyspace_hits: u64,
+    pub stat_keyspace_misses: u64,
+    pub stat_expiredkeys: u64,
+    pub stat_evictedkeys: u64,
+    pub stat_rejected_conn: u64,
+    pub slowlog: Slowlog,
+    pub channel_map: HashMap<String, HashMap<u64, Subscriber>>,
+    pub pattern_map: HashMap<String, HashMap<u64, Subscriber>>,
+    // Watched key -> (version, number of connections watching it)
+    pub key_versions: HashMap<String, (u64, usize)>,
+    // Live connections by CLIENT ID, for CLIENT LIST
+    pub clients: BTreeMap<u64, Arc<Mutex<ClientInfo>>>,
 }
 
 impl RedisGlobal {
@@ -28,6 +197,223 @@ impl RedisGlobal {
         self.port = port;
     }
 
+    pub fn aof_path(&self) -> String {
+        format!("{}/{}", self.dir_path, self.appendfilename)
+    }
+
+    /// Appends a propagated write to the AOF, and to the rewrite buffer while
+    /// BGREWRITEAOF runs.
+    pub fn feed_aof(&mut self, message: &[u8]) {
+        if let Some(file) = self.aof.as_mut() {
+            if let Err(e) = file.write_all(message) {
+                eprintln!("Failed to append to AOF: {e}");
+            }
+        }
+        if let Some(buffer) = self.aof_rewrite_buffer.as_mut() {
+            buffer.extend_from_slice(message);
+        }
+    }
+
+    /// Records the outcome of a SAVE.
+    pub fn finish_save(&mut self, ok: bool) {
+        if ok {
+            self.rdb_last_save_time = unix_time();
+            self.dirty = 0;
+        }
+    }
+
+    /// Records the outcome of a BGSAVE and allows the next one to start.
+    pub fn finish_bgsave(&mut self, ok: bool) {
+        self.rdb_bgsave_in_progress = false;
+        self.rdb_last_bgsave_status = ok;
+        if ok {
+            self.rdb_last_save_time = unix_time();
+            self.dirty = self.dirty.saturating_sub(self.dirty_before_bgsave);
+        }
+    }
+
+    /// True when some save point has both enough changes and enough time
+    /// since the last save.
+    pub fn save_point_reached(&self) -> bool {
+        let elapsed = unix_time().saturating_sub(self.rdb_last_save_time);
+        !self.rdb_bgsave_in_progress
+            && self
+                .save_params
+                .iter()
+                .any(|&(seconds, changes)| self.dirty >= changes && elapsed >= seconds)
+    }
+
+    pub fn get_config(&self, name: &str) -> Option<String> {
+        let value = match name {
+            "dir" => self.dir_path.clone(),
+            "dbfilename" => self.dbfilename.clone(),
+            "port" => self.port.clone(),
+            "bind" => self
+                .bind
+                .iter()
+                .map(|ip| ip.to_string())
+                .collect::<Vec<_>>()
+                .join(" "),
+            "protected-mode" => yes_no(self.protected_mode),
+            "loglevel" => self.loglevel.clone(),
+            "unixsocket" => self.unixsocket.clone().unwrap_or_default(),
+            "unixsocketperm" => format!("{:o}", self.unixsocketperm.unwrap_or(0)),
+            "appendonly" => yes_no(self.appendonly),
+            "appendfilename" => self.appendfilename.clone(),
+            "save" => self
+                .save_params
+                .iter()
+                .map(|(seconds, changes)| format!("{} {}", seconds, changes))
+                .collect::<Vec<_>>()
+                .join(" "),
+            "timeout" => self.timeout.to_string(),
+            "maxclients" => self.maxclients.to_string(),
+            "io-threads" => self.io_threads.to_string(),
+            "proto-max-bulk-len" => self.proto_max_bulk_len.to_string(),
+            "maxmemory" => self.maxmemory.to_string(),
+            "maxmemory-policy" => self.maxmemory_policy.name().to_string(),
+            "replica-read-only" => yes_no(self.replica_read_only),
+            "requirepass" => self.requirepass.clone().unwrap_or_default(),
+            "repl-backlog-size" => self.repl_backlog.capacity().to_string(),
+            "repl-ping-replica-period" => self.repl_ping_replica_period.to_string(),
+            "client-output-buffer-limit" => {
+                let limit = self.replica_output_buffer_limit;
+                format!(
+                    "replica {} {} {}",
+                    limit.hard, limit.soft, limit.soft_seconds
+                )
+            }
+            "min-replicas-to-write" => self.min_replicas_to_write.to_string(),
+            "min-replicas-max-lag" => self.min_replicas_max_lag.to_string(),
+            "slowlog-log-slower-than" => self.slowlog.log_slower_than.to_string(),
+            "slowlog-max-len" => self.slowlog.max_len.to_string(),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    pub fn is_config_param(name: &str) -> bool {
+        CONFIG_PARAMS.contains(&name)
+    }
+
+    /// Every parameter name and value matching the CONFIG GET `pattern`.
+    pub fn matching_configs(&self, pattern: &str) -> Vec<(&'static str, String)> {
+        CONFIG_PARAMS
+            .iter()
+            .filter(|name| is_matched(pattern, name))
+            .filter_map(|&name| Some((name, self.get_config(name)?)))
+            .collect()
+    }
+
+    /// Applies a CONFIG SET pair, returning the error message on failure.
+    /// Turning appendonly on only sets the flag; the caller starts the
+    /// rewrite that creates the file.
+    pub fn set_config(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let invalid = || format!("Invalid argument '{}' for CONFIG SET '{}'", value, name);
+        match name {
+            "dir" => {
+                if !Path::new(value).is_dir() {
+                    return Err(invalid());
+                }
+                self.dir_path = value.to_string();
+            }
+            "dbfilename" => {
+                if value.is_empty() || value.contains('/') {
+                    return Err(invalid());
+                }
+                self.dbfilename = value.to_string();
+            }
+            "appendonly" => {
+                self.appendonly = parse_yes_no(value).ok_or_else(invalid)?;
+                if !self.appendonly {
+                    self.aof = None;
+                }
+            }
+            "timeout" => self.timeout = value.parse().map_err(|_| invalid())?,
+            "maxclients" => match value.parse::<usize>() {
+                Ok(limit) if limit > 0 => self.maxclients = limit,
+                _ => return Err(invalid()),
+            },
+            "proto-max-bulk-len" => match parse_memory(value) {
+                Some(len) if len >= 1024 * 1024 => self.proto_max_bulk_len = len,
+                _ => return Err(invalid()),
+            },
+            "maxmemory" => self.maxmemory = parse_memory(value).ok_or_else(invalid)?,
+            "maxmemory-policy" => {
+                self.maxmemory_policy = EvictionPolicy::from_name(value).ok_or_else(invalid)?
+            }
+            "replica-read-only" => {
+                self.replica_read_only = parse_yes_no(value).ok_or_else(invalid)?
+            }
+            "requirepass" => {
+                self.requirepass = Some(value.to_string()).filter(|pass| !pass.is_empty());
+                self.acl.set_requirepass(self.requirepass.as_deref());
+            }
+            "protected-mode" => self.protected_mode = parse_yes_no(value).ok_or_else(invalid)?,
+            "loglevel" => self.loglevel = parse_loglevel(value).ok_or_else(invalid)?,
+            "port" | "bind" | "unixsocket" | "unixsocketperm" | "appendfilename"
+            | "repl-backlog-size" | "io-threads" => {
+                return Err(format!("can't set immutable config '{}'", name))
+            }
+            "repl-ping-replica-period" => match value.parse::<u64>() {
+                Ok(period) if period > 0 => self.repl_ping_replica_period = period,
+                _ => return Err(invalid()),
+            },
+            "client-output-buffer-limit" => {
+                self.replica_output_buffer_limit =
+                    parse_output_buffer_limit(value).ok_or_else(invalid)?
+            }
+            "min-replicas-to-write" => {
+                self.min_replicas_to_write = value.parse().map_err(|_| invalid())?
+            }
+            "min-replicas-max-lag" => {
+                self.min_replicas_max_lag = value.parse().map_err(|_| invalid())?
+            }
+            "save" => self.save_params = parse_save_params(value).ok_or_else(invalid)?,
+            "slowlog-log-slower-than" => {
+                self.slowlog.log_slower_than = value.parse().map_err(|_| invalid())?
+            }
+            "slowlog-max-len" => {
+                self.slowlog.max_len = value.parse().map_err(|_| invalid())?;
+                self.slowlog.trim();
+            }
+            _ => {
+                return Err(format!(
+                    "Unknown option or number of arguments for CONFIG SET - '{}'",
+                    name
+                ))
+            }
+        }
+        self.changed_configs.insert(name.to_string());
+        Ok(())
+    }
+
+    /// CONFIG RESETSTAT.
+    pub fn reset_stats(&mut self) {
+        self.stat_numcommands = 0;
+        self.stat_numconnections = 0;
+        self.stat_keyspace_hits = 0;
+        self.stat_keyspace_misses = 0;
+        self.stat_expiredkeys = 0;
+        self.stat_evictedkeys = 0;
+        self.stat_rejected_conn = 0;
+    }
+
+    /// Whether enough replicas acked recently for a write to be accepted.
+    pub fn has_enough_good_replicas(&self) -> bool {
+        if !self.is_master() || self.min_replicas_to_write == 0 {
+            return true;
+        }
+        let good_replicas = self
+            .replica_states
+            .values()
+            .filter(|replica| {
+                replica.last_ack_time.elapsed().as_secs() <= self.min_replicas_max_lag
+            })
+            .count();
+        good_replicas >= self.min_replicas_to_write
+    }
+
     pub fn set_slave_caps(&mut self, slave_port: String, caps: Vec<String>) {
         self.replica_caps.insert(slave_port, caps);
     }
@@ -36,21 +422,264 @@ impl RedisGlobal {
         self.master_address = master;
     }
 
+    pub fn subscribe(&mut self, channel: &str, connection_id: u64, subscriber: Subscriber) {
+        self.channel_map
+            .entry(channel.to_string())
+            .or_default()
+            .insert(connection_id, subscriber);
+    }
+
+    pub fn unsubscribe(&mut self, channel: &str, connection_id: u64) {
+        if let Some(subscribers) = self.channel_map.get_mut(channel) {
+            subscribers.remove(&connection_id);
+            if subscribers.is_empty() {
+                self.channel_map.remove(channel);
+            }
+        }
+    }
+
+    pub fn psubscribe(&mut self, pattern: &str, connection_id: u64, subscriber: Subscriber) {
+        self.pattern_map
+            .entry(pattern.to_string())
+            .or_default()
+            .insert(connection_id, subscriber);
+    }
+
+    pub fn punsubscribe(&mut self, pattern: &str, connection_id: u64) {
+        if let Some(subscribers) = self.pattern_map.get_mut(pattern) {
+            subscribers.remove(&connection_id);
+            if subscribers.is_empty() {
+                self.pattern_map.remove(pattern);
+            }
+        }
+    }
+
+    /// Delivers `message` to the subscribers of `channel` and of every pattern
+    /// matching it, pruning the ones whose connection is gone. Returns how many
+    /// receivers got it.
+    pub fn publish(&mut self, channel: &str, message: &str) -> usize {
+        let mut delivered = 0;
+        let mut gone = Vec::new();
+        if let Some(subscribers) = self.channel_map.get(channel) {
+            let frame = Bytes::from(encode_resp_command(&["message", channel, message]));
+            for (connection_id, subscriber) in subscribers {
+                if !subscriber.is_alive() {
+                    gone.push(*connection_id);
+                    continue;
+                }
+                if subscriber.deliver(&frame) {
+                    delivered += 1;
+                } else {
+                    gone.push(*connection_id);
+                }
+            }
+        }
+        for connection_id in gone {
+            self.unsubscribe(channel, connection_id);
+        }
+
+        let mut gone = Vec::new();
+        for (pattern, subscribers) in &self.pattern_map {
+            if !is_matched(pattern, channel) {
+                continue;
+            }
+            let frame = Bytes::from(encode_resp_command(&[
+                "pmessage", pattern, channel, message,
+            ]));
+            for (connection_id, subscriber) in subscribers {
+                if !subscriber.is_alive() {
+                    gone.push((pattern.clone(), *connection_id));
+                    continue;
+                }
+                if subscriber.deliver(&frame) {
+                    delivered += 1;
+                } else {
+                    gone.push((pattern.clone(), *connection_id));
+                }
+            }
+        }
+        for (pattern, connection_id) in gone {
+            self.punsubscribe(&pattern, connection_id);
+        }
+
+        delivered
+    }
+
+    /// Channels with at least one live subscriber, optionally filtered by a glob.
+    pub fn active_channels(&self, pattern: Option<&str>) -> Vec<String> {
+        self.channel_map
+            .iter()
+            .filter(|(channel, _)| pattern.is_none_or(|pattern| is_matched(pattern, channel)))
+            .filter(|(_, subscribers)| subscribers.values().any(Subscriber::is_alive))
+            .map(|(channel, _)| channel.clone())
+            .collect()
+    }
+
+    pub fn channel_subscribers(&self, channel: &str) -> usize {
+        self.channel_map.get(channel).map_or(0, |subscribers| {
+            subscribers.values().filter(|s| s.is_alive()).count()
+        })
+    }
+
+    pub fn pattern_count(&self) -> usize {
+        self.pattern_map
+            .values()
+            .filter(|subscribers| subscribers.values().any(Subscriber::is_alive))
+            .count()
+    }
+
+    /// Registers a watcher on `key` and returns its current version.
+    pub fn watch_key(&mut self, key: &str) -> u64 {
+        let entry = self.key_versions.entry(key.to_string()).or_insert((0, 0));
+        entry.1 += 1;
+        entry.0
+    }
+
+    pub fn unwatch_key(&mut self, key: &str) {
+        if let Some(entry) = self.key_versions.get_mut(key) {
+            entry.1 -= 1;
+            if entry.1 == 0 {
+                self.key_versions.remove(key);
+            }
+        }
+    }
+
+    /// Counts a write towards the save points and bumps the version of a
+    /// watched `key` so transactions watching it abort on EXEC.
+    pub fn mark_dirty(&mut self, key: &str) {
+        self.dirty += 1;
+        if let Some(entry) = self.key_versions.get_mut(key) {
+            entry.0 += 1;
+        }
+    }
+
+    pub fn key_version(&self, key: &str) -> u64 {
+        self.key_versions.get(key).map_or(0, |entry| entry.0)
+    }
+
+    /// The command a client-sent `name` runs under rename-command, or None
+    /// when the name was renamed away or disabled.
+    pub fn resolve_command(&self, name: &str) -> Option<String> {
+        let renamed_to = self
+            .renamed_commands
+            .iter()
+            .find(|(_, new_name)| !new_name.is_empty() && new_name.as_str() == name);
+        if let Some((original, _)) = renamed_to {
+            return Some(original.clone());
+        }
+        if self.renamed_commands.contains_key(name) {
+            return None;
+        }
+        Some(name.to_string())
+    }
+
+    /// Whether protected mode turns away a client connecting from `peer`.
+    pub fn denies_in_protected_mode(&self, peer: IpAddr) -> bool {
+        self.protected_mode
+            && self.acl.default_is_open()
+            && self.bind.iter().any(|ip| !ip.is_loopback())
+            && !peer.is_loopback()
+    }
+
+    /// Whether maxclients leaves room for one more normal client.
+    pub fn accepts_new_client(&self) -> bool {
+        let connected = self
+            .clients
+            .values()
+            .filter(|info| info.lock().unwrap().kind == ClientKind::Normal)
+            .count();
+        connected < self.maxclients
+    }
+
     pub fn is_master(&self) -> bool {
-        let is_master = !(self.master_address.is_some() && self.master_stream.is_some());
-        is_master
+        self.master_address.is_none()
     }
 
-    pub fn init(mut args: Args) -> Self {
+    /// Starts following `master`: drops the current master link and any
+    /// replicas of our own. The caller spawns the new replication thread.
+    pub fn follow_master(&mut self, master: (String, String)) {
+        self.master_address = Some(master);
+        self.drop_master_link();
+        for replica in self.replica_states.values() {
+            let _ = replica.stream.lock().unwrap().shutdown(Shutdown::Both);
+        }
+        self.replica_states.clear();
+        self.replica_caps.clear();
+    }
+
+    /// REPLICAOF NO ONE: keeps the dataset and offset but starts a new history.
+    pub fn promote_to_master(&mut self) {
+        self.master_address = None;
+        self.drop_master_link();
+        self.master_replid = generate_replid();
+        self.repl_backlog =
+            ReplBacklog::starting_at(self.repl_backlog.capacity(), self.offset_replica_sync);
+    }
+
+    fn drop_master_link(&mut self) {
+        self.master_link_generation += 1;
+        if let Some(stream) = self.master_stream.take() {
+            let _ = stream.lock().unwrap().shutdown(Shutdown::Both);
+        }
+    }
+
+    pub fn init(args: Args) -> Self {
+        let mut args: Vec<String> = args.skip(1).collect();
+        // Like Redis, a leading positional argument names a config file. Its
+        // directives go ahead of the real flags, so the flags override them.
+        let mut config_file = None;
+        let mut file_settings = Vec::new();
+        let mut cli_params = HashSet::new();
+        if let Some(path) = args.first().filter(|arg| !arg.starts_with("--")).cloned() {
+            match read_config_file(&path) {
+                Ok(file) => {
+                    cli_params = args[1..]
+                        .iter()
+                        .filter_map(|arg| arg.strip_prefix("--"))
+                        .map(str::to_string)
+                        .collect();
+                    args.splice(0..1, file.flags);
+                    file_settings = file.settings;
+                    config_file = Some(
+                        fs::canonicalize(&path).map_or(path, |path| path.display().to_string()),
+                    );
+                }
+                Err(e) => {
+                    eprintln!("{e}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        let mut args = args.into_iter().peekable();
         let mut port = "6379".to_string();
+        let mut bind = vec![IpAddr::V4(Ipv4Addr::LOCALHOST)];
+        let mut protected_mode = true;
+        let mut unixsocket = None;
+        let mut unixsocketperm = None;
         let mut master_address: Option<(String, String)> = None;
-        let master_replid = "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb";
-        let master_repl_offset = 0;
+        let mut master_replid = generate_replid();
         let mut dir_path = String::from("/var/tmp/redis");
         let mut dbfilename = String::from("dump.rdb");
         let mut master_stream = None;
-
-        args.next(); // skip program name
+        let mut offset_replica_sync = 0;
+        let mut repl_backlog_size = DEFAULT_REPL_BACKLOG_SIZE;
+        let mut repl_ping_replica_period = DEFAULT_REPL_PING_REPLICA_PERIOD;
+        let mut replica_output_buffer_limit = DEFAULT_REPLICA_OUTPUT_BUFFER_LIMIT;
+        let mut skip_rdb_checksum = false;
+        let mut ignore_rdb_errors = false;
+        let mut appendonly = false;
+        let mut save_params = default_save_params();
+        let mut appendfilename = String::from("appendonly.aof");
+        let mut timeout = 0;
+        let mut renamed_commands = HashMap::new();
+        let mut maxclients = DEFAULT_MAXCLIENTS;
+        let mut io_threads = DEFAULT_IO_THREADS;
+        let mut proto_max_bulk_len = DEFAULT_PROTO_MAX_BULK_LEN;
+        let mut maxmemory = 0;
+        let mut maxmemory_policy = EvictionPolicy::NoEviction;
+        let mut replica_read_only = true;
+        let mut requirepass = None;
+        let mut loglevel = "notice".to_string();
 
         while let Some(arg) = args.next() {
             match arg.as_str() {
@@ -59,6 +688,37 @@ impl RedisGlobal {
                         port = val;
                     }
                 }
+                "--bind" => {
+                    let mut addrs = Vec::new();
+                    while let Some(addr) = args.next_if(|arg| !arg.starts_with("--")) {
+                        match addr.parse::<IpAddr>() {
+                            Ok(ip) => addrs.push(ip),
+                            Err(_) => eprintln!("Error: invalid bind address '{}'", addr),
+                        }
+                    }
+                    if addrs.is_empty() {
+                        eprintln!("Error: --bind requires at least one IP address");
+                    } else {
+                        bind = addrs;
+                    }
+                }
+                "--unixsocket" => match args.next() {
+                    Some(path) if !path.is_empty() => unixsocket = Some(path),
+                    _ => eprintln!("Error: --unixsocket requires a path"),
+                },
+                "--unixsocketperm" => {
+                    match args
+                        .next()
+                        .and_then(|val| u32::from_str_radix(&val, 8).ok())
+                    {
+                        Some(perm) if perm <= 0o777 => unixsocketperm = Some(perm),
+                        _ => eprintln!("Error: --unixsocketperm requires octal permissions"),
+                    }
+                }
+                "--protected-mode" => match args.next().as_deref().and_then(parse_yes_no) {
+                    Some(enabled) => protected_mode = enabled,
+                    None => eprintln!("Error: --protected-mode requires yes or no"),
+                },
                 "--dir" => {
                     if let Some(val) = args.next() {
                         dir_path = val.to_string();
@@ -73,38 +733,304 @@ impl RedisGlobal {
                         eprintln!("Error: --dbfilename requires a value");
                     }
                 }
+                "--repl-backlog-size" => {
+                    match args.next().and_then(|val| val.parse::<usize>().ok()) {
+                        Some(size) => repl_backlog_size = size,
+                        None => eprintln!("Error: --repl-backlog-size requires a byte count"),
+                    }
+                }
+                "--save" => match args.next().as_deref().and_then(parse_save_params) {
+                    Some(params) => save_params = params,
+                    None => eprintln!("Error: --save requires \"<seconds> <changes> ...\""),
+                },
+                "--appendonly" => match args.next().as_deref() {
+                    Some("yes") => appendonly = true,
+                    Some("no") => appendonly = false,
+                    _ => eprintln!("Error: --appendonly requires yes or no"),
+                },
+                "--appendfilename" => {
+                    if let Some(val) = args.next() {
+                        appendfilename = val.to_string();
+                    } else {
+                        eprintln!("Error: --appendfilename requires a value");
+                    }
+                }
+                "--rename-command" => match (args.next(), args.next()) {
+                    (Some(name), Some(new_name)) => {
+                        let name = name.to_ascii_lowercase();
+                        if CommandSpec::lookup(&name).is_some() {
+                            renamed_commands.insert(name, new_name.to_ascii_lowercase());
+                        } else {
+                            eprintln!("Error: no such command in rename-command: {}", name);
+                        }
+                    }
+                    _ => eprintln!("Error: --rename-command requires a command and a new name"),
+                },
+                "--maxclients" => match args.next().and_then(|val| val.parse::<usize>().ok()) {
+                    Some(limit) if limit > 0 => maxclients = limit,
+                    _ => eprintln!("Error: --maxclients requires a positive number"),
+                },
+                "--io-threads" => match args.next().and_then(|val| val.parse::<usize>().ok()) {
+                    Some(threads) if threads > 0 => io_threads = threads,
+                    _ => eprintln!("Error: --io-threads requires a positive number"),
+                },
+                "--timeout" => match args.next().and_then(|val| val.parse::<u64>().ok()) {
+                    Some(seconds) => timeout = seconds,
+                    None => eprintln!("Error: --timeout requires a number of seconds"),
+                },
+                "--proto-max-bulk-len" => match args.next().as_deref().and_then(parse_memory) {
+                    Some(len) if len >= 1024 * 1024 => proto_max_bulk_len = len,
+                    _ => eprintln!("Error: --proto-max-bulk-len requires a size of at least 1mb"),
+                },
+                "--maxmemory" => match args.next().as_deref().and_then(parse_memory) {
+                    Some(bytes) => maxmemory = bytes,
+                    None => eprintln!("Error: --maxmemory requires a size such as 100mb"),
+                },
+                "--maxmemory-policy" => {
+                    match args.next().as_deref().and_then(EvictionPolicy::from_name) {
+                        Some(policy) => maxmemory_policy = policy,
+                        None => eprintln!("Error: --maxmemory-policy requires a known policy"),
+                    }
+                }
+                "--replica-read-only" => match args.next().as_deref().and_then(parse_yes_no) {
+                    Some(read_only) => replica_read_only = read_only,
+                    None => eprintln!("Error: --replica-read-only requires yes or no"),
+                },
+                "--loglevel" => match args.next().as_deref().and_then(parse_loglevel) {
+                    Some(level) => loglevel = level,
+                    None => eprintln!(
+                        "Error: --loglevel requires debug, verbose, notice, warning or nothing"
+                    ),
+                },
+                "--requirepass" => {
+                    requirepass = args.next().filter(|pass| !pass.is_empty());
+                }
+                "--skip-rdb-checksum" => {
+                    skip_rdb_checksum = true;
+                }
+                "--ignore-rdb-errors" => {
+                    ignore_rdb_errors = true;
+                }
+                "--repl-ping-replica-period" => {
+                    match args.next().and_then(|val| val.parse::<u64>().ok()) {
+                        Some(period) if period > 0 => repl_ping_replica_period = period,
+                        _ => eprintln!(
+                            "Error: --repl-ping-replica-period requires a positive number of seconds"
+                        ),
+                    }
+                }
+                "--client-output-buffer-limit" => {
+                    match args.next().as_deref().and_then(parse_output_buffer_limit) {
+                        Some(limit) => replica_output_buffer_limit = limit,
+                        None => eprintln!(
+                            "Error: --client-output-buffer-limit requires \"replica <hard> <soft> <seconds>\""
+                        ),
+                    }
+                }
 
                 "--replicaof" => {
                     if let Some(host_port) = args.next() {
                         let mut parts = host_port.splitn(2, ' ');
                         if let (Some(host), Some(port_str)) = (parts.next(), parts.next()) {
-                            let stream = Some(Arc::new(Mutex::new(sync_with_master(
-                                host,
-                                port_str,
-                                &port,
-                                &dbfilename,
-                            ))));
-                            master_stream = stream;
                             master_address = Some((host.to_string(), port_str.to_string()));
                         }
                     }
                 }
-                _ => {}
+                other => eprintln!("Warning: unknown option '{}'", other),
             }
         }
 
-        RedisGlobal {
+        // Sync once every flag is known so the RDB lands where start-up reads it.
+        // A master that can't be reached is retried by the replication thread.
+        if let Some((host, port_str)) = &master_address {
+            let rdb_path = format!("{}/{}", dir_path, dbfilename);
+            match sync_with_master(host, port_str, &port, &rdb_path, None) {
+                Ok((stream, result)) => {
+                    master_stream = Some(Arc::new(Mutex::new(stream)));
+                    if let PsyncResult::FullResync { replid, offset } = result {
+                        // A replica follows its master's history so it can resume later
+                        master_replid = replid;
+                        offset_replica_sync = offset;
+                    }
+                }
+                Err(e) => eprintln!("Failed to sync with master {host}:{port_str}: {e}"),
+            }
+        }
+
+        let mut global = RedisGlobal {
             port,
+            config_file,
+            changed_configs: BTreeSet::new(),
+            loglevel,
+            bind,
+            unixsocket,
+            unixsocketperm,
+            protected_mode,
             master_address,
             replica_caps: HashMap::new(),
             replica_states: HashMap::new(),
-            master_repl_offset,
+            replica_acked: Arc::new(Condvar::new()),
             master_stream,
-            master_replid: master_replid.to_string(),
+            master_link_generation: 0,
+            master_replid,
             dbfilename,
             dir_path,
-            offset_replica_sync: 0,
+            skip_rdb_checksum,
+            ignore_rdb_errors,
+            offset_replica_sync,
+            repl_backlog: ReplBacklog::new(repl_backlog_size),
+            repl_ping_replica_period,
+            replica_output_buffer_limit,
+            min_replicas_to_write: 0,
+            min_replicas_max_lag: DEFAULT_MIN_REPLICAS_MAX_LAG,
+            rdb_bgsave_in_progress: false,
+            rdb_last_save_time: unix_time(),
+            rdb_last_bgsave_status: true,
+            dirty: 0,
+            dirty_before_bgsave: 0,
+            save_params,
+            appendonly,
+            appendfilename,
+            aof: None,
+            aof_rewrite_buffer: None,
+            loading: false,
+            active_expire: true,
+            maxclients,
+            io_threads,
+            renamed_commands,
+            timeout,
+            shutdown: watch::channel(false).0,
+            proto_max_bulk_len,
+            maxmemory,
+            maxmemory_policy,
+            replica_read_only,
+            acl: Acl::new(requirepass.as_deref()),
+            requirepass,
+            start_time: Instant::now(),
+            stat_numcommands: 0,
+            stat_numconnections: 0,
+            stat_keyspace_hits: 0,
+            stat_keyspace_misses: 0,
+            stat_expiredkeys: 0,
+            stat_evictedkeys: 0,
+            stat_rejected_conn: 0,
+            slowlog: Slowlog::new(),
             channel_map: HashMap::new(),
+            pattern_map: HashMap::new(),
+            key_versions: HashMap::new(),
+            clients: BTreeMap::new(),
+        };
+        for (line_number, name, value) in file_settings {
+            if cli_params.contains(&name) {
+                continue;
+            }
+            if let Err(e) = global.set_config(&name, &value) {
+                eprintln!("Bad config file line {}: {}", line_number, e);
+                std::process::exit(1);
+            }
         }
+        // Only CONFIG SET counts as a runtime change
+        global.changed_configs.clear();
+        global
+    }
+}
+
+/// A fresh 40 character hex replication id.
+pub fn generate_replid() -> String {
+    let mut rng = rand::rng();
+    (0..40)
+        .map(|_| char::from_digit(rng.random_range(0..16), 16).unwrap())
+        .collect()
+}
+
+/// Redis's default save points.
+fn default_save_params() -> Vec<(u64, u64)> {
+    vec![(3600, 1), (300, 100), (60, 10000)]
+}
+
+fn parse_loglevel(value: &str) -> Option<String> {
+    let level = value.to_ascii_lowercase();
+    matches!(
+        level.as_str(),
+        "debug" | "verbose" | "notice" | "warning" | "nothing"
+    )
+    .then_some(level)
+}
+
+/// Parses "<seconds> <changes> ..."; an empty string disables saving.
+fn parse_save_params(value: &str) -> Option<Vec<(u64, u64)>> {
+    let numbers: Vec<u64> = value
+        .split_whitespace()
+        .map(|n| n.parse().ok())
+        .collect::<Option<_>>()?;
+    if !numbers.len().is_multiple_of(2) {
+        return None;
+    }
+    Some(numbers.chunks(2).map(|pair| (pair[0], pair[1])).collect())
+}
+
+/// Parses "<class> <hard> <soft> <seconds>" with sizes in parse_memory's
+/// units. Only the replica class (or its old name, slave) is supported.
+fn parse_output_buffer_limit(value: &str) -> Option<OutputBufferLimit> {
+    let args: Vec<&str> = value.split_whitespace().collect();
+    let [class, hard, soft, soft_seconds] = args[..] else {
+        return None;
+    };
+    if !matches!(class.to_ascii_lowercase().as_str(), "replica" | "slave") {
+        return None;
+    }
+    Some(OutputBufferLimit {
+        hard: parse_memory(hard)?,
+        soft: parse_memory(soft)?,
+        soft_seconds: soft_seconds.parse().ok()?,
+    })
+}
+
+fn yes_no(flag: bool) -> String {
+    if flag { "yes" } else { "no" }.to_string()
+}
+
+pub fn parse_yes_no(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "yes" => Some(true),
+        "no" => Some(false),
+        _ => None,
     }
 }
+
+/// Parses a byte count with Redis's units: k/m/g are powers of 1000,
+/// kb/mb/gb powers of 1024.
+fn parse_memory(value: &str) -> Option<u64> {
+    let value = value.to_ascii_lowercase();
+    let unit_start = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(unit_start);
+    let multiplier: u64 = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1 << 10,
+        "m" => 1000 * 1000,
+        "mb" => 1 << 20,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1 << 30,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+/// Byte count in INFO's human-readable form, e.g. 1.50M.
+pub fn format_memory(bytes: u64) -> String {
+    let units = [("G", 1u64 << 30), ("M", 1 << 20), ("K", 1 << 10)];
+    match units.iter().find(|(_, size)| bytes >= *size) {
+        Some((unit, size)) => format!("{:.2}{}", bytes as f64 / *size as f64, unit),
+        None => format!("{}B", bytes),
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
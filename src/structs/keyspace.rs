@@ -0,0 +1,536 @@
+use std::cell::Cell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rand::seq::IndexedRandom;
+
+use crate::enums::val_type::ValueType;
+use crate::structs::blocked_clients::BlockedClients;
+
+// Number of independently locked parts the keys are spread over
+const SHARD_COUNT: usize = 16;
+
+thread_local! {
+    // Set while this thread has shards held by `hold_keys`
+    static HOLDING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// One shard's keys, plus the ones with an expiry kept apart so active
+/// expiration can sample them without walking every key.
+struct Shard {
+    entries: HashMap<String, Entry>,
+    // Every key, so eviction can sample them; each entry records its position
+    keys: Vec<String>,
+    volatile: Vec<String>,
+    // Position of each volatile key in `volatile`
+    volatile_index: HashMap<String, usize>,
+    // Estimated memory of the whole keyspace, shared by all its shards
+    used_memory: Arc<AtomicU64>,
+}
+
+impl Shard {
+    fn new(used_memory: Arc<AtomicU64>) -> Self {
+        Shard {
+            entries: HashMap::new(),
+            keys: Vec::new(),
+            volatile: Vec::new(),
+            volatile_index: HashMap::new(),
+            used_memory,
+        }
+    }
+
+    fn insert(&mut self, key: String, mut entry: Entry) {
+        if entry.expire_at.is_some() {
+            self.track_volatile(&key);
+        } else {
+            self.untrack_volatile(&key);
+        }
+        entry.size = key_memory(&key, &entry.value);
+        self.used_memory.fetch_add(entry.size, Ordering::Relaxed);
+        match self.entries.get_mut(&key) {
+            Some(old) => {
+                self.used_memory.fetch_sub(old.size, Ordering::Relaxed);
+                entry.slot = old.slot;
+                *old = entry;
+            }
+            None => {
+                entry.slot = self.keys.len();
+                self.keys.push(key.clone());
+                self.entries.insert(key, entry);
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        self.untrack_volatile(key);
+        let entry = self.entries.remove(key)?;
+        self.used_memory.fetch_sub(entry.size, Ordering::Relaxed);
+        self.keys.swap_remove(entry.slot);
+        if let Some(moved) = self.keys.get(entry.slot) {
+            if let Some(moved) = self.entries.get_mut(moved) {
+                moved.slot = entry.slot;
+            }
+        }
+        Some(entry)
+    }
+
+    /// Re-estimates the memory of `key` after its value changed in place.
+    fn resize(&mut self, key: &str) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            let size = key_memory(key, &entry.value);
+            self.used_memory.fetch_add(size, Ordering::Relaxed);
+            self.used_memory.fetch_sub(entry.size, Ordering::Relaxed);
+            entry.size = size;
+        }
+    }
+
+    fn track_volatile(&mut self, key: &str) {
+        if !self.volatile_index.contains_key(key) {
+            self.volatile_index
+                .insert(key.to_string(), self.volatile.len());
+            self.volatile.push(key.to_string());
+        }
+    }
+
+    fn untrack_volatile(&mut self, key: &str) {
+        if let Some(position) = self.volatile_index.remove(key) {
+            self.volatile.swap_remove(position);
+            if let Some(moved) = self.volatile.get(position) {
+                self.volatile_index.insert(moved.clone(), position);
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        let freed: u64 = self.entries.values().map(|entry| entry.size).sum();
+        self.used_memory.fetch_sub(freed, Ordering::Relaxed);
+        self.entries.clear();
+        self.keys.clear();
+        self.volatile.clear();
+        self.volatile_index.clear();
+    }
+}
+
+/// A key's value and the metadata kept alongside it.
+#[derive(Clone)]
+pub struct Entry {
+    pub value: ValueType,
+    pub expire_at: Option<u64>, // epoch in ms
+    // Epoch in ms of the last command that touched the key, for LRU eviction
+    pub last_access_ms: u64,
+    // Estimated memory of the key and value, as last counted in `used_memory`
+    size: u64,
+    // Position of the key in its shard's `keys`
+    slot: usize,
+}
+
+impl Entry {
+    pub fn new(value: ValueType) -> Self {
+        Entry {
+            value,
+            expire_at: None,
+            last_access_ms: 0,
+            size: 0,
+            slot: 0,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        if let Some(expire_ts) = self.expire_at {
+            let now_ms = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64;
+            return now_ms >= expire_ts;
+        }
+        false
+    }
+}
+
+/// Estimated memory held by one key and its value.
+fn key_memory(key: &str, value: &ValueType) -> u64 {
+    // Per-key bookkeeping: the map entry plus its expiry slot
+    const KEY_OVERHEAD: usize = 48;
+    (key.len() + value.estimated_size() + KEY_OVERHEAD) as u64
+}
+
+/// The dataset, hashed by key over shards that are locked separately, so
+/// commands on unrelated keys don't wait on each other.
+///
+/// Locking rules: a thread holds at most one `KeyspaceGuard` at a time, a
+/// guard takes its shards in ascending order, and the global state is only
+/// locked after the keyspace. Multi-key commands lock all their keys with one
+/// `lock_keys` call rather than one key at a time. Shards held by
+/// `hold_keys` are only locked by the thread holding them.
+pub struct Keyspace {
+    shards: Vec<Mutex<Shard>>,
+    // Shards kept away from every thread but one, see `hold_keys`
+    held: Mutex<[bool; SHARD_COUNT]>,
+    released: Condvar,
+    // Bumped whenever a stream gets new entries, waking blocked XREADs
+    ready_version: Mutex<u64>,
+    ready: Condvar,
+    used_memory: Arc<AtomicU64>,
+    pub blocked_clients: BlockedClients,
+}
+
+impl Keyspace {
+    pub fn new() -> Self {
+        let used_memory = Arc::new(AtomicU64::new(0));
+        Keyspace {
+            shards: (0..SHARD_COUNT)
+                .map(|_| Mutex::new(Shard::new(used_memory.clone())))
+                .collect(),
+            held: Mutex::new([false; SHARD_COUNT]),
+            released: Condvar::new(),
+            ready_version: Mutex::new(0),
+            ready: Condvar::new(),
+            used_memory,
+            blocked_clients: BlockedClients::new(),
+        }
+    }
+
+    /// Locks the shard holding `key`.
+    pub fn lock(&self, key: &str) -> KeyspaceGuard<'_> {
+        self.lock_keys([key])
+    }
+
+    /// Locks the shards holding each of `keys`.
+    pub fn lock_keys<'k>(&self, keys: impl IntoIterator<Item = &'k str>) -> KeyspaceGuard<'_> {
+        let mut indices: Vec<usize> = keys.into_iter().map(shard_index).collect();
+        indices.sort_unstable();
+        indices.dedup();
+        self.lock_shards(indices)
+    }
+
+    /// Keeps the shards of `keys` from other threads until the returned hold
+    /// is dropped, while this thread goes on locking them a command at a
+    /// time. EXEC holds its keys like this, so no other client writes them
+    /// before the transaction is done and sent to the replicas.
+    pub fn hold_keys<'k>(&self, keys: impl IntoIterator<Item = &'k str>) -> KeyspaceHold<'_> {
+        let mut indices: Vec<usize> = keys.into_iter().map(shard_index).collect();
+        indices.sort_unstable();
+        indices.dedup();
+        // All at once, so two holders never wait on each other
+        let mut held = self
+            .released
+            .wait_while(self.held.lock().unwrap(), |held| {
+                indices.iter().any(|&index| held[index])
+            })
+            .unwrap();
+        for &index in &indices {
+            held[index] = true;
+        }
+        HOLDING.with(|holding| holding.set(true));
+        KeyspaceHold {
+            keyspace: self,
+            indices,
+        }
+    }
+
+    /// Locks every shard, for commands that walk the whole dataset.
+    pub fn lock_all(&self) -> KeyspaceGuard<'_> {
+        self.lock_shards((0..SHARD_COUNT).collect())
+    }
+
+    /// Locks shard `index` alone, for walking the dataset a shard at a time
+    /// without holding up commands on the others.
+    pub fn lock_shard(&self, index: usize) -> KeyspaceGuard<'_> {
+        self.lock_shards(vec![index])
+    }
+
+    pub fn shard_count(&self) -> usize {
+        SHARD_COUNT
+    }
+
+    /// Estimated memory held by the dataset, keys included. It is kept up to
+    /// date as keys change, so reading it locks nothing.
+    pub fn used_memory(&self) -> u64 {
+        self.used_memory.load(Ordering::Relaxed)
+    }
+
+    /// Up to `samples` random keys of shard `index`, only ones with an expiry
+    /// if `volatile_only`, each with when it was last accessed.
+    pub fn eviction_sample(
+        &self,
+        index: usize,
+        samples: usize,
+        volatile_only: bool,
+    ) -> Vec<(String, u64)> {
+        self.wait_until_unheld(&[index]);
+        let shard = self.shards[index].lock().unwrap();
+        let candidates = if volatile_only {
+            &shard.volatile
+        } else {
+            &shard.keys
+        };
+        candidates
+            .choose_multiple(&mut rand::rng(), samples)
+            .filter_map(|key| {
+                let entry = shard.entries.get(key)?;
+                Some((key.clone(), entry.last_access_ms))
+            })
+            .collect()
+    }
+
+    /// Checks up to `samples` random keys with an expiry in shard `index`
+    /// and removes the expired ones, holding only that shard. Returns how
+    /// many were checked and the keys removed.
+    pub fn expire_sample(&self, index: usize, samples: usize) -> (usize, Vec<String>) {
+        self.wait_until_unheld(&[index]);
+        let mut shard = self.shards[index].lock().unwrap();
+        let mut rng = rand::rng();
+        let sampled: Vec<String> = shard
+            .volatile
+            .choose_multiple(&mut rng, samples)
+            .cloned()
+            .collect();
+        let mut expired = Vec::new();
+        for key in &sampled {
+            match shard.entries.get(key) {
+                Some(entry) if entry.is_expired() => {
+                    shard.remove(key);
+                    expired.push(key.clone());
+                }
+                // Lost its expiry through `entry_mut`
+                Some(entry) if entry.expire_at.is_none() => shard.untrack_volatile(key),
+                _ => {}
+            }
+        }
+        (sampled.len(), expired)
+    }
+
+    /// Wakes commands blocked until a stream has new entries. They recheck
+    /// their keys, so signalling for a key nobody waits on is harmless.
+    pub fn signal_ready(&self) {
+        *self.ready_version.lock().unwrap() += 1;
+        self.ready.notify_all();
+    }
+
+    /// Taken before checking the keys a command blocks on, and passed to
+    /// `wait_ready` so a signal in between isn't missed.
+    pub fn ready_version(&self) -> u64 {
+        *self.ready_version.lock().unwrap()
+    }
+
+    /// Waits, holding no shard, until `signal_ready` is called after `seen`
+    /// was taken or `timeout` passes.
+    pub fn wait_ready(&self, seen: u64, timeout: Duration) {
+        let version = self.ready_version.lock().unwrap();
+        let _ = self
+            .ready
+            .wait_timeout_while(version, timeout, |version| *version == seen);
+    }
+
+    /// Waits out other threads' holds on `indices`. The holding thread's own
+    /// commands go straight on.
+    fn wait_until_unheld(&self, indices: &[usize]) {
+        if HOLDING.with(Cell::get) {
+            return;
+        }
+        let _held = self
+            .released
+            .wait_while(self.held.lock().unwrap(), |held| {
+                indices.iter().any(|&index| held[index])
+            })
+            .unwrap();
+    }
+
+    fn lock_shards(&self, indices: Vec<usize>) -> KeyspaceGuard<'_> {
+        self.wait_until_unheld(&indices);
+        KeyspaceGuard {
+            shards: indices
+                .into_iter()
+                .map(|index| (index, self.shards[index].lock().unwrap()))
+                .collect(),
+            changed: Vec::new(),
+        }
+    }
+}
+
+impl Default for Keyspace {
+    fn default() -> Self {
+        Keyspace::new()
+    }
+}
+
+fn shard_index(key: &str) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % SHARD_COUNT as u64) as usize
+}
+
+/// Shards held by `Keyspace::hold_keys`, released when dropped, also when
+/// the holder panics.
+pub struct KeyspaceHold<'a> {
+    keyspace: &'a Keyspace,
+    indices: Vec<usize>,
+}
+
+impl Drop for KeyspaceHold<'_> {
+    fn drop(&mut self) {
+        let mut held = self.keyspace.held.lock().unwrap();
+        for &index in &self.indices {
+            held[index] = false;
+        }
+        HOLDING.with(|holding| holding.set(false));
+        self.keyspace.released.notify_all();
+    }
+}
+
+/// The locked shards of a `Keyspace`. Looking up a key whose shard it
+/// didn't lock is a bug and panics.
+pub struct KeyspaceGuard<'a> {
+    shards: Vec<(usize, MutexGuard<'a, Shard>)>,
+    // Keys handed out mutably, re-measured when the guard is dropped
+    changed: Vec<String>,
+}
+
+impl KeyspaceGuard<'_> {
+    fn shard(&self, key: &str) -> &Shard {
+        let index = shard_index(key);
+        match self.shards.iter().find(|(locked, _)| *locked == index) {
+            Some((_, shard)) => shard,
+            None => panic!("shard of key '{}' is not locked", key),
+        }
+    }
+
+    fn shard_mut(&mut self, key: &str) -> &mut Shard {
+        let index = shard_index(key);
+        match self.shards.iter_mut().find(|(locked, _)| *locked == index) {
+            Some((_, shard)) => shard,
+            None => panic!("shard of key '{}' is not locked", key),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&ValueType> {
+        self.entry(key).map(|entry| &entry.value)
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut ValueType> {
+        self.entry_mut(key).map(|entry| &mut entry.value)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.shard(key).entries.contains_key(key)
+    }
+
+    pub fn entry(&self, key: &str) -> Option<&Entry> {
+        self.shard(key).entries.get(key)
+    }
+
+    /// A key's entry, for changing its value or access time. A new expiry
+    /// goes through `insert_entry`, so active expiration knows about it.
+    pub fn entry_mut(&mut self, key: &str) -> Option<&mut Entry> {
+        if self.shard(key).entries.contains_key(key) {
+            self.changed.push(key.to_string());
+        }
+        self.shard_mut(key).entries.get_mut(key)
+    }
+
+    /// Sets the value of `key`. A key that already exists keeps its expiry,
+    /// so callers that replace a value wholesale clear it themselves.
+    pub fn insert(&mut self, key: String, value: ValueType) -> Option<ValueType> {
+        let shard = self.shard_mut(&key);
+        match shard.entries.get_mut(&key) {
+            Some(entry) => {
+                let old = mem::replace(&mut entry.value, value);
+                shard.resize(&key);
+                Some(old)
+            }
+            None => {
+                shard.insert(key, Entry::new(value));
+                None
+            }
+        }
+    }
+
+    pub fn insert_entry(&mut self, key: String, entry: Entry) {
+        self.shard_mut(&key).insert(key, entry);
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<ValueType> {
+        self.shard_mut(key).remove(key).map(|entry| entry.value)
+    }
+
+    /// Keys in the locked shards and their entries.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Entry)> {
+        self.shards
+            .iter()
+            .flat_map(|(_, shard)| shard.entries.iter())
+    }
+
+    /// A copy of the locked part of the dataset, for saving in the background.
+    pub fn snapshot(&self) -> HashMap<String, Entry> {
+        self.iter()
+            .map(|(key, entry)| (key.clone(), entry.clone()))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|(_, shard)| shard.entries.len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&mut self) {
+        for (_, shard) in self.shards.iter_mut() {
+            shard.clear();
+        }
+    }
+}
+
+impl Drop for KeyspaceGuard<'_> {
+    fn drop(&mut self) {
+        for key in mem::take(&mut self.changed) {
+            self.shard_mut(&key).resize(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn held_keys_wait_for_other_threads_but_not_the_holder() {
+        let keyspace = Arc::new(Keyspace::new());
+        let hold = keyspace.hold_keys(["a", "b"]);
+        keyspace
+            .lock("a")
+            .insert("a".to_string(), ValueType::Int(1));
+
+        let (done, finished) = mpsc::channel();
+        let writer = thread::spawn({
+            let keyspace = keyspace.clone();
+            move || {
+                keyspace
+                    .lock("a")
+                    .insert("a".to_string(), ValueType::Int(2));
+                done.send(()).unwrap();
+            }
+        });
+        assert!(finished.recv_timeout(Duration::from_millis(200)).is_err());
+
+        drop(hold);
+        finished.recv_timeout(Duration::from_secs(5)).unwrap();
+        writer.join().unwrap();
+        assert!(matches!(
+            keyspace.lock("a").get("a"),
+            Some(ValueType::Int(2))
+        ));
+    }
+}
@@ -1,12 +1,23 @@
-pub mod config;
+pub mod acl;
+pub mod blocked_clients;
+pub mod client_info;
+pub mod client_stream;
+pub mod command_spec;
 pub mod connection;
+pub mod consumer_group;
+pub mod geosearch_config;
 pub mod global;
+pub mod keyspace;
+pub mod outbox;
+pub mod repl_backlog;
 pub mod replica;
 pub mod request;
 pub mod runner;
 pub mod skiplist;
+pub mod slowlog;
+pub mod socket;
 pub mod stream;
 pub mod transaction;
-pub mod transaction_runner;
+pub mod worker_pool;
 pub mod xread_config;
 pub mod zset;
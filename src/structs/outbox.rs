@@ -0,0 +1,77 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use bytes::Bytes;
+use tokio::sync::mpsc::{self, error::SendError, UnboundedReceiver, UnboundedSender};
+
+/// Frames queued for a connection's writer, which sends them in order. A
+/// frame fanned out to several connections is shared rather than copied.
+///
+/// Queuing never blocks. The bytes still waiting are counted instead, so a
+/// consumer that falls behind can be cut off (`client-output-buffer-limit`).
+#[derive(Debug, Clone)]
+pub struct Outbox {
+    sender: UnboundedSender<Bytes>,
+    queued: Arc<AtomicUsize>,
+}
+
+/// The writer's end of an `Outbox`.
+pub struct OutboxReceiver {
+    receiver: UnboundedReceiver<Bytes>,
+    queued: Arc<AtomicUsize>,
+}
+
+pub fn outbox() -> (Outbox, OutboxReceiver) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let queued = Arc::new(AtomicUsize::new(0));
+    (
+        Outbox {
+            sender,
+            queued: Arc::clone(&queued),
+        },
+        OutboxReceiver { receiver, queued },
+    )
+}
+
+impl Outbox {
+    pub fn send(&self, frame: Bytes) -> Result<(), SendError<Bytes>> {
+        // Counted first so the writer never takes off more than was added
+        let len = frame.len();
+        self.queued.fetch_add(len, Ordering::Relaxed);
+        self.sender.send(frame).inspect_err(|_| {
+            self.queued.fetch_sub(len, Ordering::Relaxed);
+        })
+    }
+
+    /// Bytes queued and not yet taken by the writer.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Whether the writer is gone, as `closed` would report without waiting.
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+
+    /// Completes once the writer is gone, after a failed write or once the
+    /// connection closed.
+    pub async fn closed(&self) {
+        self.sender.closed().await
+    }
+}
+
+impl OutboxReceiver {
+    pub async fn recv(&mut self) -> Option<Bytes> {
+        let frame = self.receiver.recv().await?;
+        self.queued.fetch_sub(frame.len(), Ordering::Relaxed);
+        Some(frame)
+    }
+
+    pub fn blocking_recv(&mut self) -> Option<Bytes> {
+        let frame = self.receiver.blocking_recv()?;
+        self.queued.fetch_sub(frame.len(), Ordering::Relaxed);
+        Some(frame)
+    }
+}
@@ -0,0 +1,57 @@
+use std::collections::VecDeque;
+
+pub const DEFAULT_REPL_BACKLOG_SIZE: usize = 1024 * 1024;
+
+/// The tail of the replication stream, kept so a replica that dropped for a
+/// moment can resume with `PSYNC <replid> <offset>` instead of a full resync.
+#[derive(Debug)]
+pub struct ReplBacklog {
+    buffer: VecDeque<u8>,
+    capacity: usize,
+    // Replication offset just past the last byte in `buffer`
+    end_offset: usize,
+}
+
+impl ReplBacklog {
+    pub fn new(capacity: usize) -> Self {
+        Self::starting_at(capacity, 0)
+    }
+
+    /// An empty backlog whose next byte sits at `offset`.
+    pub fn starting_at(capacity: usize, offset: usize) -> Self {
+        ReplBacklog {
+            buffer: VecDeque::new(),
+            capacity,
+            end_offset: offset,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.end_offset += bytes.len();
+        self.buffer.extend(bytes);
+        let overflow = self.buffer.len().saturating_sub(self.capacity);
+        self.buffer.drain(..overflow);
+    }
+
+    pub fn start_offset(&self) -> usize {
+        self.end_offset - self.buffer.len()
+    }
+
+    /// Everything from `offset` onwards, or None once part of it was evicted.
+    pub fn range_from(&self, offset: usize) -> Option<Vec<u8>> {
+        if offset < self.start_offset() || offset > self.end_offset {
+            return None;
+        }
+        Some(
+            self.buffer
+                .iter()
+                .skip(offset - self.start_offset())
+                .copied()
+                .collect(),
+        )
+    }
+}
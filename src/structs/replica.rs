@@ -1,58 +1,252 @@
 use std::{
-    io::Write,
-    net::TcpStream,
-    sync::{mpsc, Arc, Mutex},
+    collections::HashMap,
+    io,
+    net::Shutdown,
+    sync::{Arc, Mutex},
     thread,
+    time::{Duration, Instant},
 };
 
+use bytes::Bytes;
+
+use crate::rdb::save::encode_rdb;
+use crate::structs::global::RedisGlobal;
+use crate::structs::keyspace::Entry;
+use crate::structs::outbox::Outbox;
+use crate::structs::socket::Socket;
+use crate::types::RedisGlobalType;
+
+// Size of the pieces a snapshot is queued in, and how many may wait at once
+const SNAPSHOT_CHUNK: usize = 256 * 1024;
+const SNAPSHOT_CHUNKS_QUEUED: usize = 4;
+
+pub const DEFAULT_REPLICA_OUTPUT_BUFFER_LIMIT: OutputBufferLimit = OutputBufferLimit {
+    hard: 256 * 1024 * 1024,
+    soft: 64 * 1024 * 1024,
+    soft_seconds: 60,
+};
+
+/// `client-output-buffer-limit` for replicas: a replica is dropped once its
+/// queued stream passes `hard` bytes, or stays past `soft` bytes for
+/// `soft_seconds`. A limit of 0 is off.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutputBufferLimit {
+    pub hard: u64,
+    pub soft: u64,
+    pub soft_seconds: u64,
+}
+
 #[derive(Debug)]
 pub struct ReplicaState {
-    pub sender: mpsc::Sender<String>,
-    pub stream: Arc<Mutex<TcpStream>>,
+    pub sender: Outbox,
+    pub stream: Arc<Mutex<Socket>>,
     pub local_offset: usize,
+    pub last_ack_time: Instant,
+    // Bytes queued ahead of the propagated stream, mostly the snapshot, that
+    // may still be waiting; the output buffer limit leaves them out
+    pub snapshot_pending: usize,
+    // Since when the queued stream has been past the soft limit
+    pub soft_limit_since: Option<Instant>,
+    // The propagated stream, held back while the snapshot is still queued
+    pub sync_buffer: Option<Vec<u8>>,
 }
 
 impl ReplicaState {
-    pub fn new(stream: Arc<Mutex<TcpStream>>, sender: mpsc::Sender<String>) -> Self {
+    pub fn new(stream: Arc<Mutex<Socket>>, sender: Outbox, local_offset: usize) -> Self {
         ReplicaState {
             stream,
+            snapshot_pending: sender.queued(),
             sender,
-            local_offset: 0,
+            local_offset,
+            last_ack_time: Instant::now(),
+            soft_limit_since: None,
+            sync_buffer: None,
+        }
+    }
+
+    /// Whether the propagated stream queued for this replica is over `limit`.
+    pub fn exceeds(&mut self, limit: &OutputBufferLimit) -> bool {
+        // The queue drains in order, so no more snapshot than is queued can
+        // still be waiting
+        let queued = self.sender.queued();
+        self.snapshot_pending = self.snapshot_pending.min(queued);
+        let backlog = (queued - self.snapshot_pending) as u64;
+
+        if limit.hard > 0 && backlog > limit.hard {
+            return true;
+        }
+        if limit.soft == 0 || backlog <= limit.soft {
+            self.soft_limit_since = None;
+            return false;
         }
+        let since = *self.soft_limit_since.get_or_insert_with(Instant::now);
+        since.elapsed().as_secs() >= limit.soft_seconds
     }
 }
 
+/// Registers a replica that just received its snapshot. The propagated
+/// stream goes out through `sender`, its connection's outbox, after the
+/// snapshot. It starts out acked at the current offset, since the snapshot
+/// already covers everything before it.
 pub fn add_replica(
-    guard: &mut std::sync::MutexGuard<'_, crate::structs::global::RedisGlobal>,
-    stream: TcpStream,
+    guard: &mut std::sync::MutexGuard<'_, RedisGlobal>,
+    sender: Outbox,
+    stream: Socket,
     replica_port: &str,
-) {
-    let (tx, rx) = mpsc::channel::<String>();
-
-    let stream_arc = Arc::new(Mutex::new(stream));
-    let stream_for_thread = Arc::clone(&stream_arc);
+) -> Arc<Mutex<Socket>> {
+    let offset = guard.offset_replica_sync;
+    let stream = Arc::new(Mutex::new(stream));
+    guard.replica_states.insert(
+        replica_port.to_string(),
+        ReplicaState::new(Arc::clone(&stream), sender, offset),
+    );
+    stream
+}
 
-    spawn_replica_stream_sender(stream_for_thread, rx);
+/// Registers a replica whose snapshot, taken with the dataset and the global
+/// state locked, still has to be sent. A background thread encodes it and
+/// queues it a chunk at a time, while what is propagated meanwhile waits in
+/// the replica's `sync_buffer`, the way an AOF rewrite buffers its tail.
+pub fn spawn_replica_sync(
+    guard: &mut std::sync::MutexGuard<'_, RedisGlobal>,
+    global_state: &RedisGlobalType,
+    sender: Outbox,
+    stream: Socket,
+    replica_port: &str,
+    snapshot: HashMap<String, Entry>,
+) {
+    let link = add_replica(guard, sender.clone(), stream, replica_port);
+    if let Some(replica) = guard.replica_states.get_mut(replica_port) {
+        replica.sync_buffer = Some(Vec::new());
+    }
 
-    guard
-        .replica_states
-        .insert(replica_port.to_string(), ReplicaState::new(stream_arc, tx));
+    let global_state = Arc::clone(global_state);
+    let replica_port = replica_port.to_string();
+    thread::spawn(move || {
+        let rdb = Bytes::from(encode_rdb(snapshot.iter()));
+        drop(snapshot);
+        let transfer = queue_snapshot(&sender, rdb);
+        finish_replica_sync(&global_state, &replica_port, &link, transfer);
+    });
 }
 
-fn spawn_replica_stream_sender(stream: Arc<Mutex<TcpStream>>, receiver: mpsc::Receiver<String>) {
-    thread::spawn(move || {
-        while let Ok(msg) = receiver.recv() {
-            let mut stream_guard = match stream.lock() {
-                Ok(guard) => guard,
-                Err(_) => {
-                    eprintln!("Failed to lock stream for replica");
-                    break;
-                }
-            };
-            if let Err(e) = stream_guard.write_all(msg.as_bytes()) {
-                eprintln!("Failed to write to replica: {:?}", e);
-                break;
+/// Queues `rdb` as a bulk payload, in chunks that share its buffer. Waits
+/// for the writer to take the earlier ones first, so a slow replica isn't
+/// handed the whole image at once.
+fn queue_snapshot(sender: &Outbox, rdb: Bytes) -> io::Result<()> {
+    let broken = |_| io::Error::from(io::ErrorKind::BrokenPipe);
+    sender
+        .send(Bytes::from(format!("${}\r\n", rdb.len())))
+        .map_err(broken)?;
+    for start in (0..rdb.len()).step_by(SNAPSHOT_CHUNK) {
+        while sender.queued() > SNAPSHOT_CHUNK * SNAPSHOT_CHUNKS_QUEUED {
+            if sender.is_closed() {
+                return Err(io::ErrorKind::BrokenPipe.into());
             }
+            thread::sleep(Duration::from_millis(1));
+        }
+        let end = (start + SNAPSHOT_CHUNK).min(rdb.len());
+        sender.send(rdb.slice(start..end)).map_err(broken)?;
+    }
+    Ok(())
+}
+
+/// Once the snapshot is queued, queues what was held back behind it and
+/// lets the stream flow straight to the replica. A failed transfer drops it.
+fn finish_replica_sync(
+    global_state: &RedisGlobalType,
+    replica_port: &str,
+    link: &Arc<Mutex<Socket>>,
+    transfer: io::Result<()>,
+) {
+    let mut global = global_state.lock().unwrap();
+    // Dropped or replaced by a newer link meanwhile
+    let Some(replica) = global
+        .replica_states
+        .get_mut(replica_port)
+        .filter(|replica| Arc::ptr_eq(&replica.stream, link))
+    else {
+        return;
+    };
+    let held_back = replica.sync_buffer.take().unwrap_or_default();
+    replica.snapshot_pending = replica.sender.queued();
+    let queued = transfer.and_then(|_| {
+        if held_back.is_empty() {
+            return Ok(());
         }
+        replica
+            .sender
+            .send(held_back.into())
+            .map_err(|_| io::ErrorKind::BrokenPipe.into())
     });
+    if let Err(e) = queued {
+        eprintln!("Sync with replica {} failed: {}", replica_port, e);
+        drop_replica(&mut global, replica_port, "its snapshot transfer failed");
+    }
+}
+
+/// Drops a replica that can't keep up or whose writer failed, closing its
+/// link so the connection winds down too.
+pub fn drop_replica(global: &mut RedisGlobal, replica_port: &str, reason: &str) {
+    if let Some(replica) = global.replica_states.remove(replica_port) {
+        global.replica_caps.remove(replica_port);
+        let _ = replica.stream.lock().unwrap().shutdown(Shutdown::Both);
+        eprintln!("Replica {} dropped: {}", replica_port, reason);
+    }
+}
+
+/// Drops a replica whose link failed. `stream` identifies the link, so a
+/// replica that already reconnected on the same port is left alone.
+pub fn remove_replica(
+    global_state: &RedisGlobalType,
+    replica_port: &str,
+    stream: &Arc<Mutex<Socket>>,
+) {
+    let mut global = global_state.lock().unwrap();
+    let is_same_link = global
+        .replica_states
+        .get(replica_port)
+        .is_some_and(|replica| Arc::ptr_eq(&replica.stream, stream));
+    if is_same_link {
+        global.replica_states.remove(replica_port);
+        global.replica_caps.remove(replica_port);
+        eprintln!("Replica {} disconnected; removed", replica_port);
+    }
+}
+
+/// The link of the replica registered on `replica_port`, which identifies it
+/// for `remove_replica`, and the outbox its stream is queued on.
+pub fn replica_link(
+    global_state: &RedisGlobalType,
+    replica_port: &str,
+) -> Option<(Arc<Mutex<Socket>>, Outbox)> {
+    let global = global_state.lock().unwrap();
+    global
+        .replica_states
+        .get(replica_port)
+        .map(|replica| (Arc::clone(&replica.stream), replica.sender.clone()))
+}
+
+/// Records a `REPLCONF ACK <offset>` read from a replica's link. Anything
+/// else the replica sends is ignored.
+pub fn record_replica_ack(global_state: &RedisGlobalType, replica_port: &str, args: &[Vec<u8>]) {
+    if args.len() < 3
+        || !args[0].eq_ignore_ascii_case(b"REPLCONF")
+        || !args[1].eq_ignore_ascii_case(b"ACK")
+    {
+        return;
+    }
+    let Some(replica_offset) = std::str::from_utf8(&args[2])
+        .ok()
+        .and_then(|offset| offset.parse::<usize>().ok())
+    else {
+        return;
+    };
+
+    let mut global = global_state.lock().unwrap();
+    if let Some(replica) = global.replica_states.get_mut(replica_port) {
+        replica.local_offset = replica_offset;
+        replica.last_ack_time = Instant::now();
+    }
+    global.replica_acked.notify_all();
 }
@@ -1,42 +1,273 @@
+use crate::structs::global::DEFAULT_PROTO_MAX_BULK_LEN;
+use crate::utils::split_args;
+
+// Redis's limits, so a malformed request fails instead of buffering forever.
+// A line without its newline can't grow past MAX_INLINE_LEN. Bulk lengths are
+// capped by proto-max-bulk-len.
+const MAX_INLINE_LEN: usize = 64 * 1024;
+const MAX_MULTIBULK_LEN: i64 = 1024 * 1024;
+// Consumed bytes are only moved out once there are this many of them
+const COMPACT_AFTER: usize = 64 * 1024;
+
 #[derive(Debug)]
 pub struct Request {
-    pub args: Vec<String>,
+    pub args: Vec<Vec<u8>>,
+}
+
+enum Frame {
+    Complete(Vec<Vec<u8>>, usize),
+    // Not all here yet; holds the fewest bytes the whole frame can take
+    Incomplete(usize),
 }
 
 impl Request {
-    pub fn try_parse(buffer: &[u8]) -> Option<(Self, usize)> {
-        let buffer_string = String::from_utf8_lossy(buffer);
+    /// Parses one request from the front of `buffer`, returning it with the
+    /// number of bytes it took, or None while it isn't complete yet. Empty
+    /// requests are skipped over.
+    ///
+    /// Requests are RESP arrays of bulk strings, or inline commands (a line
+    /// of space-separated, optionally quoted arguments) for telnet-style
+    /// clients. Bulks are read by their declared length, so they may hold
+    /// "\r\n" or any other bytes, and are returned exactly as sent. Inline
+    /// commands are text and must be valid UTF-8.
+    ///
+    /// Errors are protocol errors; the connection can't be read past them.
+    pub fn try_parse(buffer: &[u8]) -> Result<Option<(Self, usize)>, String> {
+        match parse_frame(buffer, DEFAULT_PROTO_MAX_BULK_LEN)? {
+            Frame::Complete(args, consumed) => Ok(Some((Request { args }, consumed))),
+            Frame::Incomplete(_) => Ok(None),
+        }
+    }
+}
 
-        let mut lines = buffer_string.split("\r\n").peekable();
+/// Bytes read from a connection, parsed into requests as they complete.
+/// A partial request is only parsed again once enough bytes arrived to
+/// finish it, so large bulks read in small chunks take linear time.
+pub struct RequestBuffer {
+    bytes: Vec<u8>,
+    // Start of the first request not handed out yet
+    start: usize,
+    // Bytes from `start` the pending request needs at the least
+    needed: usize,
+    // proto-max-bulk-len, kept current by the connection
+    pub max_bulk_len: u64,
+}
 
-        let array_header = lines.next()?;
-        if !array_header.starts_with('*') {
-            return None;
+impl RequestBuffer {
+    pub fn new() -> Self {
+        RequestBuffer {
+            bytes: Vec::new(),
+            start: 0,
+            needed: 0,
+            max_bulk_len: DEFAULT_PROTO_MAX_BULK_LEN,
         }
-        let num_args: usize = array_header[1..].parse().ok()?;
-
-        let mut args = Vec::with_capacity(num_args);
-        let mut consumed_bytes = array_header.len() + 2; // +2 for \r\n
+    }
 
-        for _ in 0..num_args {
-            let len_line = lines.next()?;
-            consumed_bytes += len_line.len() + 2;
+    pub fn extend(&mut self, data: &[u8]) {
+        if self.start == self.bytes.len() {
+            self.bytes.clear();
+            self.start = 0;
+        } else if self.start >= COMPACT_AFTER && self.start * 2 >= self.bytes.len() {
+            self.bytes.drain(..self.start);
+            self.start = 0;
+        }
+        self.bytes.extend_from_slice(data);
+    }
 
-            if !len_line.starts_with('$') {
-                return None;
+    /// The next complete request and the bytes it took, as for
+    /// `Request::try_parse`.
+    pub fn next_request(&mut self) -> Result<Option<(Request, usize)>, String> {
+        let pending = &self.bytes[self.start..];
+        if pending.len() < self.needed {
+            return Ok(None);
+        }
+        match parse_frame(pending, self.max_bulk_len)? {
+            Frame::Complete(args, consumed) => {
+                self.start += consumed;
+                self.needed = 0;
+                Ok(Some((Request { args }, consumed)))
+            }
+            Frame::Incomplete(needed) => {
+                self.needed = needed;
+                Ok(None)
             }
-            let len: usize = len_line[1..].parse().ok()?;
+        }
+    }
 
-            let value = lines.next()?;
-            consumed_bytes += value.len() + 2;
+    /// The bytes not yet handed out as requests.
+    pub fn into_remaining(mut self) -> Vec<u8> {
+        self.bytes.drain(..self.start);
+        self.bytes
+    }
+}
+
+impl Default for RequestBuffer {
+    fn default() -> Self {
+        RequestBuffer::new()
+    }
+}
 
-            if value.len() < len {
-                return None;
+/// Parses the first request in `buffer`, skipping empty ones.
+fn parse_frame(buffer: &[u8], max_bulk_len: u64) -> Result<Frame, String> {
+    let mut skipped = 0;
+    loop {
+        let rest = &buffer[skipped..];
+        let frame = if rest.first() == Some(&b'*') {
+            parse_multibulk(rest, max_bulk_len)?
+        } else {
+            parse_inline(rest)?
+        };
+        match frame {
+            Frame::Complete(args, consumed) if args.is_empty() => skipped += consumed,
+            Frame::Complete(args, consumed) => {
+                return Ok(Frame::Complete(args, skipped + consumed))
             }
+            Frame::Incomplete(needed) => return Ok(Frame::Incomplete(skipped + needed)),
+        }
+    }
+}
 
-            args.push(value.to_string());
+fn parse_multibulk(buffer: &[u8], max_bulk_len: u64) -> Result<Frame, String> {
+    let Some((num_args, mut pos)) = read_line(buffer, "mbulk")? else {
+        return Ok(Frame::Incomplete(buffer.len() + 1));
+    };
+    // "*-1" and "*0" are empty requests
+    let num_args = match parse_number(&num_args[1..]) {
+        Some(n) if n <= 0 => return Ok(Frame::Complete(Vec::new(), pos)),
+        Some(n) if n <= MAX_MULTIBULK_LEN => n as usize,
+        _ => return Err("Protocol error: invalid multibulk length".to_string()),
+    };
+
+    let mut args = Vec::with_capacity(num_args.min(1024));
+    for _ in 0..num_args {
+        let Some((header, header_len)) = read_line(&buffer[pos..], "bulk")? else {
+            return Ok(Frame::Incomplete(buffer.len() + 1));
+        };
+        let Some((b'$', digits)) = header.split_first() else {
+            let got = header.first().map_or(' ', |&byte| byte as char);
+            return Err(format!("Protocol error: expected '$', got '{}'", got));
+        };
+        let len = match parse_number(digits) {
+            Some(len) if len >= 0 && len as u64 <= max_bulk_len => len as usize,
+            _ => return Err("Protocol error: invalid bulk length".to_string()),
+        };
+        pos += header_len;
+
+        // Wait for the whole bulk and its terminator
+        let end = pos + len;
+        if buffer.len() < end + 2 {
+            return Ok(Frame::Incomplete(end + 2));
+        }
+        if &buffer[end..end + 2] != b"\r\n" {
+            return Err("Protocol error: invalid bulk length".to_string());
         }
+        args.push(buffer[pos..end].to_vec());
+        pos = end + 2;
+    }
+    Ok(Frame::Complete(args, pos))
+}
+
+fn parse_inline(buffer: &[u8]) -> Result<Frame, String> {
+    let Some(newline) = buffer.iter().position(|&byte| byte == b'\n') else {
+        if buffer.len() > MAX_INLINE_LEN {
+            return Err("Protocol error: too big inline request".to_string());
+        }
+        return Ok(Frame::Incomplete(buffer.len() + 1));
+    };
+    let line = std::str::from_utf8(&buffer[..newline])
+        .map_err(|_| "Protocol error: invalid UTF-8 in inline request".to_string())?;
+    let args = split_args(line.trim_end_matches('\r'))
+        .map_err(|_| "Protocol error: unbalanced quotes in request".to_string())?;
+    Ok(Frame::Complete(
+        args.into_iter().map(String::into_bytes).collect(),
+        newline + 1,
+    ))
+}
+
+/// The line at the front of `buffer` without its "\r\n", and the line's
+/// length including it. `kind` names the count line in the error for one
+/// that grew too long.
+fn read_line<'a>(buffer: &'a [u8], kind: &str) -> Result<Option<(&'a [u8], usize)>, String> {
+    match buffer.windows(2).position(|pair| pair == b"\r\n") {
+        Some(line_end) => Ok(Some((&buffer[..line_end], line_end + 2))),
+        None if buffer.len() > MAX_INLINE_LEN => {
+            Err(format!("Protocol error: too big {} count string", kind))
+        }
+        None => Ok(None),
+    }
+}
+
+fn parse_number(digits: &[u8]) -> Option<i64> {
+    std::str::from_utf8(digits).ok()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(args: &[&[u8]]) -> Vec<u8> {
+        let mut frame = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            frame.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+            frame.extend_from_slice(arg);
+            frame.extend_from_slice(b"\r\n");
+        }
+        frame
+    }
+
+    #[test]
+    fn bulks_keep_crlf_dollars_and_non_utf8_bytes() {
+        let value: &[u8] = b"a\r\n$3\r\nb\r\n*1\r\n\x00\xff";
+        let frame = encode(&[b"SET", b"k", value]);
+        let (request, consumed) = Request::try_parse(&frame).unwrap().unwrap();
+        assert_eq!(consumed, frame.len());
+        assert_eq!(
+            request.args,
+            vec![b"SET".to_vec(), b"k".to_vec(), value.to_vec()]
+        );
+    }
+
+    #[test]
+    fn incomplete_requests_consume_nothing() {
+        let frame = encode(&[b"RPUSH", b"list", b"with\r\ncrlf"]);
+        for end in 0..frame.len() {
+            assert!(Request::try_parse(&frame[..end]).unwrap().is_none());
+
+            let mut buffer = RequestBuffer::new();
+            buffer.extend(&frame[..end]);
+            assert!(buffer.next_request().unwrap().is_none());
+            assert_eq!(buffer.into_remaining(), &frame[..end]);
+        }
+    }
+
+    #[test]
+    fn multi_megabyte_bulk_split_across_reads() {
+        let value: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let mut frame = encode(&[b"SET", b"big", &value]);
+        frame.extend_from_slice(&encode(&[b"PING"]));
+
+        let mut buffer = RequestBuffer::new();
+        let mut requests = Vec::new();
+        for chunk in frame.chunks(16 * 1024) {
+            buffer.extend(chunk);
+            while let Some((request, _)) = buffer.next_request().unwrap() {
+                requests.push(request.args);
+            }
+        }
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0][2], value);
+        assert_eq!(requests[1], vec![b"PING".to_vec()]);
+        assert!(buffer.into_remaining().is_empty());
+    }
 
-        Some((Request { args }, consumed_bytes))
+    #[test]
+    fn inline_requests_must_be_utf8() {
+        let (request, consumed) = Request::try_parse(b"SET k \"a b\"\r\n").unwrap().unwrap();
+        assert_eq!(consumed, 13);
+        assert_eq!(
+            request.args,
+            vec![b"SET".to_vec(), b"k".to_vec(), b"a b".to_vec()]
+        );
+        assert!(Request::try_parse(b"SET k \xff\r\n").is_err());
     }
 }
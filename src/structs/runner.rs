@@ -1,170 +1,445 @@
+use crate::aof::spawn_aof_rewrite;
+use crate::config_file::rewrite_config_file;
 use crate::enums::add_stream_entries_result::StreamResult;
+use crate::enums::client_kind::ClientKind;
 use crate::enums::val_type::ValueType;
-use crate::geo::{decode, encode, geo_distance, validate_latitude, validate_longitude};
-use crate::structs::config::Config;
+use crate::error;
+use crate::geo::{
+    self, decode, encode, format_coordinate, geo_distance, unit_to_meters, validate_latitude,
+    validate_longitude, GeoMatch,
+};
+use crate::rdb::save::{save_rdb, serialized_length, spawn_bgsave, used_memory_rss, REDIS_VER};
+use crate::rdb::start_up::start_up;
+use crate::replication::spawn_master_link;
+use crate::structs::client_info::ClientInfo;
+use crate::structs::client_stream::ClientStream;
+use crate::structs::command_spec::{all_categories, CommandSpec, COMMAND_TABLE};
 use crate::structs::connection::Connection;
-use crate::structs::replica::add_replica;
-use crate::structs::stream::Stream;
-use crate::structs::transaction_runner::TransactionRunner;
+use crate::structs::consumer_group::{now_ms, ConsumerGroup};
+use crate::structs::geosearch_config::{GeoCenter, GeoOrder, GeoRadiusOptions, GeoSearchConfig};
+use crate::structs::global::{format_memory, parse_yes_no};
+use crate::structs::keyspace::{Entry, KeyspaceGuard};
+use crate::structs::replica::{add_replica, spawn_replica_sync};
+use crate::structs::stream::{GroupEntry, Stream};
+use crate::structs::transaction::Transaction;
 use crate::structs::xread_config::XreadConfig;
 use crate::structs::zset::ZSet;
-use crate::types::{DbConfigType, DbType, RedisGlobalType};
+use crate::types::{DbType, RedisGlobalType};
 use crate::utils::{
-    is_matched, parse_range, propagate_slaves, write_array, write_bulk_string, write_error,
-    write_integer, write_null_array, write_null_bulk_string, write_redis_file, write_resp_array,
-    write_simple_string,
+    begin_exec_propagation, encode_resp_command, encode_stream_entry, evict_if_needed,
+    expire_if_needed, is_matched, mark_dirty, parse_range, propagate_slaves, read_live,
+    send_to_replicas, serialize_entries, touch_keys, write_array, write_bulk_string, write_double,
+    write_error, write_integer, write_map_header, write_null, write_null_array,
+    write_null_bulk_string, write_resp_array, write_simple_string, write_subscription_reply,
 };
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::io::Write;
-use std::net::TcpStream;
-use std::sync::mpsc::channel;
+use std::net::Shutdown;
+use std::sync::{Arc, Mutex};
 use std::thread::sleep;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+// Blocked commands wake at least this often to notice CLIENT KILL or the
+// client hanging up
+const BLOCKED_RECHECK: Duration = Duration::from_millis(100);
+
+/// Runs one command: `args[0]` names it and the rest are its arguments.
 pub struct Runner {
     pub args: Vec<String>,
-    pub cur_step: usize,
+    // Exact bytes of the arguments that aren't valid UTF-8, by position in
+    // `args`, which holds them lossily
+    binary: Vec<(usize, Vec<u8>)>,
+    // Command names were already mapped through rename-command, as for the
+    // commands EXEC replays
+    pub canonical_names: bool,
+    // Replayed by EXEC, where nothing may block: a command that would wait
+    // replies as if its timeout had already passed, as in Redis
+    pub in_exec: bool,
 }
 
 impl Runner {
-    pub fn new(args: Vec<String>) -> Self {
-        Runner { args, cur_step: 0 }
+    /// A command as parsed off the wire. Arguments are read as text, and
+    /// the ones that aren't valid UTF-8 keep their bytes for the commands
+    /// that store values.
+    pub fn new(args: Vec<Vec<u8>>) -> Self {
+        let mut binary = Vec::new();
+        let args = args
+            .into_iter()
+            .enumerate()
+            .map(|(position, arg)| match String::from_utf8(arg) {
+                Ok(arg) => arg,
+                Err(e) => {
+                    let bytes = e.into_bytes();
+                    let text = String::from_utf8_lossy(&bytes).into_owned();
+                    binary.push((position, bytes));
+                    text
+                }
+            })
+            .collect();
+        Runner {
+            args,
+            binary,
+            canonical_names: false,
+            in_exec: false,
+        }
+    }
+
+    /// A command queued by MULTI, for EXEC to run.
+    pub fn for_exec(args: Vec<Vec<u8>>) -> Self {
+        Runner {
+            canonical_names: true,
+            in_exec: true,
+            ..Runner::new(args)
+        }
+    }
+
+    /// The exact bytes of `args[index]`, counting from the argument after
+    /// the command name as handlers do.
+    fn arg_bytes(&self, index: usize) -> &[u8] {
+        self.raw_arg(index + 1)
+    }
+
+    fn raw_arg(&self, position: usize) -> &[u8] {
+        match self.binary.iter().find(|(at, _)| *at == position) {
+            Some((_, bytes)) => bytes,
+            None => self.args[position].as_bytes(),
+        }
     }
 
     pub fn run(
         &mut self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         db: &DbType,
-        db_config: &DbConfigType,
         global_state: &RedisGlobalType,
         connection: &mut Connection,
         local_offset: &usize,
         is_propagation: bool,
     ) {
-        while self.cur_step < self.args.len() {
-            self.step(
-                stream,
-                db,
-                db_config,
-                global_state,
-                connection,
-                local_offset,
-                is_propagation,
-            );
-            self.cur_step += 1;
+        if let Some(command) = self.args.first() {
+            let mut info = connection.info.lock().unwrap();
+            info.last_interaction = Instant::now();
+            // Reuses the previous name's buffer
+            info.last_command.clear();
+            info.last_command.push_str(command);
+            info.last_command.make_ascii_lowercase();
         }
+        self.step(
+            stream,
+            db,
+            global_state,
+            connection,
+            local_offset,
+            is_propagation,
+        );
+        connection.info.lock().unwrap().multi = connection
+            .transaction
+            .is_txing
+            .then_some(connection.transaction.tasks.len());
     }
 
+    /// Runs the command, marks the keys it names as used and
+    /// logs it to the SLOWLOG when it was slow. Time spent blocked doesn't
+    /// count.
     pub fn step(
         &mut self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
+        db: &DbType,
+        global_state: &RedisGlobalType,
+        connection: &mut Connection,
+        local_offset: &usize,
+        is_propagation: bool,
+    ) {
+        let blocked_before = connection.info.lock().unwrap().blocked_time;
+        let started_at = Instant::now();
+        self.dispatch(
+            stream,
+            db,
+            global_state,
+            connection,
+            local_offset,
+            is_propagation,
+        );
+        let Some((command, args)) = self.args.split_first() else {
+            return;
+        };
+        // OBJECT IDLETIME only looks, so it doesn't reset the idle time
+        if let Some(spec) =
+            CommandSpec::lookup(&command.to_ascii_lowercase()).filter(|spec| spec.name != "object")
+        {
+            touch_keys(db, &spec.keys(args));
+        }
+        if is_propagation {
+            return;
+        }
+        let blocked = connection
+            .info
+            .lock()
+            .unwrap()
+            .blocked_time
+            .saturating_sub(blocked_before);
+        let duration = started_at.elapsed().saturating_sub(blocked);
+        let mut global = global_state.lock().unwrap();
+        if global.slowlog.is_slow(duration) {
+            let info = connection.info.lock().unwrap();
+            let name = info.name.as_deref().unwrap_or_default();
+            global
+                .slowlog
+                .record(&self.args, duration, &info.addr, name);
+        }
+    }
+
+    fn dispatch(
+        &mut self,
+        stream: &mut ClientStream,
         db: &DbType,
-        db_config: &DbConfigType,
         global_state: &RedisGlobalType,
         connection: &mut Connection,
         local_offset: &usize,
         is_propagation: bool,
     ) {
         if self.args.is_empty() {
-            write_error(stream, "empty command");
-            self.cur_step += 1;
+            write_error(stream, "ERR empty command");
             return;
         }
 
-        let command = self.args[self.cur_step].to_ascii_lowercase();
-        let args = &self.args[self.cur_step + 1..];
+        let mut command = self.args[0].to_ascii_lowercase();
+        // rename-command only changes what clients type; propagated commands
+        // always carry the real name
+        if !is_propagation && !self.canonical_names {
+            let resolved = global_state.lock().unwrap().resolve_command(&command);
+            let Some(resolved) = resolved else {
+                if connection.transaction.is_txing {
+                    connection.transaction.dirty = true;
+                }
+                write_error(
+                    stream,
+                    &error::unknown_command(&self.args[0], &self.args[1..]),
+                );
+                return;
+            };
+            if resolved != command {
+                self.args[0] = resolved.clone();
+                command = resolved;
+            }
+        }
+        let args = &self.args[1..];
+
+        // Extra or missing arguments are refused before anything else runs
+        if CommandSpec::lookup(&command).is_some_and(|spec| !spec.accepts(args.len())) {
+            if connection.transaction.is_txing {
+                connection.transaction.dirty = true;
+            }
+            write_error(stream, &error::wrong_arity(&command));
+            return;
+        }
 
         eprintln!("Received command: {:?}", command);
 
-        if connection.subscribed_channels.len() > 0 {
+        let read_only_replica = {
+            let mut global = global_state.lock().unwrap();
+            global.stat_numcommands += 1;
+            // Connections that ran commands before a password was set stay in
+            if global.acl.default_is_open() {
+                connection.authenticated = true;
+            }
+            !global.is_master() && global.replica_read_only
+        };
+        if !is_propagation
+            && !connection.authenticated
+            && !matches!(command.as_str(), "auth" | "hello" | "quit")
+        {
+            write_error(stream, error::NOAUTH);
+            return;
+        }
+        if let (false, Some(spec)) = (is_propagation, CommandSpec::lookup(&command)) {
+            let user = connection.info.lock().unwrap().user.clone();
+            if let Err(message) = global_state.lock().unwrap().acl.check(&user, spec, args) {
+                write_error(stream, &format!("NOPERM {}", message));
+                return;
+            }
+        }
+
+        // RESP3 clients can keep running regular commands while subscribed
+        if connection.subscription_count() > 0 && connection.protocol == 2 {
             match command.as_str() {
-                "subscribe" => {
-                    self.cur_step += self.handle_subscribe(stream, args, global_state, connection)
-                }
+                "subscribe" => self.handle_subscribe(stream, args, global_state, connection),
 
-                "unsubscribe" => {
-                    self.cur_step += self.handle_unsubscribe(stream, args, global_state, connection)
-                }
-                "psubscribe" => {}
-                "punsubscribe" => {}
+                "unsubscribe" => self.handle_unsubscribe(stream, args, global_state, connection),
+                "psubscribe" => self.handle_psubscribe(stream, args, global_state, connection),
+                "punsubscribe" => self.handle_punsubscribe(stream, args, global_state, connection),
                 "ping" => {
-                    self.handle_subscribed_ping(stream);
+                    self.handle_subscribed_ping(stream, args);
+                }
+                "quit" => {
+                    write_simple_string(stream, "OK");
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+                "reset" => {
+                    self.handle_reset(stream, global_state, connection);
                 }
-                "quit" => {}
 
                 _ => {
-                    write_error(stream, &format!("Can't execute '{command}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context"));
-                    self.cur_step = self.args.len()
+                    write_error(stream, &format!("ERR Can't execute '{command}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context"));
+                }
+            }
+        } else if connection.transaction.is_txing
+            && !matches!(
+                command.as_str(),
+                "multi" | "exec" | "discard" | "watch" | "unwatch"
+            )
+        {
+            // Everything else inside MULTI is queued verbatim and run by EXEC.
+            // The replication link queues the same way but never replies.
+            let is_slave_and_propagation = {
+                let global = global_state.lock().unwrap();
+                !global.is_master() && is_propagation
+            };
+            match CommandSpec::lookup(&command) {
+                None => {
+                    connection.transaction.dirty = true;
+                    if !is_slave_and_propagation {
+                        write_error(stream, &error::unknown_command(&self.args[0], args));
+                    }
+                }
+                Some(_) => {
+                    let task = (0..self.args.len())
+                        .map(|position| self.raw_arg(position).to_vec())
+                        .collect();
+                    connection.transaction.tasks.push(task);
+                    if !is_slave_and_propagation {
+                        write_simple_string(stream, "QUEUED");
+                    }
                 }
             }
+        } else if is_write_command(&command) && !is_propagation && read_only_replica {
+            write_error(
+                stream,
+                "READONLY You can't write against a read only replica.",
+            );
+        } else if is_write_command(&command)
+            && !is_propagation
+            && !global_state.lock().unwrap().has_enough_good_replicas()
+        {
+            write_error(stream, "NOREPLICAS Not enough good replicas to write.");
+        } else if is_write_command(&command)
+            && !is_propagation
+            && !evict_if_needed(db, global_state)
+            && CommandSpec::lookup(&command).is_some_and(|spec| spec.flags.contains(&"denyoom"))
+        {
+            write_error(
+                stream,
+                "OOM command not allowed when used memory > 'maxmemory'",
+            );
         } else {
             match command.as_str() {
                 "ping" => {
-                    self.handle_ping(stream, connection);
+                    // Heartbeats from the master expect no reply
+                    if !is_propagation {
+                        self.handle_ping(stream, args);
+                    }
                 }
                 "echo" => {
-                    self.cur_step += self.handle_echo(stream, args, connection);
+                    self.handle_echo(stream, args, connection);
                 }
                 "set" => {
-                    self.cur_step += self.handle_set(
-                        stream,
-                        args,
-                        db,
-                        db_config,
-                        global_state,
-                        &is_propagation,
-                        connection,
-                    );
+                    self.handle_set(stream, args, db, global_state, &is_propagation, connection);
                 }
                 "get" => {
-                    self.cur_step += self.handle_get(stream, args, db, db_config, connection);
+                    self.handle_get(stream, args, db, global_state, connection);
                 }
                 "del" => {
-                    self.cur_step += self.handle_del(
-                        stream,
-                        args,
-                        db,
-                        db_config,
-                        global_state,
-                        &is_propagation,
-                        connection,
-                    );
+                    self.handle_del(stream, args, db, global_state, &is_propagation, connection);
                 }
                 "incr" => {
-                    self.cur_step += self.handle_incr(
-                        stream,
-                        args,
-                        db,
-                        db_config,
-                        global_state,
-                        &is_propagation,
-                        connection,
-                    );
+                    self.handle_incr(stream, args, db, global_state, &is_propagation, connection);
+                }
+                "pexpireat" => {
+                    self.handle_pexpireat(stream, args, db, global_state, &is_propagation);
+                }
+                "sadd" => {
+                    self.handle_sadd(stream, args, db, global_state, &is_propagation);
+                }
+                "hset" => {
+                    self.handle_hset(stream, args, db, global_state, &is_propagation);
                 }
                 "config" => {
-                    self.cur_step += self.handle_config(stream, args, global_state, connection);
+                    self.handle_config(stream, args, db, global_state, connection);
+                }
+                "auth" => {
+                    self.handle_auth(stream, args, global_state, connection);
+                }
+                "hello" => {
+                    self.handle_hello(stream, args, global_state, connection);
+                }
+                "acl" => {
+                    self.handle_acl(stream, args, global_state, connection);
+                }
+                "client" => {
+                    self.handle_client(stream, args, global_state, connection);
+                }
+                "save" => {
+                    self.handle_save(stream, db, global_state);
+                }
+                "bgsave" => {
+                    self.handle_bgsave(stream, db, global_state);
+                }
+                "bgrewriteaof" => {
+                    self.handle_bgrewriteaof(stream, db, global_state);
+                }
+                "lolwut" => {
+                    self.handle_lolwut(stream, args);
+                }
+                "slowlog" => {
+                    self.handle_slowlog(stream, args, global_state);
+                }
+                "time" => {
+                    self.handle_time(stream);
+                }
+                "shutdown" => {
+                    // Not something a master can make its replicas do
+                    if !is_propagation {
+                        self.handle_shutdown(stream, args, db, global_state);
+                    }
+                }
+                "debug" => {
+                    self.handle_debug(stream, args, db, global_state, is_propagation);
+                }
+                "lastsave" => {
+                    let last_save = global_state.lock().unwrap().rdb_last_save_time;
+                    write_integer(stream, last_save as i64);
                 }
                 "keys" => {
-                    self.cur_step += self.handle_keys(stream, args, db, db_config, connection);
+                    self.handle_keys(stream, args, db, global_state, connection);
                 }
                 "info" => {
-                    self.handle_info(stream, args, db, db_config, global_state, connection);
+                    self.handle_info(stream, args, db, global_state, connection);
                 }
                 "replconf" => {
-                    self.cur_step +=
-                        self.handle_replconf(stream, args, global_state, connection, local_offset);
+                    self.handle_replconf(stream, args, global_state, connection, local_offset);
                 }
                 "psync" => {
-                    self.cur_step += self.handle_psync(stream, args, global_state, connection);
+                    self.handle_psync(stream, args, db, global_state, connection);
                 }
                 "wait" => {
-                    self.cur_step += self.handle_wait(stream, args, global_state, connection);
+                    self.handle_wait(stream, args, global_state, connection);
+                }
+                "replicaof" | "slaveof" => {
+                    self.handle_replicaof(stream, args, db, global_state);
                 }
                 "multi" => {
-                    self.handle_multi(stream, connection);
+                    self.handle_multi(stream, global_state, &is_propagation, connection);
                 }
                 "xadd" => {
-                    self.cur_step += self.handle_xadd(
+                    self.handle_xadd(stream, args, db, global_state, &is_propagation, connection);
+                }
+                "xrange" => {
+                    self.handle_xrange(stream, args, db, connection);
+                }
+                "xread" => {
+                    self.handle_xread(stream, args, db, connection);
+                }
+                "xreadgroup" => {
+                    self.handle_xreadgroup(
                         stream,
                         args,
                         db,
@@ -173,26 +448,17 @@ impl Runner {
                         connection,
                     );
                 }
-                "xrange" => {
-                    self.cur_step += self.handle_xrange(stream, args, db, connection);
-                }
-                "xread" => {
-                    self.cur_step += self.handle_xread(stream, args, db, connection);
-                }
-                "discard" => {
-                    self.handle_discard(stream, connection);
+                "xack" => {
+                    self.handle_xack(stream, args, db, global_state, &is_propagation, connection);
                 }
-
-                "exec" => {
-                    self.handle_exec(stream, db, db_config, global_state, connection);
+                "xpending" => {
+                    self.handle_xpending(stream, args, db, connection);
                 }
-
-                "type" => {
-                    self.cur_step += self.handle_type(stream, args, db, db_config, connection);
+                "xclaim" => {
+                    self.handle_xclaim(stream, args, db, global_state, &is_propagation, connection);
                 }
-
-                "rpush" => {
-                    self.cur_step += self.handle_rpush(
+                "xautoclaim" => {
+                    self.handle_xautoclaim(
                         stream,
                         args,
                         db,
@@ -201,123 +467,131 @@ impl Runner {
                         connection,
                     );
                 }
+                "xgroup" => {
+                    self.handle_xgroup(stream, args, db, global_state, &is_propagation, connection);
+                }
+                "discard" => {
+                    self.handle_discard(stream, global_state, connection);
+                }
+                "watch" => {
+                    self.handle_watch(stream, args, global_state, connection);
+                }
+                "unwatch" => {
+                    connection.unwatch_all(&mut global_state.lock().unwrap());
+                    write_simple_string(stream, "OK");
+                }
 
-                "lpush" => {
-                    self.cur_step += self.handle_lpush(
+                "exec" => {
+                    self.handle_exec(
                         stream,
-                        args,
                         db,
                         global_state,
                         &is_propagation,
                         connection,
+                        local_offset,
                     );
                 }
 
+                "type" => {
+                    self.handle_type(stream, args, db, global_state, connection);
+                }
+
+                "object" => {
+                    self.handle_object(stream, args, db, global_state, connection);
+                }
+
+                "rpush" => {
+                    self.handle_rpush(stream, args, db, global_state, &is_propagation, connection);
+                }
+
+                "lpush" => {
+                    self.handle_lpush(stream, args, db, global_state, &is_propagation, connection);
+                }
+
                 "lpop" => {
-                    self.cur_step += self.handle_lpop(
-                        stream,
-                        args,
-                        db,
-                        global_state,
-                        &is_propagation,
-                        connection,
-                    );
+                    self.handle_lpop(stream, args, db, global_state, &is_propagation, connection);
                 }
 
                 "zadd" => {
-                    self.cur_step += self.handle_zadd(
-                        stream,
-                        args,
-                        db,
-                        global_state,
-                        &is_propagation,
-                        connection,
-                    );
+                    self.handle_zadd(stream, args, db, global_state, &is_propagation, connection);
                 }
                 "zrem" => {
-                    self.cur_step += self.handle_zrem(
-                        stream,
-                        args,
-                        db,
-                        global_state,
-                        &is_propagation,
-                        connection,
-                    );
+                    self.handle_zrem(stream, args, db, global_state, &is_propagation, connection);
                 }
 
                 "zscore" => {
-                    self.cur_step += self.handle_zscore(stream, args, db, connection);
+                    self.handle_zscore(stream, args, db, connection);
                 }
 
                 "zrank" => {
-                    self.cur_step += self.handle_zrank(stream, args, db, connection);
+                    self.handle_zrank(stream, args, db, connection);
                 }
 
                 "zrange" => {
-                    self.cur_step += self.handle_zrange(stream, args, db, connection);
+                    self.handle_zrange(stream, args, db, connection);
                 }
 
                 "zcard" => {
-                    self.cur_step += self.handle_zcard(stream, args, db, connection);
+                    self.handle_zcard(stream, args, db, connection);
                 }
 
                 "blpop" => {
-                    self.cur_step += self.handle_blpop(
-                        stream,
-                        args,
-                        db,
-                        global_state,
-                        &is_propagation,
-                        connection,
-                    );
+                    self.handle_blpop(stream, args, db, global_state, &is_propagation, connection);
                 }
 
                 "llen" => {
-                    self.cur_step += self.handle_llen(stream, args, db, connection);
+                    self.handle_llen(stream, args, db, connection);
                 }
 
                 "lrange" => {
-                    self.cur_step += self.handle_lrange(stream, args, db, connection);
+                    self.handle_lrange(stream, args, db, connection);
                 }
 
-                "command" | "docs" => {
-                    if connection.transaction.is_txing {
-                        write_simple_string(stream, "QUEUED");
-                    }
-                    write_simple_string(stream, "OK");
+                "command" => {
+                    self.handle_command(stream, args);
                 }
 
                 "geoadd" => {
-                    self.cur_step += self.handle_geoadd(
-                        stream,
-                        args,
-                        db,
-                        global_state,
-                        &is_propagation,
-                        connection,
-                    );
+                    self.handle_geoadd(stream, args, db, global_state, &is_propagation, connection);
                 }
 
                 "geopos" => {
-                    self.cur_step += self.handle_geopos(stream, args, db, connection);
+                    self.handle_geopos(stream, args, db, connection);
                 }
 
                 "geodist" => {
-                    self.cur_step += self.handle_geodist(stream, args, db, connection);
+                    self.handle_geodist(stream, args, db, connection);
                 }
 
                 "geosearch" => {
-                    self.cur_step += self.handle_geosearch(stream, args, db, connection);
+                    self.handle_geosearch(stream, args, db, connection);
                 }
 
-                "subscribe" => {
-                    self.cur_step += self.handle_subscribe(stream, args, global_state, connection)
+                "georadius" | "georadius_ro" | "georadiusbymember" | "georadiusbymember_ro" => {
+                    self.handle_georadius(
+                        stream,
+                        &command,
+                        args,
+                        db,
+                        global_state,
+                        &is_propagation,
+                    );
                 }
 
-                "publish" => self.cur_step += self.handle_publish(stream, args, global_state),
+                "subscribe" => self.handle_subscribe(stream, args, global_state, connection),
+
+                "unsubscribe" => self.handle_unsubscribe(stream, args, global_state, connection),
+
+                "psubscribe" => self.handle_psubscribe(stream, args, global_state, connection),
+
+                "punsubscribe" => self.handle_punsubscribe(stream, args, global_state, connection),
+
+                "publish" => self.handle_publish(stream, args, global_state, &is_propagation),
+
+                "pubsub" => self.handle_pubsub(stream, args, global_state),
 
                 _ => {
-                    write_error(stream, "unknown command");
+                    write_error(stream, &error::unknown_command(&self.args[0], args));
                 }
             }
         }
@@ -325,268 +599,401 @@ impl Runner {
 
     fn handle_publish(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         args: &[String],
         global_state: &RedisGlobalType,
-    ) -> usize {
+        is_propagation: &bool,
+    ) {
         if args.len() < 2 {
-            write_error(stream, "wrong number of arguments for 'PUBLISH'");
-            return 0;
+            write_error(stream, &error::wrong_arity("publish"));
+            return;
         }
         let channel_name = &args[0];
         let msg = &args[1];
 
-        let (senders, length) = {
-            match global_state.lock().unwrap().channel_map.get(channel_name) {
-                Some(senders) => (senders.clone(), senders.len()),
-                None => {
-                    write_error(stream, &format!("channel {channel_name} not found"));
-                    return 2;
-                }
-            }
+        let (receivers, is_slave_and_propagation) = {
+            let mut global = global_state.lock().unwrap();
+            (
+                global.publish(channel_name, msg),
+                !global.is_master() && *is_propagation,
+            )
         };
 
-        for (_, s) in senders {
-            let _ = s.send(msg.clone());
+        // Replicas deliver to their own subscribers and stay silent on the link
+        if is_slave_and_propagation {
+            return;
         }
 
-        write_integer(stream, length as i64);
-        2
+        propagate_slaves(
+            global_state,
+            &encode_resp_command(&["PUBLISH", channel_name, msg]),
+        );
+        write_integer(stream, receivers as i64);
     }
 
-    fn handle_subscribe(
+    fn handle_pubsub(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         args: &[String],
         global_state: &RedisGlobalType,
-        connection: &mut Connection,
-    ) -> usize {
-        if args.len() < 1 {
-            write_error(stream, "wrong number of arguments for 'SUBSCRIBE'");
-            return 0;
-        }
-
-        let channel_name = &args[0];
-
-        let message: &str = "subscribe";
+    ) {
+        let Some(subcommand) = args.first() else {
+            write_error(stream, &error::wrong_arity("pubsub"));
+            return;
+        };
+        let global = global_state.lock().unwrap();
 
-        {
-            if connection.subscribed_channels.get(channel_name).is_some() {
-                write_error(
+        match subcommand.to_ascii_lowercase().as_str() {
+            "channels" if args.len() <= 2 => {
+                let mut channels = global.active_channels(args.get(1).map(|p| p.as_str()));
+                channels.sort();
+                write_array(
                     stream,
-                    &format!("Already subscribed to channel {channel_name}"),
+                    &channels.iter().map(Some).collect::<Vec<Option<&String>>>(),
                 );
-                return 1;
             }
-            let mut global = global_state.lock().unwrap();
-            let subscribed_channel = global.channel_map.get_mut(channel_name);
-            let (sender, receiver) = channel::<String>();
-            if let Some(channel) = subscribed_channel {
-                channel.insert(connection.id.clone(), sender);
-                connection
-                    .subscribed_channels
-                    .insert(channel_name.clone(), receiver);
-            } else {
-                let mut map = HashMap::new();
-                map.insert(connection.id.clone(), sender);
-                global.channel_map.insert(channel_name.clone(), map);
-                connection
-                    .subscribed_channels
-                    .insert(channel_name.clone(), receiver);
+            "numsub" => {
+                let mut items = Vec::new();
+                for channel in &args[1..] {
+                    items.push(Some(format!("${}\r\n{}\r\n", channel.len(), channel)));
+                    items.push(Some(format!(
+                        ":{}\r\n",
+                        global.channel_subscribers(channel)
+                    )));
+                }
+                write_resp_array(stream, &items);
+            }
+            "numpat" if args.len() == 1 => {
+                write_integer(stream, global.pattern_count() as i64);
             }
+            name @ ("channels" | "numpat") => {
+                write_error(stream, &error::wrong_arity(&format!("pubsub|{}", name)));
+            }
+            _ => write_error(stream, &error::unknown_subcommand("pubsub", subcommand)),
         }
-
-        let channel_number = connection.subscribed_channels.len();
-
-        let _ = stream.write_all(format!("*{}\r\n", 3).as_bytes());
-        let _ = stream.write_all(format!("${}\r\n{}\r\n", message.len(), message).as_bytes());
-        let _ =
-            stream.write_all(format!("${}\r\n{}\r\n", channel_name.len(), channel_name).as_bytes());
-        let _ = stream.write_all(format!(":{}\r\n", channel_number).as_bytes());
-
-        1
     }
 
-    fn handle_unsubscribe(
+    fn handle_subscribe(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         args: &[String],
         global_state: &RedisGlobalType,
         connection: &mut Connection,
-    ) -> usize {
-        if args.len() < 1 {
-            write_error(stream, "wrong number of arguments for 'SUBSCRIBE'");
-            return 0;
+    ) {
+        if args.is_empty() {
+            write_error(stream, &error::wrong_arity("subscribe"));
+            return;
         }
 
-        let channel_name = &args[0];
-
-        let message: &str = "unsubscribe";
-
-        {
-            if let Some(_) = connection.subscribed_channels.remove(channel_name) {
+        for channel_name in args {
+            if connection.subscribed_channels.insert(channel_name.clone()) {
                 let mut global = global_state.lock().unwrap();
-                if let Some(channel_map) = global.channel_map.get_mut(channel_name) {
-                    channel_map.remove(&connection.id);
-                    if channel_map.is_empty() {
-                        global.channel_map.remove(channel_name);
-                    }
-                }
+                global.subscribe(channel_name, connection.id, connection.subscriber());
             }
+            write_subscription_reply(
+                stream,
+                "subscribe",
+                Some(channel_name),
+                connection.subscription_count(),
+                connection.protocol,
+            );
         }
+    }
+
+    fn handle_unsubscribe(
+        &self,
+        stream: &mut ClientStream,
+        args: &[String],
+        global_state: &RedisGlobalType,
+        connection: &mut Connection,
+    ) {
+        // Without arguments, every channel of this connection is dropped
+        let channels: Vec<String> = if args.is_empty() {
+            connection.subscribed_channels.iter().cloned().collect()
+        } else {
+            args.to_vec()
+        };
+
+        if channels.is_empty() {
+            write_subscription_reply(
+                stream,
+                "unsubscribe",
+                None,
+                connection.subscription_count(),
+                connection.protocol,
+            );
+            return;
+        }
+
+        for channel_name in &channels {
+            if connection.subscribed_channels.remove(channel_name) {
+                let mut global = global_state.lock().unwrap();
+                global.unsubscribe(channel_name, connection.id);
+            }
+            write_subscription_reply(
+                stream,
+                "unsubscribe",
+                Some(channel_name),
+                connection.subscription_count(),
+                connection.protocol,
+            );
+        }
+    }
+
+    fn handle_psubscribe(
+        &self,
+        stream: &mut ClientStream,
+        args: &[String],
+        global_state: &RedisGlobalType,
+        connection: &mut Connection,
+    ) {
+        if args.is_empty() {
+            write_error(stream, &error::wrong_arity("psubscribe"));
+            return;
+        }
+
+        for pattern in args {
+            if connection.subscribed_patterns.insert(pattern.clone()) {
+                let mut global = global_state.lock().unwrap();
+                global.psubscribe(pattern, connection.id, connection.subscriber());
+            }
+            write_subscription_reply(
+                stream,
+                "psubscribe",
+                Some(pattern),
+                connection.subscription_count(),
+                connection.protocol,
+            );
+        }
+    }
 
-        let channel_number = connection.subscribed_channels.len();
+    fn handle_punsubscribe(
+        &self,
+        stream: &mut ClientStream,
+        args: &[String],
+        global_state: &RedisGlobalType,
+        connection: &mut Connection,
+    ) {
+        // Without arguments, every pattern of this connection is dropped
+        let patterns: Vec<String> = if args.is_empty() {
+            connection.subscribed_patterns.iter().cloned().collect()
+        } else {
+            args.to_vec()
+        };
 
-        let _ = stream.write_all(format!("*{}\r\n", 3).as_bytes());
-        let _ = stream.write_all(format!("${}\r\n{}\r\n", message.len(), message).as_bytes());
-        let _ =
-            stream.write_all(format!("${}\r\n{}\r\n", channel_name.len(), channel_name).as_bytes());
-        let _ = stream.write_all(format!(":{}\r\n", channel_number).as_bytes());
+        if patterns.is_empty() {
+            write_subscription_reply(
+                stream,
+                "punsubscribe",
+                None,
+                connection.subscription_count(),
+                connection.protocol,
+            );
+            return;
+        }
 
-        1
+        for pattern in &patterns {
+            if connection.subscribed_patterns.remove(pattern) {
+                let mut global = global_state.lock().unwrap();
+                global.punsubscribe(pattern, connection.id);
+            }
+            write_subscription_reply(
+                stream,
+                "punsubscribe",
+                Some(pattern),
+                connection.subscription_count(),
+                connection.protocol,
+            );
+        }
     }
 
     fn handle_zadd(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         args: &[String],
         db: &DbType,
         global_state: &RedisGlobalType,
         is_propagation: &bool,
         _connection: &mut Connection,
-    ) -> usize {
-        // TODO: transaction
+    ) {
         let is_slave_and_propagation = {
             let global = global_state.lock().unwrap();
             !global.is_master() && *is_propagation
         };
 
-        if args.len() < 3 {
+        if args.len() < 3 || args.len().is_multiple_of(2) {
             if !is_slave_and_propagation {
-                write_error(stream, "wrong number of arguments for 'ZADD'");
+                write_error(stream, error::SYNTAX);
             }
-            return 3;
+            return;
         }
 
         let zset_key = &args[0];
-        let score = match args[1].parse::<f64>() {
-            Ok(score) => score,
-            Err(_) => {
-                if !is_slave_and_propagation {
-                    write_error(stream, "invalid score for 'ZADD': must be a number");
+        // Every score is checked before any member is added
+        let mut entries = Vec::with_capacity(args.len() / 2);
+        for pair in args[1..].chunks(2) {
+            match pair[0].parse::<f64>() {
+                Ok(score) => entries.push((score, &pair[1])),
+                Err(_) => {
+                    if !is_slave_and_propagation {
+                        write_error(stream, error::NOT_A_FLOAT);
+                    }
+                    return;
                 }
-                return 3;
             }
-        };
-        let member = &args[2];
-        let mut _added_number = 1;
+        }
+        let mut added_number = 0;
         {
-            let mut map = db.lock().unwrap();
-            let zset_opt = map.get_mut(zset_key);
-
-            if let Some(ValueType::ZSet(zset)) = zset_opt {
-                _added_number = zset.zadd(score, member.clone());
-            } else {
-                let mut new_zset = ZSet::new();
-                _added_number = new_zset.zadd(score, member.clone());
-                map.insert(zset_key.clone(), ValueType::ZSet(new_zset));
+            let mut map = db.lock(zset_key);
+            match map.get(zset_key) {
+                Some(ValueType::ZSet(_)) => {}
+                Some(_) => {
+                    if !is_slave_and_propagation {
+                        write_error(stream, error::WRONGTYPE);
+                    }
+                    return;
+                }
+                None => {
+                    map.insert(zset_key.clone(), ValueType::ZSet(ZSet::new()));
+                }
+            }
+            if let Some(ValueType::ZSet(zset)) = map.get_mut(zset_key) {
+                for (score, member) in &entries {
+                    added_number += zset.zadd(*score, (*member).clone());
+                }
             }
         }
+        mark_dirty(global_state, zset_key);
 
         if !is_slave_and_propagation {
-            write_integer(stream, _added_number);
-            let propagation = format!("ZADD {} {} {}", zset_key, score, member);
-            propagate_slaves(global_state, &propagation);
+            write_integer(stream, added_number);
+            let mut propagation = vec!["ZADD"];
+            propagation.extend(args.iter().map(|arg| arg.as_str()));
+            propagate_slaves(global_state, &encode_resp_command(&propagation));
         }
+    }
 
-        3
+    fn handle_subscribed_ping(&self, stream: &mut ClientStream, args: &[String]) {
+        match args {
+            [] => write_array(stream, &[Some("pong"), Some("")]),
+            [message] => write_array(stream, &[Some("pong"), Some(message.as_str())]),
+            _ => write_error(stream, &error::wrong_arity("ping")),
+        }
     }
 
-    fn handle_subscribed_ping(&self, stream: &mut TcpStream) {
-        write_array(stream, &[Some("pong"), Some("")]);
+    /// Drops every subscription and pending transaction, leaving a fresh connection.
+    fn handle_reset(
+        &self,
+        stream: &mut ClientStream,
+        global_state: &RedisGlobalType,
+        connection: &mut Connection,
+    ) {
+        {
+            let mut global = global_state.lock().unwrap();
+            for channel in connection.subscribed_channels.drain() {
+                global.unsubscribe(&channel, connection.id);
+            }
+            for pattern in connection.subscribed_patterns.drain() {
+                global.punsubscribe(&pattern, connection.id);
+            }
+            connection.unwatch_all(&mut global);
+        }
+        connection.transaction = Transaction::new();
+        write_simple_string(stream, "RESET");
     }
 
     fn handle_geoadd(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         args: &[String],
         db: &DbType,
         global_state: &RedisGlobalType,
         is_propagation: &bool,
         _connection: &mut Connection,
-    ) -> usize {
-        // TODO: transaction
+    ) {
         let is_slave_and_propagation = {
             let global = global_state.lock().unwrap();
             !global.is_master() && *is_propagation
         };
 
-        if args.len() < 4 {
+        if args.len() < 4 || !(args.len() - 1).is_multiple_of(3) {
             if !is_slave_and_propagation {
-                write_error(stream, "wrong number of arguments for 'GEOADD'");
+                write_error(stream, error::SYNTAX);
             }
-            return 0;
+            return;
         }
 
         let zset_key = &args[0];
-        let longitude = match args[1].parse::<f64>() {
-            Ok(long) if validate_longitude(long) => long,
-            _ => {
-                if !is_slave_and_propagation {
-                    write_error(
-                        stream,
-                        "invalid score for 'GEOADD': must be a valid longitude (-180..180)",
-                    );
+        // Every position is checked before any member is added
+        let mut entries = Vec::with_capacity(args.len() / 3);
+        for triple in args[1..].chunks(3) {
+            let longitude = match triple[0].parse::<f64>() {
+                Ok(long) if validate_longitude(long) => long,
+                _ => {
+                    if !is_slave_and_propagation {
+                        write_error(
+                            stream,
+                            "ERR invalid score for 'GEOADD': must be a valid longitude (-180..180)",
+                        );
+                    }
+                    return;
                 }
-                return 4;
-            }
-        };
-        let latitude = match args[2].parse::<f64>() {
-            Ok(lat) if validate_latitude(lat) => lat,
-            _ => {
-                if !is_slave_and_propagation {
-                    write_error(stream, "invalid score for 'GEOADD': must be a valid latitude (-85.05112878..85.05112878)");
+            };
+            let latitude = match triple[1].parse::<f64>() {
+                Ok(lat) if validate_latitude(lat) => lat,
+                _ => {
+                    if !is_slave_and_propagation {
+                        write_error(stream, "ERR invalid score for 'GEOADD': must be a valid latitude (-85.05112878..85.05112878)");
+                    }
+                    return;
                 }
-                return 4;
-            }
-        };
-
-        let score = encode(latitude, longitude);
+            };
+            entries.push((encode(latitude, longitude), &triple[2]));
+        }
 
-        let member = &args[3];
-        let mut _added_number = 1;
+        let mut added_number = 0;
         {
-            let mut map = db.lock().unwrap();
-            let zset_opt = map.get_mut(zset_key);
-
-            if let Some(ValueType::ZSet(zset)) = zset_opt {
-                _added_number = zset.zadd(score as f64, member.clone());
-            } else {
-                let mut new_zset = ZSet::new();
-                _added_number = new_zset.zadd(score as f64, member.clone());
-                map.insert(zset_key.clone(), ValueType::ZSet(new_zset));
+            let mut map = db.lock(zset_key);
+            match map.get(zset_key) {
+                Some(ValueType::ZSet(_)) => {}
+                Some(_) => {
+                    if !is_slave_and_propagation {
+                        write_error(stream, error::WRONGTYPE);
+                    }
+                    return;
+                }
+                None => {
+                    map.insert(zset_key.clone(), ValueType::ZSet(ZSet::new()));
+                }
+            }
+            if let Some(ValueType::ZSet(zset)) = map.get_mut(zset_key) {
+                for (score, member) in &entries {
+                    added_number += zset.zadd(*score as f64, (*member).clone());
+                }
             }
         }
+        mark_dirty(global_state, zset_key);
 
         if !is_slave_and_propagation {
-            write_integer(stream, _added_number);
-            let propagation = format!("ZADD {} {} {}", zset_key, 0.0, member);
-            propagate_slaves(global_state, &propagation);
+            write_integer(stream, added_number);
+            let mut propagation = vec!["ZADD".to_string(), zset_key.clone()];
+            for (score, member) in &entries {
+                propagation.push(score.to_string());
+                propagation.push((*member).clone());
+            }
+            propagate_slaves(global_state, &encode_resp_command(&propagation));
         }
-
-        4
     }
 
     fn handle_zrem(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         args: &[String],
         db: &DbType,
         global_state: &RedisGlobalType,
         is_propagation: &bool,
         _connection: &mut Connection,
-    ) -> usize {
-        // TODO: transaction
+    ) {
         let is_slave_and_propagation = {
             let global = global_state.lock().unwrap();
             !global.is_master() && *is_propagation
@@ -594,45 +1001,42 @@ impl Runner {
 
         if args.len() < 2 {
             if !is_slave_and_propagation {
-                write_error(stream, "wrong number of arguments for 'ZREM'");
+                write_error(stream, &error::wrong_arity("zrem"));
             }
-            return 0;
+            return;
         }
 
         let zset_key = &args[0];
-        let member = &args[1];
-
-        let mut _removed_number = 1;
+        let mut removed_number = 0;
         {
-            let mut map = db.lock().unwrap();
-            let zset_opt = map.get_mut(zset_key);
-
-            if let Some(ValueType::ZSet(zset)) = zset_opt {
-                _removed_number = zset.zrem(member);
-            } else {
-                _removed_number = 0;
+            let mut map = db.lock(zset_key);
+            if let Some(ValueType::ZSet(zset)) = map.get_mut(zset_key) {
+                for member in &args[1..] {
+                    removed_number += zset.zrem(member);
+                }
             }
         }
+        if removed_number > 0 {
+            mark_dirty(global_state, zset_key);
+        }
 
         if !is_slave_and_propagation {
-            write_integer(stream, _removed_number as i64);
-            let propagation = format!("ZREM {} {}", zset_key, member);
-            propagate_slaves(global_state, &propagation);
+            write_integer(stream, removed_number as i64);
+            let mut propagation = vec!["ZREM"];
+            propagation.extend(args.iter().map(|arg| arg.as_str()));
+            propagate_slaves(global_state, &encode_resp_command(&propagation));
         }
-
-        3
     }
 
     fn handle_blpop(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         args: &[String],
         db: &DbType,
         global_state: &RedisGlobalType,
         is_propagation: &bool,
-        _connection: &mut Connection,
-    ) -> usize {
-        // TODO: transaction
+        connection: &mut Connection,
+    ) {
         let is_slave_and_propagation = {
             let global = global_state.lock().unwrap();
             !global.is_master() && *is_propagation
@@ -640,110 +1044,141 @@ impl Runner {
 
         if args.len() < 2 {
             if !is_slave_and_propagation {
-                write_error(stream, "wrong number of arguments for 'BLPOP'");
+                write_error(stream, &error::wrong_arity("blpop"));
             }
-            return 0;
+            return;
         }
 
-        let list_key = &args[0];
-        let timeout = match args[1].parse::<f64>() {
+        let (timeout, list_keys) = args.split_last().unwrap();
+        let timeout = match timeout.parse::<f64>() {
             Ok(t) if t >= 0.0 => t,
-            _ => {
-                write_error(
-                    stream,
-                    "invalid arguments for BLPOP: timeout must be a non-negative number",
-                );
-                return 2;
+            Ok(_) => {
+                write_error(stream, "ERR timeout is negative");
+                return;
+            }
+            Err(_) => {
+                write_error(stream, "ERR timeout is not a float or out of range");
+                return;
             }
         };
-        let timeout = timeout;
 
-        let start_time = Instant::now();
-        loop {
-            {
-                let mut map = db.lock().unwrap();
-                if let Some(val) = map.get_mut(list_key) {
-                    if let ValueType::List(ref mut redis_list) = val {
-                        if !redis_list.is_empty() {
-                            let popped = redis_list.remove(0);
-                            if !is_slave_and_propagation {
-                                write_array(
-                                    stream,
-                                    &[Some(list_key.as_str()), Some(popped.as_str())],
-                                );
-                                propagate_slaves(global_state, &format!("LPOP {}", list_key));
-                                return 2;
-                            }
+        // 0 blocks forever, as does a timeout too far out to represent
+        let deadline = Duration::try_from_secs_f64(timeout)
+            .ok()
+            .filter(|timeout| !timeout.is_zero())
+            .and_then(|timeout| Instant::now().checked_add(timeout));
+        let registration = {
+            // The first key, in argument order, with an element is popped from
+            let mut map = db.lock_keys(list_keys.iter().map(|key| key.as_str()));
+            for list_key in list_keys {
+                match map.get_mut(list_key) {
+                    Some(ValueType::List(redis_list)) if !redis_list.is_empty() => {
+                        let popped = redis_list.remove(0);
+                        if redis_list.is_empty() {
+                            map.remove(list_key);
                         }
-                    } else {
+                        drop(map);
+                        mark_dirty(global_state, list_key);
                         if !is_slave_and_propagation {
-                            write_error(
-                                stream,
-                                "WRONGTYPE Operation against a key holding the wrong kind of value",
-                            );
+                            write_array(stream, &[Some(list_key.as_bytes()), Some(&popped)]);
+                            let propagation = encode_resp_command(&["LPOP", list_key]);
+                            propagate_slaves(global_state, &propagation);
                         }
-
-                        return 2;
+                        return;
+                    }
+                    Some(ValueType::List(_)) | None => {}
+                    Some(_) => {
+                        if !is_slave_and_propagation {
+                            write_error(stream, error::WRONGTYPE);
+                        }
+                        return;
                     }
                 }
             }
 
-            if timeout > 0.0 {
-                let elapsed = start_time.elapsed();
-                if elapsed.as_secs_f64() >= timeout {
-                    let _ = stream.write_all(b"*-1\r\n");
-                    return 2;
+            if self.in_exec {
+                drop(map);
+                write_null_array(stream);
+                return;
+            }
+            // Queued while the lists are still locked, so no push slips past
+            db.blocked_clients.register(list_keys)
+        };
+
+        // A push hands the element over and propagates the LPOP itself
+        let _blocked = connection.block(stream);
+        let (list_key, popped) = loop {
+            let wait = deadline.map_or(BLOCKED_RECHECK, |deadline| {
+                deadline.saturating_duration_since(Instant::now())
+            });
+            if let Ok(popped) = registration
+                .receiver
+                .recv_timeout(wait.min(BLOCKED_RECHECK))
+            {
+                break popped;
+            }
+
+            // A killed or disconnected client is dropped without a reply
+            let is_gone = connection.is_killed() || stream.peer_closed();
+            if is_gone || deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                match db.blocked_clients.unregister(&registration) {
+                    Some(popped) if !is_gone => break popped,
+                    Some(_) => return,
+                    None => {
+                        if !is_gone {
+                            write_null_array(stream);
+                        }
+                        return;
+                    }
                 }
-                sleep(Duration::from_millis(10));
-                continue;
-            } else {
-                sleep(Duration::from_millis(10));
-                continue;
             }
-        }
+        };
+        write_array(stream, &[Some(list_key.as_bytes()), Some(&popped)]);
     }
 
     fn handle_lpop(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         args: &[String],
         db: &DbType,
         global_state: &RedisGlobalType,
         is_propagation: &bool,
         _connection: &mut Connection,
-    ) -> usize {
+    ) {
         let is_slave_and_propagation = {
             let global = global_state.lock().unwrap();
             !global.is_master() && *is_propagation
         };
 
-        if args.len() < 1 {
+        if args.is_empty() || args.len() > 2 {
             if !is_slave_and_propagation {
-                write_error(stream, "wrong number of arguments for 'LPOP'");
+                write_error(stream, &error::wrong_arity("lpop"));
             }
-            return 0;
+            return;
         }
 
         let list_key = &args[0];
+        // With a count the reply is always an array, even for one element
+        let has_count = args.len() == 2;
         let mut count = 1;
-        let mut has_count = false;
-        if args.len() >= 2 {
-            match args[1].parse::<usize>() {
-                Ok(val) if val > 0 => {
-                    count = val;
-                    has_count = true;
+        if has_count {
+            match args[1].parse::<i64>() {
+                Ok(val) if val >= 0 => count = val as usize,
+                Ok(_) => {
+                    if !is_slave_and_propagation {
+                        write_error(stream, "ERR value is out of range, must be positive");
+                    }
+                    return;
                 }
-                _ => {
+                Err(_) => {
                     if !is_slave_and_propagation {
-                        write_error(stream, "ERR value is not an integer or out of range");
+                        write_error(stream, error::NOT_AN_INTEGER);
                     }
-                    return 0;
+                    return;
                 }
             }
         }
-        let consumed = if has_count { 2 } else { 1 };
-
-        let mut map = db.lock().unwrap();
+        let mut map = db.lock(list_key);
         if let Some(val) = map.get_mut(list_key) {
             if let ValueType::List(ref mut redis_list) = val {
                 if !redis_list.is_empty() {
@@ -753,250 +1188,281 @@ impl Runner {
                         removed_elems.push(redis_list.remove(0));
                     }
                     if !is_slave_and_propagation {
-                        if count == 1 {
-                            if !removed_elems.is_empty() {
-                                write_bulk_string(stream, &removed_elems[0]);
-                            } else {
-                                write_null_bulk_string(stream);
-                            }
-                        } else {
-                            let arr: Vec<Option<&str>> =
-                                removed_elems.iter().map(|s| Some(s.as_str())).collect();
+                        if has_count {
+                            let arr: Vec<Option<&[u8]>> =
+                                removed_elems.iter().map(|s| Some(s.as_slice())).collect();
                             write_array(stream, &arr);
+                        } else {
+                            write_bulk_string(stream, &removed_elems[0]);
                         }
                     }
                     if redis_list.is_empty() {
                         map.remove(list_key);
                     }
+                    mark_dirty(global_state, list_key);
                     if !is_slave_and_propagation {
                         let propagation = if args.len() >= 2 {
-                            format!("LPOP {} {}", list_key, count)
+                            encode_resp_command(&["LPOP", list_key, &count.to_string()])
                         } else {
-                            format!("LPOP {}", list_key)
+                            encode_resp_command(&["LPOP", list_key])
                         };
                         propagate_slaves(global_state, &propagation);
                     }
-                    return consumed;
+                    return;
                 } else {
                     if !is_slave_and_propagation {
-                        if count == 1 {
-                            write_null_bulk_string(stream);
+                        if has_count {
+                            write_null_array(stream);
                         } else {
-                            write_array::<&str>(stream, &[]);
+                            write_null_bulk_string(stream);
                         }
                     }
                     if !is_slave_and_propagation {
                         let propagation = if args.len() >= 2 {
-                            format!("LPOP {} {}", list_key, count)
+                            encode_resp_command(&["LPOP", list_key, &count.to_string()])
                         } else {
-                            format!("LPOP {}", list_key)
+                            encode_resp_command(&["LPOP", list_key])
                         };
                         propagate_slaves(global_state, &propagation);
                     }
-                    return consumed;
+                    return;
                 }
             } else {
                 if !is_slave_and_propagation {
-                    write_error(
-                        stream,
-                        "WRONGTYPE Operation against a key holding the wrong kind of value",
-                    );
+                    write_error(stream, error::WRONGTYPE);
                 }
-                return consumed;
+                return;
             }
         }
 
         if !is_slave_and_propagation {
-            if count == 1 {
-                write_null_bulk_string(stream);
+            if has_count {
+                write_null_array(stream);
             } else {
-                write_array::<&str>(stream, &[]);
+                write_null_bulk_string(stream);
             }
             let propagation = if args.len() >= 2 {
-                format!("LPOP {} {}", list_key, count)
+                encode_resp_command(&["LPOP", list_key, &count.to_string()])
             } else {
-                format!("LPOP {}", list_key)
+                encode_resp_command(&["LPOP", list_key])
             };
             propagate_slaves(global_state, &propagation);
         }
-        consumed
     }
 
     fn handle_llen(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         args: &[String],
         db: &DbType,
         _connection: &mut Connection,
-    ) -> usize {
-        if args.len() < 1 {
-            write_error(stream, "wrong number of arguments for 'LLEN'");
-            return 0;
+    ) {
+        if args.is_empty() {
+            write_error(stream, &error::wrong_arity("llen"));
+            return;
         }
         let list_key = &args[0];
 
-        let map = db.lock().unwrap();
+        let map = db.lock(list_key);
         if let Some(val) = map.get(list_key) {
             if let ValueType::List(ref redis_list) = val {
                 write_integer(stream, redis_list.len() as i64);
             } else {
-                write_integer(stream, 0);
+                write_error(stream, error::WRONGTYPE);
             }
         } else {
             write_integer(stream, 0);
         }
-        1
     }
     fn handle_zrank(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         args: &[String],
         db: &DbType,
-        _connection: &mut Connection,
-    ) -> usize {
-        // TODO: transaction
+        connection: &mut Connection,
+    ) {
         if args.len() < 2 {
-            write_error(stream, "wrong number of arguments for 'ZRANK'");
-            return 0;
+            write_error(stream, &error::wrong_arity("zrank"));
+            return;
         }
         let zset_key = &args[0];
         let member = &args[1];
+        let with_score = match &args[2..] {
+            [] => false,
+            [option] if option.eq_ignore_ascii_case("withscore") => true,
+            _ => {
+                write_error(stream, error::SYNTAX);
+                return;
+            }
+        };
 
-        let map = db.lock().unwrap();
-        if let Some(ValueType::ZSet(zset)) = map.get(zset_key) {
-            if let Some(rank) = zset.zrank(member) {
-                write_integer(stream, rank as i64);
-            } else {
-                write_null_bulk_string(stream);
+        let map = db.lock(zset_key);
+        let found = match map.get(zset_key) {
+            Some(ValueType::ZSet(zset)) => zset.zrank(member).zip(zset.zscore(member)),
+            Some(_) => {
+                write_error(stream, error::WRONGTYPE);
+                return;
             }
-        } else {
-            write_null_bulk_string(stream);
+            None => None,
+        };
+        match found {
+            Some((rank, score)) if with_score => {
+                let _ = stream.write_all(format!("*2\r\n:{}\r\n", rank).as_bytes());
+                write_bulk_string(stream, &score.to_string());
+            }
+            Some((rank, _)) => write_integer(stream, rank as i64),
+            None => write_null(stream, connection.protocol),
         }
-        2
     }
 
     fn handle_zrange(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         args: &[String],
         db: &DbType,
         _connection: &mut Connection,
-    ) -> usize {
-        // TODO: transaction
+    ) {
         if args.len() < 3 {
-            write_error(stream, "wrong number of arguments for 'ZRANGE'");
-            return 0;
+            write_error(stream, &error::wrong_arity("zrange"));
+            return;
         }
         let zset_key = &args[0];
         let start = match args[1].parse::<i64>() {
             Ok(v) => v,
             Err(_) => {
-                write_error(stream, "ERR value is not an integer or out of range");
-                return 0;
+                write_error(stream, error::NOT_AN_INTEGER);
+                return;
             }
         };
         let end = match args[2].parse::<i64>() {
             Ok(v) => v,
             Err(_) => {
-                write_error(stream, "ERR value is not an integer or out of range");
-                return 0;
+                write_error(stream, error::NOT_AN_INTEGER);
+                return;
+            }
+        };
+        let with_scores = match &args[3..] {
+            [] => false,
+            [option] if option.eq_ignore_ascii_case("withscores") => true,
+            _ => {
+                write_error(stream, error::SYNTAX);
+                return;
             }
         };
 
-        let map = db.lock().unwrap();
+        let map = db.lock(zset_key);
         if let Some(ValueType::ZSet(zset)) = map.get(zset_key) {
-            let sorted_members: Vec<Option<String>> = zset
-                .zrange(start, end)
-                .into_iter()
-                .map(|item| Some(item.1))
-                .collect();
+            let mut sorted_members: Vec<Option<String>> = Vec::new();
+            for (score, member) in zset.zrange(start, end) {
+                sorted_members.push(Some(member));
+                if with_scores {
+                    sorted_members.push(Some(score.to_string()));
+                }
+            }
             write_array(stream, &sorted_members);
+        } else if map.contains_key(zset_key) {
+            write_error(stream, error::WRONGTYPE);
         } else {
             write_array::<&str>(stream, &[]);
         }
-        3
     }
 
     fn handle_zcard(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         args: &[String],
         db: &DbType,
         _connection: &mut Connection,
-    ) -> usize {
-        // TODO: transaction
-        if args.len() < 1 {
-            write_error(stream, "wrong number of arguments for 'ZCARD'");
-            return 0;
+    ) {
+        if args.is_empty() {
+            write_error(stream, &error::wrong_arity("zcard"));
+            return;
         }
         let zset_key = &args[0];
 
-        let map = db.lock().unwrap();
+        let map = db.lock(zset_key);
 
         if let Some(ValueType::ZSet(zset)) = map.get(zset_key) {
             write_integer(stream, zset.zcard() as i64);
+        } else if map.contains_key(zset_key) {
+            write_error(stream, error::WRONGTYPE);
         } else {
             write_integer(stream, 0);
         }
-        1
     }
 
     fn handle_geopos(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         args: &[String],
         db: &DbType,
         _connection: &mut Connection,
-    ) -> usize {
-        // TODO: transaction
+    ) {
         if args.len() < 2 {
-            write_error(stream, "wrong number of arguments for 'GEOPOS'");
-            return 0;
+            write_error(stream, &error::wrong_arity("geopos"));
+            return;
         }
         let zset_key = &args[0];
         let places = &args[1..];
 
-        let map = db.lock().unwrap();
+        let map = db.lock(zset_key);
 
         if let Some(ValueType::ZSet(zset)) = map.get(zset_key) {
             // Form RESP array of size = places.len()
             let _ = stream.write_all(format!("*{}\r\n", places.len()).as_bytes());
             for place in places {
                 if let Some(score) = zset.zscore(place) {
-                    let (lat, long) = decode(score.clone() as u64);
+                    let (lat, long) = decode(*score as u64);
                     let _ = stream.write_all(b"*2\r\n");
-                    write_bulk_string(stream, &long.to_string());
-                    write_bulk_string(stream, &lat.to_string());
+                    write_bulk_string(stream, &format_coordinate(long));
+                    write_bulk_string(stream, &format_coordinate(lat));
                 } else {
                     write_null_array(stream);
                 }
             }
+        } else if map.contains_key(zset_key) {
+            write_error(stream, error::WRONGTYPE);
         } else {
             let _ = stream.write_all(format!("*{}\r\n", places.len()).as_bytes());
             for _ in places {
                 write_null_array(stream);
             }
         }
-        args.len()
     }
 
     fn handle_geodist(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         args: &[String],
         db: &DbType,
         _connection: &mut Connection,
-    ) -> usize {
-        // TODO: handle transaction
+    ) {
         if args.len() < 3 {
-            write_error(stream, "wrong number of arguments for 'GEODIST'");
-            return 0;
+            write_error(stream, &error::wrong_arity("geodist"));
+            return;
         }
         let zset_key = &args[0];
         let place1 = &args[1];
         let place2 = &args[2];
+        let meters_per_unit = match args.get(3..) {
+            Some([]) => 1.0,
+            Some([unit]) => match unit_to_meters(unit) {
+                Some(meters) => meters,
+                None => {
+                    write_error(
+                        stream,
+                        "ERR unsupported unit provided. please use M, KM, FT, MI",
+                    );
+                    return;
+                }
+            },
+            _ => {
+                write_error(stream, error::SYNTAX);
+                return;
+            }
+        };
 
-        let map = db.lock().unwrap();
+        let map = db.lock(zset_key);
 
         if let Some(ValueType::ZSet(zset)) = map.get(zset_key) {
             let score1_opt = zset.zscore(place1);
@@ -1006,130 +1472,283 @@ impl Runner {
                 // Interpret score as stored geo-encoded value (u64)
                 let (lat1, lon1) = decode(*score1 as u64);
                 let (lat2, lon2) = decode(*score2 as u64);
-                let dist = geo_distance(lat1, lon1, lat2, lon2);
+                let dist = geo_distance(lat1, lon1, lat2, lon2) / meters_per_unit;
                 write_bulk_string(stream, &dist.to_string());
             } else {
                 write_null_bulk_string(stream);
             }
+        } else if map.contains_key(zset_key) {
+            write_error(stream, error::WRONGTYPE);
         } else {
             // ZSet doesn't exist
             write_null_bulk_string(stream);
         }
-        3
     }
 
     fn handle_geosearch(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         args: &[String],
         db: &DbType,
         _connection: &mut Connection,
-    ) -> usize {
-        // TODO: handle transaction
-        if args.len() < 7 {
-            write_error(stream, "wrong number of arguments for 'GEOSEARCH'");
-            return 0;
+    ) {
+        if args.len() < 5 {
+            write_error(stream, &error::wrong_arity("geosearch"));
+            return;
         }
         let zset_key = &args[0];
-        let lon: f64 = args[2].parse().unwrap_or(0.0);
-        let lat: f64 = args[3].parse().unwrap_or(0.0);
-        let radius_raw: f64 = args[5].parse().unwrap_or(0.0);
-        let unit = &args[6].to_lowercase();
+        let (config, err) = GeoSearchConfig::from_args(&args[1..]);
+        if let Some(e) = err {
+            write_error(stream, &e);
+            return;
+        }
 
-        let radius: f64 = match unit.as_str() {
-            "m" => radius_raw,
-            "km" => radius_raw * 1000.0,
-            "mi" => radius_raw * 1609.344,
-            "ft" => radius_raw * 0.3048,
-            "cm" => radius_raw * 0.01,
-            "mm" => radius_raw * 0.001,
-            "yd" => radius_raw * 0.9144,
-            _ => radius_raw,
+        let map = db.lock(zset_key);
+        let zset = match map.get(zset_key) {
+            Some(ValueType::ZSet(zset)) => zset,
+            Some(_) => {
+                write_error(stream, error::WRONGTYPE);
+                return;
+            }
+            None => {
+                write_array::<&str>(stream, &[]);
+                return;
+            }
         };
 
-        let map = db.lock().unwrap();
-
-        if let Some(ValueType::ZSet(zset)) = map.get(zset_key) {
-            write_array(
-                stream,
-                &zset
-                    .geosearch(lon, lat, radius)
-                    .into_iter()
-                    .map(|s| Some(s.to_string()))
-                    .collect::<Vec<Option<String>>>(),
-            );
-        } else {
-            write_null_array(stream);
+        match self.geo_search(zset, &config) {
+            Ok(matches) => self.write_geo_matches(stream, &matches, &config),
+            Err(e) => write_error(stream, &e),
         }
-        7
     }
 
-    fn handle_zscore(
+    fn handle_georadius(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
+        command: &str,
         args: &[String],
         db: &DbType,
-        _connection: &mut Connection,
-    ) -> usize {
+        global_state: &RedisGlobalType,
+        is_propagation: &bool,
+    ) {
+        let is_slave_and_propagation = {
+            let global = global_state.lock().unwrap();
+            !global.is_master() && *is_propagation
+        };
+
+        let GeoRadiusOptions {
+            key: zset_key,
+            config,
+            store,
+        } = match GeoRadiusOptions::from_args(command, args) {
+            Ok(options) => options,
+            Err(e) => {
+                if !is_slave_and_propagation {
+                    write_error(stream, &e);
+                }
+                return;
+            }
+        };
+
+        let dest_key = store.as_ref().map(|(dest, _)| dest.as_str());
+        let mut map = db.lock_keys([zset_key.as_str()].into_iter().chain(dest_key));
+        let matches = match map.get(&zset_key) {
+            Some(ValueType::ZSet(zset)) => match self.geo_search(zset, &config) {
+                Ok(matches) => matches,
+                Err(e) => {
+                    if !is_slave_and_propagation {
+                        write_error(stream, &e);
+                    }
+                    return;
+                }
+            },
+            Some(_) => {
+                if !is_slave_and_propagation {
+                    write_error(stream, error::WRONGTYPE);
+                }
+                return;
+            }
+            None => vec![],
+        };
+
+        let (dest, store_dist) = match store {
+            Some(store) => store,
+            None => {
+                drop(map);
+                if !is_slave_and_propagation {
+                    self.write_geo_matches(stream, &matches, &config);
+                }
+                return;
+            }
+        };
+
+        let unit_factor = unit_to_meters(&config.unit).unwrap_or(1.0);
+        if matches.is_empty() {
+            map.remove(&dest);
+        } else {
+            let mut stored = ZSet::new();
+            for found in &matches {
+                let score = if store_dist {
+                    found.distance / unit_factor
+                } else {
+                    found.hash as f64
+                };
+                stored.zadd(score, found.member.clone());
+            }
+            // The stored set replaces the key outright, TTL included
+            map.insert_entry(dest.clone(), Entry::new(ValueType::ZSet(stored)));
+        }
+        drop(map);
+        mark_dirty(global_state, &dest);
+
+        if !is_slave_and_propagation {
+            write_integer(stream, matches.len() as i64);
+            let mut propagation = vec![command.to_uppercase()];
+            propagation.extend(args.iter().cloned());
+            propagate_slaves(global_state, &encode_resp_command(&propagation));
+        }
+    }
+
+    /// The engine behind GEOSEARCH and the GEORADIUS family: resolves the center,
+    /// collects members inside the shape and applies ordering and COUNT.
+    fn geo_search(&self, zset: &ZSet, config: &GeoSearchConfig) -> Result<Vec<GeoMatch>, String> {
+        let (lon, lat) = match config.center.as_ref() {
+            Some(GeoCenter::LonLat(lon, lat)) => {
+                if !validate_longitude(*lon) || !validate_latitude(*lat) {
+                    return Err(format!("ERR invalid longitude,latitude pair {lon},{lat}"));
+                }
+                (*lon, *lat)
+            }
+            Some(GeoCenter::Member(member)) => match zset.zscore(member) {
+                Some(score) => {
+                    let (lat, lon) = decode(*score as u64);
+                    (lon, lat)
+                }
+                None => return Err("ERR could not decode requested zset member".to_string()),
+            },
+            None => return Err(error::SYNTAX.to_string()),
+        };
+        let shape = config.shape.ok_or_else(|| "syntax error".to_string())?;
+
+        // COUNT ANY may stop at the first matches found; otherwise sort, then cut
+        let limit = if config.any { config.count } else { None };
+        let mut matches = geo::search(zset.iter(), lon, lat, shape, limit);
+
+        match config.order {
+            Some(GeoOrder::Asc) => matches.sort_by(|a, b| a.distance.total_cmp(&b.distance)),
+            Some(GeoOrder::Desc) => matches.sort_by(|a, b| b.distance.total_cmp(&a.distance)),
+            None => {}
+        }
+        if let Some(count) = config.count {
+            matches.truncate(count);
+        }
+        Ok(matches)
+    }
+
+    fn write_geo_matches(
+        &self,
+        stream: &mut ClientStream,
+        matches: &[GeoMatch],
+        config: &GeoSearchConfig,
+    ) {
+        let with_fields =
+            config.with_dist as usize + config.with_hash as usize + config.with_coord as usize;
+        let unit_factor = unit_to_meters(&config.unit).unwrap_or(1.0);
+
+        let mut resp = format!("*{}\r\n", matches.len());
+        for found in matches {
+            let member = &found.member;
+            if with_fields == 0 {
+                resp.push_str(&format!("${}\r\n{}\r\n", member.len(), member));
+                continue;
+            }
+
+            resp.push_str(&format!("*{}\r\n", 1 + with_fields));
+            resp.push_str(&format!("${}\r\n{}\r\n", member.len(), member));
+            if config.with_dist {
+                let dist = format!("{:.4}", found.distance / unit_factor);
+                resp.push_str(&format!("${}\r\n{}\r\n", dist.len(), dist));
+            }
+            if config.with_hash {
+                resp.push_str(&format!(":{}\r\n", found.hash));
+            }
+            if config.with_coord {
+                let (lon, lat) = (
+                    format_coordinate(found.longitude),
+                    format_coordinate(found.latitude),
+                );
+                resp.push_str(&format!(
+                    "*2\r\n${}\r\n{}\r\n${}\r\n{}\r\n",
+                    lon.len(),
+                    lon,
+                    lat.len(),
+                    lat
+                ));
+            }
+        }
+        let _ = stream.write_all(resp.as_bytes());
+    }
+
+    fn handle_zscore(
+        &self,
+        stream: &mut ClientStream,
+        args: &[String],
+        db: &DbType,
+        connection: &mut Connection,
+    ) {
         if args.len() < 2 {
-            write_error(stream, "wrong number of arguments for 'ZSCORE'");
-            return 0;
+            write_error(stream, &error::wrong_arity("zscore"));
+            return;
         }
         let zset_key = &args[0];
         let member = &args[1];
 
-        let map = db.lock().unwrap();
+        let map = db.lock(zset_key);
 
-        if let Some(ValueType::ZSet(zset)) = map.get(zset_key) {
-            if let Some(score) = zset.zscore(member) {
-                write_bulk_string(stream, &score.to_string());
-            }
-        } else {
-            write_null_bulk_string(stream);
+        match map.get(zset_key) {
+            Some(ValueType::ZSet(zset)) => match zset.zscore(member) {
+                Some(score) => write_double(stream, *score, connection.protocol),
+                None => write_null(stream, connection.protocol),
+            },
+            Some(_) => write_error(stream, error::WRONGTYPE),
+            None => write_null(stream, connection.protocol),
         }
-        2
     }
 
     fn handle_lrange(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         args: &[String],
         db: &DbType,
         _connection: &mut Connection,
-    ) -> usize {
+    ) {
         if args.len() < 3 {
-            write_error(stream, "wrong number of arguments for 'LRANGE'");
-            return 0;
+            write_error(stream, &error::wrong_arity("lrange"));
+            return;
         }
         let stream_key = &args[0];
 
-        let map = db.lock().unwrap();
+        let map = db.lock(stream_key);
         let redis_list = match map.get(stream_key) {
             Some(val) => {
                 if let ValueType::List(ref redis_list) = val {
                     redis_list
                 } else {
-                    write_error(
-                        stream,
-                        "WRONGTYPE Operation against a key holding the wrong kind of value",
-                    );
-                    return 3;
+                    write_error(stream, error::WRONGTYPE);
+                    return;
                 }
             }
             None => {
                 write_array::<&str>(stream, &[]);
-                return 3;
+                return;
             }
         };
 
         let (start_i64, end_i64) = match (args[1].parse::<i64>(), args[2].parse::<i64>()) {
             (Ok(s), Ok(e)) => (s, e),
             _ => {
-                write_error(
-                    stream,
-                    "invalid arguments for LRANGE: start and end must be integers",
-                );
-                return 3;
+                write_error(stream, error::NOT_AN_INTEGER);
+                return;
             }
         };
 
@@ -1166,324 +1785,556 @@ impl Runner {
             } else {
                 end + 1
             };
-            let result: Vec<Option<&str>> = redis_list[start..upper]
+            let result: Vec<Option<&[u8]>> = redis_list[start..upper]
                 .iter()
-                .map(|s| Some(s.as_str()))
+                .map(|s| Some(s.as_slice()))
                 .collect();
             write_array(stream, &result);
         }
-        3
     }
 
     fn handle_rpush(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         args: &[String],
         db: &DbType,
         global_state: &RedisGlobalType,
         is_propagation: &bool,
         _connection: &mut Connection,
-    ) -> usize {
-        // TODO: transaction runner and enqueuing
+    ) {
         let is_slave_and_propagation = {
             let global = global_state.lock().unwrap();
             !global.is_master() && *is_propagation
         };
         if args.len() < 2 {
             if !is_slave_and_propagation {
-                write_error(stream, "wrong number of arguments for 'RPUSH'");
+                write_error(stream, &error::wrong_arity("rpush"));
             }
-            return 0;
+            return;
         }
 
         let list_key = &args[0];
-        let mut consumed = 1;
-        let mut val_vec: Vec<String> = vec![];
-        for idx in 1..args.len() {
-            val_vec.push(args[idx].clone());
-            consumed += 1;
-        }
+        let val_vec: Vec<Vec<u8>> = (1..args.len())
+            .map(|index| self.arg_bytes(index).to_vec())
+            .collect();
         let mut len = val_vec.len();
+        let mut served = 0;
 
         {
-            let mut map = db.lock().unwrap();
-            if let Some(val_ref) = map.get_mut(list_key) {
-                if let ValueType::List(ref mut redis_list) = val_ref {
-                    for val in &val_vec {
-                        redis_list.push(val.clone());
-                    }
+            let mut map = db.lock(list_key);
+            match map.get_mut(list_key) {
+                Some(ValueType::List(redis_list)) => {
+                    redis_list.extend(val_vec);
                     len = redis_list.len();
-                } else {
-                    map.insert(list_key.clone(), ValueType::List(val_vec.clone()));
                 }
-            } else {
-                map.insert(list_key.clone(), ValueType::List(val_vec.clone()));
+                Some(_) => {
+                    if !is_slave_and_propagation {
+                        write_error(stream, error::WRONGTYPE);
+                    }
+                    return;
+                }
+                None => {
+                    map.insert(list_key.clone(), ValueType::List(val_vec));
+                }
+            }
+            if !is_slave_and_propagation {
+                served = serve_blocked_clients(db, &mut map, list_key);
             }
         }
+        mark_dirty(global_state, list_key);
 
         if !is_slave_and_propagation {
             write_integer(stream, len as i64);
-            let propagation = format!("RPUSH {} {}", list_key, val_vec.join(" "));
+            let mut propagation: Vec<&[u8]> = vec![b"RPUSH"];
+            propagation.extend((0..args.len()).map(|index| self.arg_bytes(index)));
+            let propagation = encode_resp_command(&propagation);
             propagate_slaves(global_state, &propagation);
+            propagate_served_pops(global_state, list_key, served);
         }
-        consumed
     }
 
     fn handle_lpush(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         args: &[String],
         db: &DbType,
         global_state: &RedisGlobalType,
         is_propagation: &bool,
         _connection: &mut Connection,
-    ) -> usize {
-        // TODO: transaction runner and enqueuing
+    ) {
         let is_slave_and_propagation = {
             let global = global_state.lock().unwrap();
             !global.is_master() && *is_propagation
         };
         if args.len() < 2 {
             if !is_slave_and_propagation {
-                write_error(stream, "wrong number of arguments for 'LPUSH'");
+                write_error(stream, &error::wrong_arity("lpush"));
             }
-            return 0;
+            return;
         }
 
         let list_key = &args[0];
-        let mut consumed = 1;
-        let mut val_vec: Vec<String> = vec![];
-        for idx in 1..args.len() {
-            val_vec.push(args[idx].clone());
-            consumed += 1;
-        }
+        // Each element goes in at the head in turn, so they end up reversed
+        let val_vec: Vec<Vec<u8>> = (1..args.len())
+            .rev()
+            .map(|index| self.arg_bytes(index).to_vec())
+            .collect();
         let mut len = val_vec.len();
+        let mut served = 0;
 
         {
-            let mut map = db.lock().unwrap();
-            if let Some(val_ref) = map.get_mut(list_key) {
-                if let ValueType::List(ref mut redis_list) = val_ref {
-                    for val in &val_vec {
-                        redis_list.insert(0, val.clone());
-                    }
+            let mut map = db.lock(list_key);
+            match map.get_mut(list_key) {
+                Some(ValueType::List(redis_list)) => {
+                    redis_list.splice(0..0, val_vec);
                     len = redis_list.len();
-                } else {
-                    val_vec.reverse();
-                    map.insert(list_key.clone(), ValueType::List(val_vec.clone()));
                 }
-            } else {
-                val_vec.reverse();
-                map.insert(list_key.clone(), ValueType::List(val_vec.clone()));
+                Some(_) => {
+                    if !is_slave_and_propagation {
+                        write_error(stream, error::WRONGTYPE);
+                    }
+                    return;
+                }
+                None => {
+                    map.insert(list_key.clone(), ValueType::List(val_vec));
+                }
+            }
+            if !is_slave_and_propagation {
+                served = serve_blocked_clients(db, &mut map, list_key);
             }
         }
+        mark_dirty(global_state, list_key);
 
         if !is_slave_and_propagation {
             write_integer(stream, len as i64);
-            let propagation = format!("RPUSH {} {}", list_key, val_vec.join(" "));
+            let mut propagation: Vec<&[u8]> = vec![b"LPUSH"];
+            propagation.extend((0..args.len()).map(|index| self.arg_bytes(index)));
+            let propagation = encode_resp_command(&propagation);
             propagate_slaves(global_state, &propagation);
+            propagate_served_pops(global_state, list_key, served);
         }
-        consumed
     }
 
     fn handle_type(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         args: &[String],
         db: &DbType,
-        db_config: &DbConfigType,
-        connection: &mut Connection,
-    ) -> usize {
-        if args.len() < 1 {
-            write_error(stream, "wrong number of arguments for 'TYPE'");
-            return 0;
+        global_state: &RedisGlobalType,
+        _connection: &mut Connection,
+    ) {
+        if args.is_empty() {
+            write_error(stream, &error::wrong_arity("type"));
+            return;
         }
 
         let key = &args[0];
 
-        if connection.transaction.is_txing {
-            // Queue the full command with key
-            connection.transaction.tasks.push(format!("type {}", key));
-            write_simple_string(stream, "QUEUED");
-            return 1;
-        }
+        let type_name = read_live(db, global_state, key, |value| {
+            value.map_or("none", |value| value.type_name())
+        });
+        write_simple_string(stream, type_name);
+    }
 
-        // Check for expiration
-        let mut config_map = db_config.lock().unwrap();
-        let expired = if let Some(config) = config_map.get(key) {
-            config.is_expired()
-        } else {
-            false
+    /// OBJECT ENCODING | REFCOUNT | IDLETIME on one key, null if it is
+    /// missing.
+    fn handle_object(
+        &self,
+        stream: &mut ClientStream,
+        args: &[String],
+        db: &DbType,
+        global_state: &RedisGlobalType,
+        connection: &mut Connection,
+    ) {
+        let Some(subcommand) = args.first() else {
+            write_error(stream, &error::wrong_arity("object"));
+            return;
         };
-        if expired {
-            config_map.remove(key);
-            let mut map = db.lock().unwrap();
-            map.remove(key);
-            write_simple_string(stream, "none");
-            return 1;
+        let subcommand = subcommand.to_ascii_lowercase();
+        if !matches!(subcommand.as_str(), "encoding" | "refcount" | "idletime") {
+            write_error(stream, &error::unknown_subcommand("object", &args[0]));
+            return;
         }
-        drop(config_map);
+        let [_, key] = args else {
+            write_error(
+                stream,
+                &error::wrong_arity(&format!("object|{}", subcommand)),
+            );
+            return;
+        };
 
-        let map = db.lock().unwrap();
-        if let Some(val) = map.get(key) {
-            write_simple_string(stream, val.type_name());
-        } else {
-            write_simple_string(stream, "none");
+        if expire_if_needed(db, global_state, key) {
+            write_null(stream, connection.protocol);
+            return;
+        }
+        let map = db.lock(key);
+        let Some(entry) = map.entry(key) else {
+            write_null(stream, connection.protocol);
+            return;
+        };
+        match subcommand.as_str() {
+            "encoding" => write_bulk_string(stream, entry.value.encoding()),
+            // No value is ever shared between keys here
+            "refcount" => write_integer(stream, 1),
+            _ => {
+                let idle_ms = now_ms().saturating_sub(entry.last_access_ms);
+                write_integer(stream, (idle_ms / 1000) as i64);
+            }
         }
-        1
     }
 
-    fn handle_discard(&self, stream: &mut TcpStream, connection: &mut Connection) {
+    fn handle_discard(
+        &self,
+        stream: &mut ClientStream,
+        global_state: &RedisGlobalType,
+        connection: &mut Connection,
+    ) {
         if !connection.transaction.is_txing {
-            write_error(stream, "DISCARD without MULTI");
+            write_error(stream, "ERR DISCARD without MULTI");
             return;
         }
-        connection.transaction.is_txing = false;
-        connection.transaction.tasks.clear();
-        connection.transaction.response.clear();
-        connection.transaction.job_done_at = None;
+        connection.transaction = Transaction::new();
+        connection.unwatch_all(&mut global_state.lock().unwrap());
         write_simple_string(stream, "OK");
     }
 
-    fn handle_multi(&self, stream: &mut TcpStream, connection: &mut Connection) {
+    fn handle_watch(
+        &self,
+        stream: &mut ClientStream,
+        args: &[String],
+        global_state: &RedisGlobalType,
+        connection: &mut Connection,
+    ) {
+        if args.is_empty() {
+            write_error(stream, &error::wrong_arity("watch"));
+            return;
+        }
         if connection.transaction.is_txing {
-            write_error(stream, "Transaction has already started");
+            write_error(stream, "ERR WATCH inside MULTI is not allowed");
+            return;
         }
 
-        connection.transaction.is_txing = true;
-        connection.transaction.tasks.clear();
-        connection.transaction.response.clear();
-        connection.transaction.job_done_at = None;
+        let mut global = global_state.lock().unwrap();
+        for key in args {
+            if !connection.watched_keys.contains_key(key) {
+                let version = global.watch_key(key);
+                connection.watched_keys.insert(key.clone(), version);
+            }
+        }
         write_simple_string(stream, "OK");
     }
 
+    fn handle_multi(
+        &self,
+        stream: &mut ClientStream,
+        global_state: &RedisGlobalType,
+        is_propagation: &bool,
+        connection: &mut Connection,
+    ) {
+        let is_slave_and_propagation = {
+            let global = global_state.lock().unwrap();
+            !global.is_master() && *is_propagation
+        };
+        if connection.transaction.is_txing {
+            if !is_slave_and_propagation {
+                write_error(stream, "ERR MULTI calls can not be nested");
+            }
+            return;
+        }
+
+        connection.transaction = Transaction::new();
+        connection.transaction.is_txing = true;
+        if !is_slave_and_propagation {
+            write_simple_string(stream, "OK");
+        }
+    }
+
     fn handle_exec(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         db: &DbType,
-        db_config: &DbConfigType,
         global_state: &RedisGlobalType,
+        is_propagation: &bool,
         connection: &mut Connection,
+        local_offset: &usize,
     ) {
         if !connection.transaction.is_txing {
-            write_error(stream, "EXEC without MULTI");
+            write_error(stream, "ERR EXEC without MULTI");
             return;
         }
+        let is_slave_and_propagation = {
+            let global = global_state.lock().unwrap();
+            !global.is_master() && *is_propagation
+        };
 
-        let mut runner = TransactionRunner::new(connection);
-        runner.execute_transactions(db, db_config, global_state);
+        let transaction = std::mem::take(&mut connection.transaction);
+        let tasks = transaction.tasks;
+
+        let runners: Vec<Runner> = tasks.into_iter().map(Runner::for_exec).collect();
+        let keys: Vec<String> = runners
+            .iter()
+            .filter_map(|runner| {
+                let (command, args) = runner.args.split_first()?;
+                let spec = CommandSpec::lookup(&command.to_ascii_lowercase())?;
+                Some(spec.keys(args).into_iter().cloned().collect::<Vec<_>>())
+            })
+            .flatten()
+            .collect();
+        // Held from the WATCH check on; dropped after the propagation guard,
+        // so the block reaches the replicas before other clients get the
+        // keys back
+        let _hold = db.hold_keys(keys.iter().map(String::as_str));
+
+        // A watched key touched since WATCH aborts the whole transaction
+        let is_stale = {
+            let mut global = global_state.lock().unwrap();
+            let is_stale = connection
+                .watched_keys
+                .iter()
+                .any(|(key, version)| global.key_version(key) != *version);
+            connection.unwatch_all(&mut global);
+            is_stale
+        };
+        if transaction.dirty {
+            write_error(
+                stream,
+                "EXECABORT Transaction discarded because of previous errors.",
+            );
+            return;
+        }
+        if is_stale {
+            write_null_array(stream);
+            return;
+        }
 
-        write_resp_array(stream, &connection.transaction.response);
-        connection.transaction.is_txing = false;
+        // Replies stay buffered on the stream until the whole request is done,
+        // so each queued command's reply lands as one element of this array
+        if !is_slave_and_propagation {
+            let _ = stream.write_all(format!("*{}\r\n", runners.len()).as_bytes());
+        }
+        let _propagation = begin_exec_propagation(global_state);
+        for mut runner in runners {
+            runner.run(
+                stream,
+                db,
+                global_state,
+                connection,
+                local_offset,
+                *is_propagation,
+            );
+        }
     }
 
     pub fn handle_wait(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         args: &[String],
         global_state: &RedisGlobalType,
-        _connection: &mut Connection,
-    ) -> usize {
+        connection: &mut Connection,
+    ) {
         if args.len() < 2 {
-            write_error(stream, "wrong number of arguments for 'WAIT'");
-            return 0;
+            write_error(stream, &error::wrong_arity("wait"));
+            return;
         }
 
         let numreplicas = match args[0].parse::<usize>() {
             Ok(n) => n,
             Err(_) => {
-                write_error(stream, "ERR invalid number of replicas");
-                return 0;
+                write_error(stream, error::NOT_AN_INTEGER);
+                return;
             }
         };
 
         let timeout_ms = match args[1].parse::<u64>() {
             Ok(t) => t,
             Err(_) => {
-                write_error(stream, "ERR invalid timeout");
-                return 0;
+                write_error(stream, "ERR timeout is not an integer or out of range");
+                return;
             }
         };
 
-        let connected_replicas = {
+        // Everything written before WAIT must be acknowledged, not the GETACK itself
+        let (target_offset, connected_replicas, pending) = {
             let global = global_state.lock().unwrap();
-            global.replica_states.len()
+            let pending = global
+                .replica_states
+                .values()
+                .any(|replica| replica.local_offset < global.offset_replica_sync);
+            (
+                global.offset_replica_sync,
+                global.replica_states.len(),
+                pending,
+            )
         };
 
-        let satisfied = if connected_replicas >= numreplicas {
-            numreplicas
-        } else {
-            connected_replicas
-        };
+        if !pending {
+            write_integer(stream, connected_replicas as i64);
+            return;
+        }
 
-        let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+        send_to_replicas(
+            global_state,
+            &encode_resp_command(&["REPLCONF", "GETACK", "*"]),
+        );
 
-        let offset = {
-            let guard = global_state.lock().unwrap();
-            guard.offset_replica_sync
-        };
+        // A timeout of 0 blocks until enough replicas acknowledge
+        let deadline = (timeout_ms > 0).then(|| Instant::now() + Duration::from_millis(timeout_ms));
 
-        write_integer(stream, satisfied as i64);
+        let mut blocked = None;
+        let acked = loop {
+            let global = global_state.lock().unwrap();
+            let acked = global
+                .replica_states
+                .values()
+                .filter(|replica| replica.local_offset >= target_offset)
+                .count();
+            let now = Instant::now();
+            if acked >= numreplicas
+                || deadline.is_some_and(|d| now >= d)
+                || connection.is_killed()
+                || self.in_exec
+            {
+                break acked;
+            }
 
-        loop {
-            let acks = {
-                let guard = global_state.lock().unwrap();
-                guard
-                    .replica_states
-                    .values()
-                    .filter(|replica| replica.local_offset == offset)
-                    .count()
-            };
-            if acks >= satisfied {
-                return 2;
+            if blocked.is_none() {
+                drop(global);
+                blocked = Some(connection.block(stream));
+                continue;
             }
+            // Waiting releases the lock, so acks can come in meanwhile
+            let wait = deadline.map_or(BLOCKED_RECHECK, |d| (d - now).min(BLOCKED_RECHECK));
+            let replica_acked = Arc::clone(&global.replica_acked);
+            let _ = replica_acked.wait_timeout(global, wait);
+        };
+
+        write_integer(stream, acked as i64);
+    }
+
+    /// REPLICAOF host port | REPLICAOF NO ONE
+    pub fn handle_replicaof(
+        &self,
+        stream: &mut ClientStream,
+        args: &[String],
+        db: &DbType,
+        global_state: &RedisGlobalType,
+    ) {
+        if args.len() < 2 {
+            write_error(stream, &error::wrong_arity("replicaof"));
+            return;
+        }
 
-            if Instant::now() >= deadline {
-                return 2;
+        if args[0].eq_ignore_ascii_case("no") && args[1].eq_ignore_ascii_case("one") {
+            let mut global = global_state.lock().unwrap();
+            if !global.is_master() {
+                global.promote_to_master();
+                eprintln!("Promoted to master");
             }
+            write_simple_string(stream, "OK");
+            return;
+        }
 
-            std::thread::sleep(Duration::from_millis(10));
+        if args[1].parse::<u16>().is_err() {
+            write_error(stream, "ERR Invalid master port");
+            return;
+        }
+
+        let master = (args[0].clone(), args[1].clone());
+        {
+            let mut global = global_state.lock().unwrap();
+            if global.master_address.as_ref() == Some(&master) {
+                write_simple_string(stream, "OK Already connected to specified master");
+                return;
+            }
+            global.follow_master(master);
         }
+
+        // The new link syncs in the background; INFO already reports the new role
+        spawn_master_link(db.clone(), global_state.clone());
+        write_simple_string(stream, "OK");
     }
 
     pub fn handle_psync(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         args: &[String],
+        db: &DbType,
         global_state: &RedisGlobalType,
         connection: &mut Connection,
-    ) -> usize {
+    ) {
+        // The snapshot is taken and the replica registered under the same
+        // locks, so no write falls between the two. Handlers take the keyspace
+        // locks before the global one, so this does too.
+        let map = db.lock_all();
         let mut global = global_state.lock().unwrap();
-        if args.len() >= 2 {
+        if args.len() < 2 {
+            return;
+        }
+        // Resume from the backlog when the replica follows our history and
+        // the bytes it is missing are still there
+        let backlog = if args[0] == global.master_replid {
+            args[1]
+                .parse::<usize>()
+                .ok()
+                .and_then(|offset| global.repl_backlog.range_from(offset))
+        } else {
+            None
+        };
+
+        let snapshot = if let Some(missing) = backlog {
+            write_simple_string(stream, &format!("CONTINUE {}", global.master_replid));
+            let _ = stream.write_all(&missing);
+            None
+        } else {
             write_simple_string(
                 stream,
                 &format!(
                     "FULLRESYNC {} {}",
-                    global.master_replid, global.master_repl_offset
+                    global.master_replid, global.offset_replica_sync
                 ),
             );
+            connection.slave_port.is_some().then(|| map.snapshot())
+        };
+        drop(map);
 
-            let stream_clone = stream.try_clone().unwrap();
-            if let Some(ref slave_port) = connection.slave_port {
-                add_replica(&mut global, stream_clone, slave_port);
-                write_redis_file(
-                    stream,
-                    &format!("{}/{}", global.dir_path, global.dbfilename),
-                );
-                connection.is_slave_established = true;
+        let Some(ref slave_port) = connection.slave_port else {
+            return;
+        };
+        // The reply must be queued before the snapshot and any propagated
+        // write. A replica that went away meanwhile isn't registered at all
+        let socket = match stream.flush().and_then(|_| stream.share_socket()) {
+            Ok(socket) => socket,
+            Err(e) => {
+                eprintln!("Sync with replica {} failed: {}", slave_port, e);
+                return;
+            }
+        };
+        match snapshot {
+            // Encoded and sent off this thread, with no lock held
+            Some(snapshot) => spawn_replica_sync(
+                &mut global,
+                global_state,
+                stream.outbox(),
+                socket,
+                slave_port,
+                snapshot,
+            ),
+            None => {
+                add_replica(&mut global, stream.outbox(), socket, slave_port);
             }
-            return 2;
         }
-        0
+        connection.is_slave_established = true;
+        connection.info.lock().unwrap().kind = ClientKind::Replica;
     }
 
     pub fn handle_replconf(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         args: &[String],
         global_state: &RedisGlobalType,
         connection: &mut Connection,
         local_offset: &usize,
-    ) -> usize {
+    ) {
         if args.len() >= 2 {
             let subcmd = args[0].to_ascii_lowercase();
             match subcmd.as_str() {
@@ -1492,7 +2343,7 @@ impl Runner {
                     let connection_port = &args[1];
 
                     connection.slave_port = Some(connection_port.clone());
-                    return 2;
+                    return;
                 }
                 "capa" => {
                     let mut idx = 1;
@@ -1517,9 +2368,9 @@ impl Runner {
                         } else {
                             panic!("slave_port is not set before REPLCONF capa");
                         }
-                        return 1 + caps.len();
+                        return;
                     }
-                    return 1;
+                    return;
                 }
 
                 "getack" => {
@@ -1531,464 +2382,2193 @@ impl Runner {
                             Some(&local_offset.to_string()),
                         ],
                     );
-                    return 2;
+                    return;
                 }
-                _ => return 0,
+                _ => return,
             }
         }
-        write_error(stream, "syntax error");
-        0
+        write_error(stream, error::SYNTAX);
     }
 
+    /// INFO [section ...]. Every section here is part of the default set, so
+    /// "default", "all" and "everything" all return the lot.
     fn handle_info(
         &self,
-        stream: &mut TcpStream,
-        _args: &[String],
-        _db: &DbType,
-        _db_config: &DbConfigType,
+        stream: &mut ClientStream,
+        args: &[String],
+        db: &DbType,
         global_state: &RedisGlobalType,
-        connection: &mut Connection,
+        _connection: &mut Connection,
     ) {
-        // If in transaction, queue the command and return
-        if connection.transaction.is_txing {
-            connection.transaction.tasks.push("info".to_string());
-            write_simple_string(stream, "QUEUED");
-            return;
-        }
+        let requested: Vec<String> = args.iter().map(|arg| arg.to_ascii_lowercase()).collect();
+        let wants = |section: &str| {
+            requested.is_empty()
+                || requested.iter().any(|name| {
+                    matches!(name.as_str(), "default" | "all" | "everything") || name == section
+                })
+        };
 
-        let global = global_state.lock().unwrap();
-        let role = if global.is_master() {
-            "master"
-        } else {
-            "slave"
+        let (keys, expires) = {
+            let db = db.lock_all();
+            let expires = db
+                .iter()
+                .filter(|(_, entry)| entry.expire_at.is_some())
+                .count();
+            (db.len(), expires)
         };
+        let used_memory = db.used_memory();
+        let global = global_state.lock().unwrap();
+        let mut sections: Vec<String> = Vec::new();
+
+        if wants("server") {
+            let uptime = global.start_time.elapsed().as_secs();
+            sections.push(info_section(
+                "Server",
+                &[
+                    ("redis_version", REDIS_VER.to_string()),
+                    ("redis_mode", "standalone".to_string()),
+                    ("os", std::env::consts::OS.to_string()),
+                    ("arch_bits", usize::BITS.to_string()),
+                    ("process_id", std::process::id().to_string()),
+                    ("tcp_port", global.port.clone()),
+                    ("uptime_in_seconds", uptime.to_string()),
+                    ("uptime_in_days", (uptime / 86400).to_string()),
+                ],
+            ));
+        }
+
+        if wants("clients") {
+            let (mut connected, mut blocked) = (0, 0);
+            for info in global.clients.values() {
+                let info = info.lock().unwrap();
+                if info.kind == ClientKind::Normal {
+                    connected += 1;
+                }
+                if info.blocked {
+                    blocked += 1;
+                }
+            }
+            sections.push(info_section(
+                "Clients",
+                &[
+                    ("connected_clients", connected.to_string()),
+                    ("maxclients", global.maxclients.to_string()),
+                    ("blocked_clients", blocked.to_string()),
+                    (
+                        "rejected_connections",
+                        global.stat_rejected_conn.to_string(),
+                    ),
+                ],
+            ));
+        }
 
-        let mut info = format!("role:{}", role);
+        if wants("memory") {
+            sections.push(info_section(
+                "Memory",
+                &[
+                    ("used_memory", used_memory.to_string()),
+                    ("used_memory_human", format_memory(used_memory)),
+                    ("used_memory_rss", used_memory_rss().to_string()),
+                    ("maxmemory", global.maxmemory.to_string()),
+                    ("maxmemory_human", format_memory(global.maxmemory)),
+                    (
+                        "maxmemory_policy",
+                        global.maxmemory_policy.name().to_string(),
+                    ),
+                ],
+            ));
+        }
+
+        if wants("persistence") {
+            let bgsave_status = if global.rdb_last_bgsave_status {
+                "ok"
+            } else {
+                "err"
+            };
+            sections.push(info_section(
+                "Persistence",
+                &[
+                    ("loading", (global.loading as u8).to_string()),
+                    ("rdb_changes_since_last_save", global.dirty.to_string()),
+                    (
+                        "rdb_bgsave_in_progress",
+                        (global.rdb_bgsave_in_progress as u8).to_string(),
+                    ),
+                    ("rdb_last_save_time", global.rdb_last_save_time.to_string()),
+                    ("rdb_last_bgsave_status", bgsave_status.to_string()),
+                    ("aof_enabled", (global.appendonly as u8).to_string()),
+                    (
+                        "aof_rewrite_in_progress",
+                        (global.aof_rewrite_buffer.is_some() as u8).to_string(),
+                    ),
+                ],
+            ));
+        }
 
-        if role == "master" {
-            info.push_str(&format!("\nmaster_replid:{}", global.master_replid));
-            info.push_str(&format!(
-                "\nmaster_repl_offset:{}",
-                global.master_repl_offset
+        if wants("stats") {
+            sections.push(info_section(
+                "Stats",
+                &[
+                    (
+                        "total_connections_received",
+                        global.stat_numconnections.to_string(),
+                    ),
+                    (
+                        "total_commands_processed",
+                        global.stat_numcommands.to_string(),
+                    ),
+                    ("expired_keys", global.stat_expiredkeys.to_string()),
+                    ("evicted_keys", global.stat_evictedkeys.to_string()),
+                    ("keyspace_hits", global.stat_keyspace_hits.to_string()),
+                    ("keyspace_misses", global.stat_keyspace_misses.to_string()),
+                ],
             ));
         }
 
-        write_bulk_string(stream, &info);
+        if wants("replication") {
+            let mut fields: Vec<(String, String)> = Vec::new();
+            if global.is_master() {
+                fields.push(("role".to_string(), "master".to_string()));
+                fields.push((
+                    "connected_slaves".to_string(),
+                    global.replica_states.len().to_string(),
+                ));
+                for (i, (port, replica)) in global.replica_states.iter().enumerate() {
+                    let ip = replica
+                        .stream
+                        .lock()
+                        .unwrap()
+                        .peer_ip()
+                        .map_or("?".to_string(), |ip| ip.to_string());
+                    fields.push((
+                        format!("slave{}", i),
+                        format!(
+                            "ip={},port={},state=online,offset={},lag={}",
+                            ip,
+                            port,
+                            replica.local_offset,
+                            replica.last_ack_time.elapsed().as_secs()
+                        ),
+                    ));
+                }
+                fields.push(("master_replid".to_string(), global.master_replid.clone()));
+                fields.push((
+                    "master_repl_offset".to_string(),
+                    global.offset_replica_sync.to_string(),
+                ));
+            } else {
+                fields.push(("role".to_string(), "slave".to_string()));
+                if let Some((host, port)) = &global.master_address {
+                    let link_status = if global.master_stream.is_some() {
+                        "up"
+                    } else {
+                        "down"
+                    };
+                    fields.push(("master_host".to_string(), host.clone()));
+                    fields.push(("master_port".to_string(), port.clone()));
+                    fields.push(("master_link_status".to_string(), link_status.to_string()));
+                }
+            }
+            sections.push(info_section("Replication", &fields));
+        }
+
+        if wants("keyspace") {
+            // Empty databases are left out, as Redis does
+            let db0 = format!("keys={},expires={},avg_ttl=0", keys, expires);
+            let fields = if keys > 0 {
+                vec![("db0", db0)]
+            } else {
+                Vec::new()
+            };
+            sections.push(info_section("Keyspace", &fields));
+        }
+
+        write_bulk_string(stream, &sections.join("\r\n"));
     }
 
     fn handle_keys(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         args: &[String],
         db: &DbType,
-        db_config: &DbConfigType,
-        connection: &mut Connection,
-    ) -> usize {
-        if connection.transaction.is_txing {
-            if args.len() >= 1 {
-                connection
-                    .transaction
-                    .tasks
-                    .push(format!("keys {}", args[0]));
-            } else {
-                connection.transaction.tasks.push("keys".to_string());
-            }
-            write_simple_string(stream, "QUEUED");
-            if args.len() == 1 {
-                1
-            } else {
-                0
-            }
-        } else if args.len() == 1 {
-            let mut db_config = db_config.lock().unwrap();
-            let mut db = db.lock().unwrap();
-
-            let expired_keys: Vec<String> = db_config
-                .iter()
-                .filter_map(|(key, config)| {
+        global_state: &RedisGlobalType,
+        _connection: &mut Connection,
+    ) {
+        if args.len() == 1 {
+            let mut matched_keys = Vec::new();
+            let mut expired_keys = Vec::new();
+            for index in 0..db.shard_count() {
+                let shard = db.lock_shard(index);
+                for (key, entry) in shard.iter() {
                     if !is_matched(&args[0], key) {
-                        return None;
+                        continue;
                     }
-
-                    if config.is_expired() {
-                        Some(key.clone())
+                    if entry.is_expired() {
+                        expired_keys.push(key.clone());
                     } else {
-                        None
+                        matched_keys.push(key.clone());
                     }
-                })
-                .collect();
-
+                }
+            }
+            // Removed once the walk is done, each under its own shard's lock
             for key in &expired_keys {
-                db_config.remove(key.as_str());
-                db.remove(key.as_str());
+                expire_if_needed(db, global_state, key);
             }
 
-            let valid_keys: Vec<Option<&str>> = db_config
-                .keys()
-                .filter(|key| is_matched(&args[0], key))
-                .map(|key| Some(key.as_str()))
-                .collect();
-
+            let valid_keys: Vec<Option<&str>> =
+                matched_keys.iter().map(|key| Some(key.as_str())).collect();
             write_array(stream, &valid_keys);
-            1
         } else {
             write_array::<&str>(stream, &[]);
-            0
         }
     }
 
-    fn handle_ping(&self, stream: &mut TcpStream, connection: &mut Connection) {
-        if connection.transaction.is_txing {
-            connection.transaction.tasks.push(String::from("Ping"));
-            write_simple_string(stream, "QUEUED");
-
-            return;
+    fn handle_ping(&self, stream: &mut ClientStream, args: &[String]) {
+        match args {
+            [] => write_simple_string(stream, "PONG"),
+            [message] => write_bulk_string(stream, message),
+            _ => write_error(stream, &error::wrong_arity("ping")),
         }
-        write_simple_string(stream, "PONG");
     }
 
     fn handle_echo(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         args: &[String],
-        connection: &mut Connection,
-    ) -> usize {
-        if let Some(msg) = args.get(0) {
-            if connection.transaction.is_txing {
-                connection.transaction.tasks.push(format!("echo {msg}"));
-                write_simple_string(stream, "QUEUED");
-                return 1;
-            }
-            write_simple_string(stream, msg);
-            1
-        } else {
-            if connection.transaction.is_txing {
-                connection.transaction.tasks.push(format!("echo"));
-                write_simple_string(stream, "QUEUED");
-                return 0;
-            }
-            write_simple_string(stream, "");
-            0
+        _connection: &mut Connection,
+    ) {
+        match args {
+            [msg] => write_bulk_string(stream, msg),
+            _ => write_error(stream, &error::wrong_arity("echo")),
         }
     }
 
     fn handle_config(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         args: &[String],
+        db: &DbType,
         global_state: &RedisGlobalType,
         connection: &mut Connection,
-    ) -> usize {
-        if args.len() >= 2 && args[0].to_ascii_lowercase() == "get" {
-            let mut consumed = 1;
-            let config_key = args[1].to_ascii_lowercase();
-
-            if connection.transaction.is_txing {
-                connection
-                    .transaction
-                    .tasks
-                    .push(format!("config get {}", args[1]));
-                write_simple_string(stream, "QUEUED");
-                consumed += 1;
-                return consumed;
-            }
-
-            match config_key.as_str() {
-                "dir" => {
-                    let global = global_state.lock().unwrap();
-                    write_array(stream, &[Some("dir"), Some(&global.dir_path)]);
-                    consumed += 1;
-                }
-                "dbfilename" => {
-                    let global = global_state.lock().unwrap();
-                    write_array(stream, &[Some("dbfilename"), Some(&global.dbfilename)]);
-                    consumed += 1;
+    ) {
+        if args.len() >= 2 && args[0].eq_ignore_ascii_case("get") {
+            let global = global_state.lock().unwrap();
+            let mut reply: Vec<String> = Vec::new();
+            for pattern in &args[1..] {
+                for (name, value) in global.matching_configs(&pattern.to_ascii_lowercase()) {
+                    // Overlapping patterns list each parameter once
+                    if !reply.chunks(2).any(|pair| pair[0] == name) {
+                        reply.push(name.to_string());
+                        reply.push(value);
+                    }
                 }
-                _ => {
-                    write_array::<&str>(stream, &[]);
+            }
+            write_map_header(stream, reply.len() / 2, connection.protocol);
+            for item in &reply {
+                write_bulk_string(stream, item);
+            }
+        } else if args.len() >= 3 && args.len() % 2 == 1 && args[0].eq_ignore_ascii_case("set") {
+            let mut start_aof = false;
+            {
+                let mut global = global_state.lock().unwrap();
+                for pair in args[1..].chunks(2) {
+                    let name = pair[0].to_ascii_lowercase();
+                    let was_appendonly = global.appendonly;
+                    if let Err(e) = global.set_config(&name, &pair[1]) {
+                        write_error(stream, &format!("ERR {}", e));
+                        return;
+                    }
+                    start_aof |= name == "appendonly" && global.appendonly && !was_appendonly;
                 }
             }
-            consumed
+            // The new AOF starts as a rewrite of the current dataset
+            if start_aof && !spawn_aof_rewrite(db, global_state) {
+                eprintln!("AOF rewrite already running; appendonly applies once it finishes");
+            }
+            write_simple_string(stream, "OK");
+        } else if args.len() == 1 && args[0].eq_ignore_ascii_case("resetstat") {
+            global_state.lock().unwrap().reset_stats();
+            write_simple_string(stream, "OK");
+        } else if args.len() == 1 && args[0].eq_ignore_ascii_case("rewrite") {
+            let global = global_state.lock().unwrap();
+            match &global.config_file {
+                None => write_error(stream, "ERR The server is running without a config file"),
+                Some(path) => match rewrite_config_file(path, &global) {
+                    Ok(()) => write_simple_string(stream, "OK"),
+                    Err(e) => write_error(stream, &format!("ERR Rewriting config file: {}", e)),
+                },
+            }
+        } else if args.len() >= 2 && args[0].eq_ignore_ascii_case("set") {
+            write_error(
+                stream,
+                &format!(
+                    "ERR Unknown option or number of arguments for CONFIG SET - '{}'",
+                    args[1]
+                ),
+            );
         } else {
-            write_error(stream, "invalid config argument");
-            0
+            write_error(stream, "ERR invalid config argument");
         }
     }
 
-    fn handle_get(
+    fn handle_auth(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         args: &[String],
-        db: &DbType,
-        db_config: &DbConfigType,
+        global_state: &RedisGlobalType,
         connection: &mut Connection,
-    ) -> usize {
-        if args.len() < 1 {
-            write_error(stream, "wrong number of arguments for 'GET'");
-            return 0;
-        }
-        if connection.transaction.is_txing {
-            connection
-                .transaction
-                .tasks
-                .push(format!("get {}", args[0]));
-            write_simple_string(stream, "QUEUED");
-            return 1;
-        }
+    ) {
+        // AUTH <password> for the default user, or AUTH <username> <password>
+        let (username, password) = match args {
+            [password] => ("default", password),
+            [username, password] => (username.as_str(), password),
+            _ => {
+                write_error(stream, &error::wrong_arity("auth"));
+                return;
+            }
+        };
 
-        let key = &args[0];
+        if args.len() == 1 && global_state.lock().unwrap().acl.default_user().nopass {
+            write_error(
+                stream,
+                "ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?",
+            );
+        } else if self.authenticate(stream, global_state, connection, username, password) {
+            write_simple_string(stream, "OK");
+        }
+    }
 
-        let mut config_map = db_config.lock().unwrap();
-        let expired = if let Some(config) = config_map.get(key) {
-            config.is_expired()
-        } else {
-            false
+    fn handle_acl(
+        &self,
+        stream: &mut ClientStream,
+        args: &[String],
+        global_state: &RedisGlobalType,
+        connection: &mut Connection,
+    ) {
+        let Some(subcommand) = args.first() else {
+            write_error(stream, &error::wrong_arity("acl"));
+            return;
         };
-        if expired {
-            config_map.remove(key);
-            let mut map = db.lock().unwrap();
-            map.remove(key);
-            write_null_bulk_string(stream);
-        } else {
-            drop(config_map);
-
-            let map = db.lock().unwrap();
-            if let Some(val) = map.get(key) {
-                write_bulk_string(stream, &val.to_string());
-            } else {
-                write_null_bulk_string(stream);
+        match (subcommand.to_ascii_lowercase().as_str(), &args[1..]) {
+            ("whoami", []) => {
+                let user = connection.info.lock().unwrap().user.clone();
+                write_bulk_string(stream, &user);
+            }
+            ("users", []) => {
+                let global = global_state.lock().unwrap();
+                let names: Vec<Option<&str>> = global
+                    .acl
+                    .users
+                    .keys()
+                    .map(|name| Some(name.as_str()))
+                    .collect();
+                write_array(stream, &names);
+            }
+            ("list", []) => {
+                let global = global_state.lock().unwrap();
+                let lines: Vec<Option<String>> = global
+                    .acl
+                    .users
+                    .iter()
+                    .map(|(name, user)| Some(user.describe(name)))
+                    .collect();
+                write_array(stream, &lines);
+            }
+            ("getuser", [name]) => {
+                let global = global_state.lock().unwrap();
+                let Some(user) = global.acl.users.get(name) else {
+                    write_null(stream, connection.protocol);
+                    return;
+                };
+                let mut flags = vec![Some(if user.enabled { "on" } else { "off" })];
+                if user.nopass {
+                    flags.push(Some("nopass"));
+                }
+                let passwords: Vec<Option<&str>> = user
+                    .passwords
+                    .iter()
+                    .map(|hash| Some(hash.as_str()))
+                    .collect();
+                write_map_header(stream, 4, connection.protocol);
+                write_bulk_string(stream, "flags");
+                write_array(stream, &flags);
+                write_bulk_string(stream, "passwords");
+                write_array(stream, &passwords);
+                write_bulk_string(stream, "commands");
+                write_bulk_string(stream, &user.describe_commands());
+                write_bulk_string(stream, "keys");
+                write_bulk_string(stream, &user.describe_keys());
+            }
+            ("setuser", [name, rules @ ..]) => {
+                match global_state.lock().unwrap().acl.set_user(name, rules) {
+                    Ok(()) => write_simple_string(stream, "OK"),
+                    Err(e) => write_error(stream, &format!("ERR {}", e)),
+                }
+            }
+            ("deluser", names) if !names.is_empty() => {
+                if names.iter().any(|name| name == "default") {
+                    write_error(stream, "ERR The 'default' user cannot be removed");
+                    return;
+                }
+                let removed: Vec<&String> = {
+                    let mut global = global_state.lock().unwrap();
+                    names
+                        .iter()
+                        .filter(|name| global.acl.users.remove(*name).is_some())
+                        .collect()
+                };
+                // Clients logged in as a removed user are disconnected
+                self.kill_clients(global_state, connection, |_, info| {
+                    removed.contains(&&info.user)
+                });
+                write_integer(stream, removed.len() as i64);
+            }
+            ("cat", []) => {
+                let categories: Vec<Option<String>> =
+                    all_categories().into_iter().map(Some).collect();
+                write_array(stream, &categories);
+            }
+            ("cat", [category]) => {
+                let category = category.to_ascii_lowercase();
+                if !all_categories().contains(&category) {
+                    write_error(stream, &format!("ERR Unknown category '{}'", category));
+                    return;
+                }
+                let tag = format!("@{}", category);
+                let commands: Vec<Option<&str>> = COMMAND_TABLE
+                    .iter()
+                    .filter(|spec| spec.categories().contains(&tag))
+                    .map(|spec| Some(spec.name))
+                    .collect();
+                write_array(stream, &commands);
+            }
+            ("whoami" | "users" | "list" | "getuser" | "setuser" | "deluser" | "cat", _) => {
+                write_error(stream, &error::wrong_arity(&format!("acl|{}", subcommand)))
             }
+            _ => write_error(stream, &error::unknown_subcommand("acl", subcommand)),
         }
-        1
     }
 
-    fn handle_xread(
+    /// Logs the connection in as `username`, replying WRONGPASS on failure.
+    fn authenticate(
         &self,
-        stream: &mut TcpStream,
-        args: &[String],
-        db: &DbType,
-        _connection: &mut Connection,
-    ) -> usize {
-        let (mut xread_config, consumed, err) = XreadConfig::from_args(&args);
-        if let Some(e) = err {
-            write_error(stream, &e);
-            return consumed;
+        stream: &mut ClientStream,
+        global_state: &RedisGlobalType,
+        connection: &mut Connection,
+        username: &str,
+        password: &str,
+    ) -> bool {
+        let valid = global_state
+            .lock()
+            .unwrap()
+            .acl
+            .authenticate(username, password);
+        if valid {
+            connection.authenticated = true;
+            connection.info.lock().unwrap().user = username.to_string();
+        } else {
+            write_error(
+                stream,
+                "WRONGPASS invalid username-password pair or user is disabled.",
+            );
         }
+        valid
+    }
 
-        if let Some(block) = xread_config.block {
-            let start_time = Instant::now();
-            let block_duration = Duration::from_millis(block as u64);
+    /// HELLO [protover [AUTH username password] [SETNAME clientname]]
+    fn handle_hello(
+        &self,
+        stream: &mut ClientStream,
+        args: &[String],
+        global_state: &RedisGlobalType,
+        connection: &mut Connection,
+    ) {
+        let protocol = match args.first().map(|version| version.parse::<i64>()) {
+            None => connection.protocol,
+            Some(Ok(version @ 2..=3)) => version as u8,
+            Some(Ok(_)) => {
+                write_error(stream, "NOPROTO unsupported protocol version");
+                return;
+            }
+            Some(Err(_)) => {
+                write_error(
+                    stream,
+                    "ERR Protocol version is not an integer or out of range",
+                );
+                return;
+            }
+        };
 
-            let latest_snapshot = {
-                let db_guard = db.lock().unwrap();
-                let map: HashMap<_, _> = xread_config
-                    .streams
-                    .iter()
-                    .filter_map(|(key, _)| {
-                        db_guard.get(key).and_then(|val| {
-                            if let ValueType::Stream(redis_stream) = val {
-                                Some((key.clone(), redis_stream.entries.len()))
-                            } else {
-                                Some((key.clone(), 0))
-                            }
-                        })
-                    })
-                    .collect();
+        let mut credentials = None;
+        let mut name = None;
+        let mut i = 1;
+        while i < args.len() {
+            match args[i].to_ascii_lowercase().as_str() {
+                "auth" if i + 2 < args.len() => {
+                    credentials = Some((&args[i + 1], &args[i + 2]));
+                    i += 3;
+                }
+                "setname" if i + 1 < args.len() => {
+                    name = Some(&args[i + 1]);
+                    i += 2;
+                }
+                _ => {
+                    let message = format!("ERR Syntax error in HELLO option '{}'", args[i]);
+                    write_error(stream, &message);
+                    return;
+                }
+            }
+        }
+        if name.is_some_and(|name| !is_valid_client_name(name)) {
+            write_error(
+                stream,
+                "ERR Client names cannot contain spaces, newlines or special characters.",
+            );
+            return;
+        }
 
-                map
-            };
+        if let Some((username, password)) = credentials {
+            if !self.authenticate(stream, global_state, connection, username, password) {
+                return;
+            }
+        } else if !connection.authenticated {
+            write_error(
+                stream,
+                "NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time",
+            );
+            return;
+        }
+        if let Some(name) = name {
+            connection.info.lock().unwrap().name =
+                Some(name.clone()).filter(|name| !name.is_empty());
+        }
+        connection.protocol = protocol;
 
-            loop {
-                let mut found_entries = false;
-
-                for (key, range) in &mut xread_config.streams {
-                    let db_guard = db.lock().unwrap();
-                    if let Some(ValueType::Stream(redis_stream)) = db_guard.get(key) {
-                        if range == "$" {
-                            if let Some(latest_num) = latest_snapshot.get(key) {
-                                if let Some(ValueType::Stream(redis_stream)) = db_guard.get(key) {
-                                    if redis_stream.entries.len() > *latest_num {
-                                        found_entries = true;
-                                        break;
-                                    }
-                                }
-                            }
-                        } else {
-                            let range_opt = parse_range(range, None);
+        let role = if global_state.lock().unwrap().is_master() {
+            "master"
+        } else {
+            "replica"
+        };
+        write_map_header(stream, 7, protocol);
+        write_bulk_string(stream, "server");
+        write_bulk_string(stream, "redis");
+        write_bulk_string(stream, "version");
+        write_bulk_string(stream, REDIS_VER);
+        write_bulk_string(stream, "proto");
+        write_integer(stream, protocol as i64);
+        write_bulk_string(stream, "id");
+        write_integer(stream, connection.id as i64);
+        write_bulk_string(stream, "mode");
+        write_bulk_string(stream, "standalone");
+        write_bulk_string(stream, "role");
+        write_bulk_string(stream, role);
+        write_bulk_string(stream, "modules");
+        let _ = stream.write_all(b"*0\r\n");
+    }
 
-                            if let Some(start_range) = range_opt {
-                                let entries = redis_stream.range_start(start_range, true);
-                                if !entries.is_empty() {
-                                    found_entries = true;
-                                    break;
-                                }
+    fn handle_client(
+        &self,
+        stream: &mut ClientStream,
+        args: &[String],
+        global_state: &RedisGlobalType,
+        connection: &mut Connection,
+    ) {
+        let Some(subcommand) = args.first() else {
+            write_error(stream, &error::wrong_arity("client"));
+            return;
+        };
+        match (subcommand.to_ascii_lowercase().as_str(), &args[1..]) {
+            ("id", []) => write_integer(stream, connection.id as i64),
+            ("getname", []) => match &connection.info.lock().unwrap().name {
+                Some(name) => write_bulk_string(stream, name),
+                None => write_null(stream, connection.protocol),
+            },
+            ("list", filter) => {
+                let kind = match filter {
+                    [] => None,
+                    [option, type_name] if option.eq_ignore_ascii_case("type") => {
+                        match ClientKind::from_type_name(type_name) {
+                            Some(kind) => Some(kind),
+                            None => {
+                                let message = format!("ERR Unknown client type '{}'", type_name);
+                                write_error(stream, &message);
+                                return;
                             }
                         }
                     }
+                    _ => {
+                        write_error(stream, error::SYNTAX);
+                        return;
+                    }
+                };
+                let mut list = String::new();
+                for (id, info) in client_handles(global_state) {
+                    let info = info.lock().unwrap();
+                    if kind.is_none_or(|kind| info.kind == kind) {
+                        list.push_str(&info.describe(id));
+                        list.push('\n');
+                    }
                 }
-
-                if found_entries {
-                    break;
+                write_bulk_string(stream, &list);
+            }
+            ("kill", [addr]) => {
+                // Legacy form: a single address, +OK or an error
+                if self.kill_clients(global_state, connection, |_, info| &info.addr == addr) > 0 {
+                    write_simple_string(stream, "OK");
+                } else {
+                    write_error(stream, "ERR No such client");
                 }
-
-                if block_duration != Duration::from_millis(0)
-                    && start_time.elapsed() >= block_duration
-                {
-                    let _ = stream.write_all(b"*-1\r\n");
-                    return consumed;
+            }
+            ("kill", filters) if !filters.is_empty() && filters.len() % 2 == 0 => {
+                let mut id = None;
+                let mut addr = None;
+                let mut laddr = None;
+                let mut kind = None;
+                let mut skip_me = true;
+                for pair in filters.chunks(2) {
+                    let value = &pair[1];
+                    match pair[0].to_ascii_lowercase().as_str() {
+                        "id" => match value.parse::<u64>() {
+                            Ok(parsed) if parsed > 0 => id = Some(parsed),
+                            _ => {
+                                write_error(stream, "ERR client-id should be greater than 0");
+                                return;
+                            }
+                        },
+                        "addr" => addr = Some(value),
+                        "laddr" => laddr = Some(value),
+                        "type" => match ClientKind::from_type_name(value) {
+                            Some(parsed) => kind = Some(parsed),
+                            None => {
+                                let message = format!("ERR Unknown client type '{}'", value);
+                                write_error(stream, &message);
+                                return;
+                            }
+                        },
+                        "skipme" => match parse_yes_no(value) {
+                            Some(parsed) => skip_me = parsed,
+                            None => {
+                                write_error(stream, error::SYNTAX);
+                                return;
+                            }
+                        },
+                        _ => {
+                            write_error(stream, error::SYNTAX);
+                            return;
+                        }
+                    }
+                }
+                let own_id = connection.id;
+                let killed = self.kill_clients(global_state, connection, |client_id, info| {
+                    id.is_none_or(|id| id == client_id)
+                        && addr.is_none_or(|addr| &info.addr == addr)
+                        && laddr.is_none_or(|laddr| &info.laddr == laddr)
+                        && kind.is_none_or(|kind| info.kind == kind)
+                        && !(skip_me && client_id == own_id)
+                });
+                write_integer(stream, killed as i64);
+            }
+            ("setname", [name]) => {
+                if !is_valid_client_name(name) {
+                    write_error(
+                        stream,
+                        "ERR Client names cannot contain spaces, newlines or special characters.",
+                    );
+                } else {
+                    connection.info.lock().unwrap().name =
+                        Some(name.clone()).filter(|name| !name.is_empty());
+                    write_simple_string(stream, "OK");
                 }
-
-                sleep(Duration::from_millis(10));
             }
+            ("id" | "getname" | "setname" | "kill", _) => write_error(
+                stream,
+                &error::wrong_arity(&format!("client|{}", subcommand)),
+            ),
+            _ => write_error(stream, &error::unknown_subcommand("client", subcommand)),
         }
+    }
 
-        if let Some(_count) = xread_config.count {}
-
-        if xread_config.streams.is_empty() {
-            write_error(stream, "no streams specified for XREAD");
-            return consumed;
+    /// Kills every registered client matching `filter` and returns how many.
+    /// Other clients are shut down right away; this connection is only
+    /// flagged, so its reply goes out before the connection loop closes it.
+    fn kill_clients(
+        &self,
+        global_state: &RedisGlobalType,
+        connection: &Connection,
+        filter: impl Fn(u64, &ClientInfo) -> bool,
+    ) -> usize {
+        let mut killed = 0;
+        for (id, info) in client_handles(global_state) {
+            let mut info = info.lock().unwrap();
+            if info.killed || !filter(id, &info) {
+                continue;
+            }
+            if id == connection.id {
+                info.killed = true;
+            } else {
+                info.kill();
+            }
+            killed += 1;
         }
+        killed
+    }
 
-        let _ = stream.write_all(format!("*{}\r\n", xread_config.streams.len()).as_bytes());
-
-        for (key, range) in xread_config.streams {
-            let db_guard = db.lock().unwrap();
-            if let Some(ValueType::Stream(redis_stream)) = db_guard.get(&key) {
-                let range_opt = parse_range(&range, redis_stream.last_entry_id());
-
-                if range_opt.is_none() {
-                    write_error(stream, "not valid id");
+    /// COMMAND [COUNT | INFO name... | DOCS name...], answered from the
+    /// static command table.
+    fn handle_command(&self, stream: &mut ClientStream, args: &[String]) {
+        let Some(subcommand) = args.first() else {
+            let mut resp = format!("*{}\r\n", COMMAND_TABLE.len());
+            for spec in COMMAND_TABLE {
+                resp.push_str(&spec.encode_info());
+            }
+            let _ = stream.write_all(resp.as_bytes());
+            return;
+        };
+        let names = &args[1..];
+        match subcommand.to_ascii_lowercase().as_str() {
+            "count" if names.is_empty() => write_integer(stream, COMMAND_TABLE.len() as i64),
+            "info" => {
+                let specs: Vec<Option<&CommandSpec>> = if names.is_empty() {
+                    COMMAND_TABLE.iter().map(Some).collect()
+                } else {
+                    names
+                        .iter()
+                        .map(|name| CommandSpec::lookup(&name.to_ascii_lowercase()))
+                        .collect()
+                };
+                let mut resp = format!("*{}\r\n", specs.len());
+                for spec in specs {
+                    match spec {
+                        Some(spec) => resp.push_str(&spec.encode_info()),
+                        None => resp.push_str("*-1\r\n"),
+                    }
+                }
+                let _ = stream.write_all(resp.as_bytes());
+            }
+            "docs" => {
+                // Unknown names are left out of the reply
+                let specs: Vec<&CommandSpec> = if names.is_empty() {
+                    COMMAND_TABLE.iter().collect()
+                } else {
+                    names
+                        .iter()
+                        .filter_map(|name| CommandSpec::lookup(&name.to_ascii_lowercase()))
+                        .collect()
+                };
+                let mut resp = format!("*{}\r\n", specs.len() * 2);
+                for spec in specs {
+                    resp.push_str(&spec.encode_docs());
+                }
+                let _ = stream.write_all(resp.as_bytes());
+            }
+            "count" => write_error(stream, &error::wrong_arity("command|count")),
+            _ => write_error(stream, &error::unknown_subcommand("command", subcommand)),
+        }
+    }
+
+    fn handle_save(&self, stream: &mut ClientStream, db: &DbType, global_state: &RedisGlobalType) {
+        match save_now(db, global_state) {
+            Ok(()) => {
+                eprintln!("DB saved on disk");
+                write_simple_string(stream, "OK");
+            }
+            Err(e) => write_error(stream, &format!("ERR {}", e)),
+        }
+    }
+
+    fn handle_bgsave(
+        &self,
+        stream: &mut ClientStream,
+        db: &DbType,
+        global_state: &RedisGlobalType,
+    ) {
+        if spawn_bgsave(db, global_state) {
+            write_simple_string(stream, "Background saving started");
+        } else {
+            write_error(stream, "ERR Background save already in progress");
+        }
+    }
+
+    /// DEBUG RELOAD | SLEEP | OBJECT | SET-ACTIVE-EXPIRE, plus a few tuning
+    /// subcommands that are accepted and ignored.
+    fn handle_debug(
+        &self,
+        stream: &mut ClientStream,
+        args: &[String],
+        db: &DbType,
+        global_state: &RedisGlobalType,
+        is_propagation: bool,
+    ) {
+        let Some(subcommand) = args.first() else {
+            write_error(stream, &error::wrong_arity("debug"));
+            return;
+        };
+        // Only ever run on behalf of a client, never from the replication
+        // stream or the AOF
+        if is_propagation {
+            return;
+        }
+        match (subcommand.to_ascii_lowercase().as_str(), &args[1..]) {
+            ("reload", _) => self.debug_reload(stream, db, global_state),
+            ("sleep", [seconds]) => match seconds.parse::<f64>() {
+                Ok(seconds) if seconds.is_finite() && seconds >= 0.0 => {
+                    sleep(Duration::from_secs_f64(seconds));
+                    write_simple_string(stream, "OK");
+                }
+                _ => write_error(stream, error::NOT_A_FLOAT),
+            },
+            ("object", [key]) => {
+                if expire_if_needed(db, global_state, key) {
+                    write_error(stream, error::NO_SUCH_KEY);
+                    return;
+                }
+                let map = db.lock(key);
+                let Some(value) = map.get(key) else {
+                    write_error(stream, error::NO_SUCH_KEY);
+                    return;
+                };
+                write_simple_string(
+                    stream,
+                    &format!(
+                        "Value at:{:p} refcount:1 encoding:{} serializedlength:{} elements:{}",
+                        value,
+                        value.encoding(),
+                        serialized_length(value),
+                        value.element_count()
+                    ),
+                );
+            }
+            // Fails the command the way a bug would, taking only this client down
+            ("panic", []) => panic!("DEBUG PANIC called by a client"),
+            ("set-active-expire", [flag]) => match flag.as_str() {
+                "0" | "1" => {
+                    global_state.lock().unwrap().active_expire = flag == "1";
+                    write_simple_string(stream, "OK");
+                }
+                _ => write_error(stream, error::NOT_AN_INTEGER),
+            },
+            // Tuning knobs with nothing behind them here
+            ("quicklist-packed-threshold" | "jmap" | "replybuffer" | "dict-resizing", _) => {
+                write_simple_string(stream, "OK");
+            }
+            _ => write_error(stream, &error::unknown_subcommand("debug", subcommand)),
+        }
+    }
+
+    /// DEBUG RELOAD: saves, empties the dataset and loads the dump back.
+    fn debug_reload(&self, stream: &mut ClientStream, db: &DbType, global_state: &RedisGlobalType) {
+        // Reloading under the replication stream would drop what it sends next
+        if !global_state.lock().unwrap().is_master() {
+            write_error(stream, "ERR DEBUG RELOAD is not allowed on replicas");
+            return;
+        }
+
+        if let Err(e) = save_now(db, global_state) {
+            write_error(stream, &format!("ERR {}", e));
+            return;
+        }
+        db.lock_all().clear();
+        match start_up(Arc::clone(db), Arc::clone(global_state)) {
+            Ok(()) => {
+                eprintln!("DB reloaded by DEBUG RELOAD");
+                write_simple_string(stream, "OK");
+            }
+            Err(e) => write_error(
+                stream,
+                &format!("ERR Error trying to load the RDB dump: {}", e),
+            ),
+        }
+    }
+
+    /// LOLWUT [VERSION version]. Every version gets the same art: rows of
+    /// squares that come apart further down, after Georg Nees' Schotter.
+    fn handle_lolwut(&self, stream: &mut ClientStream, args: &[String]) {
+        match args {
+            [] => {}
+            [option, version] if option.eq_ignore_ascii_case("version") => {
+                if version.parse::<i64>().is_err() {
+                    write_error(stream, error::NOT_AN_INTEGER);
+                    return;
+                }
+            }
+            _ => {
+                write_error(stream, error::SYNTAX);
+                return;
+            }
+        }
+        use rand::Rng;
+        let mut rng = rand::rng();
+        let mut art = String::new();
+        for row in 0..12 {
+            let disorder = row as f64 / 12.0;
+            for _ in 0..16 {
+                let square = if rng.random_bool(disorder * 0.6) {
+                    ['/', '\\', '.', ' '][rng.random_range(0..4)]
+                } else {
+                    '#'
+                };
+                art.push(square);
+                art.push(square);
+            }
+            art.push('\n');
+        }
+        art.push_str(&format!(
+            "\nGeorg Nees - schotter, plotter on paper, 1968. Redis ver. {}\n",
+            REDIS_VER
+        ));
+        write_bulk_string(stream, &art);
+    }
+
+    /// SLOWLOG GET [count] | LEN | RESET. GET returns the newest entries
+    /// first, 10 by default and all of them for -1.
+    fn handle_slowlog(
+        &self,
+        stream: &mut ClientStream,
+        args: &[String],
+        global_state: &RedisGlobalType,
+    ) {
+        let Some(subcommand) = args.first() else {
+            write_error(stream, &error::wrong_arity("slowlog"));
+            return;
+        };
+        let mut global = global_state.lock().unwrap();
+        match (subcommand.to_ascii_lowercase().as_str(), &args[1..]) {
+            ("get", rest) if rest.len() <= 1 => {
+                let count = match rest.first().map(|count| count.parse::<i64>()) {
+                    None => 10,
+                    Some(Ok(-1)) => global.slowlog.entries.len(),
+                    Some(Ok(count)) if count >= 0 => count as usize,
+                    _ => {
+                        write_error(stream, "ERR count should be greater than or equal to -1");
+                        return;
+                    }
+                };
+                let entries: Vec<_> = global.slowlog.entries.iter().take(count).collect();
+                let mut reply = format!("*{}\r\n", entries.len()).into_bytes();
+                for entry in entries {
+                    reply.extend(entry.encode());
+                }
+                let _ = stream.write_all(&reply);
+            }
+            ("len", []) => write_integer(stream, global.slowlog.entries.len() as i64),
+            ("reset", []) => {
+                global.slowlog.reset();
+                write_simple_string(stream, "OK");
+            }
+            (name @ ("get" | "len" | "reset"), _) => {
+                write_error(stream, &error::wrong_arity(&format!("slowlog|{}", name)))
+            }
+            _ => write_error(stream, &error::unknown_subcommand("slowlog", subcommand)),
+        }
+    }
+
+    fn handle_time(&self, stream: &mut ClientStream) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let seconds = now.as_secs().to_string();
+        let micros = now.subsec_micros().to_string();
+        write_array(stream, &[Some(seconds), Some(micros)]);
+    }
+
+    /// SHUTDOWN [NOSAVE | SAVE]. On success nothing is replied; the
+    /// connection closes when the process exits.
+    fn handle_shutdown(
+        &self,
+        stream: &mut ClientStream,
+        args: &[String],
+        db: &DbType,
+        global_state: &RedisGlobalType,
+    ) {
+        let save = match args {
+            [] => !global_state.lock().unwrap().save_params.is_empty(),
+            [option] if option.eq_ignore_ascii_case("save") => true,
+            [option] if option.eq_ignore_ascii_case("nosave") => false,
+            _ => {
+                write_error(stream, error::SYNTAX);
+                return;
+            }
+        };
+        if save {
+            if let Err(e) = save_now(db, global_state) {
+                eprintln!("Error trying to save the DB, can't exit: {}", e);
+                write_error(stream, "ERR Errors trying to SHUTDOWN. Check logs.");
+                return;
+            }
+            eprintln!("DB saved on disk");
+        }
+
+        let global = global_state.lock().unwrap();
+        for replica in global.replica_states.values() {
+            let _ = replica.stream.lock().unwrap().shutdown(Shutdown::Both);
+        }
+        global.shutdown.send_replace(true);
+    }
+
+    fn handle_bgrewriteaof(
+        &self,
+        stream: &mut ClientStream,
+        db: &DbType,
+        global_state: &RedisGlobalType,
+    ) {
+        if spawn_aof_rewrite(db, global_state) {
+            write_simple_string(stream, "Background append only file rewriting started");
+        } else {
+            write_error(
+                stream,
+                "ERR Background append only file rewriting already in progress",
+            );
+        }
+    }
+
+    fn handle_get(
+        &self,
+        stream: &mut ClientStream,
+        args: &[String],
+        db: &DbType,
+        global_state: &RedisGlobalType,
+        _connection: &mut Connection,
+    ) {
+        if args.is_empty() {
+            write_error(stream, &error::wrong_arity("get"));
+            return;
+        }
+        let key = &args[0];
+
+        let found = read_live(db, global_state, key, |value| match value {
+            Some(ValueType::String(val)) => {
+                write_bulk_string(stream, val);
+                Some(true)
+            }
+            Some(ValueType::Int(n)) => {
+                write_bulk_string(stream, &n.to_string());
+                Some(true)
+            }
+            Some(_) => {
+                write_error(stream, error::WRONGTYPE);
+                None
+            }
+            None => {
+                write_null_bulk_string(stream);
+                Some(false)
+            }
+        });
+        let Some(found) = found else {
+            return;
+        };
+        let mut global = global_state.lock().unwrap();
+        if found {
+            global.stat_keyspace_hits += 1;
+        } else {
+            global.stat_keyspace_misses += 1;
+        }
+    }
+
+    fn handle_xread(
+        &self,
+        stream: &mut ClientStream,
+        args: &[String],
+        db: &DbType,
+        connection: &mut Connection,
+    ) {
+        let (xread_config, err) = XreadConfig::from_args(args);
+        if let Some(e) = err {
+            write_error(stream, &e);
+            return;
+        }
+        if xread_config.noack {
+            write_error(stream, error::SYNTAX);
+            return;
+        }
+
+        if xread_config.streams.is_empty() {
+            write_error(stream, "ERR no streams specified for XREAD");
+            return;
+        }
+
+        // Resolve every ID (including `$`) once, so blocking and the final read
+        // agree on what "new" means
+        let mut starts: Vec<(String, (u64, u64))> = Vec::new();
+        {
+            let db_guard = db.lock_keys(xread_config.streams.iter().map(|(key, _)| key.as_str()));
+            for (key, range) in &xread_config.streams {
+                let last_entry_id = match db_guard.get(key) {
+                    Some(ValueType::Stream(redis_stream)) => Some(redis_stream.last_entry_id()),
+                    Some(_) => {
+                        write_error(stream, error::WRONGTYPE);
+                        return;
+                    }
+                    None => Some((0, 0)),
+                };
+                match parse_range(range, last_entry_id, false) {
+                    Some((start, false)) => starts.push((key.clone(), start)),
+                    _ => {
+                        write_error(stream, error::INVALID_STREAM_ID);
+                        return;
+                    }
+                }
+            }
+        }
+
+        let start_time = Instant::now();
+        let block_duration = xread_config
+            .block
+            .filter(|_| !self.in_exec)
+            .map(|block| Duration::from_millis(block as u64));
+        let mut blocked = None;
+
+        loop {
+            let seen = db.ready_version();
+            let mut resp = Vec::new();
+            let mut found = 0;
+            {
+                let db_guard = db.lock_keys(starts.iter().map(|(key, _)| key.as_str()));
+                for (key, start) in &starts {
+                    let redis_stream = match db_guard.get(key) {
+                        Some(ValueType::Stream(redis_stream)) => redis_stream,
+                        _ => continue,
+                    };
+                    let mut entries = redis_stream.range_start(*start, true).peekable();
+                    if entries.peek().is_none() {
+                        continue;
+                    }
+                    let entries = entries.take(xread_config.count.unwrap_or(usize::MAX));
+
+                    found += 1;
+                    let header = format!("*2\r\n${}\r\n{}\r\n", key.len(), key);
+                    resp.extend_from_slice(header.as_bytes());
+                    resp.extend_from_slice(&serialize_entries(entries));
+                }
+            }
+
+            if found > 0 {
+                let _ = stream.write_all(format!("*{}\r\n", found).as_bytes());
+                let _ = stream.write_all(&resp);
+                return;
+            }
+            // A killed or disconnected client is dropped without a reply
+            if connection.is_killed() || stream.peer_closed() {
+                return;
+            }
+            let wait = match block_duration {
+                Some(block) if block.is_zero() => BLOCKED_RECHECK,
+                Some(block) if start_time.elapsed() < block => {
+                    (block - start_time.elapsed()).min(BLOCKED_RECHECK)
+                }
+                _ => {
+                    write_null_array(stream);
+                    return;
+                }
+            };
+            blocked.get_or_insert_with(|| connection.block(stream));
+            db.wait_ready(seen, wait);
+        }
+    }
+
+    fn handle_xrange(
+        &self,
+        stream: &mut ClientStream,
+        args: &[String],
+        db: &DbType,
+        _connection: &mut Connection,
+    ) {
+        if args.len() < 3 {
+            write_error(stream, &error::wrong_arity("xrange"));
+            return;
+        };
+        let stream_key = &args[0];
+
+        let mut count: Option<usize> = None;
+        if args.len() >= 4 {
+            if !args[3].eq_ignore_ascii_case("count") || args.len() < 5 {
+                write_error(stream, error::SYNTAX);
+                return;
+            }
+            count = match args[4].parse::<i64>() {
+                Ok(n) => Some(n.max(0) as usize),
+                Err(_) => {
+                    write_error(stream, error::NOT_AN_INTEGER);
+                    return;
+                }
+            };
+        }
+
+        let mut _stream_obj: Option<&Stream> = None;
+
+        let map = db.lock(stream_key);
+        if let Some(val) = map.get(stream_key) {
+            if let ValueType::Stream(ref stream) = val {
+                _stream_obj = Some(stream);
+            } else {
+                write_error(stream, error::WRONGTYPE);
+                return;
+            }
+        } else {
+            write_array::<&str>(stream, &[]);
+            return;
+        };
+
+        if let Some(redis_stream) = _stream_obj {
+            let (start, end) = (
+                parse_range(&args[1], None, false),
+                parse_range(&args[2], Some(redis_stream.last_entry_id()), true),
+            );
+            if start.is_none() || end.is_none() {
+                write_error(stream, error::INVALID_STREAM_ID);
+                return;
+            }
+
+            let (start, end) = (start.unwrap(), end.unwrap());
+
+            let range = redis_stream.range(start, end, count);
+            let _ = stream.write_all(&serialize_entries(range));
+        }
+    }
+
+    fn handle_xreadgroup(
+        &self,
+        stream: &mut ClientStream,
+        args: &[String],
+        db: &DbType,
+        global_state: &RedisGlobalType,
+        is_propagation: &bool,
+        connection: &mut Connection,
+    ) {
+        let is_slave_and_propagation = {
+            let global = global_state.lock().unwrap();
+            !global.is_master() && *is_propagation
+        };
+
+        if args.len() < 6 {
+            if !is_slave_and_propagation {
+                write_error(stream, &error::wrong_arity("xreadgroup"));
+            }
+            return;
+        }
+        if !args[0].eq_ignore_ascii_case("group") {
+            if !is_slave_and_propagation {
+                write_error(stream, error::SYNTAX);
+            }
+            return;
+        }
+
+        let group_name = &args[1];
+        let consumer = &args[2];
+        let (xread_config, err) = XreadConfig::from_args(&args[3..]);
+        if let Some(e) = err {
+            if !is_slave_and_propagation {
+                write_error(stream, &e);
+            }
+            return;
+        }
+
+        // `>` asks for never-delivered entries, anything else re-reads the PEL
+        let mut specs: Vec<(String, Option<(u64, u64)>)> = Vec::new();
+        for (key, id) in &xread_config.streams {
+            if id == ">" {
+                specs.push((key.clone(), None));
+                continue;
+            }
+            match parse_range(id, None, false) {
+                Some((start, false)) => specs.push((key.clone(), Some(start))),
+                _ => {
+                    if !is_slave_and_propagation {
+                        write_error(stream, error::INVALID_STREAM_ID);
+                    }
+                    return;
+                }
+            }
+        }
+
+        {
+            let map = db.lock_keys(specs.iter().map(|(key, _)| key.as_str()));
+            for (key, _) in &specs {
+                let has_group = matches!(
+                    map.get(key),
+                    Some(ValueType::Stream(redis_stream)) if redis_stream.groups.contains_key(group_name)
+                );
+                if !has_group {
+                    if !is_slave_and_propagation {
+                        write_error(
+                            stream,
+                            &format!(
+                                "NOGROUP No such key '{}' or consumer group '{}' in XREADGROUP with GROUP option",
+                                key, group_name
+                            ),
+                        );
+                    }
+                    return;
+                }
+            }
+        }
+
+        let only_new = specs.iter().all(|(_, start)| start.is_none());
+        let block = xread_config.block.filter(|_| !self.in_exec);
+        if let (Some(block), true) = (block, only_new) {
+            let start_time = Instant::now();
+            let block_duration = Duration::from_millis(block as u64);
+            let mut blocked = None;
+
+            loop {
+                let seen = db.ready_version();
+                let has_new = {
+                    let map = db.lock_keys(specs.iter().map(|(key, _)| key.as_str()));
+                    specs.iter().any(|(key, _)| match map.get(key) {
+                        Some(ValueType::Stream(redis_stream)) => {
+                            match redis_stream.groups.get(group_name) {
+                                Some(group) => {
+                                    redis_stream.last_entry_id() > group.last_delivered_id
+                                }
+                                None => false,
+                            }
+                        }
+                        _ => false,
+                    })
+                };
+                if has_new {
+                    break;
+                }
+                if connection.is_killed() || stream.peer_closed() {
+                    return;
+                }
+                blocked.get_or_insert_with(|| connection.block(stream));
+
+                let remaining = if block_duration == Duration::from_millis(0) {
+                    BLOCKED_RECHECK
+                } else {
+                    match block_duration.checked_sub(start_time.elapsed()) {
+                        Some(remaining) if !remaining.is_zero() => remaining,
+                        _ => {
+                            write_null_array(stream);
+                            return;
+                        }
+                    }
+                };
+                db.wait_ready(seen, remaining.min(BLOCKED_RECHECK));
+            }
+        }
+
+        let mut results: Vec<(String, Vec<GroupEntry>)> = Vec::new();
+        let mut propagations: Vec<Vec<u8>> = Vec::new();
+        let mut dirty_keys: Vec<String> = Vec::new();
+        {
+            let mut map = db.lock_keys(specs.iter().map(|(key, _)| key.as_str()));
+            for (key, start) in &specs {
+                let redis_stream = match map.get_mut(key) {
+                    Some(ValueType::Stream(redis_stream)) => redis_stream,
+                    _ => continue,
+                };
+                let is_new_consumer = redis_stream
+                    .groups
+                    .get(group_name)
+                    .is_some_and(|group| !group.consumers.contains_key(consumer));
+                let delivered = redis_stream
+                    .read_group(
+                        group_name,
+                        consumer,
+                        *start,
+                        xread_config.count,
+                        xread_config.noack,
+                    )
+                    .unwrap_or_default();
+
+                // Replicas replay the delivery with the same COUNT so their PELs match
+                if start.is_none() && !delivered.is_empty() {
+                    let count = delivered.len().to_string();
+                    let mut propagation =
+                        vec!["XREADGROUP", "GROUP", group_name, consumer, "COUNT", &count];
+                    if xread_config.noack {
+                        propagation.push("NOACK");
+                    }
+                    propagation.extend(["STREAMS", key, ">"]);
+                    propagations.push(encode_resp_command(&propagation));
+                    dirty_keys.push(key.clone());
+                } else if is_new_consumer {
+                    propagations.push(encode_resp_command(&[
+                        "XGROUP",
+                        "CREATECONSUMER",
+                        key,
+                        group_name,
+                        consumer,
+                    ]));
+                }
+
+                if start.is_some() || !delivered.is_empty() {
+                    results.push((key.clone(), delivered));
+                }
+            }
+        }
+        for key in &dirty_keys {
+            mark_dirty(global_state, key);
+        }
+
+        if !is_slave_and_propagation {
+            if results.is_empty() {
+                write_null_array(stream);
+            } else {
+                let mut resp = format!("*{}\r\n", results.len());
+                for (key, entries) in &results {
+                    resp.push_str(&format!("*2\r\n${}\r\n{}\r\n", key.len(), key));
+                    resp.push_str(&format!("*{}\r\n", entries.len()));
+                    for (id, fields) in entries {
+                        resp.push_str(&encode_stream_entry(*id, fields.as_deref()));
+                    }
+                }
+                let _ = stream.write_all(resp.as_bytes());
+            }
+
+            for propagation in propagations {
+                propagate_slaves(global_state, &propagation);
+            }
+        }
+    }
+
+    fn handle_xack(
+        &self,
+        stream: &mut ClientStream,
+        args: &[String],
+        db: &DbType,
+        global_state: &RedisGlobalType,
+        is_propagation: &bool,
+        _connection: &mut Connection,
+    ) {
+        let is_slave_and_propagation = {
+            let global = global_state.lock().unwrap();
+            !global.is_master() && *is_propagation
+        };
+
+        if args.len() < 3 {
+            if !is_slave_and_propagation {
+                write_error(stream, &error::wrong_arity("xack"));
+            }
+            return;
+        }
+
+        let stream_key = &args[0];
+        let group_name = &args[1];
+
+        let mut ids = Vec::with_capacity(args.len() - 2);
+        for id in &args[2..] {
+            match parse_range(id, None, false) {
+                Some((id, false)) => ids.push(id),
+                _ => {
+                    if !is_slave_and_propagation {
+                        write_error(stream, error::INVALID_STREAM_ID);
+                    }
+                    return;
+                }
+            }
+        }
+
+        let acked = {
+            let mut map = db.lock(stream_key);
+            match map.get_mut(stream_key) {
+                Some(ValueType::Stream(redis_stream)) => {
+                    match redis_stream.groups.get_mut(group_name) {
+                        Some(group) => ids
+                            .iter()
+                            .filter(|id| group.pending.remove(id).is_some())
+                            .count(),
+                        None => 0,
+                    }
+                }
+                Some(_) => {
+                    if !is_slave_and_propagation {
+                        write_error(stream, error::WRONGTYPE);
+                    }
+                    return;
+                }
+                None => 0,
+            }
+        };
+
+        if acked > 0 {
+            mark_dirty(global_state, stream_key);
+        }
+
+        if !is_slave_and_propagation {
+            write_integer(stream, acked as i64);
+            if acked > 0 {
+                let mut propagation = vec!["XACK"];
+                propagation.extend(args.iter().map(|arg| arg.as_str()));
+                propagate_slaves(global_state, &encode_resp_command(&propagation));
+            }
+        }
+    }
+
+    fn handle_xpending(
+        &self,
+        stream: &mut ClientStream,
+        args: &[String],
+        db: &DbType,
+        _connection: &mut Connection,
+    ) {
+        if args.len() < 2 {
+            write_error(stream, &error::wrong_arity("xpending"));
+            return;
+        }
+        let stream_key = &args[0];
+        let group_name = &args[1];
+
+        // Extended form: [IDLE min-idle-time] start end count [consumer]
+        let mut min_idle: Option<u64> = None;
+        let mut idx = 2;
+        if args.len() > 2 && args[2].eq_ignore_ascii_case("idle") {
+            match args.get(3).and_then(|n| n.parse::<u64>().ok()) {
+                Some(n) => min_idle = Some(n),
+                None => {
+                    write_error(stream, error::NOT_AN_INTEGER);
+                    return;
+                }
+            }
+            idx = 4;
+        }
+        let extended = args.len() > idx;
+        if (extended && args.len() < idx + 3) || args.len() > idx + 4 || (!extended && idx > 2) {
+            write_error(stream, error::SYNTAX);
+            return;
+        }
+
+        let map = db.lock(stream_key);
+        let group = match map.get(stream_key) {
+            Some(ValueType::Stream(redis_stream)) => redis_stream.groups.get(group_name),
+            Some(_) => {
+                write_error(stream, error::WRONGTYPE);
+                return;
+            }
+            None => None,
+        };
+        let group = match group {
+            Some(group) => group,
+            None => {
+                write_error(
+                    stream,
+                    &format!(
+                        "NOGROUP No such key '{}' or consumer group '{}'",
+                        stream_key, group_name
+                    ),
+                );
+                return;
+            }
+        };
+
+        if !extended {
+            if group.pending.is_empty() {
+                let _ = stream.write_all(b"*4\r\n:0\r\n$-1\r\n$-1\r\n*-1\r\n");
+                return;
+            }
+
+            let mut per_consumer: BTreeMap<&str, usize> = BTreeMap::new();
+            for pending in group.pending.values() {
+                *per_consumer.entry(pending.consumer.as_str()).or_insert(0) += 1;
+            }
+            let (min_id, _) = group.pending.first_key_value().unwrap();
+            let (max_id, _) = group.pending.last_key_value().unwrap();
+            let min_id = format!("{}-{}", min_id.0, min_id.1);
+            let max_id = format!("{}-{}", max_id.0, max_id.1);
+
+            let mut resp = format!("*4\r\n:{}\r\n", group.pending.len());
+            resp.push_str(&format!("${}\r\n{}\r\n", min_id.len(), min_id));
+            resp.push_str(&format!("${}\r\n{}\r\n", max_id.len(), max_id));
+            resp.push_str(&format!("*{}\r\n", per_consumer.len()));
+            for (consumer, count) in per_consumer {
+                let count = count.to_string();
+                resp.push_str(&format!(
+                    "*2\r\n${}\r\n{}\r\n${}\r\n{}\r\n",
+                    consumer.len(),
+                    consumer,
+                    count.len(),
+                    count
+                ));
+            }
+            let _ = stream.write_all(resp.as_bytes());
+            return;
+        }
+
+        let (start, end) = match (
+            parse_range(&args[idx], None, false),
+            parse_range(&args[idx + 1], None, true),
+        ) {
+            (Some(start), Some(end)) => (start, end),
+            _ => {
+                write_error(stream, error::INVALID_STREAM_ID);
+                return;
+            }
+        };
+        let count = match args[idx + 2].parse::<i64>() {
+            Ok(n) => n.max(0) as usize,
+            Err(_) => {
+                write_error(stream, error::NOT_AN_INTEGER);
+                return;
+            }
+        };
+        let consumer = args.get(idx + 3);
+
+        let now = now_ms();
+        let matching: Vec<_> = group
+            .pending
+            .iter()
+            .filter(|(id, _)| {
+                (**id > start.0 || (!start.1 && **id == start.0))
+                    && (**id < end.0 || (!end.1 && **id == end.0))
+            })
+            .filter(|(_, pending)| consumer.is_none_or(|c| pending.consumer == *c))
+            .map(|(id, pending)| (id, pending, now.saturating_sub(pending.delivery_time)))
+            .filter(|(_, _, idle)| min_idle.is_none_or(|min| *idle >= min))
+            .take(count)
+            .collect();
+
+        let mut resp = format!("*{}\r\n", matching.len());
+        for (id, pending, idle) in matching {
+            let id = format!("{}-{}", id.0, id.1);
+            resp.push_str(&format!(
+                "*4\r\n${}\r\n{}\r\n${}\r\n{}\r\n:{}\r\n:{}\r\n",
+                id.len(),
+                id,
+                pending.consumer.len(),
+                pending.consumer,
+                idle,
+                pending.delivery_count
+            ));
+        }
+        let _ = stream.write_all(resp.as_bytes());
+    }
+
+    fn handle_xclaim(
+        &self,
+        stream: &mut ClientStream,
+        args: &[String],
+        db: &DbType,
+        global_state: &RedisGlobalType,
+        is_propagation: &bool,
+        _connection: &mut Connection,
+    ) {
+        let is_slave_and_propagation = {
+            let global = global_state.lock().unwrap();
+            !global.is_master() && *is_propagation
+        };
+
+        if args.len() < 5 {
+            if !is_slave_and_propagation {
+                write_error(stream, &error::wrong_arity("xclaim"));
+            }
+            return;
+        }
+        let stream_key = &args[0];
+        let group_name = &args[1];
+        let consumer = &args[2];
+        let min_idle = match args[3].parse::<i64>() {
+            Ok(n) => n.max(0) as u64,
+            Err(_) => {
+                if !is_slave_and_propagation {
+                    write_error(stream, "ERR Invalid min-idle-time argument for XCLAIM");
+                }
+                return;
+            }
+        };
+
+        let now = now_ms();
+        let mut ids = Vec::new();
+        let mut delivery_time = now;
+        let mut retry_count: Option<u64> = None;
+        let mut force = false;
+        let mut justid = false;
+        let mut idx = 4;
+        while idx < args.len() {
+            if let Some((id, false)) = parse_range(&args[idx], None, false) {
+                ids.push(id);
+                idx += 1;
+                continue;
+            }
+            break;
+        }
+        while idx < args.len() {
+            let opt = args[idx].to_ascii_lowercase();
+            let value = args.get(idx + 1).and_then(|n| n.parse::<i64>().ok());
+            match (opt.as_str(), value) {
+                ("force", _) => {
+                    force = true;
+                    idx += 1;
+                }
+                ("justid", _) => {
+                    justid = true;
+                    idx += 1;
+                }
+                ("idle", Some(ms)) => {
+                    delivery_time = now.saturating_sub(ms.max(0) as u64);
+                    idx += 2;
+                }
+                ("time", Some(ms)) => {
+                    delivery_time = ms.max(0) as u64;
+                    idx += 2;
+                }
+                ("retrycount", Some(count)) => {
+                    retry_count = Some(count.max(0) as u64);
+                    idx += 2;
+                }
+                ("lastid", _) if idx + 1 < args.len() => {
+                    idx += 2;
+                }
+                _ => {
+                    if !is_slave_and_propagation {
+                        write_error(
+                            stream,
+                            &format!("ERR Unrecognized XCLAIM option '{}'", args[idx]),
+                        );
+                    }
+                    return;
+                }
+            }
+        }
+
+        let mut claimed: Vec<GroupEntry> = Vec::new();
+        let mut propagations: Vec<Vec<u8>> = Vec::new();
+        {
+            let mut map = db.lock(stream_key);
+            let redis_stream = match map.get_mut(stream_key) {
+                Some(ValueType::Stream(redis_stream))
+                    if redis_stream.groups.contains_key(group_name) =>
+                {
+                    redis_stream
+                }
+                _ => {
+                    if !is_slave_and_propagation {
+                        write_error(
+                            stream,
+                            &format!(
+                                "NOGROUP No such key '{}' or consumer group '{}'",
+                                stream_key, group_name
+                            ),
+                        );
+                    }
+                    return;
+                }
+            };
+
+            for id in ids {
+                let fields = redis_stream.entry(id).cloned();
+                let group = redis_stream.groups.get_mut(group_name).unwrap();
+
+                // Entries deleted from the stream are dropped from the PEL instead
+                if fields.is_none() {
+                    if group.pending.remove(&id).is_some() {
+                        propagations.push(encode_resp_command(&[
+                            "XACK".to_string(),
+                            stream_key.clone(),
+                            group_name.clone(),
+                            format!("{}-{}", id.0, id.1),
+                        ]));
+                    }
+                    continue;
+                }
+                if let Some(pending) = group.pending.get(&id) {
+                    if now.saturating_sub(pending.delivery_time) < min_idle {
+                        continue;
+                    }
+                }
+                if !group.claim(id, consumer, delivery_time, retry_count, !justid, force) {
                     continue;
                 }
+                propagations.push(claim_propagation(
+                    stream_key, group_name, consumer, id, group,
+                ));
+                claimed.push((id, fields));
+            }
+        }
 
-                let start_range = range_opt.unwrap();
-                let entries = redis_stream.range_start(start_range, range != "$");
+        if !propagations.is_empty() {
+            mark_dirty(global_state, stream_key);
+        }
 
-                let _ = stream.write_all(b"*2\r\n");
-                let _ = stream.write_all(format!("${}\r\n{}\r\n", key.len(), key).as_bytes());
+        if !is_slave_and_propagation {
+            let mut resp = format!("*{}\r\n", claimed.len());
+            for (id, fields) in &claimed {
+                if justid {
+                    let id = format!("{}-{}", id.0, id.1);
+                    resp.push_str(&format!("${}\r\n{}\r\n", id.len(), id));
+                } else {
+                    resp.push_str(&encode_stream_entry(*id, fields.as_deref()));
+                }
+            }
+            let _ = stream.write_all(resp.as_bytes());
 
-                let _ = stream.write_all(format!("*{}\r\n", entries.len()).as_bytes());
+            for propagation in propagations {
+                propagate_slaves(global_state, &propagation);
+            }
+        }
+    }
 
-                for entry in entries {
-                    let entry_id = format!("{}-{}", entry.milisec, entry.sequence_number);
+    fn handle_xautoclaim(
+        &self,
+        stream: &mut ClientStream,
+        args: &[String],
+        db: &DbType,
+        global_state: &RedisGlobalType,
+        is_propagation: &bool,
+        _connection: &mut Connection,
+    ) {
+        let is_slave_and_propagation = {
+            let global = global_state.lock().unwrap();
+            !global.is_master() && *is_propagation
+        };
 
-                    let _ = stream.write_all(b"*2\r\n");
-                    let _ = stream
-                        .write_all(format!("${}\r\n{}\r\n", entry_id.len(), entry_id).as_bytes());
+        if args.len() < 5 {
+            if !is_slave_and_propagation {
+                write_error(stream, &error::wrong_arity("xautoclaim"));
+            }
+            return;
+        }
+        let stream_key = &args[0];
+        let group_name = &args[1];
+        let consumer = &args[2];
+        let min_idle = match args[3].parse::<i64>() {
+            Ok(n) => n.max(0) as u64,
+            Err(_) => {
+                if !is_slave_and_propagation {
+                    write_error(stream, "ERR Invalid min-idle-time argument for XAUTOCLAIM");
+                }
+                return;
+            }
+        };
+        let start = match parse_range(&args[4], None, false) {
+            Some((id, false)) => id,
+            _ => {
+                if !is_slave_and_propagation {
+                    write_error(stream, error::INVALID_STREAM_ID);
+                }
+                return;
+            }
+        };
+
+        let mut count = 100;
+        let mut justid = false;
+        let mut idx = 5;
+        while idx < args.len() {
+            let opt = args[idx].to_ascii_lowercase();
+            match opt.as_str() {
+                "count" => {
+                    match args.get(idx + 1).and_then(|n| n.parse::<usize>().ok()) {
+                        Some(n) if n > 0 => count = n,
+                        _ => {
+                            if !is_slave_and_propagation {
+                                write_error(stream, "ERR COUNT must be > 0");
+                            }
+                            return;
+                        }
+                    }
+                    idx += 2;
+                }
+                "justid" => {
+                    justid = true;
+                    idx += 1;
+                }
+                _ => {
+                    if !is_slave_and_propagation {
+                        write_error(stream, error::SYNTAX);
+                    }
+                    return;
+                }
+            }
+        }
+
+        let now = now_ms();
+        let mut claimed: Vec<GroupEntry> = Vec::new();
+        let mut deleted: Vec<(u64, u64)> = Vec::new();
+        let mut propagations: Vec<Vec<u8>> = Vec::new();
+        let mut next_cursor = (0, 0);
+        {
+            let mut map = db.lock(stream_key);
+            let redis_stream = match map.get_mut(stream_key) {
+                Some(ValueType::Stream(redis_stream))
+                    if redis_stream.groups.contains_key(group_name) =>
+                {
+                    redis_stream
+                }
+                _ => {
+                    if !is_slave_and_propagation {
+                        write_error(
+                            stream,
+                            &format!(
+                                "NOGROUP No such key '{}' or consumer group '{}'",
+                                stream_key, group_name
+                            ),
+                        );
+                    }
+                    return;
+                }
+            };
+
+            // Like Redis, examine at most COUNT * 10 PEL entries per call
+            let mut attempts = count * 10;
+            let candidates: Vec<((u64, u64), u64)> = redis_stream.groups[group_name]
+                .pending
+                .range(start..)
+                .map(|(id, pending)| (*id, pending.delivery_time))
+                .collect();
+            let mut scanned = 0;
+            for (id, last_delivery) in &candidates {
+                if claimed.len() >= count || attempts == 0 {
+                    break;
+                }
+                attempts -= 1;
+                scanned += 1;
+
+                let fields = redis_stream.entry(*id).cloned();
+                let group = redis_stream.groups.get_mut(group_name).unwrap();
+                if fields.is_none() {
+                    group.pending.remove(id);
+                    deleted.push(*id);
+                    propagations.push(encode_resp_command(&[
+                        "XACK".to_string(),
+                        stream_key.clone(),
+                        group_name.clone(),
+                        format!("{}-{}", id.0, id.1),
+                    ]));
+                    continue;
+                }
+                if now.saturating_sub(*last_delivery) < min_idle {
+                    continue;
+                }
+                group.claim(*id, consumer, now, None, !justid, false);
+                propagations.push(claim_propagation(
+                    stream_key, group_name, consumer, *id, group,
+                ));
+                claimed.push((*id, fields));
+            }
+            if let Some((id, _)) = candidates.get(scanned) {
+                next_cursor = *id;
+            }
+        }
+        if !propagations.is_empty() {
+            mark_dirty(global_state, stream_key);
+        }
+
+        if !is_slave_and_propagation {
+            let cursor = format!("{}-{}", next_cursor.0, next_cursor.1);
+            let mut resp = format!("*3\r\n${}\r\n{}\r\n", cursor.len(), cursor);
+            resp.push_str(&format!("*{}\r\n", claimed.len()));
+            for (id, fields) in &claimed {
+                if justid {
+                    let id = format!("{}-{}", id.0, id.1);
+                    resp.push_str(&format!("${}\r\n{}\r\n", id.len(), id));
+                } else {
+                    resp.push_str(&encode_stream_entry(*id, fields.as_deref()));
+                }
+            }
+            resp.push_str(&format!("*{}\r\n", deleted.len()));
+            for id in &deleted {
+                let id = format!("{}-{}", id.0, id.1);
+                resp.push_str(&format!("${}\r\n{}\r\n", id.len(), id));
+            }
+            let _ = stream.write_all(resp.as_bytes());
+
+            for propagation in propagations {
+                propagate_slaves(global_state, &propagation);
+            }
+        }
+    }
+
+    fn handle_xgroup(
+        &self,
+        stream: &mut ClientStream,
+        args: &[String],
+        db: &DbType,
+        global_state: &RedisGlobalType,
+        is_propagation: &bool,
+        _connection: &mut Connection,
+    ) {
+        let is_slave_and_propagation = {
+            let global = global_state.lock().unwrap();
+            !global.is_master() && *is_propagation
+        };
+
+        let subcmd = match args.first() {
+            Some(subcmd) => subcmd.to_ascii_lowercase(),
+            None => {
+                if !is_slave_and_propagation {
+                    write_error(stream, &error::wrong_arity("xgroup"));
+                }
+                return;
+            }
+        };
 
-                    let _ =
-                        stream.write_all(format!("*{}\r\n", entry.key_val.len() * 2).as_bytes());
+        let min_args = match subcmd.as_str() {
+            "create" | "setid" => 4,
+            "destroy" => 3,
+            "createconsumer" | "delconsumer" => 4,
+            _ => {
+                if !is_slave_and_propagation {
+                    write_error(stream, &error::unknown_subcommand("xgroup", &args[0]));
+                }
+                return;
+            }
+        };
+        if args.len() < min_args {
+            if !is_slave_and_propagation {
+                write_error(stream, &error::wrong_arity(&format!("xgroup|{}", subcmd)));
+            }
+            return;
+        }
+
+        let stream_key = &args[1];
+        let group_name = &args[2];
 
-                    for (field, value) in &entry.key_val {
-                        let _ = stream
-                            .write_all(format!("${}\r\n{}\r\n", field.len(), field).as_bytes());
-                        let _ = stream
-                            .write_all(format!("${}\r\n{}\r\n", value.len(), value).as_bytes());
+        // CREATE and SETID accept MKSTREAM / ENTRIESREAD n after the ID
+        let mut mkstream = false;
+        let mut entries_read: Option<u64> = None;
+        let mut idx = min_args;
+        while idx < args.len() {
+            let opt = args[idx].to_ascii_lowercase();
+            match opt.as_str() {
+                "mkstream" if subcmd == "create" => {
+                    mkstream = true;
+                    idx += 1;
+                }
+                "entriesread" if subcmd == "create" || subcmd == "setid" => {
+                    match args.get(idx + 1).and_then(|n| n.parse::<u64>().ok()) {
+                        Some(n) => entries_read = Some(n),
+                        None => {
+                            if !is_slave_and_propagation {
+                                write_error(stream, error::NOT_AN_INTEGER);
+                            }
+                            return;
+                        }
                     }
+                    idx += 2;
+                }
+                _ => {
+                    if !is_slave_and_propagation {
+                        write_error(stream, error::SYNTAX);
+                    }
+                    return;
                 }
-            } else {
-                write_error(stream, "stream not found or not of type 'stream'");
             }
         }
 
-        consumed
-    }
-
-    fn handle_xrange(
-        &self,
-        stream: &mut TcpStream,
-        args: &[String],
-        db: &DbType,
-        _connection: &mut Connection,
-    ) -> usize {
-        if args.len() < 3 {
-            write_error(stream, "wrong number of arguments for 'XRANGE'");
-            return 0;
-        };
-        let stream_key = &args[0];
+        let mut map = db.lock(stream_key);
 
-        let mut _stream_obj: Option<&Stream> = None;
+        if !map.contains_key(stream_key) && subcmd == "create" && mkstream {
+            map.insert(stream_key.clone(), ValueType::Stream(Stream::new()));
+        }
 
-        let map = db.lock().unwrap();
-        if let Some(val) = map.get(stream_key) {
-            if let ValueType::Stream(ref stream) = val {
-                _stream_obj = Some(stream);
-            } else {
-                write_error(
-                    stream,
-                    "WRONGTYPE Operation against a key holding the wrong kind of value",
-                );
-                return 3;
+        let redis_stream = match map.get_mut(stream_key) {
+            Some(ValueType::Stream(redis_stream)) => redis_stream,
+            Some(_) => {
+                if !is_slave_and_propagation {
+                    write_error(stream, error::WRONGTYPE);
+                }
+                return;
+            }
+            None => {
+                if !is_slave_and_propagation {
+                    write_error(stream, "ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.");
+                }
+                return;
             }
-        } else {
-            write_null_bulk_string(stream);
-            return 3;
         };
 
-        if let Some(redis_stream) = _stream_obj {
-            let (start, end) = (
-                parse_range(&args[1], None),
-                parse_range(&args[2], redis_stream.last_entry_id()),
-            );
-            if start.is_none() || end.is_none() {
+        let needs_group = subcmd != "create" && subcmd != "destroy";
+        if needs_group && !redis_stream.groups.contains_key(group_name) {
+            if !is_slave_and_propagation {
                 write_error(
                     stream,
-                    "invalid arguments for XRANGE: start and end must be integers",
+                    &format!(
+                        "NOGROUP No such consumer group '{}' for key name '{}'",
+                        group_name, stream_key
+                    ),
                 );
-                return 3;
             }
+            return;
+        }
 
-            let (start, end) = (start.unwrap(), end.unwrap());
-
-            let range = redis_stream.range(start, end);
+        // Replicas receive concrete IDs so `$` resolves identically on both sides
+        let mut propagation: Vec<String> = vec!["XGROUP".to_string()];
+        propagation.extend(args.iter().cloned());
 
-            let _ = stream.write_all(format!("*{}\r\n", range.len()).as_bytes());
-            for entry in range {
-                let id = format!("{}-{}", entry.milisec, entry.sequence_number);
+        match subcmd.as_str() {
+            "create" | "setid" => {
+                let last_id = match parse_range(&args[3], Some(redis_stream.last_entry_id()), false)
+                {
+                    Some((id, false)) => id,
+                    _ => {
+                        if !is_slave_and_propagation {
+                            write_error(stream, error::INVALID_STREAM_ID);
+                        }
+                        return;
+                    }
+                };
+                propagation[4] = format!("{}-{}", last_id.0, last_id.1);
 
-                let mut fields = Vec::with_capacity(entry.key_val.len());
-                for (k, v) in &entry.key_val {
-                    fields.push(k.clone());
-                    fields.push(v.clone());
+                if subcmd == "create" {
+                    if redis_stream.groups.contains_key(group_name) {
+                        if !is_slave_and_propagation {
+                            write_error(stream, "BUSYGROUP Consumer Group name already exists");
+                        }
+                        return;
+                    }
+                    redis_stream.groups.insert(
+                        group_name.clone(),
+                        ConsumerGroup::new(last_id, entries_read),
+                    );
+                } else {
+                    let group = redis_stream.groups.get_mut(group_name).unwrap();
+                    group.last_delivered_id = last_id;
+                    if entries_read.is_some() {
+                        group.entries_read = entries_read;
+                    }
                 }
-
-                let mut resp = String::new();
-                resp.push_str("*2\r\n");
-
-                resp.push_str(&format!("${}\r\n{}\r\n", id.len(), id));
-
-                // Second element: key-value array
-                resp.push_str(&format!("*{}\r\n", entry.key_val.len() * 2));
-                for (k, v) in &entry.key_val {
-                    resp.push_str(&format!("${}\r\n{}\r\n", k.len(), k));
-                    resp.push_str(&format!("${}\r\n{}\r\n", v.len(), v));
+                if !is_slave_and_propagation {
+                    write_simple_string(stream, "OK");
+                }
+            }
+            "destroy" => {
+                let removed = redis_stream.groups.remove(group_name).is_some();
+                if !is_slave_and_propagation {
+                    write_integer(stream, removed as i64);
+                }
+            }
+            "createconsumer" => {
+                let group = redis_stream.groups.get_mut(group_name).unwrap();
+                let created = group.create_consumer(&args[3]);
+                if !is_slave_and_propagation {
+                    write_integer(stream, created as i64);
+                }
+            }
+            "delconsumer" => {
+                let group = redis_stream.groups.get_mut(group_name).unwrap();
+                let pending = group.delete_consumer(&args[3]);
+                if !is_slave_and_propagation {
+                    write_integer(stream, pending as i64);
                 }
-
-                let _ = stream.write_all(resp.as_bytes());
             }
+            _ => unreachable!(),
+        }
+        drop(map);
+        mark_dirty(global_state, stream_key);
+
+        if !is_slave_and_propagation {
+            propagate_slaves(global_state, &encode_resp_command(&propagation));
         }
-        3
     }
 
     fn handle_xadd(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         args: &[String],
         db: &DbType,
         global_state: &RedisGlobalType,
         is_propagation: &bool,
         _connection: &mut Connection,
-    ) -> usize {
-        // TODO: transaction runner and enqueuing
+    ) {
         let is_slave_and_propagation = {
             let global = global_state.lock().unwrap();
             !global.is_master() && *is_propagation
         };
         if args.len() < 4 {
             if !is_slave_and_propagation {
-                write_error(stream, "wrong number of arguments for 'XADD'");
+                write_error(stream, &error::wrong_arity("xadd"));
             }
-            return 0;
+            return;
         }
 
         let stream_key = &args[0];
@@ -2002,22 +4582,19 @@ impl Runner {
             idx += 2;
         }
         {
-            let mut map = db.lock().unwrap();
+            let mut map = db.lock(stream_key);
 
-            let add_result = if let Some(existing) = map.get_mut(stream_key) {
-                if let ValueType::Stream(ref mut stream_obj) = existing {
+            let add_result = match map.get_mut(stream_key) {
+                Some(ValueType::Stream(stream_obj)) => {
                     stream_obj.add_entries(id.clone(), kv.clone())
-                } else {
+                }
+                Some(_) => StreamResult::Err(error::WRONGTYPE.to_string()),
+                None => {
                     let mut s = Stream::new();
                     let ok = s.add_entries(id.clone(), kv.clone());
                     map.insert(stream_key.clone(), ValueType::Stream(s));
                     ok
                 }
-            } else {
-                let mut s = Stream::new();
-                let ok = s.add_entries(id.clone(), kv.clone());
-                map.insert(stream_key.clone(), ValueType::Stream(s));
-                ok
             };
 
             match add_result {
@@ -2025,83 +4602,49 @@ impl Runner {
                     if !is_slave_and_propagation {
                         write_error(stream, &err);
                     }
-                    return idx;
+                    return;
                 }
                 StreamResult::Some(new_id) => id = new_id,
             }
         }
+        mark_dirty(global_state, stream_key);
+        db.signal_ready();
         if !is_slave_and_propagation {
             write_bulk_string(stream, &id);
-            let mut propagation = format!("XADD {}", id);
+            // Replicas get the ID actually assigned, so `*` resolves identically
+            let mut propagation = vec!["XADD", stream_key, &id];
             for (k, v) in &kv {
-                propagation.push(' ');
-                propagation.push_str(k);
-                propagation.push(' ');
-                propagation.push_str(v);
+                propagation.push(k);
+                propagation.push(v);
             }
-            propagate_slaves(global_state, &propagation);
+            propagate_slaves(global_state, &encode_resp_command(&propagation));
         }
-        idx
     }
 
     fn handle_set(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         args: &[String],
         db: &DbType,
-        db_config: &DbConfigType,
         global_state: &RedisGlobalType,
         is_propagation: &bool,
-        connection: &mut Connection,
-    ) -> usize {
+        _connection: &mut Connection,
+    ) {
         let is_slave_and_propagation = {
             let global = global_state.lock().unwrap();
             !global.is_master() && *is_propagation
         };
         if args.len() < 2 {
             if !is_slave_and_propagation {
-                write_error(stream, "wrong number of arguments for 'SET'");
-            }
-            return 0;
-        }
-
-        if connection.transaction.is_txing {
-            let mut consumed = 2;
-            let mut idx = 2;
-            while idx < args.len() {
-                let opt = args[idx].to_ascii_lowercase();
-                match opt.as_str() {
-                    "ex" | "px" => {
-                        if idx + 1 < args.len() {
-                            consumed += 2;
-                            idx += 2;
-                        } else {
-                            write_error(stream, "invalid EX argument");
-                            return consumed;
-                        }
-                    }
-                    _ => break,
-                }
-            }
-            let mut task = String::from("set");
-            for i in 0..consumed {
-                if let Some(arg) = args.get(i) {
-                    task.push(' ');
-                    task.push_str(arg);
-                }
+                write_error(stream, &error::wrong_arity("set"));
             }
-            connection.transaction.tasks.push(task);
-            write_simple_string(stream, "QUEUED");
-            return consumed;
+            return;
         }
 
-        let mut consumed = 0;
-
-        let key = args[0].clone();
-        let value = args[1].clone();
-        consumed += 2;
+        let key = &args[0];
+        let value = self.arg_bytes(1);
 
-        let mut config: Config = Default::default();
+        let mut entry = Entry::new(ValueType::string(value.to_vec()));
 
         let mut idx = 2;
         let mut ex_arg: Option<String> = None;
@@ -2112,167 +4655,128 @@ impl Runner {
             match opt.as_str() {
                 "ex" => {
                     if let Some(sec_str) = args.get(idx + 1) {
-                        if let Ok(secs) = sec_str.parse::<u64>() {
+                        if let Some(secs) = sec_str.parse::<u64>().ok().filter(|&secs| secs > 0) {
                             let now_ms = SystemTime::now()
                                 .duration_since(UNIX_EPOCH)
                                 .unwrap()
                                 .as_millis() as u64;
-                            let expire_at = now_ms + (secs as u64) * 1000;
-                            config.expire_at = Some(expire_at);
+                            let expire_at = now_ms + secs * 1000;
+                            entry.expire_at = Some(expire_at);
                             ex_arg = Some(sec_str.clone());
                         } else {
                             if !is_slave_and_propagation {
-                                write_error(stream, "invalid EX argument");
+                                write_error(stream, expire_error(sec_str));
                             }
-                            return 0;
+                            return;
                         }
                         idx += 2;
                     } else {
                         if !is_slave_and_propagation {
-                            write_error(stream, "missing EX argument");
+                            write_error(stream, error::SYNTAX);
                         }
-                        return 0;
+                        return;
                     }
-                    consumed += 2;
                 }
                 "px" => {
                     if let Some(ms_str) = args.get(idx + 1) {
-                        if let Ok(ms) = ms_str.parse::<u64>() {
+                        if let Some(ms) = ms_str.parse::<u64>().ok().filter(|&ms| ms > 0) {
                             let now_ms = SystemTime::now()
                                 .duration_since(UNIX_EPOCH)
                                 .unwrap()
                                 .as_millis() as u64;
-                            let expire_at = now_ms + (ms as u64);
-                            config.expire_at = Some(expire_at);
+                            let expire_at = now_ms + ms;
+                            entry.expire_at = Some(expire_at);
                             px_arg = Some(ms_str.clone());
                         } else {
                             if !is_slave_and_propagation {
-                                write_error(stream, "invalid PX argument");
+                                write_error(stream, expire_error(ms_str));
                             }
-                            return 0;
+                            return;
                         }
                         idx += 2;
                     } else {
                         if !is_slave_and_propagation {
-                            write_error(stream, "missing PX argument");
+                            write_error(stream, error::SYNTAX);
                         }
-                        return 0;
+                        return;
                     }
-                    consumed += 2;
                 }
                 _ => {
-                    break;
+                    if !is_slave_and_propagation {
+                        write_error(stream, error::SYNTAX);
+                    }
+                    return;
                 }
             }
         }
 
-        {
-            let mut map = db.lock().unwrap();
-            map.insert(key.clone(), ValueType::String(value.clone()));
-        }
-        {
-            let mut config_map = db_config.lock().unwrap();
-            config_map.insert(key.clone(), config);
-        }
+        // Replacing the whole entry drops any TTL the old value had
+        db.lock(key).insert_entry(key.clone(), entry);
+        mark_dirty(global_state, key);
 
         // Propagate to slaves, with correct SET form
-        let propagation = if let Some(ex) = ex_arg {
-            format!(
-                "*5\r\n$3\r\nSET\r\n${}\r\n{}\r\n${}\r\n{}\r\n$2\r\nEX\r\n${}\r\n{}\r\n",
-                key.len(),
-                key,
-                value.len(),
-                value,
-                ex.len(),
-                ex
-            )
-        } else if let Some(px) = px_arg {
-            format!(
-                "*5\r\n$3\r\nSET\r\n${}\r\n{}\r\n${}\r\n{}\r\n$2\r\nPX\r\n${}\r\n{}\r\n",
-                key.len(),
-                key,
-                value.len(),
-                value,
-                px.len(),
-                px
-            )
-        } else {
-            format!(
-                "*3\r\n$3\r\nSET\r\n${}\r\n{}\r\n${}\r\n{}\r\n",
-                key.len(),
-                key,
-                value.len(),
-                value
-            )
-        };
-        propagate_slaves(global_state, &propagation);
+        let mut propagation: Vec<&[u8]> = vec![b"SET", key.as_bytes(), value];
+        if let Some(ex) = &ex_arg {
+            propagation.extend([b"EX".as_slice(), ex.as_bytes()]);
+        } else if let Some(px) = &px_arg {
+            propagation.extend([b"PX".as_slice(), px.as_bytes()]);
+        }
+        propagate_slaves(global_state, &encode_resp_command(&propagation));
 
         if !is_slave_and_propagation {
             write_simple_string(stream, "OK");
         }
-        consumed
     }
 
     fn handle_del(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         args: &[String],
         db: &DbType,
-        db_config: &DbConfigType,
         global_state: &RedisGlobalType,
         is_propagation: &bool,
-        connection: &mut Connection,
-    ) -> usize {
+        _connection: &mut Connection,
+    ) {
         let is_slave_and_propagation = {
             let global = global_state.lock().unwrap();
             !global.is_master() && *is_propagation
         };
         if args.is_empty() {
             if !is_slave_and_propagation {
-                write_error(stream, "wrong number of arguments for 'DEL'");
+                write_error(stream, &error::wrong_arity("del"));
             }
-            return 0;
-        }
-
-        if connection.transaction.is_txing {
-            connection
-                .transaction
-                .tasks
-                .push(format!("del {}", args[0]));
-            write_simple_string(stream, "QUEUED");
-            return 1;
+            return;
         }
 
-        let key = &args[0];
-        let mut removed = 0;
+        let mut removed = Vec::new();
         {
-            let mut map = db.lock().unwrap();
-            let mut config_map = db_config.lock().unwrap();
-            if map.remove(key).is_some() {
-                removed += 1;
+            let mut map = db.lock_keys(args.iter().map(String::as_str));
+            for key in args {
+                if map.remove(key).is_some() {
+                    removed.push(key);
+                }
             }
-            config_map.remove(key);
+        }
+        for key in &removed {
+            mark_dirty(global_state, key);
         }
         if !is_slave_and_propagation {
-            write_integer(stream, removed);
+            write_integer(stream, removed.len() as i64);
         }
-        propagate_slaves(
-            global_state,
-            &format!("*2\r\n$3\r\nDEL\r\n${}\r\n{}\r\n", key.len(), key),
-        );
-        1
+        let mut propagation = vec!["DEL"];
+        propagation.extend(args.iter().map(|arg| arg.as_str()));
+        propagate_slaves(global_state, &encode_resp_command(&propagation));
     }
 
     fn handle_incr(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         args: &[String],
         db: &DbType,
-        db_config: &DbConfigType,
         global_state: &RedisGlobalType,
         is_propagation: &bool,
-        connection: &mut Connection,
-    ) -> usize {
+        _connection: &mut Connection,
+    ) {
         let is_slave_and_propagation = {
             let global = global_state.lock().unwrap();
             !global.is_master() && *is_propagation
@@ -2280,66 +4784,319 @@ impl Runner {
 
         if args.is_empty() {
             if !is_slave_and_propagation {
-                write_error(stream, "wrong number of arguments for 'INCR'");
+                write_error(stream, &error::wrong_arity("incr"));
             }
-            return 0;
+            return;
         }
 
-        if connection.transaction.is_txing {
-            connection
-                .transaction
-                .tasks
-                .push(format!("incr {}", args[0]));
-            write_simple_string(stream, "QUEUED");
-            return 1;
+        let key = &args[0];
+
+        // An expired counter starts over; its DEL reaches replicas before the INCR
+        expire_if_needed(db, global_state, key);
+
+        let new_value = {
+            let mut map = db.lock(key);
+            // The counter is updated in place, keeping any TTL
+            match map.get_mut(key) {
+                None => {
+                    map.insert(key.clone(), ValueType::Int(1));
+                    1
+                }
+                Some(value) => {
+                    let current = match value {
+                        ValueType::Int(n) => Some(*n),
+                        ValueType::String(s) => std::str::from_utf8(s)
+                            .ok()
+                            .and_then(|s| s.parse::<i64>().ok()),
+                        _ => None,
+                    };
+                    let Some(current) = current else {
+                        write_error(stream, error::NOT_AN_INTEGER);
+                        return;
+                    };
+                    let Some(new_value) = current.checked_add(1) else {
+                        write_error(stream, "ERR increment or decrement would overflow");
+                        return;
+                    };
+                    *value = ValueType::Int(new_value);
+                    new_value
+                }
+            }
+        };
+        mark_dirty(global_state, key);
+        if !is_slave_and_propagation {
+            write_integer(stream, new_value);
         }
+        propagate_slaves(global_state, &encode_resp_command(&["INCR", key]));
+    }
 
+    /// Sets the key's expiry to an absolute Unix time in ms. One already
+    /// past deletes the key, and replicas get the DEL.
+    fn handle_pexpireat(
+        &self,
+        stream: &mut ClientStream,
+        args: &[String],
+        db: &DbType,
+        global_state: &RedisGlobalType,
+        is_propagation: &bool,
+    ) {
+        let is_slave_and_propagation = {
+            let global = global_state.lock().unwrap();
+            !global.is_master() && *is_propagation
+        };
+        if args.len() != 2 {
+            if !is_slave_and_propagation {
+                write_error(stream, &error::wrong_arity("pexpireat"));
+            }
+            return;
+        }
         let key = &args[0];
-        let mut _result_value = 0;
-
-        {
-            let mut map = db.lock().unwrap();
-            let mut config_map = db_config.lock().unwrap();
+        let Ok(expire_at) = args[1].parse::<i64>() else {
+            if !is_slave_and_propagation {
+                write_error(stream, error::NOT_AN_INTEGER);
+            }
+            return;
+        };
 
-            if !config_map.contains_key(key) || !map.contains_key(key) {
-                map.insert(key.clone(), ValueType::String("1".to_string()));
-                config_map.insert(key.clone(), Default::default());
-                _result_value = 1;
+        expire_if_needed(db, global_state, key);
+        let propagation = {
+            let mut map = db.lock(key);
+            let Some(last_access_ms) = map.entry(key).map(|entry| entry.last_access_ms) else {
+                drop(map);
+                if !is_slave_and_propagation {
+                    write_integer(stream, 0);
+                }
+                return;
+            };
+            if expire_at <= now_ms() as i64 {
+                map.remove(key);
+                encode_resp_command(&["DEL", key])
             } else {
-                if let Some(cfg) = config_map.get(key) {
-                    if cfg.is_expired() {
-                        map.remove(key);
-                        config_map.remove(key);
-                        write_error(stream, &format!("key {key} is expired"));
-                        return 1;
-                    }
+                // Re-inserted so active expiration learns about the deadline
+                let mut entry = Entry::new(map.remove(key).unwrap());
+                entry.expire_at = Some(expire_at as u64);
+                entry.last_access_ms = last_access_ms;
+                map.insert_entry(key.clone(), entry);
+                encode_resp_command(&["PEXPIREAT", key, &args[1]])
+            }
+        };
+        mark_dirty(global_state, key);
+        if !is_slave_and_propagation {
+            write_integer(stream, 1);
+        }
+        propagate_slaves(global_state, &propagation);
+    }
+
+    fn handle_sadd(
+        &self,
+        stream: &mut ClientStream,
+        args: &[String],
+        db: &DbType,
+        global_state: &RedisGlobalType,
+        is_propagation: &bool,
+    ) {
+        let is_slave_and_propagation = {
+            let global = global_state.lock().unwrap();
+            !global.is_master() && *is_propagation
+        };
+        if args.len() < 2 {
+            if !is_slave_and_propagation {
+                write_error(stream, &error::wrong_arity("sadd"));
+            }
+            return;
+        }
+        let key = &args[0];
+
+        expire_if_needed(db, global_state, key);
+        let added = {
+            let mut map = db.lock(key);
+            if !map.contains_key(key) {
+                map.insert(key.clone(), ValueType::Set(Vec::new()));
+            }
+            let Some(ValueType::Set(members)) = map.get_mut(key) else {
+                drop(map);
+                if !is_slave_and_propagation {
+                    write_error(stream, error::WRONGTYPE);
                 }
-                let value = map.get(key).unwrap();
-                let parsed = match value {
-                    ValueType::String(s) => s.parse::<i64>(),
-                    _ => {
-                        write_error(stream, "value is not an integer or out of range");
-                        return 1;
-                    }
-                };
-                let new_value = match parsed {
-                    Ok(val) => val + 1,
-                    Err(_) => {
-                        write_error(stream, "value is not an integer or out of range");
-                        return 1;
-                    }
-                };
-                map.insert(key.clone(), ValueType::String(new_value.to_string()));
-                _result_value = new_value;
+                return;
+            };
+            let mut added = 0;
+            for index in 1..args.len() {
+                let member = self.arg_bytes(index);
+                let is_member = members
+                    .iter()
+                    .any(|existing| existing.string_bytes().as_deref() == Some(member));
+                if !is_member {
+                    members.push(ValueType::string(member.to_vec()));
+                    added += 1;
+                }
+            }
+            added
+        };
+        mark_dirty(global_state, key);
+        if !is_slave_and_propagation {
+            write_integer(stream, added);
+        }
+        let mut propagation: Vec<&[u8]> = vec![b"SADD"];
+        propagation.extend((0..args.len()).map(|index| self.arg_bytes(index)));
+        propagate_slaves(global_state, &encode_resp_command(&propagation));
+    }
+
+    fn handle_hset(
+        &self,
+        stream: &mut ClientStream,
+        args: &[String],
+        db: &DbType,
+        global_state: &RedisGlobalType,
+        is_propagation: &bool,
+    ) {
+        let is_slave_and_propagation = {
+            let global = global_state.lock().unwrap();
+            !global.is_master() && *is_propagation
+        };
+        // The key, then field-value pairs
+        if args.len() < 3 || args.len().is_multiple_of(2) {
+            if !is_slave_and_propagation {
+                write_error(stream, &error::wrong_arity("hset"));
             }
+            return;
         }
+        let key = &args[0];
+
+        expire_if_needed(db, global_state, key);
+        let added = {
+            let mut map = db.lock(key);
+            if !map.contains_key(key) {
+                map.insert(key.clone(), ValueType::Hash(HashMap::new()));
+            }
+            let Some(ValueType::Hash(fields)) = map.get_mut(key) else {
+                drop(map);
+                if !is_slave_and_propagation {
+                    write_error(stream, error::WRONGTYPE);
+                }
+                return;
+            };
+            let mut added = 0;
+            for index in (1..args.len()).step_by(2) {
+                let value = ValueType::string(self.arg_bytes(index + 1).to_vec());
+                if fields.insert(args[index].clone(), value).is_none() {
+                    added += 1;
+                }
+            }
+            added
+        };
+        mark_dirty(global_state, key);
         if !is_slave_and_propagation {
-            write_integer(stream, _result_value);
+            write_integer(stream, added);
         }
-        propagate_slaves(
-            global_state,
-            &format!("*2\r\n$4\r\nINCR\r\n${}\r\n{}\r\n", key.len(), key),
-        );
-        1
+        let mut propagation: Vec<&[u8]> = vec![b"HSET"];
+        propagation.extend((0..args.len()).map(|index| self.arg_bytes(index)));
+        propagate_slaves(global_state, &encode_resp_command(&propagation));
+    }
+}
+
+/// Hands elements from the front of the list at `key` to the clients blocked
+/// in BLPOP on it, dropping the list if that empties it. Returns how many
+/// were handed over.
+fn serve_blocked_clients(db: &DbType, map: &mut KeyspaceGuard, key: &str) -> usize {
+    let Some(ValueType::List(redis_list)) = map.get_mut(key) else {
+        return 0;
+    };
+    let served = db.blocked_clients.serve(key, redis_list);
+    if redis_list.is_empty() {
+        map.remove(key);
+    }
+    served
+}
+
+/// Each element a push handed to a blocked client reaches replicas as an
+/// LPOP right after the push itself.
+fn propagate_served_pops(global_state: &RedisGlobalType, key: &str, served: usize) {
+    for _ in 0..served {
+        propagate_slaves(global_state, &encode_resp_command(&["LPOP", key]));
+    }
+}
+
+/// Replicas replay claims as a forced, exact-state XCLAIM so they don't depend on
+/// their own idle-time view of the PEL.
+fn claim_propagation(
+    stream_key: &str,
+    group_name: &str,
+    consumer: &str,
+    id: (u64, u64),
+    group: &ConsumerGroup,
+) -> Vec<u8> {
+    let pending = &group.pending[&id];
+    encode_resp_command(&[
+        "XCLAIM",
+        stream_key,
+        group_name,
+        consumer,
+        "0",
+        &format!("{}-{}", id.0, id.1),
+        "TIME",
+        &pending.delivery_time.to_string(),
+        "RETRYCOUNT",
+        &pending.delivery_count.to_string(),
+        "FORCE",
+        "JUSTID",
+    ])
+}
+
+/// Commands that modify the dataset, refused on read-only replicas and while
+/// min-replicas-to-write is unmet.
+fn is_write_command(command: &str) -> bool {
+    CommandSpec::lookup(command).is_some_and(|spec| spec.is_write())
+}
+
+/// SET's error for an EX or PX value that isn't a positive integer.
+fn expire_error(value: &str) -> &'static str {
+    match value.parse::<i64>() {
+        Ok(_) => "ERR invalid expire time in 'set' command",
+        Err(_) => error::NOT_AN_INTEGER,
+    }
+}
+
+/// Synchronous SAVE to the configured dump path, shared with DEBUG RELOAD.
+fn save_now(db: &DbType, global_state: &RedisGlobalType) -> Result<(), String> {
+    let path = {
+        let global = global_state.lock().unwrap();
+        if global.rdb_bgsave_in_progress {
+            return Err("Background save already in progress".to_string());
+        }
+        format!("{}/{}", global.dir_path, global.dbfilename)
+    };
+
+    let result = {
+        let db = db.lock_all();
+        save_rdb(db.iter(), &path)
+    };
+    global_state.lock().unwrap().finish_save(result.is_ok());
+    result.map_err(|e| format!("failed to save RDB: {}", e))
+}
+
+/// One INFO section: a "# Title" header followed by field:value lines.
+fn info_section<K: AsRef<str>>(title: &str, fields: &[(K, String)]) -> String {
+    let mut section = format!("# {}\r\n", title);
+    for (field, value) in fields {
+        section.push_str(&format!("{}:{}\r\n", field.as_ref(), value));
     }
+    section
+}
+
+/// Names show up in CLIENT LIST, which is space and line separated.
+fn is_valid_client_name(name: &str) -> bool {
+    name.chars().all(|c| ('!'..='~').contains(&c))
+}
+
+/// Snapshot of the client registry, so no client info is locked under the
+/// global lock.
+fn client_handles(global_state: &RedisGlobalType) -> Vec<(u64, Arc<Mutex<ClientInfo>>)> {
+    global_state
+        .lock()
+        .unwrap()
+        .clients
+        .iter()
+        .map(|(&id, info)| (id, Arc::clone(info)))
+        .collect()
 }
@@ -1,12 +1,14 @@
 use std::cmp::Ordering;
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::mem;
 
 use crate::geo::{bounding_box, decode, encode, haversine};
 
 const MAX_LEVEL: usize = 32;
 
-type NodeType = Arc<RwLock<Node>>;
+// Index of the head node in `SkipList::nodes`. Links point to nodes by index,
+// and a node's slot is only reused after it has been unlinked everywhere.
+const HEAD: u32 = 0;
 
 fn is_head() -> bool {
     use rand::Rng;
@@ -14,36 +16,25 @@ fn is_head() -> bool {
     rng.random_bool(0.5)
 }
 
-#[derive(Debug)]
-pub struct Node {
-    pub member: String,
+#[derive(Debug, Clone)]
+struct Node {
+    member: String,
     score: f64,
-    is_special: bool,
-    forwards: Vec<Option<NodeType>>,
+    // One link per level the node is on, lowest first
+    forwards: Vec<Option<u32>>,
 }
 
-impl Node {
-    fn new(entry: (f64, String)) -> NodeType {
-        return Arc::new(RwLock::new(Self {
-            member: entry.1,
-            score: entry.0,
-            forwards: vec![None; MAX_LEVEL],
-            is_special: false,
-        }));
-    }
-    fn new_dummy() -> NodeType {
-        return Arc::new(RwLock::new(Self {
-            member: String::new(),
-            score: 0.0,
-            forwards: vec![None; MAX_LEVEL],
-            is_special: true,
-        }));
-    }
-}
-#[derive(Debug)]
+/// Members ordered by (score, member). Nodes live in one arena and link to
+/// each other by index; the zset owning the list is locked as a whole, so
+/// nothing here needs locking of its own.
+#[derive(Debug, Clone)]
 pub struct SkipList {
-    head: NodeType,
+    nodes: Vec<Node>,
+    // Slots of removed nodes, reused by `add`
+    free: Vec<u32>,
+    // Levels in use, at least 1
     level: usize,
+    len: usize,
 }
 
 fn cmp(a_score: f64, a_member: &str, b_score: f64, b_member: &str) -> Ordering {
@@ -57,70 +48,89 @@ fn cmp(a_score: f64, a_member: &str, b_score: f64, b_member: &str) -> Ordering {
 impl SkipList {
     pub fn new() -> Self {
         Self {
-            head: Node::new_dummy(),
-            level: 0,
+            nodes: vec![Node {
+                member: String::new(),
+                score: 0.0,
+                forwards: vec![None; MAX_LEVEL],
+            }],
+            free: Vec::new(),
+            level: 1,
+            len: 0,
         }
     }
 
+    fn node(&self, index: u32) -> &Node {
+        &self.nodes[index as usize]
+    }
+
+    fn next(&self, index: u32, level: usize) -> Option<u32> {
+        self.node(index).forwards[level]
+    }
+
+    /// For each level, the last node ordered before (score, member).
+    fn predecessors(&self, score: f64, member: &str) -> [u32; MAX_LEVEL] {
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut cur = HEAD;
+        for lvl in (0..self.level).rev() {
+            while let Some(next) = self.next(cur, lvl) {
+                let next_node = self.node(next);
+                if cmp(next_node.score, &next_node.member, score, member) != Ordering::Less {
+                    break;
+                }
+                cur = next;
+            }
+            update[lvl] = cur;
+        }
+        update
+    }
+
     pub fn add(&mut self, entry: (f64, String), member_dict: &mut HashMap<String, f64>) -> bool {
-        let (score, member) = entry.clone();
+        let (score, member) = entry;
         let mut is_new = true;
 
         if let Some(s) = member_dict.get(&member) {
-            self.remove_entry(&s, &member);
+            self.remove_entry(&s.clone(), &member);
             is_new = false;
         }
 
         member_dict.insert(member.clone(), score);
 
-        let mut update: Vec<NodeType> = vec![Arc::clone(&self.head); MAX_LEVEL];
-        let mut cur = Arc::clone(&self.head);
-
-        for top_to_bottom_lvl in (0..=self.level).rev() {
-            loop {
-                let next_opt = cur.read().unwrap().forwards[top_to_bottom_lvl]
-                    .as_ref()
-                    .map(Arc::clone);
-
-                match next_opt {
-                    Some(next) => {
-                        let next_ref = next.read().unwrap();
-                        match cmp(next_ref.score, &next_ref.member, score, &member) {
-                            Ordering::Less => {
-                                cur = Arc::clone(&next);
-                            }
-                            _ => break,
-                        }
-                    }
-                    None => break,
-                }
+        let update = self.predecessors(score, &member);
+        let node_level = SkipList::rand_number();
+        // Levels above the current top start from the head, which `update`
+        // already holds for them
+        self.level = self.level.max(node_level);
+
+        let forwards = (0..node_level)
+            .map(|lvl| self.next(update[lvl], lvl))
+            .collect();
+        let node = Node {
+            member,
+            score,
+            forwards,
+        };
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.nodes[index as usize] = node;
+                index
             }
-            update[top_to_bottom_lvl] = Arc::clone(&cur);
-        }
-
-        let highest_level = SkipList::rand_number();
-        if highest_level > self.level {
-            for i in self.level + 1..=highest_level {
-                update[i] = Arc::clone(&self.head);
+            None => {
+                self.nodes.push(node);
+                (self.nodes.len() - 1) as u32
             }
-            self.level = highest_level;
-        }
+        };
 
-        let new_node = Node::new(entry);
-
-        for bottom_to_top_lvl in 0..=highest_level {
-            let next_opt = update[bottom_to_top_lvl].read().unwrap().forwards[bottom_to_top_lvl]
-                .as_ref()
-                .map(Arc::clone);
-            new_node.write().unwrap().forwards[bottom_to_top_lvl] = next_opt;
-            update[bottom_to_top_lvl].write().unwrap().forwards[bottom_to_top_lvl] =
-                Some(Arc::clone(&new_node));
+        for (lvl, &prev) in update.iter().enumerate().take(node_level) {
+            self.nodes[prev as usize].forwards[lvl] = Some(index);
         }
+        self.len += 1;
         is_new
     }
 
+    /// A random level count between 1 and MAX_LEVEL, each level above the
+    /// first half as likely as the one below.
     fn rand_number() -> usize {
-        let mut lvl = 0;
+        let mut lvl = 1;
 
         while is_head() && lvl < MAX_LEVEL {
             lvl += 1;
@@ -130,65 +140,27 @@ impl SkipList {
     }
 
     pub fn remove_entry(&mut self, score: &f64, member: &str) -> bool {
-        let mut update: Vec<Option<NodeType>> = vec![None; MAX_LEVEL];
-        let mut cur = Arc::clone(&self.head);
-        let mut is_removed = false;
-
-        for top_to_bottom_lvl in (0..=self.level).rev() {
-            let mut is_found = false;
-            loop {
-                let next_opt = cur.read().unwrap().forwards[top_to_bottom_lvl]
-                    .as_ref()
-                    .map(Arc::clone);
-                match next_opt {
-                    Some(next) => {
-                        let next_ref = next.read().unwrap();
-                        match cmp(next_ref.score, &next_ref.member, *score, member) {
-                            Ordering::Less => cur = Arc::clone(&next),
-                            Ordering::Equal => {
-                                is_found = true;
-                                break;
-                            }
-                            _ => break,
-                        }
-                    }
-                    None => break,
-                }
-            }
-
-            if is_found {
-                update[top_to_bottom_lvl] = Some(Arc::clone(&cur));
-            }
+        let update = self.predecessors(*score, member);
+        let Some(target) = self.next(update[0], 0).filter(|&next| {
+            let node = self.node(next);
+            cmp(node.score, &node.member, *score, member) == Ordering::Equal
+        }) else {
+            return false;
+        };
+
+        let forwards = mem::take(&mut self.nodes[target as usize].forwards);
+        for (lvl, next) in forwards.into_iter().enumerate() {
+            self.nodes[update[lvl] as usize].forwards[lvl] = next;
         }
-
-        for bottom_to_top_lvl in 0..=self.level {
-            if let Some(ref update_node) = update[bottom_to_top_lvl] {
-                is_removed = true;
-                let delete_node = update_node.read().unwrap().forwards[bottom_to_top_lvl]
-                    .as_ref()
-                    .map(Arc::clone)
-                    .expect("This shouldn't be possible");
-
-                let next_node = {
-                    if let Some(ref next) = delete_node.read().unwrap().forwards[bottom_to_top_lvl]
-                    {
-                        Some(Arc::clone(next))
-                    } else {
-                        None
-                    }
-                };
-
-                update_node.write().unwrap().forwards[bottom_to_top_lvl] = next_node;
-
-                if bottom_to_top_lvl == self.level
-                    && self.head.read().unwrap().forwards[bottom_to_top_lvl].is_none()
-                {
-                    self.level -= 1
-                }
-            }
+        while self.level > 1 && self.next(HEAD, self.level - 1).is_none() {
+            self.level -= 1;
         }
 
-        is_removed
+        // Free the member's memory now; the slot itself waits for reuse
+        self.nodes[target as usize].member = String::new();
+        self.free.push(target);
+        self.len -= 1;
+        true
     }
 
     pub fn geo_range(&self, longitude: f64, latitude: f64, radius: f64) -> Vec<String> {
@@ -201,43 +173,29 @@ impl SkipList {
             return vec![];
         }
 
-        let mut cur = Arc::clone(&self.head);
-
-        for lvl in (0..=self.level).rev() {
-            loop {
-                let next_opt = cur.read().unwrap().forwards[lvl].as_ref().map(Arc::clone);
-                match next_opt {
-                    Some(next) if next.read().unwrap().score < min_hash => cur = Arc::clone(&next),
-                    _ => break,
+        let mut cur = HEAD;
+        for lvl in (0..self.level).rev() {
+            while let Some(next) = self.next(cur, lvl) {
+                if self.node(next).score >= min_hash {
+                    break;
                 }
+                cur = next;
             }
         }
 
-        let mut cur_opt = cur.read().unwrap().forwards[0].as_ref().map(Arc::clone);
-        let mut candidates: Vec<Arc<RwLock<Node>>> = vec![];
-
-        while let Some(node) = cur_opt {
-            let score = node.read().unwrap().score;
-            if score > max_hash {
+        let mut nodes: Vec<String> = vec![];
+        let mut cur_opt = self.next(cur, 0);
+        while let Some(index) = cur_opt {
+            let node = self.node(index);
+            if node.score > max_hash {
                 break;
             }
-            if !node.read().unwrap().is_special {
-                candidates.push(Arc::clone(&node));
-            }
-            cur_opt = node.read().unwrap().forwards[0].as_ref().map(Arc::clone);
-        }
-
-        let mut nodes: Vec<String> = vec![];
-
-        for candidate in candidates {
-            let node = candidate.read().unwrap();
-            let encoded = node.score;
-            let (plat, plon) = decode(encoded as u64);
-
+            let (plat, plon) = decode(node.score as u64);
             let distance = haversine(latitude, longitude, plat, plon);
             if distance <= radius {
                 nodes.push(node.member.clone());
             }
+            cur_opt = node.forwards[0];
         }
 
         nodes
@@ -245,93 +203,182 @@ impl SkipList {
 
     pub fn range(&self, start: i64, end: i64) -> Vec<(f64, String)> {
         let mut result = Vec::new();
-        let mut cur = Arc::clone(&self.head);
-
-        let mut total_len = 0;
-        {
-            let mut node = Arc::clone(&self.head);
-            loop {
-                let next_opt = {
-                    let node_ref = node.read().unwrap();
-                    node_ref.forwards[0].as_ref().map(Arc::clone)
-                };
-                match next_opt {
-                    Some(next) => {
-                        total_len += 1;
-                        node = next;
-                    }
-                    None => break,
-                }
-            }
-        }
+        let total_len = self.len as i64;
+
         let start = if start < 0 {
-            (total_len as i64 + start).max(0)
+            (total_len + start).max(0)
         } else {
-            start.min(total_len as i64)
-        } as usize;
-
-        let mut end = if end < 0 {
-            (total_len as i64 + end).max(0)
+            start
+        };
+        // An end still negative after counting from the tail selects
+        // nothing, as in Redis
+        let end = if end < 0 {
+            total_len + end
         } else {
-            end
-        } as usize;
-
-        if end >= total_len {
-            end = if total_len == 0 { 0 } else { total_len - 1 };
-        }
+            end.min(total_len - 1)
+        };
 
         if start > end || start >= total_len {
             return result;
         }
+        let (start, end) = (start as usize, end as usize);
 
-        // Move to start node
+        let mut cur_opt = self.next(HEAD, 0);
         let mut idx = 0;
-        loop {
-            let next_opt = {
-                let cur_ref = cur.read().unwrap();
-                cur_ref.forwards[0].as_ref().map(Arc::clone)
-            };
-            match next_opt {
-                Some(next) => {
-                    if idx >= start {
-                        let nref = next.read().unwrap();
-                        result.push((nref.score, nref.member.clone()));
-                        if idx == end {
-                            break;
-                        }
-                    }
-                    cur = next;
-                    idx += 1;
-                }
-                None => {
+        while let Some(index) = cur_opt {
+            let node = self.node(index);
+            if idx >= start {
+                result.push((node.score, node.member.clone()));
+                if idx == end {
                     break;
                 }
             }
+            cur_opt = node.forwards[0];
+            idx += 1;
         }
         result
     }
 
     pub fn rank(&self, score: &f64, member: &str) -> Option<u64> {
         let mut rank = 0;
-        let mut cur = Arc::clone(&self.head);
-
-        loop {
-            let next_opt = {
-                let cur_ref = cur.read().unwrap();
-                cur_ref.forwards[0].as_ref().map(Arc::clone)
-            };
-
-            match next_opt {
-                Some(next) => {
-                    let next_ref = next.read().unwrap();
-                    if next_ref.score == *score && next_ref.member == member {
-                        return Some(rank);
-                    }
-                    cur = Arc::clone(&next);
-                    rank += 1;
+        let mut cur_opt = self.next(HEAD, 0);
+
+        while let Some(index) = cur_opt {
+            let node = self.node(index);
+            if node.score == *score && node.member == member {
+                return Some(rank);
+            }
+            cur_opt = node.forwards[0];
+            rank += 1;
+        }
+        None
+    }
+}
+
+impl Default for SkipList {
+    fn default() -> Self {
+        SkipList::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    /// The entries of `model` in the order the list should keep them.
+    fn sorted(model: &HashMap<String, f64>) -> Vec<(f64, String)> {
+        let mut entries: Vec<(f64, String)> = model
+            .iter()
+            .map(|(member, &score)| (score, member.clone()))
+            .collect();
+        entries.sort_by(|a, b| cmp(a.0, &a.1, b.0, &b.1));
+        entries
+    }
+
+    /// ZRANGE's reading of `start` and `end` over `entries`.
+    fn expected_range(entries: &[(f64, String)], start: i64, end: i64) -> Vec<(f64, String)> {
+        let len = entries.len() as i64;
+        let start = if start < 0 {
+            (len + start).max(0)
+        } else {
+            start
+        };
+        let end = if end < 0 { len + end } else { end.min(len - 1) };
+        if start > end || start >= len {
+            return Vec::new();
+        }
+        entries[start as usize..=end as usize].to_vec()
+    }
+
+    /// Every level links a strictly ordered subset of the level below.
+    fn assert_levels_ordered(list: &SkipList) {
+        let mut below: Option<Vec<u32>> = None;
+        for lvl in 0..MAX_LEVEL {
+            let mut chain = Vec::new();
+            let mut cur = list.next(HEAD, lvl);
+            while let Some(index) = cur {
+                chain.push(index);
+                cur = list.next(index, lvl);
+            }
+            if lvl >= list.level {
+                assert!(chain.is_empty(), "level {} is above the top", lvl);
+            }
+            for pair in chain.windows(2) {
+                let (a, b) = (list.node(pair[0]), list.node(pair[1]));
+                assert_eq!(cmp(a.score, &a.member, b.score, &b.member), Ordering::Less);
+            }
+            if let Some(below) = &below {
+                assert!(chain.iter().all(|index| below.contains(index)));
+            }
+            below = Some(chain);
+        }
+    }
+
+    #[test]
+    fn random_adds_and_removes_match_a_sorted_model() {
+        for seed in 0..20 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut list = SkipList::new();
+            // The member to score map ZSet passes to `add`
+            let mut dict = HashMap::new();
+            let mut model = HashMap::new();
+
+            for _ in 0..500 {
+                // Few distinct scores, so ties are ordered by member
+                let member = format!("m{}", rng.random_range(0..60));
+                let score = rng.random_range(-4..4) as f64 / 2.0;
+                if rng.random_bool(0.7) {
+                    let is_new = list.add((score, member.clone()), &mut dict);
+                    assert_eq!(is_new, model.insert(member, score).is_none());
+                } else if let Some(old) = model.remove(&member) {
+                    dict.remove(&member);
+                    assert!(list.remove_entry(&old, &member));
+                } else {
+                    assert!(!list.remove_entry(&score, &member));
                 }
-                None => return None,
+
+                let entries = sorted(&model);
+                assert_eq!(list.len, entries.len());
+                assert_eq!(list.range(0, -1), entries);
+                let (start, end) = (rng.random_range(-70..70), rng.random_range(-70..70));
+                assert_eq!(
+                    list.range(start, end),
+                    expected_range(&entries, start, end),
+                    "range({}, {})",
+                    start,
+                    end
+                );
+            }
+
+            let entries = sorted(&model);
+            for (rank, (score, member)) in entries.iter().enumerate() {
+                assert_eq!(list.rank(score, member), Some(rank as u64));
             }
+            assert_eq!(list.rank(&0.0, "missing"), None);
+            assert_levels_ordered(&list);
+        }
+    }
+
+    #[test]
+    fn removing_everything_leaves_an_empty_reusable_list() {
+        let mut list = SkipList::new();
+        let mut dict = HashMap::new();
+        for n in 0..100 {
+            list.add((n as f64, format!("m{}", n)), &mut dict);
+        }
+        for n in 0..100 {
+            assert!(list.remove_entry(&(n as f64), &format!("m{}", n)));
         }
+        assert_eq!(list.len, 0);
+        assert_eq!(list.level, 1);
+        assert!(list.range(0, -1).is_empty());
+
+        // Slots of removed nodes are taken before the arena grows
+        list.add((1.0, "again".to_string()), &mut HashMap::new());
+        assert_eq!(list.nodes.len(), 101);
+        assert_eq!(list.range(0, -1), vec![(1.0, "again".to_string())]);
     }
 }
@@ -0,0 +1,123 @@
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::utils::encode_resp_command;
+
+// Like Redis, long commands are cut down before they are kept
+const MAX_ARGS: usize = 32;
+const MAX_ARG_LEN: usize = 128;
+
+#[derive(Debug)]
+pub struct SlowlogEntry {
+    pub id: u64,
+    // Unix time in seconds the command was logged at
+    pub timestamp: u64,
+    pub duration_micros: u64,
+    pub args: Vec<String>,
+    pub client_addr: String,
+    pub client_name: String,
+}
+
+impl SlowlogEntry {
+    /// The six-element SLOWLOG GET reply for this entry.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut resp = format!(
+            "*6\r\n:{}\r\n:{}\r\n:{}\r\n",
+            self.id, self.timestamp, self.duration_micros
+        )
+        .into_bytes();
+        resp.extend(encode_resp_command(&self.args));
+        for field in [&self.client_addr, &self.client_name] {
+            resp.extend(format!("${}\r\n{}\r\n", field.len(), field).into_bytes());
+        }
+        resp
+    }
+}
+
+/// Commands slower than `log_slower_than`, newest first, at most `max_len`.
+#[derive(Debug)]
+pub struct Slowlog {
+    pub entries: VecDeque<SlowlogEntry>,
+    pub next_id: u64,
+    // Microseconds; 0 logs every command and a negative value none
+    pub log_slower_than: i64,
+    pub max_len: usize,
+}
+
+impl Slowlog {
+    pub fn new() -> Self {
+        Slowlog {
+            entries: VecDeque::new(),
+            next_id: 0,
+            log_slower_than: 10000,
+            max_len: 128,
+        }
+    }
+
+    /// Whether a command that ran for `duration` is logged.
+    pub fn is_slow(&self, duration: Duration) -> bool {
+        self.log_slower_than >= 0 && duration.as_micros() as u64 >= self.log_slower_than as u64
+    }
+
+    /// Logs the command if it ran for at least the threshold.
+    pub fn record(&mut self, args: &[String], duration: Duration, addr: &str, name: &str) {
+        if !self.is_slow(duration) {
+            return;
+        }
+        let duration_micros = duration.as_micros() as u64;
+        let entry = SlowlogEntry {
+            id: self.next_id,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |now| now.as_secs()),
+            duration_micros,
+            args: truncate_args(args),
+            client_addr: addr.to_string(),
+            client_name: name.to_string(),
+        };
+        self.next_id += 1;
+        self.entries.push_front(entry);
+        self.trim();
+    }
+
+    /// Drops the oldest entries beyond `max_len`.
+    pub fn trim(&mut self) {
+        self.entries.truncate(self.max_len);
+    }
+
+    pub fn reset(&mut self) {
+        self.entries.clear();
+    }
+}
+
+impl Default for Slowlog {
+    fn default() -> Self {
+        Slowlog::new()
+    }
+}
+
+fn truncate_args(args: &[String]) -> Vec<String> {
+    // The last kept slot says how many arguments were left out
+    let omitted = if args.len() > MAX_ARGS {
+        args.len() - MAX_ARGS + 1
+    } else {
+        0
+    };
+    let mut kept: Vec<String> = args[..args.len() - omitted]
+        .iter()
+        .map(|arg| {
+            if arg.len() <= MAX_ARG_LEN {
+                return arg.clone();
+            }
+            let mut end = MAX_ARG_LEN;
+            while !arg.is_char_boundary(end) {
+                end -= 1;
+            }
+            format!("{}... ({} more bytes)", &arg[..end], arg.len() - end)
+        })
+        .collect();
+    if omitted > 0 {
+        kept.push(format!("... ({} more arguments)", omitted));
+    }
+    kept
+}
@@ -0,0 +1,166 @@
+use std::{
+    io::{self, Read, Write},
+    net::{IpAddr, Shutdown, TcpStream},
+    os::fd::{AsRawFd, RawFd},
+    os::unix::net::{SocketAddr, UnixStream},
+    sync::Arc,
+    time::Duration,
+};
+
+/// The socket behind a connection: TCP, or a unix domain socket from
+/// `--unixsocket`. Connection handling only goes through this type, so the
+/// transport matters nowhere past the listeners.
+///
+/// Handles are shared rather than duplicated, so a connection costs a single
+/// file descriptor however many parts of the server keep one.
+#[derive(Debug)]
+pub enum Socket {
+    Tcp(Arc<TcpStream>),
+    Unix(Arc<UnixStream>),
+}
+
+impl Socket {
+    /// Another handle on the same socket.
+    pub fn share(&self) -> Socket {
+        match self {
+            Socket::Tcp(stream) => Socket::Tcp(Arc::clone(stream)),
+            Socket::Unix(stream) => Socket::Unix(Arc::clone(stream)),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => stream.shutdown(how),
+            Socket::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => stream.set_read_timeout(timeout),
+            Socket::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    /// Whether the peer hung up, checked without waiting for or consuming
+    /// input. Only TCP can tell; unix peers count as connected.
+    pub fn peer_closed(&self) -> bool {
+        match self {
+            Socket::Tcp(stream) => {
+                if stream.set_nonblocking(true).is_err() {
+                    return false;
+                }
+                let closed = match stream.peek(&mut [0u8; 1]) {
+                    Ok(n) => n == 0,
+                    Err(e) => !matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+                    ),
+                };
+                let _ = stream.set_nonblocking(false);
+                closed
+            }
+            Socket::Unix(_) => false,
+        }
+    }
+
+    /// The peer's IP, None for unix sockets, whose peers are always local.
+    pub fn peer_ip(&self) -> Option<IpAddr> {
+        match self {
+            Socket::Tcp(stream) => stream.peer_addr().ok().map(|addr| addr.ip()),
+            Socket::Unix(_) => None,
+        }
+    }
+
+    /// The peer address as CLIENT LIST shows it. Unix clients are unnamed,
+    /// so they show the server's socket path instead, as Redis does.
+    pub fn peer_name(&self) -> String {
+        match self {
+            Socket::Tcp(stream) => stream
+                .peer_addr()
+                .map_or_else(|_| String::new(), |addr| addr.to_string()),
+            Socket::Unix(stream) => unix_name(stream.local_addr()),
+        }
+    }
+
+    pub fn local_name(&self) -> String {
+        match self {
+            Socket::Tcp(stream) => stream
+                .local_addr()
+                .map_or_else(|_| String::new(), |addr| addr.to_string()),
+            Socket::Unix(stream) => unix_name(stream.local_addr()),
+        }
+    }
+}
+
+fn unix_name(addr: io::Result<SocketAddr>) -> String {
+    let path = addr
+        .ok()
+        .and_then(|addr| addr.as_pathname().map(|path| path.to_owned()));
+    format!(
+        "{}:0",
+        path.map_or_else(String::new, |path| path.display().to_string())
+    )
+}
+
+impl From<TcpStream> for Socket {
+    fn from(stream: TcpStream) -> Self {
+        Socket::Tcp(Arc::new(stream))
+    }
+}
+
+impl From<UnixStream> for Socket {
+    fn from(stream: UnixStream) -> Self {
+        Socket::Unix(Arc::new(stream))
+    }
+}
+
+impl AsRawFd for Socket {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Socket::Tcp(stream) => stream.as_raw_fd(),
+            Socket::Unix(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+impl Read for &Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => (&**stream).read(buf),
+            Socket::Unix(stream) => (&**stream).read(buf),
+        }
+    }
+}
+
+impl Write for &Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => (&**stream).write(buf),
+            Socket::Unix(stream) => (&**stream).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => (&**stream).flush(),
+            Socket::Unix(stream) => (&**stream).flush(),
+        }
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
@@ -1,113 +1,118 @@
 use crate::enums::add_stream_entries_result::StreamResult;
+use crate::error;
+use crate::structs::consumer_group::{now_ms, ConsumerGroup, PendingEntry};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::ops::Bound::{self, Excluded, Included, Unbounded};
 use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(Debug)]
+/// An entry's ID and its field-value pairs, as the range methods yield them.
+pub type EntryRef<'a> = (&'a (u64, u64), &'a Vec<(String, String)>);
+
+/// An entry handed to a consumer group, without fields once it was deleted
+/// while still pending.
+pub type GroupEntry = ((u64, u64), Option<Vec<(String, String)>>);
+
+#[derive(Debug, Clone)]
 pub struct Stream {
-    pub entries: Vec<Entry>,
+    // Field-value pairs of each entry, keyed and ordered by ID
+    pub entries: BTreeMap<(u64, u64), Vec<(String, String)>>,
+    pub groups: HashMap<String, ConsumerGroup>,
+    pub last_id: Option<(u64, u64)>,
+    pub max_deleted_id: (u64, u64),
+    pub entries_added: u64,
 }
 
-#[derive(Debug)]
-pub struct Entry {
-    pub milisec: u64,
-    pub sequence_number: u64,
-    pub key_val: Vec<(String, String)>,
-}
 impl Stream {
     pub fn new() -> Self {
-        Stream { entries: vec![] }
+        Stream {
+            entries: BTreeMap::new(),
+            groups: HashMap::new(),
+            last_id: None,
+            max_deleted_id: (0, 0),
+            entries_added: 0,
+        }
     }
 
-    pub fn range(&self, start: (u64, u64), end: (u64, u64)) -> Vec<&Entry> {
-        let start_idx = self
-            .entries
-            .binary_search_by(|e| (e.milisec, e.sequence_number).cmp(&start))
-            .unwrap_or_else(|x| x);
+    /// Entries between `start` and `end`, each paired with whether it is
+    /// exclusive, oldest first and at most `count` of them.
+    pub fn range(
+        &self,
+        start: ((u64, u64), bool),
+        end: ((u64, u64), bool),
+        count: Option<usize>,
+    ) -> impl Iterator<Item = EntryRef<'_>> {
+        let ((start, start_exclusive), (end, end_exclusive)) = (start, end);
 
-        let end_idx = self
-            .entries
-            .binary_search_by(|e| (e.milisec, e.sequence_number).cmp(&end))
-            .unwrap_or_else(|x| x);
+        // BTreeMap::range panics on an inverted range, or an empty one with
+        // both ends excluded
+        let is_empty = start > end || (start == end && start_exclusive && end_exclusive);
+        let bounds = (bound(start, start_exclusive), bound(end, end_exclusive));
 
-        self.entries[start_idx..=end_idx].iter().collect()
+        (!is_empty)
+            .then(|| self.entries.range(bounds))
+            .into_iter()
+            .flatten()
+            .take(count.unwrap_or(usize::MAX))
     }
 
-    pub fn range_start(&self, start: (u64, u64), is_greater_than_range: bool) -> Vec<&Entry> {
-        let start_idx = match self
-            .entries
-            .binary_search_by(|e| (e.milisec, e.sequence_number).cmp(&start))
-        {
-            Ok(i) => {
-                if is_greater_than_range {
-                    i + 1
-                } else {
-                    i
-                }
-            }
-            Err(i) => i,
-        };
-
-        self.entries[start_idx..].iter().collect()
+    /// Entries from `start` on, leaving `start` itself out when
+    /// `is_greater_than_range` is set.
+    pub fn range_start(
+        &self,
+        start: (u64, u64),
+        is_greater_than_range: bool,
+    ) -> impl Iterator<Item = EntryRef<'_>> {
+        self.entries
+            .range((bound(start, is_greater_than_range), Unbounded))
     }
 
     pub fn add_entries(&mut self, id: String, key_val: Vec<(String, String)>) -> StreamResult {
+        // IDs are generated against the last ID ever added, not the last entry still
+        // present, so deleting entries never lets an old ID come back
+        let last_id = self.last_id;
+
         if id == "*" {
             let curr_ms = SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .map(|d| d.as_millis() as u64)
                 .unwrap_or(0);
 
-            let mut curr_seq = 0;
-            if let Some(last_entry) = self.entries.last() {
-                if last_entry.milisec == curr_ms {
-                    curr_seq = last_entry.sequence_number + 1;
-                }
-            }
-
-            self.entries.push(Entry {
-                milisec: curr_ms,
-                sequence_number: curr_seq,
-                key_val,
-            });
+            let new_id = match last_id {
+                Some((last_ms, last_seq)) if curr_ms <= last_ms => (last_ms, last_seq + 1),
+                _ => (curr_ms, 0),
+            };
 
-            return StreamResult::Some(format!("{curr_ms}-{curr_seq}"));
+            self.push_entry(new_id, key_val);
+            return StreamResult::Some(format!("{}-{}", new_id.0, new_id.1));
         }
 
         let mili_sequence_vec: Vec<&str> = id.split('-').collect();
         if mili_sequence_vec.len() != 2 {
-            return StreamResult::Err("The ID specified in XADD is not valid".to_string());
+            return StreamResult::Err(error::INVALID_STREAM_ID.to_string());
         }
 
         if mili_sequence_vec[1] == "*" {
             let curr_ms = mili_sequence_vec[0].parse::<u64>();
 
             if curr_ms.is_err() {
-                return StreamResult::Err("The ID specified in XADD is not valid".to_string());
+                return StreamResult::Err(error::INVALID_STREAM_ID.to_string());
             }
             let curr_ms = curr_ms.unwrap();
-            let mut curr_seq = 1;
-
-            if !self.entries.is_empty() {
-                let last_entry = self.entries.last().unwrap();
-                let last_ms = last_entry.milisec;
-                if curr_ms == last_ms {
-                    curr_seq = curr_seq + last_entry.sequence_number;
-                } else {
-                    curr_seq = 0;
-                }
 
-                if curr_ms < last_ms {
+            let curr_seq = match last_id {
+                Some((last_ms, _)) if curr_ms < last_ms => {
                     return StreamResult::Err(
-                    "The ID specified in XADD is equal or smaller than the target stream top item"
+                    "ERR The ID specified in XADD is equal or smaller than the target stream top item"
                         .to_string(),
                 );
                 }
-            }
+                Some((last_ms, last_seq)) if curr_ms == last_ms => last_seq + 1,
+                _ if curr_ms == 0 => 1,
+                _ => 0,
+            };
 
-            self.entries.push(Entry {
-                milisec: curr_ms,
-                sequence_number: curr_seq,
-                key_val,
-            });
+            self.push_entry((curr_ms, curr_seq), key_val);
 
             StreamResult::Some(format!("{curr_ms}-{curr_seq}"))
         } else {
@@ -115,76 +120,228 @@ impl Stream {
             let curr_seq = mili_sequence_vec[1].parse::<u64>();
 
             if curr_ms.is_err() || curr_seq.is_err() {
-                return StreamResult::Err("The ID specified in XADD is not valid".to_string());
+                return StreamResult::Err(error::INVALID_STREAM_ID.to_string());
             }
             let (curr_ms, curr_seq) = (curr_ms.unwrap(), curr_seq.unwrap());
 
             if curr_ms == 0 && curr_seq == 0 {
                 return StreamResult::Err(
-                    "The ID specified in XADD must be greater than 0-0".to_string(),
+                    "ERR The ID specified in XADD must be greater than 0-0".to_string(),
                 );
             }
-            if curr_ms == 0 && curr_seq < 1 {
-                return StreamResult::Err(
-                    "The ID specified in XADD must be greater than 0-0".to_string(),
-                );
-            }
-            if !self.entries.is_empty() {
-                let last_entry = self.entries.last().unwrap();
-                let last_ms = last_entry.milisec;
-                let last_seq = last_entry.sequence_number;
-
-                if curr_ms < last_ms {
-                    return StreamResult::Err(
-                    "The ID specified in XADD is equal or smaller than the target stream top item"
-                        .to_string(),
-                );
-                }
-                if curr_ms == last_ms && curr_seq <= last_seq {
+            if let Some(last_id) = last_id {
+                if (curr_ms, curr_seq) <= last_id {
                     return StreamResult::Err(
-                    "The ID specified in XADD is equal or smaller than the target stream top item"
+                    "ERR The ID specified in XADD is equal or smaller than the target stream top item"
                         .to_string(),
                 );
                 }
             }
-            self.entries.push(Entry {
-                milisec: curr_ms,
-                sequence_number: curr_seq,
-                key_val,
-            });
+            self.push_entry((curr_ms, curr_seq), key_val);
 
             StreamResult::Some(id)
         }
     }
 
-    pub fn last_entry_id(&self) -> Option<(u64, u64)> {
-        if let Some(entry) = self.entries.last() {
-            Some((entry.milisec, entry.sequence_number))
-        } else {
-            Some((0, 0))
+    fn push_entry(&mut self, id: (u64, u64), key_val: Vec<(String, String)>) {
+        self.entries.insert(id, key_val);
+        self.last_id = Some(id);
+        self.entries_added += 1;
+    }
+
+    /// Removes entries by ID (XDEL/XTRIM), keeping `max_deleted_id` up to date.
+    pub fn remove_entries(&mut self, ids: &[(u64, u64)]) -> usize {
+        let mut removed = 0;
+        for id in ids {
+            if self.entries.remove(id).is_some() {
+                self.max_deleted_id = self.max_deleted_id.max(*id);
+                removed += 1;
+            }
         }
+        removed
+    }
+
+    /// The field-value pairs of the entry with ID `id`.
+    pub fn entry(&self, id: (u64, u64)) -> Option<&Vec<(String, String)>> {
+        self.entries.get(&id)
+    }
+
+    /// Delivers entries to a group consumer. `start` of None means `>`: new entries
+    /// are handed out and recorded in the PEL. An explicit start re-reads the
+    /// consumer's own pending entries; deleted ones come back without fields.
+    pub fn read_group(
+        &mut self,
+        group_name: &str,
+        consumer: &str,
+        start: Option<(u64, u64)>,
+        count: Option<usize>,
+        noack: bool,
+    ) -> Option<Vec<GroupEntry>> {
+        let group = self.groups.get_mut(group_name)?;
+        group.create_consumer(consumer);
+        let now = now_ms();
+        if let Some(c) = group.consumers.get_mut(consumer) {
+            c.seen_time = now;
+        }
+
+        let limit = count.unwrap_or(usize::MAX);
+        let mut delivered = Vec::new();
+
+        match start {
+            None => {
+                let after_last = (Excluded(group.last_delivered_id), Unbounded);
+                for (&id, key_val) in self.entries.range(after_last).take(limit) {
+                    group.last_delivered_id = id;
+                    group.entries_read = group.entries_read.map(|n| n + 1);
+                    if !noack {
+                        group.pending.insert(
+                            id,
+                            PendingEntry {
+                                consumer: consumer.to_string(),
+                                delivery_time: now,
+                                delivery_count: 1,
+                            },
+                        );
+                    }
+                    delivered.push((id, Some(key_val.clone())));
+                }
+            }
+            Some(start) => {
+                let ids: Vec<(u64, u64)> = group
+                    .pending
+                    .range((Excluded(start), Unbounded))
+                    .filter(|(_, pending)| pending.consumer == consumer)
+                    .map(|(id, _)| *id)
+                    .take(limit)
+                    .collect();
+                for id in ids {
+                    let fields = self.entry(id).cloned();
+                    delivered.push((id, fields));
+                }
+            }
+        }
+
+        Some(delivered)
+    }
+
+    pub fn last_entry_id(&self) -> (u64, u64) {
+        self.last_id.unwrap_or((0, 0))
+    }
+}
+
+impl Default for Stream {
+    fn default() -> Self {
+        Stream::new()
     }
 }
 
-impl ToString for Stream {
-    fn to_string(&self) -> String {
-        let entries_str: Vec<String> = self.entries.iter().map(|entry| entry.to_string()).collect();
-        format!("[{}]", entries_str.join(", "))
+fn bound(id: (u64, u64), exclusive: bool) -> Bound<(u64, u64)> {
+    if exclusive {
+        Excluded(id)
+    } else {
+        Included(id)
     }
 }
 
-impl ToString for Entry {
-    fn to_string(&self) -> String {
-        let mut keyvals = Vec::new();
-        for (k, v) in &self.key_val {
-            keyvals.push(k.clone());
-            keyvals.push(v.clone());
+impl fmt::Display for Stream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let entries_str: Vec<String> = self
+            .entries
+            .iter()
+            .map(|((ms, seq), key_val)| {
+                let mut keyvals = Vec::new();
+                for (k, v) in key_val {
+                    keyvals.push(k.clone());
+                    keyvals.push(v.clone());
+                }
+                format!("{}-{} [{}]", ms, seq, keyvals.join(", "))
+            })
+            .collect();
+        write!(f, "[{}]", entries_str.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    const ENTRIES: u64 = 1_000_000;
+
+    /// Adds entries `from..to` with explicit IDs, each ms getting two.
+    fn add(stream: &mut Stream, from: u64, to: u64) -> Duration {
+        let started = Instant::now();
+        for n in from..to {
+            let id = format!("{}-{}", n / 2 + 1, n % 2);
+            let fields = vec![("n".to_string(), n.to_string())];
+            assert!(matches!(
+                stream.add_entries(id, fields),
+                StreamResult::Some(_)
+            ));
         }
-        format!(
-            "{}-{} [{}]",
-            self.milisec,
-            self.sequence_number,
-            keyvals.join(", ")
-        )
+        started.elapsed()
+    }
+
+    fn id(n: u64) -> (u64, u64) {
+        (n / 2 + 1, n % 2)
+    }
+
+    #[test]
+    fn a_million_entries_insert_and_range_in_log_time() {
+        let mut stream = Stream::new();
+        let first_half = add(&mut stream, 0, ENTRIES / 2);
+        let second_half = add(&mut stream, ENTRIES / 2, ENTRIES);
+        // Appending costs the same however long the stream already is
+        assert!(
+            second_half < first_half * 3,
+            "first half in {:?}, second in {:?}",
+            first_half,
+            second_half
+        );
+        assert_eq!(stream.entries.len() as u64, ENTRIES);
+        assert_eq!(stream.entries_added, ENTRIES);
+        assert_eq!(stream.last_entry_id(), id(ENTRIES - 1));
+
+        let started = Instant::now();
+        assert_eq!(stream.range_start((0, 0), false).count() as u64, ENTRIES);
+        let full_scan = started.elapsed();
+
+        // A thousand short ranges across the stream cost less than one scan
+        let started = Instant::now();
+        for n in (0..ENTRIES).step_by(1000) {
+            let entries: Vec<_> = stream
+                .range((id(n), true), (id(n + 10), false), Some(5))
+                .collect();
+            let ids: Vec<(u64, u64)> = entries.iter().map(|(id, _)| **id).collect();
+            assert_eq!(ids, (n + 1..n + 6).map(id).collect::<Vec<_>>());
+            assert_eq!(entries[0].1[0].1, (n + 1).to_string());
+        }
+        let lookups = started.elapsed();
+        assert!(
+            lookups < full_scan,
+            "1000 ranges in {:?}, a full scan in {:?}",
+            lookups,
+            full_scan
+        );
+
+        let tail: Vec<_> = stream.range_start(id(ENTRIES - 3), true).collect();
+        assert_eq!(tail.len(), 2);
+        assert_eq!(*tail[1].0, id(ENTRIES - 1));
+
+        // Deleting from the middle leaves the neighbours in place
+        let middle: Vec<(u64, u64)> = (ENTRIES / 2..ENTRIES / 2 + 100).map(id).collect();
+        assert_eq!(stream.remove_entries(&middle), 100);
+        let around: Vec<(u64, u64)> = stream
+            .range(
+                (id(ENTRIES / 2 - 1), false),
+                (id(ENTRIES / 2 + 100), false),
+                None,
+            )
+            .map(|(id, _)| *id)
+            .collect();
+        assert_eq!(around, vec![id(ENTRIES / 2 - 1), id(ENTRIES / 2 + 100)]);
+        assert_eq!(stream.max_deleted_id, id(ENTRIES / 2 + 99));
+        assert_eq!(stream.entries_added, ENTRIES);
     }
 }
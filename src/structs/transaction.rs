@@ -1,17 +1,22 @@
 pub struct Transaction {
     pub is_txing: bool,
-    pub tasks: Vec<String>,
-    pub job_done_at: Option<usize>,
-    pub response: Vec<Option<String>>,
+    // Set when a command failed to queue; EXEC then aborts
+    pub dirty: bool,
+    pub tasks: Vec<Vec<Vec<u8>>>,
 }
 
 impl Transaction {
     pub fn new() -> Self {
         Transaction {
             is_txing: false,
+            dirty: false,
             tasks: Vec::new(),
-            job_done_at: None,
-            response: Vec::new(),
         }
     }
 }
+
+impl Default for Transaction {
+    fn default() -> Self {
+        Transaction::new()
+    }
+}
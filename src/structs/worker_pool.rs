@@ -0,0 +1,69 @@
+use std::sync::{Arc, Mutex};
+
+use tokio::runtime::Handle;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+pub const DEFAULT_IO_THREADS: usize = 4;
+
+// Connections allowed to wait for a worker before the listeners stop accepting
+const QUEUE_PER_WORKER: usize = 256;
+
+/// Bounds how many connections run commands at once (`--io-threads`).
+/// Connections with requests ready wait for a worker in arrival order; once
+/// the line is full, the listeners stop accepting until it shortens.
+pub struct WorkerPool {
+    workers: Arc<Semaphore>,
+    queue: Semaphore,
+}
+
+impl WorkerPool {
+    pub fn new(workers: usize) -> Self {
+        WorkerPool {
+            workers: Arc::new(Semaphore::new(workers)),
+            queue: Semaphore::new(workers * QUEUE_PER_WORKER),
+        }
+    }
+
+    /// Waits for a place in the queue, then for a worker.
+    pub async fn acquire(&self) -> Arc<WorkerSlot> {
+        let _place = self.queue.acquire().await.expect("worker queue closed");
+        let permit = Arc::clone(&self.workers)
+            .acquire_owned()
+            .await
+            .expect("worker pool closed");
+        Arc::new(WorkerSlot {
+            workers: Arc::clone(&self.workers),
+            permit: Mutex::new(Some(permit)),
+        })
+    }
+
+    /// Returns once the queue has room for another connection.
+    pub async fn until_queue_has_room(&self) {
+        let _place = self.queue.acquire().await.expect("worker queue closed");
+    }
+}
+
+/// A worker held by a connection for one batch of requests. A blocked
+/// command hands it back for the length of its wait.
+pub struct WorkerSlot {
+    workers: Arc<Semaphore>,
+    permit: Mutex<Option<OwnedSemaphorePermit>>,
+}
+
+impl WorkerSlot {
+    pub fn release(&self) {
+        self.permit.lock().unwrap().take();
+    }
+
+    /// Waits for a worker again after `release`. Must be called from a
+    /// thread of the runtime's blocking pool.
+    pub fn reacquire(&self) {
+        if self.permit.lock().unwrap().is_some() {
+            return;
+        }
+        let permit = Handle::current()
+            .block_on(Arc::clone(&self.workers).acquire_owned())
+            .expect("worker pool closed");
+        *self.permit.lock().unwrap() = Some(permit);
+    }
+}
@@ -1,14 +1,18 @@
+use crate::error;
+
 #[derive(Debug)]
 pub struct XreadConfig {
     pub count: Option<usize>,
     pub block: Option<usize>,
+    pub noack: bool,
     pub streams: Vec<(String, String)>,
 }
 
 impl XreadConfig {
-    pub fn from_args(args: &[String]) -> (Self, usize, Option<String>) {
+    pub fn from_args(args: &[String]) -> (Self, Option<String>) {
         let mut count = None;
         let mut block = None;
+        let mut noack = false;
         let mut streams: Vec<(String, String)> = Vec::new();
 
         let mut i = 0;
@@ -20,13 +24,13 @@ impl XreadConfig {
             match arg.as_str() {
                 "count" => {
                     if i + 1 >= args.len() {
-                        err = Some("COUNT requires an argument".to_string());
+                        err = Some(error::SYNTAX.to_string());
                         break;
                     }
                     match args[i + 1].parse::<usize>() {
                         Ok(n) => count = Some(n),
                         Err(_) => {
-                            err = Some("COUNT must be an integer".to_string());
+                            err = Some(error::NOT_AN_INTEGER.to_string());
                             break;
                         }
                     }
@@ -34,25 +38,32 @@ impl XreadConfig {
                 }
                 "block" => {
                     if i + 1 >= args.len() {
-                        err = Some("BLOCK requires an argument".to_string());
+                        err = Some(error::SYNTAX.to_string());
                         break;
                     }
                     match args[i + 1].parse::<usize>() {
                         Ok(n) => block = Some(n),
                         Err(_) => {
-                            err = Some("BLOCK must be an integer".to_string());
+                            err = Some("ERR timeout is not an integer or out of range".to_string());
                             break;
                         }
                     }
                     i += 2;
                 }
+                "noack" => {
+                    noack = true;
+                    i += 1;
+                }
                 "streams" => {
                     found_streams = true;
                     i += 1;
 
                     let remaining = args.len() - i;
-                    if remaining == 0 || remaining % 2 != 0 {
-                        err = Some("Missing ids for keys".to_string());
+                    if remaining == 0 || !remaining.is_multiple_of(2) {
+                        err = Some(
+                            "ERR Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified."
+                                .to_string(),
+                        );
                         break;
                     }
 
@@ -62,30 +73,26 @@ impl XreadConfig {
                         let id = args[mid + j].clone();
                         streams.push((key, id));
                     }
-
-                    i += remaining;
                     break;
                 }
                 _ => {
-                    err = Some(format!("Unknown or misplaced argument: {}", args[i]));
+                    err = Some(error::SYNTAX.to_string());
                     break;
                 }
             }
         }
 
         if !found_streams {
-            err = Some("Missing STREAMS argument".to_string());
+            err = Some(error::SYNTAX.to_string());
         }
 
-        let consumed = if let Some(_e) = &err { i.max(1) } else { i };
-
         (
             XreadConfig {
                 count,
                 block,
+                noack,
                 streams,
             },
-            consumed,
             err,
         )
     }
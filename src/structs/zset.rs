@@ -2,6 +2,7 @@ use std::collections::HashMap;
 
 use crate::structs::skiplist::SkipList;
 
+#[derive(Clone)]
 pub struct ZSet {
     dict: HashMap<String, f64>,
     skiplist: SkipList,
@@ -47,6 +48,10 @@ impl ZSet {
         self.skiplist.geo_range(lon, lat, radius)
     }
 
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &f64)> {
+        self.dict.iter()
+    }
+
     pub fn zrem(&mut self, member: &String) -> u32 {
         if let Some(score) = self.dict.get(member) {
             if self.skiplist.remove_entry(score, member) {
@@ -60,3 +65,9 @@ impl ZSet {
         }
     }
 }
+
+impl Default for ZSet {
+    fn default() -> Self {
+        ZSet::new()
+    }
+}
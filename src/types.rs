@@ -1,13 +1,6 @@
-use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
-};
+use std::sync::{Arc, Mutex};
 
-use crate::{
-    enums::val_type::ValueType,
-    structs::{config::Config, global::RedisGlobal},
-};
+use crate::structs::{global::RedisGlobal, keyspace::Keyspace};
 
-pub type DbType = Arc<Mutex<HashMap<String, ValueType>>>;
+pub type DbType = Arc<Keyspace>;
 pub type RedisGlobalType = Arc<Mutex<RedisGlobal>>;
-pub type DbConfigType = Arc<Mutex<HashMap<String, Config>>>;
@@ -1,51 +1,202 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{Read, Write};
 use std::net::TcpStream;
+use std::time::{Duration, Instant};
 
-use crate::structs::request::Request;
-use crate::types::RedisGlobalType;
+use bytes::Bytes;
 
-pub fn write_simple_string(stream: &mut TcpStream, msg: &str) {
+use crate::enums::{
+    eviction_policy::EvictionPolicy, psync_result::PsyncResult, val_type::ValueType,
+};
+use crate::rdb::save::{
+    RDB_TYPE_HASH, RDB_TYPE_HASH_LISTPACK, RDB_TYPE_HASH_ZIPLIST, RDB_TYPE_HASH_ZIPMAP,
+    RDB_TYPE_LIST, RDB_TYPE_LIST_QUICKLIST, RDB_TYPE_LIST_QUICKLIST_2, RDB_TYPE_LIST_ZIPLIST,
+    RDB_TYPE_SET, RDB_TYPE_SET_INTSET, RDB_TYPE_SET_LISTPACK, RDB_TYPE_STREAM_LISTPACKS,
+    RDB_TYPE_STREAM_LISTPACKS_2, RDB_TYPE_STREAM_LISTPACKS_3, RDB_TYPE_STRING, RDB_TYPE_ZSET,
+    RDB_TYPE_ZSET_2, RDB_TYPE_ZSET_LISTPACK, RDB_TYPE_ZSET_ZIPLIST, STREAM_ITEM_FLAG_DELETED,
+    STREAM_ITEM_FLAG_SAMEFIELDS,
+};
+use crate::structs::consumer_group::{now_ms, Consumer, ConsumerGroup, PendingEntry};
+use crate::structs::replica::drop_replica;
+use crate::structs::stream::{EntryRef, Stream};
+use crate::structs::zset::ZSet;
+use crate::types::{DbType, RedisGlobalType};
+
+pub fn write_simple_string(stream: &mut impl Write, msg: &str) {
     let _ = stream.write_all(format!("+{}\r\n", msg).as_bytes());
 }
 
-pub fn write_error(stream: &mut TcpStream, msg: &str) {
-    let _ = stream.write_all(format!("-ERR {}\r\n", msg).as_bytes());
+/// `msg` starts with its error code, as the messages in `crate::error` do.
+pub fn write_error(stream: &mut impl Write, msg: &str) {
+    let _ = stream.write_all(format!("-{}\r\n", msg).as_bytes());
 }
 
-pub fn write_bulk_string(stream: &mut TcpStream, msg: &str) {
-    let resp = format!("${}\r\n{}\r\n", msg.len(), msg);
-    let _ = stream.write_all(resp.as_bytes());
+/// Backslash-escapes quotes and non-printable characters so arbitrary
+/// client input can be echoed back on a single line.
+pub fn escape_repr(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\'' => escaped.push_str("\\'"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Splits a line into arguments like Redis's sdssplitargs: blanks separate
+/// them, "double quotes" take C-style escapes and 'single quotes' only \'.
+pub fn split_args(line: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(&first) = chars.peek() else {
+            return Ok(args);
+        };
+
+        let mut arg = String::new();
+        if first == '"' || first == '\'' {
+            chars.next();
+            loop {
+                match chars.next() {
+                    None => return Err("unbalanced quotes".to_string()),
+                    Some(c) if c == first => break,
+                    Some('\\') if first == '\'' && chars.peek() == Some(&'\'') => {
+                        arg.push(chars.next().unwrap_or('\''))
+                    }
+                    Some('\\') if first == '"' => arg.push(unescape(&mut chars)?),
+                    Some(c) => arg.push(c),
+                }
+            }
+            // A closing quote must end the argument
+            if chars.peek().is_some_and(|c| !c.is_whitespace()) {
+                return Err("unbalanced quotes".to_string());
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                arg.push(c);
+            }
+        }
+        args.push(arg);
+    }
+}
+
+fn unescape(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<char, String> {
+    let escaped = match chars.next() {
+        Some('n') => '\n',
+        Some('r') => '\r',
+        Some('t') => '\t',
+        Some('b') => '\u{8}',
+        Some('a') => '\u{7}',
+        Some('x') => {
+            let hex: String = [chars.next(), chars.next()].into_iter().flatten().collect();
+            let byte = u8::from_str_radix(&hex, 16).map_err(|_| "bad \\x escape".to_string())?;
+            byte as char
+        }
+        Some(c) => c,
+        None => return Err("unbalanced quotes".to_string()),
+    };
+    Ok(escaped)
 }
 
-pub fn write_null_bulk_string(stream: &mut TcpStream) {
+pub fn write_bulk_string(stream: &mut impl Write, msg: &(impl AsRef<[u8]> + ?Sized)) {
+    let msg = msg.as_ref();
+    let mut resp = format!("${}\r\n", msg.len()).into_bytes();
+    resp.extend_from_slice(msg);
+    resp.extend_from_slice(b"\r\n");
+    let _ = stream.write_all(&resp);
+}
+
+pub fn write_null_bulk_string(stream: &mut impl Write) {
     let _ = stream.write_all(b"$-1\r\n");
 }
 
-pub fn write_integer(stream: &mut TcpStream, val: i64) {
+pub fn write_integer(stream: &mut impl Write, val: i64) {
     let resp = format!(":{}\r\n", val);
     let _ = stream.write_all(resp.as_bytes());
 }
 
-pub fn write_array<T: AsRef<str>>(stream: &mut TcpStream, items: &[Option<T>]) {
+pub fn write_array<T: AsRef<[u8]>>(stream: &mut impl Write, items: &[Option<T>]) {
     let _ = stream.write_all(format!("*{}\r\n", items.len()).as_bytes());
     for item in items {
         match item {
-            Some(val) => {
-                let s = val.as_ref();
-                let _ = stream.write_all(format!("${}\r\n{}\r\n", s.len(), s).as_bytes());
-            }
+            Some(val) => write_bulk_string(stream, val),
             None => {
                 let _ = stream.write_all(b"$-1\r\n");
             }
         }
     }
 }
-pub fn write_null_array(stream: &mut TcpStream) {
+
+/// Null reply: `_` in RESP3, a null bulk string in RESP2.
+pub fn write_null(stream: &mut impl Write, protocol: u8) {
+    if protocol == 3 {
+        let _ = stream.write_all(b"_\r\n");
+    } else {
+        write_null_bulk_string(stream);
+    }
+}
+
+/// Map header for `len` key/value pairs. RESP2 clients get a flat array.
+pub fn write_map_header(stream: &mut impl Write, len: usize, protocol: u8) {
+    let header = if protocol == 3 {
+        format!("%{}\r\n", len)
+    } else {
+        format!("*{}\r\n", len * 2)
+    };
+    let _ = stream.write_all(header.as_bytes());
+}
+
+/// Double reply. RESP2 clients get it as a bulk string.
+pub fn write_double(stream: &mut impl Write, value: f64, protocol: u8) {
+    if protocol == 3 {
+        let _ = stream.write_all(format!(",{}\r\n", value).as_bytes());
+    } else {
+        write_bulk_string(stream, &value.to_string());
+    }
+}
+
+/// Boolean reply. RESP2 clients get 1 or 0.
+pub fn write_boolean(stream: &mut impl Write, value: bool, protocol: u8) {
+    if protocol == 3 {
+        let _ = stream.write_all(if value { b"#t\r\n" } else { b"#f\r\n" });
+    } else {
+        write_integer(stream, value as i64);
+    }
+}
+
+/// Subscribe/unsubscribe confirmation, sent as a push frame to RESP3 clients.
+pub fn write_subscription_reply(
+    stream: &mut impl Write,
+    kind: &str,
+    channel: Option<&str>,
+    count: usize,
+    protocol: u8,
+) {
+    let header = if protocol == 3 { '>' } else { '*' };
+    let mut resp = format!("{}3\r\n${}\r\n{}\r\n", header, kind.len(), kind);
+    match channel {
+        Some(channel) => resp.push_str(&format!("${}\r\n{}\r\n", channel.len(), channel)),
+        None if protocol == 3 => resp.push_str("_\r\n"),
+        None => resp.push_str("$-1\r\n"),
+    }
+    resp.push_str(&format!(":{}\r\n", count));
+    let _ = stream.write_all(resp.as_bytes());
+}
+
+pub fn write_null_array(stream: &mut impl Write) {
     let _ = stream.write_all(b"*-1\r\n");
 }
 
-pub fn write_resp_array(stream: &mut TcpStream, items: &[Option<String>]) {
+pub fn write_resp_array(stream: &mut impl Write, items: &[Option<String>]) {
     let _ = stream.write_all(format!("*{}\r\n", items.len()).as_bytes());
     for item in items {
         match item {
@@ -60,46 +211,6 @@ pub fn write_resp_array(stream: &mut TcpStream, items: &[Option<String>]) {
     }
 }
 
-pub fn write_redis_file(stream: &mut TcpStream, file_name: &str) {
-    const EMPTY_RDB: &[u8] = &[
-        0x52, 0x45, 0x44, 0x49, 0x53, 0x30, 0x30, 0x31, 0x31, 0xfa, 0x09, 0x72, 0x65, 0x64, 0x69,
-        0x73, 0x2d, 0x76, 0x65, 0x72, 0x05, 0x37, 0x2e, 0x32, 0x2e, 0x30, 0xfa, 0x0a, 0x72, 0x65,
-        0x64, 0x69, 0x73, 0x2d, 0x62, 0x69, 0x74, 0x73, 0xc0, 0x40, 0xfa, 0x05, 0x63, 0x74, 0x69,
-        0x6d, 0x65, 0xc2, 0x6d, 0x08, 0xbc, 0x65, 0xfa, 0x08, 0x75, 0x73, 0x65, 0x64, 0x2d, 0x6d,
-        0x65, 0x6d, 0xc2, 0xb0, 0xc4, 0x10, 0x00, 0xfa, 0x08, 0x61, 0x6f, 0x66, 0x2d, 0x62, 0x61,
-        0x73, 0x65, 0xc0, 0x00, 0xff, 0xf0, 0x6e, 0x3b, 0xfe, 0xc0, 0xff, 0x5a, 0xa2,
-    ];
-
-    let mut file = match File::open(file_name) {
-        Ok(f) => f,
-        Err(_) => {
-            // If the file does not exist, use the embedded empty RDB content.
-            let file_len = EMPTY_RDB.len();
-            let mut resp = format!("${}\r\n", file_len).into_bytes();
-            resp.extend_from_slice(EMPTY_RDB);
-            let _ = stream.write_all(&resp);
-            return;
-        }
-    };
-
-    let file_len = match file.metadata() {
-        Ok(meta) => meta.len(),
-        Err(_) => {
-            let _ = stream.write_all(b"-ERR could not stat file\r\n");
-            return;
-        }
-    };
-
-    let mut content = Vec::with_capacity(file_len as usize);
-    if let Err(_) = file.read_to_end(&mut content) {
-        let _ = stream.write_all(b"-ERR could not read file\r\n");
-        return;
-    }
-    let mut resp = format!("${}\r\n", file_len).into_bytes();
-    resp.extend_from_slice(&content);
-    let _ = stream.write_all(&resp);
-}
-
 pub fn is_matched(pattern: &str, word: &str) -> bool {
     if pattern.is_empty() {
         return false;
@@ -126,114 +237,616 @@ pub fn is_matched(pattern: &str, word: &str) -> bool {
     pattern == word
 }
 
-pub fn parse_len(bytes: &[u8]) -> (usize, usize) {
-    let first_byte = bytes[0];
+const RDB_UNEXPECTED_EOF: &str = "unexpected end of file";
+
+/// `len` bytes at `start`, or an error if the input is too short.
+fn take(bytes: &[u8], start: usize, len: usize) -> Result<&[u8], String> {
+    start
+        .checked_add(len)
+        .and_then(|end| bytes.get(start..end))
+        .ok_or_else(|| RDB_UNEXPECTED_EOF.to_string())
+}
+
+fn take_array<const N: usize>(bytes: &[u8], start: usize) -> Result<[u8; N], String> {
+    Ok(take(bytes, start, N)?.try_into().unwrap())
+}
+
+fn byte_at(bytes: &[u8], i: usize) -> Result<u8, String> {
+    bytes
+        .get(i)
+        .copied()
+        .ok_or_else(|| RDB_UNEXPECTED_EOF.to_string())
+}
+
+pub fn parse_len(bytes: &[u8]) -> Result<(usize, usize), String> {
+    let first_byte = byte_at(bytes, 0)?;
     let msb2 = (first_byte & 0b1100_0000) >> 6;
 
     match msb2 {
         0b00 => {
             // 6 bit length
             let len = (first_byte & 0b0011_1111) as usize;
-            (len, 1)
+            Ok((len, 1))
         }
         0b01 => {
             // 14 bit length
-            let second_byte = bytes[1];
+            let second_byte = byte_at(bytes, 1)?;
             let len = (((first_byte & 0b0011_1111) as usize) << 8) | (second_byte as usize);
-            (len, 2)
+            Ok((len, 2))
         }
-        0b10 => {
-            // 32 bit length
-            let len = u32::from_be_bytes(bytes[1..5].try_into().unwrap()) as usize;
-            (len, 5)
+        0b10 if first_byte == 0x81 => {
+            // 64 bit length
+            let len = u64::from_be_bytes(take_array(bytes, 1)?) as usize;
+            Ok((len, 9))
         }
-        _ => {
-            panic!("Invalid length encoding in parse_len");
+        0b10 if first_byte == 0x80 => {
+            // 32 bit length
+            let len = u32::from_be_bytes(take_array(bytes, 1)?) as usize;
+            Ok((len, 5))
         }
+        _ => Err(format!("invalid length encoding {:#x}", first_byte)),
     }
 }
 
-pub fn parse_string(bytes: &[u8]) -> (String, usize) {
-    let first_byte = bytes[0];
+/// Reads a string-encoded blob as raw bytes, expanding integer and
+/// LZF-compressed encodings.
+pub fn parse_bytes(bytes: &[u8]) -> Result<(Vec<u8>, usize), String> {
+    let first_byte = byte_at(bytes, 0)?;
     let msb2 = (first_byte & 0b1100_0000) >> 6;
 
-    match msb2 {
-        0b00 | 0b01 | 0b10 => {
-            let (len, offset) = parse_len(bytes);
-            let s = String::from_utf8_lossy(&bytes[offset..offset + len]).to_string();
-            (s, offset + len)
-        }
-        0b11 => {
-            let format = first_byte & 0b0011_1111;
-            match format {
-                0 => {
-                    let int_val = bytes[1] as i8;
-                    (int_val.to_string(), 2)
+    if msb2 != 0b11 {
+        let (len, offset) = parse_len(bytes)?;
+        return Ok((take(bytes, offset, len)?.to_vec(), offset + len));
+    }
+
+    let format = first_byte & 0b0011_1111;
+    // Integer encodings are little-endian
+    match format {
+        0 => {
+            let int_val = byte_at(bytes, 1)? as i8;
+            Ok((int_val.to_string().into_bytes(), 2))
+        }
+        1 => {
+            let int_val = i16::from_le_bytes(take_array(bytes, 1)?);
+            Ok((int_val.to_string().into_bytes(), 3))
+        }
+        2 => {
+            let int_val = i32::from_le_bytes(take_array(bytes, 1)?);
+            Ok((int_val.to_string().into_bytes(), 5))
+        }
+        3 => {
+            let (compressed_len, used1) = parse_len(&bytes[1..])?;
+            let (len, used2) = parse_len(&bytes[1 + used1..])?;
+            let start = 1 + used1 + used2;
+            // A back-reference expands 3 bytes to at most 264, so anything
+            // claiming more is corrupt and would only exhaust memory
+            if len / 88 > compressed_len {
+                return Err(format!("implausible LZF length {}", len));
+            }
+            let data = lzf::decompress(take(bytes, start, compressed_len)?, len)
+                .map_err(|e| format!("corrupt LZF string: {:?}", e))?;
+            Ok((data, start + compressed_len))
+        }
+        _ => Err(format!("unknown special string encoding {}", format)),
+    }
+}
+
+pub fn parse_string(bytes: &[u8]) -> Result<(String, usize), String> {
+    let (raw, used) = parse_bytes(bytes)?;
+    Ok((String::from_utf8_lossy(&raw).to_string(), used))
+}
+
+pub fn parse_expiry(bytes: &[u8]) -> Result<Option<(u64, bool, usize)>, String> {
+    match byte_at(bytes, 0)? {
+        0xFD => {
+            let ts = u32::from_le_bytes(take_array(bytes, 1)?) as u64;
+            Ok(Some((ts, false, 5)))
+        }
+        0xFC => {
+            let ts = u64::from_le_bytes(take_array(bytes, 1)?);
+            Ok(Some((ts, true, 9)))
+        }
+        _ => Ok(None),
+    }
+}
+
+pub fn parse_key_value(bytes: &[u8]) -> Result<(String, usize, u8), String> {
+    let value_type = byte_at(bytes, 0)?;
+    let (key, key_used) = parse_string(&bytes[1..])?;
+    Ok((key, key_used + 1, value_type))
+}
+
+/// Decodes one value. `Ok((None, used))` means the encoding is understood
+/// well enough to skip but not to load; `Err` means the rest of the file
+/// can't be located.
+pub fn parse_value_by_type(
+    value_type: u8,
+    bytes: &[u8],
+) -> Result<(Option<ValueType>, usize), String> {
+    let (value, used) = match value_type {
+        RDB_TYPE_STRING => {
+            let (value, used) = parse_bytes(bytes)?;
+            (ValueType::string(value), used)
+        }
+        RDB_TYPE_LIST => {
+            let (items, used) = parse_bytes_list(bytes)?;
+            (ValueType::List(items), used)
+        }
+        RDB_TYPE_LIST_ZIPLIST => {
+            let (ziplist, used) = parse_bytes(bytes)?;
+            (to_list(parse_ziplist(&ziplist)?), used)
+        }
+        RDB_TYPE_LIST_QUICKLIST | RDB_TYPE_LIST_QUICKLIST_2 => {
+            let (nodes, mut offset) = parse_len(bytes)?;
+            let mut items = Vec::new();
+            for _ in 0..nodes {
+                // Quicklist 2 tags each node as plain (1) or packed (2)
+                let mut container = 2;
+                if value_type == RDB_TYPE_LIST_QUICKLIST_2 {
+                    let (tag, used) = parse_len(&bytes[offset..])?;
+                    offset += used;
+                    container = tag;
                 }
-                1 => {
-                    let int_val = i16::from_be_bytes([bytes[1], bytes[2]]);
-                    (int_val.to_string(), 3)
+                let (node, used) = parse_bytes(&bytes[offset..])?;
+                offset += used;
+                if container == 1 {
+                    items.push(node);
+                } else if value_type == RDB_TYPE_LIST_QUICKLIST {
+                    items.extend(parse_ziplist(&node)?.into_iter().map(String::into_bytes));
+                } else {
+                    items.extend(parse_listpack(&node)?.into_iter().map(String::into_bytes));
                 }
-                2 => {
-                    let int_val = i32::from_be_bytes(bytes[1..5].try_into().unwrap());
-                    (int_val.to_string(), 5)
+            }
+            (ValueType::List(items), offset)
+        }
+        RDB_TYPE_SET => {
+            let (members, used) = parse_bytes_list(bytes)?;
+            (to_set(members), used)
+        }
+        RDB_TYPE_SET_INTSET => {
+            let (intset, used) = parse_bytes(bytes)?;
+            (to_set(parse_intset(&intset)?), used)
+        }
+        RDB_TYPE_SET_LISTPACK => {
+            let (listpack, used) = parse_bytes(bytes)?;
+            (to_set(parse_listpack(&listpack)?), used)
+        }
+        RDB_TYPE_HASH => {
+            let (pairs, used) = parse_string_pairs(bytes)?;
+            (to_hash(pairs), used)
+        }
+        RDB_TYPE_HASH_ZIPLIST => {
+            let (ziplist, used) = parse_bytes(bytes)?;
+            (to_hash(parse_ziplist(&ziplist)?), used)
+        }
+        RDB_TYPE_HASH_LISTPACK => {
+            let (listpack, used) = parse_bytes(bytes)?;
+            (to_hash(parse_listpack(&listpack)?), used)
+        }
+        RDB_TYPE_ZSET => {
+            let (len, mut offset) = parse_len(bytes)?;
+            let mut zset = ZSet::new();
+            for _ in 0..len {
+                let (member, used) = parse_string(&bytes[offset..])?;
+                offset += used;
+                // Scores are stored as text behind a one-byte length
+                let score = match byte_at(bytes, offset)? {
+                    253 => return Err(ZSET_NAN_SCORE.to_string()),
+                    254 => f64::INFINITY,
+                    255 => f64::NEG_INFINITY,
+                    len => {
+                        let text = take(bytes, offset + 1, len as usize)?;
+                        offset += len as usize;
+                        String::from_utf8_lossy(text).parse().unwrap_or(0.0)
+                    }
+                };
+                offset += 1;
+                zset.zadd(zset_score(score)?, member);
+            }
+            (ValueType::ZSet(zset), offset)
+        }
+        RDB_TYPE_ZSET_2 => {
+            let (len, mut offset) = parse_len(bytes)?;
+            let mut zset = ZSet::new();
+            for _ in 0..len {
+                let (member, used) = parse_string(&bytes[offset..])?;
+                offset += used;
+                let score = f64::from_le_bytes(take_array(bytes, offset)?);
+                offset += 8;
+                zset.zadd(zset_score(score)?, member);
+            }
+            (ValueType::ZSet(zset), offset)
+        }
+        RDB_TYPE_ZSET_ZIPLIST => {
+            let (ziplist, used) = parse_bytes(bytes)?;
+            (to_zset(parse_ziplist(&ziplist)?)?, used)
+        }
+        RDB_TYPE_ZSET_LISTPACK => {
+            let (listpack, used) = parse_bytes(bytes)?;
+            (to_zset(parse_listpack(&listpack)?)?, used)
+        }
+        RDB_TYPE_STREAM_LISTPACKS | RDB_TYPE_STREAM_LISTPACKS_2 | RDB_TYPE_STREAM_LISTPACKS_3 => {
+            let (stream, used) = parse_stream(value_type, bytes)?;
+            (ValueType::Stream(stream), used)
+        }
+        RDB_TYPE_HASH_ZIPMAP => {
+            // Pre-2.6 hash encoding, stored as a single blob
+            let (_, used) = parse_bytes(bytes)?;
+            return Ok((None, used));
+        }
+        _ => return Err(format!("unsupported value type {:#x}", value_type)),
+    };
+    Ok((Some(value), used))
+}
+
+fn to_list(items: Vec<String>) -> ValueType {
+    ValueType::List(items.into_iter().map(String::into_bytes).collect())
+}
+
+fn to_set<T: Into<Vec<u8>>>(members: Vec<T>) -> ValueType {
+    ValueType::Set(
+        members
+            .into_iter()
+            .map(|member| ValueType::String(member.into()))
+            .collect(),
+    )
+}
+
+fn to_hash(pairs: Vec<String>) -> ValueType {
+    let mut fields = HashMap::with_capacity(pairs.len() / 2);
+    for pair in pairs.chunks_exact(2) {
+        fields.insert(
+            pair[0].clone(),
+            ValueType::String(pair[1].clone().into_bytes()),
+        );
+    }
+    ValueType::Hash(fields)
+}
+
+fn to_zset(pairs: Vec<String>) -> Result<ValueType, String> {
+    let mut zset = ZSet::new();
+    for pair in pairs.chunks_exact(2) {
+        zset.zadd(zset_score(pair[1].parse().unwrap_or(0.0))?, pair[0].clone());
+    }
+    Ok(ValueType::ZSet(zset))
+}
+
+const ZSET_NAN_SCORE: &str = "sorted set with NaN score";
+
+/// The skiplist can't order NaN, so Redis refuses such files too.
+fn zset_score(score: f64) -> Result<f64, String> {
+    if score.is_nan() {
+        return Err(ZSET_NAN_SCORE.to_string());
+    }
+    Ok(score)
+}
+
+fn parse_string_pairs(bytes: &[u8]) -> Result<(Vec<String>, usize), String> {
+    let (len, mut offset) = parse_len(bytes)?;
+    // Lengths come from the file, so don't trust them for preallocation
+    let mut items = Vec::new();
+    for _ in 0..len {
+        for _ in 0..2 {
+            let (item, used) = parse_string(&bytes[offset..])?;
+            offset += used;
+            items.push(item);
+        }
+    }
+    Ok((items, offset))
+}
+
+fn parse_bytes_list(bytes: &[u8]) -> Result<(Vec<Vec<u8>>, usize), String> {
+    let (len, mut offset) = parse_len(bytes)?;
+    let mut items = Vec::new();
+    for _ in 0..len {
+        let (item, used) = parse_bytes(&bytes[offset..])?;
+        offset += used;
+        items.push(item);
+    }
+    Ok((items, offset))
+}
+
+fn parse_stream_id(bytes: &[u8]) -> Result<(u64, u64), String> {
+    Ok((
+        u64::from_be_bytes(take_array(bytes, 0)?),
+        u64::from_be_bytes(take_array(bytes, 8)?),
+    ))
+}
+
+/// Decodes a stream in any of the listpack layouts (v1 to v3).
+fn parse_stream(value_type: u8, bytes: &[u8]) -> Result<(Stream, usize), String> {
+    let mut stream = Stream::new();
+    let (nodes, mut offset) = parse_len(bytes)?;
+
+    for _ in 0..nodes {
+        let (master_id, used) = parse_bytes(&bytes[offset..])?;
+        offset += used;
+        let (master_ms, master_seq) = parse_stream_id(&master_id)?;
+        let (lp, used) = parse_bytes(&bytes[offset..])?;
+        offset += used;
+
+        let elements = parse_listpack(&lp)?;
+        let element = |i: usize| {
+            elements
+                .get(i)
+                .ok_or_else(|| "truncated stream listpack".to_string())
+        };
+        let int_at = |i: usize| element(i).map(|e| e.parse::<i64>().unwrap_or(0));
+
+        let count = int_at(0)?.saturating_add(int_at(1)?);
+        let master_fields_len = int_at(2)? as usize;
+        let master_fields = elements
+            .get(3..3usize.saturating_add(master_fields_len))
+            .ok_or_else(|| "truncated stream listpack".to_string())?;
+        // Skip the master entry's trailing 0
+        let mut i = 3 + master_fields_len + 1;
+
+        for _ in 0..count {
+            let flags = int_at(i)?;
+            let ms = (master_ms as i64).wrapping_add(int_at(i + 1)?) as u64;
+            let seq = (master_seq as i64).wrapping_add(int_at(i + 2)?) as u64;
+            i += 3;
+
+            let mut key_val = Vec::new();
+            if flags & STREAM_ITEM_FLAG_SAMEFIELDS != 0 {
+                for field in master_fields {
+                    key_val.push((field.clone(), element(i)?.clone()));
+                    i += 1;
                 }
-                _ => {
-                    panic!("Unknown special string encoding: {}", format);
+            } else {
+                let fields_len = int_at(i)?;
+                i += 1;
+                for _ in 0..fields_len {
+                    key_val.push((element(i)?.clone(), element(i + 1)?.clone()));
+                    i += 2;
                 }
             }
+            // lp-count, only used for backward iteration
+            i += 1;
+
+            if flags & STREAM_ITEM_FLAG_DELETED == 0 {
+                stream.entries.insert((ms, seq), key_val);
+            }
         }
-        _ => unreachable!(),
     }
-}
 
-pub fn parse_expiry(bytes: &[u8]) -> Option<(u64, bool, usize)> {
-    match bytes[0] {
-        0xFD => {
-            let ts = u32::from_le_bytes(bytes[1..5].try_into().unwrap()) as u64;
-            Some((ts * 1000, false, 5))
+    let (_length, used) = parse_len(&bytes[offset..])?;
+    offset += used;
+    let (last_ms, used) = parse_len(&bytes[offset..])?;
+    offset += used;
+    let (last_seq, used) = parse_len(&bytes[offset..])?;
+    offset += used;
+    stream.last_id = Some((last_ms as u64, last_seq as u64));
+
+    if value_type >= RDB_TYPE_STREAM_LISTPACKS_2 {
+        // First id is derived from the entries
+        for _ in 0..2 {
+            let (_, used) = parse_len(&bytes[offset..])?;
+            offset += used;
         }
-        0xFC => {
-            let ts = u64::from_le_bytes(bytes[1..9].try_into().unwrap());
-            Some((ts, true, 9))
+        let (deleted_ms, used) = parse_len(&bytes[offset..])?;
+        offset += used;
+        let (deleted_seq, used) = parse_len(&bytes[offset..])?;
+        offset += used;
+        stream.max_deleted_id = (deleted_ms as u64, deleted_seq as u64);
+        let (entries_added, used) = parse_len(&bytes[offset..])?;
+        offset += used;
+        stream.entries_added = entries_added as u64;
+    } else {
+        stream.entries_added = stream.entries.len() as u64;
+    }
+
+    let (groups, used) = parse_len(&bytes[offset..])?;
+    offset += used;
+    for _ in 0..groups {
+        let (name, used) = parse_string(&bytes[offset..])?;
+        offset += used;
+        let (last_ms, used) = parse_len(&bytes[offset..])?;
+        offset += used;
+        let (last_seq, used) = parse_len(&bytes[offset..])?;
+        offset += used;
+
+        let mut entries_read = None;
+        if value_type >= RDB_TYPE_STREAM_LISTPACKS_2 {
+            let (read, used) = parse_len(&bytes[offset..])?;
+            offset += used;
+            if read as u64 != u64::MAX {
+                entries_read = Some(read as u64);
+            }
         }
-        _ => None,
+        let mut group = ConsumerGroup::new((last_ms as u64, last_seq as u64), entries_read);
+
+        let (pending_len, used) = parse_len(&bytes[offset..])?;
+        offset += used;
+        for _ in 0..pending_len {
+            let id = parse_stream_id(&bytes[offset..])?;
+            offset += 16;
+            let delivery_time = u64::from_le_bytes(take_array(bytes, offset)?);
+            offset += 8;
+            let (delivery_count, used) = parse_len(&bytes[offset..])?;
+            offset += used;
+            group.pending.insert(
+                id,
+                PendingEntry {
+                    consumer: String::new(),
+                    delivery_time,
+                    delivery_count: delivery_count as u64,
+                },
+            );
+        }
+
+        let (consumers, used) = parse_len(&bytes[offset..])?;
+        offset += used;
+        for _ in 0..consumers {
+            let (consumer_name, used) = parse_string(&bytes[offset..])?;
+            offset += used;
+            let seen_time = u64::from_le_bytes(take_array(bytes, offset)?);
+            offset += 8;
+            if value_type >= RDB_TYPE_STREAM_LISTPACKS_3 {
+                // active-time
+                take(bytes, offset, 8)?;
+                offset += 8;
+            }
+            let (owned, used) = parse_len(&bytes[offset..])?;
+            offset += used;
+            for _ in 0..owned {
+                let id = parse_stream_id(&bytes[offset..])?;
+                offset += 16;
+                if let Some(pending) = group.pending.get_mut(&id) {
+                    pending.consumer = consumer_name.clone();
+                }
+            }
+            group
+                .consumers
+                .insert(consumer_name, Consumer { seen_time });
+        }
+
+        stream.groups.insert(name, group);
+    }
+
+    Ok((stream, offset))
+}
+
+fn lossy_string(bytes: &[u8], start: usize, len: usize) -> Result<String, String> {
+    Ok(String::from_utf8_lossy(take(bytes, start, len)?).to_string())
+}
+
+/// Decodes every ziplist entry to its string form.
+fn parse_ziplist(zl: &[u8]) -> Result<Vec<String>, String> {
+    let mut entries = Vec::new();
+    // Skip zlbytes, zltail and zllen
+    let mut i = 10;
+
+    while i < zl.len() && zl[i] != 0xFF {
+        // Previous entry length: one byte, or 0xFE and four more
+        i += if zl[i] == 0xFE { 5 } else { 1 };
+
+        let encoding = byte_at(zl, i)?;
+        let (value, size) = match encoding >> 6 {
+            0b00 => {
+                let len = (encoding & 0x3F) as usize;
+                (lossy_string(zl, i + 1, len)?, 1 + len)
+            }
+            0b01 => {
+                let len = (((encoding & 0x3F) as usize) << 8) | byte_at(zl, i + 1)? as usize;
+                (lossy_string(zl, i + 2, len)?, 2 + len)
+            }
+            0b10 => {
+                let len = u32::from_be_bytes(take_array(zl, i + 1)?) as usize;
+                (lossy_string(zl, i + 5, len)?, 5 + len)
+            }
+            _ => match encoding {
+                0xC0 => (i16::from_le_bytes(take_array(zl, i + 1)?).to_string(), 3),
+                0xD0 => (i32::from_le_bytes(take_array(zl, i + 1)?).to_string(), 5),
+                0xE0 => (i64::from_le_bytes(take_array(zl, i + 1)?).to_string(), 9),
+                0xF0 => {
+                    let [b1, b2, b3] = take_array(zl, i + 1)?;
+                    let value = i32::from_le_bytes([0, b1, b2, b3]) >> 8;
+                    (value.to_string(), 4)
+                }
+                0xFE => ((byte_at(zl, i + 1)? as i8).to_string(), 2),
+                // 4-bit immediate, stored off by one
+                0xF1..=0xFD => (((encoding & 0x0F) - 1).to_string(), 1),
+                _ => return Err(format!("invalid ziplist encoding {:#x}", encoding)),
+            },
+        };
+        entries.push(value);
+        i += size;
     }
+
+    Ok(entries)
 }
 
-pub fn parse_key_value(bytes: &[u8]) -> (String, usize, u8) {
-    let value_type = bytes[0];
-    let (key, key_used) = parse_string(&bytes[1..]);
-    (key, key_used + 1, value_type)
+/// Decodes an intset: encoding width, count, then little-endian integers.
+fn parse_intset(bytes: &[u8]) -> Result<Vec<String>, String> {
+    let width = u32::from_le_bytes(take_array(bytes, 0)?) as usize;
+    let len = u32::from_le_bytes(take_array(bytes, 4)?) as usize;
+    if !matches!(width, 2 | 4 | 8) {
+        return Err(format!("invalid intset encoding {}", width));
+    }
+    (0..len)
+        .map(|n| {
+            let item = take(bytes, 8 + n * width, width)?;
+            Ok(match width {
+                2 => i16::from_le_bytes(item.try_into().unwrap()).to_string(),
+                4 => i32::from_le_bytes(item.try_into().unwrap()).to_string(),
+                _ => i64::from_le_bytes(item.try_into().unwrap()).to_string(),
+            })
+        })
+        .collect()
 }
 
-pub fn parse_value_by_type(value_type: u8, bytes: &[u8]) -> (String, usize) {
-    match value_type {
-        0x00 => parse_string(bytes), // String
-        // Add more types as needed (e.g., list, set, etc.)
-        _ => panic!("Unsupported value type: {:#x}", value_type),
+/// Decodes every listpack element to its string form.
+fn parse_listpack(lp: &[u8]) -> Result<Vec<String>, String> {
+    let mut elements = Vec::new();
+    // Skip total-bytes and num-elements
+    let mut i = 6;
+
+    while i < lp.len() && lp[i] != 0xFF {
+        let encoding = lp[i];
+        let (value, size) = if encoding & 0x80 == 0 {
+            ((encoding & 0x7F).to_string(), 1)
+        } else if encoding & 0xC0 == 0x80 {
+            let len = (encoding & 0x3F) as usize;
+            (lossy_string(lp, i + 1, len)?, 1 + len)
+        } else if encoding & 0xE0 == 0xC0 {
+            let raw = (((encoding & 0x1F) as i64) << 8) | byte_at(lp, i + 1)? as i64;
+            let value = if raw >= 1 << 12 { raw - (1 << 13) } else { raw };
+            (value.to_string(), 2)
+        } else if encoding & 0xF0 == 0xE0 {
+            let len = (((encoding & 0x0F) as usize) << 8) | byte_at(lp, i + 1)? as usize;
+            (lossy_string(lp, i + 2, len)?, 2 + len)
+        } else {
+            match encoding {
+                0xF0 => {
+                    let len = u32::from_le_bytes(take_array(lp, i + 1)?) as usize;
+                    (lossy_string(lp, i + 5, len)?, 5 + len)
+                }
+                0xF1 => (i16::from_le_bytes(take_array(lp, i + 1)?).to_string(), 3),
+                0xF2 => {
+                    // Sign-extend the 24-bit value through the top byte
+                    let [b1, b2, b3] = take_array(lp, i + 1)?;
+                    let value = i32::from_le_bytes([0, b1, b2, b3]) >> 8;
+                    (value.to_string(), 4)
+                }
+                0xF3 => (i32::from_le_bytes(take_array(lp, i + 1)?).to_string(), 5),
+                0xF4 => (i64::from_le_bytes(take_array(lp, i + 1)?).to_string(), 9),
+                _ => return Err(format!("invalid listpack encoding {:#x}", encoding)),
+            }
+        };
+        elements.push(value);
+
+        // Skip the element plus its back-length
+        let backlen = match size {
+            0..=127 => 1,
+            128..=16382 => 2,
+            16383..=2097150 => 3,
+            2097151..=268435454 => 4,
+            _ => 5,
+        };
+        i += size + backlen;
     }
+
+    Ok(elements)
 }
 
+/// Runs the replica handshake (PING, REPLCONF, PSYNC). With `resume` set it
+/// asks to continue from that replid and offset; on a full resync the RDB the
+/// master sends is stored at `rdb_path`. The returned stream is positioned at
+/// the start of the command stream.
 pub fn sync_with_master(
     host: &str,
     port_str: &str,
     listening_port: &String,
-    dbfilename: &String,
-) -> TcpStream {
-    let mut stream = TcpStream::connect(format!("{}:{}", host, port_str)).unwrap();
+    rdb_path: &str,
+    resume: Option<(&str, usize)>,
+) -> std::io::Result<(TcpStream, PsyncResult)> {
+    let mut stream = TcpStream::connect(format!("{}:{}", host, port_str))?;
 
     let ping_cmd = b"*1\r\n$4\r\nPING\r\n";
-    stream.write_all(ping_cmd).unwrap();
-    stream.flush().unwrap();
-
-    {
-        let mut resp = [0u8; 1024];
-        let n = stream.read(&mut resp).unwrap();
-        if n == 0 {
-            panic!("No response from master after PING");
-        }
-    }
+    stream.write_all(ping_cmd)?;
+    stream.flush()?;
+    read_handshake_reply(&mut stream, "PING")?;
 
     let port_str = listening_port.to_string();
     let listening_port_len = port_str.len().to_string();
@@ -241,44 +854,64 @@ pub fn sync_with_master(
         "*3\r\n$8\r\nREPLCONF\r\n$14\r\nlistening-port\r\n${}\r\n{}\r\n",
         listening_port_len, port_str
     );
-    stream.write_all(replconf_listen.as_bytes()).unwrap();
-    stream.flush().unwrap();
-
-    {
-        let mut resp = [0u8; 1024];
-        let n = stream.read(&mut resp).unwrap();
-        if n == 0 {
-            panic!("No response from master after REPLCONF listening-port");
-        }
-    }
+    stream.write_all(replconf_listen.as_bytes())?;
+    stream.flush()?;
+    read_handshake_reply(&mut stream, "REPLCONF listening-port")?;
 
     let replconf_capa = "*3\r\n$8\r\nREPLCONF\r\n$4\r\ncapa\r\n$6\r\npsync2\r\n";
-    stream.write_all(replconf_capa.as_bytes()).unwrap();
-    stream.flush().unwrap();
+    stream.write_all(replconf_capa.as_bytes())?;
+    stream.flush()?;
+    read_handshake_reply(&mut stream, "REPLCONF capa")?;
 
-    {
-        let mut resp = [0u8; 1024];
-        let n = stream.read(&mut resp).unwrap();
-        if n == 0 {
-            panic!("No response from master after REPLCONF capa");
-        }
-    }
-
-    let psync_cmd = "*3\r\n$5\r\nPSYNC\r\n$1\r\n?\r\n$2\r\n-1\r\n";
-    stream.write_all(psync_cmd.as_bytes()).unwrap();
-    stream.flush().unwrap();
+    let psync_cmd = match resume {
+        Some((replid, offset)) => encode_resp_command(&["PSYNC", replid, &offset.to_string()]),
+        None => encode_resp_command(&["PSYNC", "?", "-1"]),
+    };
+    stream.write_all(&psync_cmd)?;
+    stream.flush()?;
 
+    let mut result = PsyncResult::FullResync {
+        replid: String::new(),
+        offset: 0,
+    };
     loop {
         let mut header = Vec::new();
         while header.len() < 2 || !header.ends_with(b"\r\n") {
             let mut byte = [0u8; 1];
-            let n = stream.read(&mut byte).unwrap();
+            let n = stream.read(&mut byte)?;
             if n == 0 {
-                break;
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "master closed connection during PSYNC",
+                ));
             }
             header.push(byte[0]);
         }
 
+        // +CONTINUE [replid]: the missing bytes follow as ordinary commands
+        if header.starts_with(b"+CONTINUE") {
+            return Ok((stream, PsyncResult::Continue));
+        }
+
+        // +FULLRESYNC <replid> <offset>
+        if header.starts_with(b"+FULLRESYNC") {
+            let header_str = String::from_utf8_lossy(&header);
+            let mut parts = header_str.split_whitespace().skip(1);
+            if let (Some(replid), Some(offset)) = (parts.next(), parts.next()) {
+                result = PsyncResult::FullResync {
+                    replid: replid.to_string(),
+                    offset: offset.parse().unwrap_or(0),
+                };
+            }
+            continue;
+        }
+
+        if header.starts_with(b"-") {
+            return Err(std::io::Error::other(
+                String::from_utf8_lossy(&header).trim_end().to_string(),
+            ));
+        }
+
         if header.starts_with(b"$") {
             // Parse the length
             let header_str = String::from_utf8_lossy(&header);
@@ -287,21 +920,28 @@ pub fn sync_with_master(
                 if let Ok(file_len) = len_str.parse::<usize>() {
                     // Read the binary contents of the file
                     let mut file_contents = vec![0u8; file_len];
-                    let mut read_total = 0;
-                    while read_total < file_len {
-                        let n = stream.read(&mut file_contents[read_total..]).unwrap();
-                        if n == 0 {
-                            break;
-                        }
-                        read_total += n;
+                    stream.read_exact(&mut file_contents)?;
+                    if let Err(e) = write_to_file(rdb_path, file_contents) {
+                        eprintln!("Failed to save RDB from master to {rdb_path}: {e}");
                     }
-                    write_to_file(&dbfilename, file_contents).unwrap();
                     break;
                 }
             }
         }
     }
-    stream
+    Ok((stream, result))
+}
+
+fn read_handshake_reply(stream: &mut TcpStream, step: &str) -> std::io::Result<()> {
+    let mut resp = [0u8; 1024];
+    let n = stream.read(&mut resp)?;
+    if n == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!("no response from master after {step}"),
+        ));
+    }
+    Ok(())
 }
 
 pub fn write_to_file(filename: &str, contents: Vec<u8>) -> std::io::Result<()> {
@@ -311,146 +951,382 @@ pub fn write_to_file(filename: &str, contents: Vec<u8>) -> std::io::Result<()> {
     Ok(())
 }
 
-pub fn propagate_slaves(global_state: &RedisGlobalType, message: &str) {
-    let msg = message.to_string();
+/// A command as a RESP array of bulk strings. Arguments are taken as bytes,
+/// so the frame carries whatever they hold unchanged.
+pub fn encode_resp_command<T: AsRef<[u8]>>(args: &[T]) -> Vec<u8> {
+    // Room for each argument plus its "$<len>\r\n...\r\n" framing
+    let size: usize = args.iter().map(|arg| arg.as_ref().len() + 16).sum();
+    let mut resp = Vec::with_capacity(size + 16);
+    let _ = write!(resp, "*{}\r\n", args.len());
+    for arg in args {
+        let arg = arg.as_ref();
+        let _ = write!(resp, "${}\r\n", arg.len());
+        resp.extend_from_slice(arg);
+        resp.extend_from_slice(b"\r\n");
+    }
+    resp
+}
 
-    // First, collect the senders while holding the lock, then drop the lock before sending.
-    let senders: Vec<_> = {
-        let mut global_guard = global_state.lock().unwrap();
-        if !global_guard.is_master() {
-            return;
+pub fn encode_stream_entry(id: (u64, u64), fields: Option<&[(String, String)]>) -> String {
+    let id = format!("{}-{}", id.0, id.1);
+    let mut resp = format!("*2\r\n${}\r\n{}\r\n", id.len(), id);
+    match fields {
+        Some(fields) => {
+            resp.push_str(&format!("*{}\r\n", fields.len() * 2));
+            for (k, v) in fields {
+                resp.push_str(&format!("${}\r\n{}\r\n", k.len(), k));
+                resp.push_str(&format!("${}\r\n{}\r\n", v.len(), v));
+            }
         }
-        global_guard.offset_replica_sync += num_bytes(&msg);
-        global_guard
-            .replica_states
-            .values()
-            .map(|replica| replica.sender.clone())
-            .collect()
-    };
+        None => resp.push_str("*-1\r\n"),
+    }
+    resp
+}
 
-    for sender in senders {
-        // Send message to replica’s channel
-        if let Err(e) = sender.send(msg.clone()) {
-            eprintln!("Failed to queue message for replica: {:?}", e);
+/// Stream entries as the array XRANGE and XREAD reply with, each one an
+/// [id, [field, value, ...]] pair, serialized in a single buffer.
+pub fn serialize_entries<'a>(entries: impl Iterator<Item = EntryRef<'a>>) -> Vec<u8> {
+    let mut body = Vec::new();
+    let mut count = 0;
+    for ((ms, seq), key_val) in entries {
+        count += 1;
+        let id = format!("{}-{}", ms, seq);
+        let _ = write!(
+            body,
+            "*2\r\n${}\r\n{}\r\n*{}\r\n",
+            id.len(),
+            id,
+            key_val.len() * 2
+        );
+        for (field, value) in key_val {
+            let _ = write!(
+                body,
+                "${}\r\n{}\r\n${}\r\n{}\r\n",
+                field.len(),
+                field,
+                value.len(),
+                value
+            );
         }
     }
+    // The count is only known once the entries were walked
+    let mut resp = Vec::with_capacity(body.len() + 16);
+    let _ = write!(resp, "*{}\r\n", count);
+    resp.extend_from_slice(&body);
+    resp
 }
 
-pub fn offset_difference(master_offset: usize, replica_offset: usize) -> usize {
-    master_offset - replica_offset
+thread_local! {
+    // Writes propagated while this connection's thread runs EXEC, sent to the
+    // replicas as one MULTI ... EXEC block once the transaction is done
+    static EXEC_PROPAGATION: RefCell<Option<Vec<Vec<u8>>>> = const { RefCell::new(None) };
 }
 
-pub fn num_bytes(s: &str) -> usize {
-    s.as_bytes().len()
+/// Buffers what this thread propagates until the returned guard is dropped,
+/// which sends it all as one MULTI ... EXEC block. That also happens when a
+/// queued command panics, so the writes EXEC already made still reach the
+/// replicas and the next client on the thread finds the buffer unset.
+pub fn begin_exec_propagation(global_state: &RedisGlobalType) -> ExecPropagation<'_> {
+    EXEC_PROPAGATION.with(|buffer| *buffer.borrow_mut() = Some(Vec::new()));
+    ExecPropagation { global_state }
 }
 
-pub fn update_replica_offsets(global_state: &RedisGlobalType) {
-    let (master_offset, replica_states_keys): (i64, Vec<String>) = {
-        let global_guard = global_state.lock().unwrap();
-        (
-            global_guard.offset_replica_sync as i64,
-            global_guard.replica_states.keys().cloned().collect(),
-        )
-    };
-
-    let mut local_offset_updates: Vec<(String, usize)> = Vec::new();
-
-    for slave_port in &replica_states_keys {
-        let replica_state_arc = {
-            let global_guard = global_state.lock().unwrap();
-            match global_guard.replica_states.get(slave_port) {
-                Some(replica) => replica.stream.clone(),
-                None => continue,
-            }
-        };
+pub struct ExecPropagation<'a> {
+    global_state: &'a RedisGlobalType,
+}
 
-        let mut stream_guard = match replica_state_arc.lock() {
-            Ok(guard) => guard,
-            Err(_) => {
-                eprintln!("Failed to lock stream for replica {}", slave_port);
-                continue;
-            }
+impl Drop for ExecPropagation<'_> {
+    fn drop(&mut self) {
+        let propagations = EXEC_PROPAGATION.with(|buffer| buffer.borrow_mut().take());
+        let Some(propagations) = propagations.filter(|p| !p.is_empty()) else {
+            return;
         };
 
-        write_array(
-            &mut *stream_guard,
-            &[Some("REPLCONF"), Some("GETACK"), Some("*")],
-        );
+        propagate_slaves(self.global_state, &encode_resp_command(&["MULTI"]));
+        for propagation in &propagations {
+            propagate_slaves(self.global_state, propagation);
+        }
+        propagate_slaves(self.global_state, &encode_resp_command(&["EXEC"]));
+    }
+}
 
-        let mut buf = [0u8; 1024];
-        match stream_guard.read(&mut buf) {
-            Ok(n) if n > 0 => {
-                let mut offset = 0;
-                while offset < n {
-                    match Request::try_parse(&buf[offset..n]) {
-                        Some((req, consumed)) => {
-                            if req.args.len() >= 3
-                                && req.args[0].eq_ignore_ascii_case("REPLCONF")
-                                && req.args[1].eq_ignore_ascii_case("ACK")
-                            {
-                                if let Ok(replica_offset) = req.args[2].parse::<i64>() {
-                                    let diff = master_offset - replica_offset;
-                                    if diff != 0 {
-                                        eprintln!("replica is behind the master by {}", diff);
-                                    }
-                                    local_offset_updates
-                                        .push((slave_port.clone(), replica_offset as usize));
-                                }
-                            }
-                            offset += consumed;
-                        }
-                        None => {
-                            break;
-                        }
-                    }
-                }
-            }
-            Ok(_) => {}
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
-            Err(e) => {
-                eprintln!("Error reading response from slave {}: {:?}", slave_port, e);
-            }
+pub fn propagate_slaves(global_state: &RedisGlobalType, message: &[u8]) {
+    let is_buffered = EXEC_PROPAGATION.with(|buffer| match buffer.borrow_mut().as_mut() {
+        Some(propagations) => {
+            propagations.push(message.to_vec());
+            true
         }
+        None => false,
+    });
+    if is_buffered {
+        return;
     }
 
     {
         let mut global_guard = global_state.lock().unwrap();
-        for (slave_port, new_offset) in local_offset_updates {
-            if let Some(replica) = global_guard.replica_states.get_mut(&slave_port) {
-                replica.local_offset = new_offset;
+        if global_guard.loading {
+            return;
+        }
+        global_guard.feed_aof(message);
+    }
+    send_to_replicas(global_state, message);
+}
+
+/// Sends to the replicas without touching the AOF. Link control (PING,
+/// REPLCONF GETACK) goes straight through here.
+///
+/// The frame is copied once and shared by every replica's queue. It is
+/// queued with the lock held, so each replica gets writes in backlog order.
+pub fn send_to_replicas(global_state: &RedisGlobalType, message: &[u8]) {
+    let mut global_guard = global_state.lock().unwrap();
+    if !global_guard.is_master() || global_guard.loading {
+        return;
+    }
+    global_guard.offset_replica_sync += message.len();
+    global_guard.repl_backlog.push(message);
+    if global_guard.replica_states.is_empty() {
+        return;
+    }
+
+    let frame = Bytes::copy_from_slice(message);
+    let limit = global_guard.replica_output_buffer_limit;
+    let mut dropped = Vec::new();
+    for (port, replica) in global_guard.replica_states.iter_mut() {
+        // Kept until the replica's snapshot is queued ahead of it
+        if let Some(held_back) = replica.sync_buffer.as_mut() {
+            held_back.extend_from_slice(message);
+            if limit.hard > 0 && held_back.len() as u64 > limit.hard {
+                dropped.push((port.clone(), "output buffer limit reached"));
             }
+            continue;
         }
-        if !global_guard.replica_states.is_empty() {
-            global_guard.offset_replica_sync += 37;
+        // Queuing never blocks, so a replica that stopped reading is cut off
+        // once too much piled up for it
+        if replica.sender.send(frame.clone()).is_err() {
+            dropped.push((port.clone(), "its link failed"));
+        } else if replica.exceeds(&limit) {
+            dropped.push((port.clone(), "output buffer limit reached"));
         }
     }
+    for (port, reason) in dropped {
+        drop_replica(&mut global_guard, &port, reason);
+    }
 }
 
-pub fn parse_range(range: &String, last_entry_id: Option<(u64, u64)>) -> Option<(u64, u64)> {
-    if range == "-" {
-        return Some((0, 0));
+pub fn mark_dirty(global_state: &RedisGlobalType, key: &str) {
+    global_state.lock().unwrap().mark_dirty(key);
+}
+
+/// Runs `read` on the value of `key` with its shard locked only once, for
+/// reads that would otherwise lock it again to check the expiry. An expired
+/// key reads as missing and is expired once the shard is unlocked.
+pub fn read_live<T>(
+    db: &DbType,
+    global_state: &RedisGlobalType,
+    key: &str,
+    read: impl FnOnce(Option<&ValueType>) -> T,
+) -> T {
+    let (result, expired) = {
+        let map = db.lock(key);
+        match map.entry(key) {
+            Some(entry) if entry.is_expired() => (read(None), true),
+            entry => (read(entry.map(|entry| &entry.value)), false),
+        }
+    };
+    if expired {
+        expire_if_needed(db, global_state, key);
+    }
+    result
+}
+
+/// Lazily expires `key`. Only the master deletes it, propagating a DEL; a
+/// replica keeps the data until that DEL arrives. Returns true when the key is
+/// logically gone either way.
+pub fn expire_if_needed(db: &DbType, global_state: &RedisGlobalType, key: &str) -> bool {
+    let expired = db
+        .lock(key)
+        .entry(key)
+        .is_some_and(|entry| entry.is_expired());
+    if !expired {
+        return false;
     }
 
-    if range == "+" || range == "$" {
-        return Some(last_entry_id.unwrap());
+    let is_master = global_state.lock().unwrap().is_master();
+    if is_master {
+        remove_expired_key(db, global_state, key);
     }
+    true
+}
 
-    if range.contains("-") {
-        let parts: Vec<&str> = range.split('-').collect();
-        if parts.len() == 2 {
-            if let (Ok(ms), Ok(seq)) = (parts[0].parse::<u64>(), parts[1].parse::<u64>()) {
-                return Some((ms, seq));
-            } else {
-                return None;
+/// Deletes an expired key on the master and tells the replicas. A key
+/// that was set again since it expired is left alone.
+pub fn remove_expired_key(db: &DbType, global_state: &RedisGlobalType, key: &str) {
+    let removed = {
+        let mut map = db.lock(key);
+        let expired = map.entry(key).is_some_and(|entry| entry.is_expired());
+        expired && map.remove(key).is_some()
+    };
+    if removed {
+        record_expired_key(global_state, key);
+    }
+}
+
+fn record_expired_key(global_state: &RedisGlobalType, key: &str) {
+    mark_dirty(global_state, key);
+    global_state.lock().unwrap().stat_expiredkeys += 1;
+    propagate_slaves(global_state, &encode_resp_command(&["DEL", key]));
+}
+
+/// One round of active expiration, the way Redis does it: sample up to 20
+/// keys with an expiry from a shard, delete the expired ones, and sample the
+/// same shard again while more than a quarter of them were expired. Each
+/// sample locks just its shard. Stops early once `budget` is spent; `cursor`
+/// remembers where the next round picks up.
+pub fn active_expire_cycle(
+    db: &DbType,
+    global_state: &RedisGlobalType,
+    cursor: &mut usize,
+    budget: Duration,
+) {
+    const KEYS_PER_LOOP: usize = 20;
+    const ACCEPTABLE_STALE_PERCENT: usize = 25;
+
+    let start = Instant::now();
+    for _ in 0..db.shard_count() {
+        let shard = *cursor;
+        *cursor = (*cursor + 1) % db.shard_count();
+        loop {
+            let (sampled, expired) = db.expire_sample(shard, KEYS_PER_LOOP);
+            for key in &expired {
+                record_expired_key(global_state, key);
+            }
+            if start.elapsed() >= budget {
+                return;
+            }
+            if sampled == 0 || expired.len() * 100 <= sampled * ACCEPTABLE_STALE_PERCENT {
+                break;
             }
-        } else {
-            return None;
         }
-    } else {
-        if let Ok(ms) = range.parse::<u64>() {
-            return Some((ms, 0));
-        } else {
-            return None;
+    }
+}
+
+/// Records an access to each of `keys` that exists, for LRU eviction.
+pub fn touch_keys(db: &DbType, keys: &[&String]) {
+    let mut map = db.lock_keys(keys.iter().map(|key| key.as_str()));
+    let now = now_ms();
+    for key in keys {
+        if let Some(entry) = map.entry_mut(key) {
+            entry.last_access_ms = now;
+        }
+    }
+}
+
+/// Evicts keys under the maxmemory policy until the dataset fits, sending
+/// a DEL for each to the replicas. Returns false if it still doesn't fit.
+pub fn evict_if_needed(db: &DbType, global_state: &RedisGlobalType) -> bool {
+    let (maxmemory, policy) = {
+        let global = global_state.lock().unwrap();
+        (global.maxmemory, global.maxmemory_policy)
+    };
+    if maxmemory == 0 {
+        return true;
+    }
+
+    while db.used_memory() > maxmemory {
+        let Some(key) = pick_eviction_victim(db, policy) else {
+            return false;
+        };
+        // Another client may have removed it since it was sampled
+        if db.lock(&key).remove(&key).is_none() {
+            continue;
         }
+        mark_dirty(global_state, &key);
+        global_state.lock().unwrap().stat_evictedkeys += 1;
+        propagate_slaves(global_state, &encode_resp_command(&["DEL", &key]));
+    }
+    true
+}
+
+/// Picks the key to evict next, sampling a few keys from one shard at a time
+/// so no write waits on the whole dataset. LRU policies take the least
+/// recently used of the sample, as Redis does.
+fn pick_eviction_victim(db: &DbType, policy: EvictionPolicy) -> Option<String> {
+    const SAMPLES: usize = 5;
+    let (samples, volatile_only) = match policy {
+        EvictionPolicy::NoEviction => return None,
+        EvictionPolicy::AllKeysRandom => (1, false),
+        EvictionPolicy::AllKeysLru => (SAMPLES, false),
+        EvictionPolicy::VolatileLru => (SAMPLES, true),
+    };
+    // Start from a random shard and move on past the ones with no candidates
+    let first = rand::random_range(0..db.shard_count());
+    (0..db.shard_count()).find_map(|offset| {
+        let index = (first + offset) % db.shard_count();
+        db.eviction_sample(index, samples, volatile_only)
+            .into_iter()
+            .min_by_key(|(_, last_access_ms)| *last_access_ms)
+            .map(|(key, _)| key)
+    })
+}
+
+pub fn offset_difference(master_offset: usize, replica_offset: usize) -> usize {
+    master_offset - replica_offset
+}
+
+/// Heartbeat on the replication stream. It goes through the same channel as
+/// every other write, so it is ordered with them and counted in the offset.
+pub fn ping_replicas(global_state: &RedisGlobalType) {
+    let is_replicated = {
+        let global_guard = global_state.lock().unwrap();
+        !global_guard.replica_states.is_empty()
+    };
+    if is_replicated {
+        send_to_replicas(global_state, &encode_resp_command(&["PING"]));
+    }
+}
+
+/// Reads a stream ID bound, returning the ID and whether it is exclusive.
+/// A bare millisecond time stands for its first entry as a start bound and
+/// for its last one as an end bound, as Redis reads it.
+pub fn parse_range(
+    range: &str,
+    last_entry_id: Option<(u64, u64)>,
+    is_end: bool,
+) -> Option<((u64, u64), bool)> {
+    // A leading '(' makes the bound exclusive (XRANGE/XREVRANGE syntax)
+    let (range, is_exclusive) = match range.strip_prefix('(') {
+        Some(rest) => (rest, true),
+        None => (range, false),
+    };
+
+    match range {
+        "-" | "+" if is_exclusive => None,
+        "-" => Some(((0, 0), false)),
+        "+" => Some(((u64::MAX, u64::MAX), false)),
+        "$" => last_entry_id.map(|id| (id, is_exclusive)),
+        _ => {
+            let id = match range.split_once('-') {
+                Some((ms, seq)) => (ms.parse().ok()?, seq.parse().ok()?),
+                None if is_end => (range.parse().ok()?, u64::MAX),
+                None => (range.parse().ok()?, 0),
+            };
+            Some((id, is_exclusive))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_range_reads_a_bare_time_by_bound_side() {
+        assert_eq!(parse_range("5", None, false), Some(((5, 0), false)));
+        assert_eq!(parse_range("5", None, true), Some(((5, u64::MAX), false)));
+        assert_eq!(parse_range("(5-1", None, false), Some(((5, 1), true)));
+        assert_eq!(parse_range("$", Some((7, 2)), false), Some(((7, 2), false)));
+        assert_eq!(parse_range("(+", None, true), None);
+        assert_eq!(parse_range("5-x", None, false), None);
+        assert_eq!(parse_range("1-2-3", None, false), None);
     }
 }
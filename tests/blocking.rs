@@ -0,0 +1,127 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::{wait_until, Reply, Server};
+
+#[test]
+fn blocked_blpop_clients_do_not_slow_down_others() {
+    let server = Server::start(&[]);
+    let mut blocked: Vec<_> = (0..50)
+        .map(|i| {
+            let mut client = server.client();
+            client.send(&["BLPOP", &format!("list:{}", i), "0"]);
+            client
+        })
+        .collect();
+    let mut client = server.client();
+    assert!(wait_until(Duration::from_secs(5), || {
+        client.info_field("clients", "blocked_clients") == 50
+    }));
+
+    let mut latencies = Vec::new();
+    for i in 0..2000 {
+        let sent = Instant::now();
+        assert_eq!(client.cmd(&["SET", "k", &i.to_string()]), Reply::ok());
+        assert_eq!(client.cmd(&["GET", "k"]), Reply::bulk(&i.to_string()));
+        latencies.push(sent.elapsed());
+    }
+    latencies.sort();
+    let p99 = latencies[latencies.len() * 99 / 100];
+    assert!(p99 < Duration::from_millis(20), "p99 was {:?}", p99);
+
+    // They are still waiting, and each gets its own element
+    for (i, waiter) in blocked.iter_mut().enumerate() {
+        let key = format!("list:{}", i);
+        client.cmd(&["RPUSH", &key, "x"]);
+        assert_eq!(waiter.read(), Reply::bulks(&[&key, "x"]));
+    }
+}
+
+#[test]
+fn blpop_serves_the_longest_waiting_client_first() {
+    let server = Server::start(&[]);
+    let mut client = server.client();
+    let mut first = server.client();
+    first.send(&["BLPOP", "q", "0"]);
+    assert!(wait_until(Duration::from_secs(5), || {
+        client.info_field("clients", "blocked_clients") == 1
+    }));
+    let mut second = server.client();
+    second.send(&["BLPOP", "q", "0"]);
+    assert!(wait_until(Duration::from_secs(5), || {
+        client.info_field("clients", "blocked_clients") == 2
+    }));
+
+    assert_eq!(
+        client.cmd(&["RPUSH", "q", "a", "b", "c"]),
+        Reply::Integer(3)
+    );
+    assert_eq!(first.read(), Reply::bulks(&["q", "a"]));
+    assert_eq!(second.read(), Reply::bulks(&["q", "b"]));
+    assert_eq!(
+        client.cmd(&["LRANGE", "q", "0", "-1"]),
+        Reply::bulks(&["c"])
+    );
+}
+
+#[test]
+fn blpop_on_several_keys_pops_the_first_with_elements() {
+    let server = Server::start(&[]);
+    let mut client = server.client();
+    client.cmd(&["RPUSH", "b", "1"]);
+    client.cmd(&["RPUSH", "c", "2"]);
+    assert_eq!(
+        client.cmd(&["BLPOP", "a", "b", "c", "0"]),
+        Reply::bulks(&["b", "1"])
+    );
+
+    let mut waiter = server.client();
+    waiter.send(&["BLPOP", "x", "y", "0"]);
+    assert!(wait_until(Duration::from_secs(5), || {
+        client.info_field("clients", "blocked_clients") == 1
+    }));
+    client.cmd(&["LPUSH", "y", "v"]);
+    assert_eq!(waiter.read(), Reply::bulks(&["y", "v"]));
+    // No longer queued on x either
+    client.cmd(&["RPUSH", "x", "w"]);
+    assert_eq!(
+        client.cmd(&["LRANGE", "x", "0", "-1"]),
+        Reply::bulks(&["w"])
+    );
+}
+
+#[test]
+fn blpop_times_out_with_a_null_reply() {
+    let server = Server::start(&[]);
+    let mut client = server.client();
+    let started = Instant::now();
+    assert_eq!(client.cmd(&["BLPOP", "empty", "0.2"]), Reply::Nil);
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    assert_eq!(client.info_field("clients", "blocked_clients"), 0);
+}
+
+#[test]
+fn element_handed_to_a_blocked_client_reaches_replicas_as_lpop() {
+    let master = Server::start(&[]);
+    let mut client = master.client();
+    let replica = Server::start(&["--replicaof", &master.address()]);
+    let mut replica_client = replica.client();
+
+    let mut waiter = master.client();
+    waiter.send(&["BLPOP", "q", "0"]);
+    assert!(wait_until(Duration::from_secs(5), || {
+        client.info_field("clients", "blocked_clients") == 1
+    }));
+    client.cmd(&["RPUSH", "q", "a", "b"]);
+    assert_eq!(waiter.read(), Reply::bulks(&["q", "a"]));
+    client.cmd(&["SET", "synced", "1"]);
+
+    assert!(wait_until(Duration::from_secs(15), || {
+        replica_client.cmd(&["GET", "synced"]) == Reply::bulk("1")
+    }));
+    assert_eq!(
+        replica_client.cmd(&["LRANGE", "q", "0", "-1"]),
+        Reply::bulks(&["b"])
+    );
+}
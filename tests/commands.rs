@@ -0,0 +1,290 @@
+mod common;
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{wait_until, Reply, Server};
+
+#[test]
+fn keys_lists_keys_of_every_type() {
+    let server = Server::start(&[]);
+    let mut client = server.client();
+    assert_eq!(client.cmd(&["RPUSH", "list", "a"]), Reply::Integer(1));
+    assert_eq!(client.cmd(&["ZADD", "zset", "1", "m"]), Reply::Integer(1));
+    assert!(!client.cmd(&["XADD", "stream", "*", "f", "v"]).is_error());
+    assert_eq!(client.cmd(&["SET", "string", "v"]), Reply::ok());
+
+    let Reply::Array(mut keys) = client.cmd(&["KEYS", "*"]) else {
+        panic!("KEYS didn't return an array");
+    };
+    keys.sort_by_key(|key| match key {
+        Reply::Bulk(name) => name.clone(),
+        other => panic!("unexpected key {:?}", other),
+    });
+    assert_eq!(
+        Reply::Array(keys),
+        Reply::bulks(&["list", "stream", "string", "zset"])
+    );
+}
+
+#[test]
+fn pipelined_zscore_on_a_missing_member_keeps_replies_in_order() {
+    let server = Server::start(&[]);
+    let mut client = server.client();
+    client.cmd(&["ZADD", "zset", "1", "one", "2.5", "half", "inf", "top"]);
+
+    let mut pipeline = Vec::new();
+    for args in [
+        &["ZSCORE", "zset", "missing"][..],
+        &["ZSCORE", "zset", "one"],
+        &["ZSCORE", "zset", "half"],
+        &["ZSCORE", "zset", "top"],
+        &["PING"],
+    ] {
+        let args: Vec<&[u8]> = args.iter().map(|arg| arg.as_bytes()).collect();
+        pipeline.extend(common::encode(&args));
+    }
+    client.send_raw(&pipeline);
+
+    assert_eq!(client.read(), Reply::Nil);
+    assert_eq!(client.read(), Reply::bulk("1"));
+    assert_eq!(client.read(), Reply::bulk("2.5"));
+    assert_eq!(client.read(), Reply::bulk("inf"));
+    assert_eq!(client.read(), Reply::Simple("PONG".to_string()));
+}
+
+#[test]
+fn unknown_commands_get_the_redis_error_text() {
+    let server = Server::start(&[]);
+    let mut client = server.client();
+    assert_eq!(
+        client.cmd(&["FOO"]),
+        Reply::Error("ERR unknown command 'FOO', with args beginning with: ".to_string())
+    );
+    assert_eq!(
+        client.cmd(&["foo", "bar", "baz"]),
+        Reply::Error(
+            "ERR unknown command 'foo', with args beginning with: 'bar' 'baz' ".to_string()
+        )
+    );
+    // Binary arguments are escaped, so the error stays on one line
+    assert_eq!(
+        client.cmd_bytes(&[b"FOO", b"a\r\nb\x00'"]),
+        Reply::Error(
+            "ERR unknown command 'FOO', with args beginning with: 'a\\r\\nb\\x00\\'' ".to_string()
+        )
+    );
+    assert_eq!(client.cmd(&["PING"]), Reply::Simple("PONG".to_string()));
+}
+
+#[test]
+fn lolwut_ends_with_the_version() {
+    let server = Server::start(&[]);
+    let mut client = server.client();
+    let Reply::Bulk(art) = client.cmd(&["LOLWUT"]) else {
+        panic!("LOLWUT didn't return a bulk string");
+    };
+    let art = String::from_utf8(art).unwrap();
+    assert!(
+        art.trim_end()
+            .lines()
+            .last()
+            .unwrap()
+            .contains("Redis ver. "),
+        "{}",
+        art
+    );
+    assert!(!client.cmd(&["LOLWUT", "VERSION", "5"]).is_error());
+    assert!(client.cmd(&["LOLWUT", "VERSION", "x"]).is_error());
+}
+
+#[test]
+fn debug_sleep_blocks_only_its_own_client() {
+    let server = Server::start(&[]);
+    let mut sleeper = server.client();
+    let mut client = server.client();
+
+    let started = Instant::now();
+    sleeper.send(&["DEBUG", "SLEEP", "0.5"]);
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(client.cmd(&["PING"]), Reply::Simple("PONG".to_string()));
+    assert!(started.elapsed() < Duration::from_millis(400));
+    assert_eq!(sleeper.read(), Reply::ok());
+    assert!(started.elapsed() >= Duration::from_millis(500));
+}
+
+#[test]
+fn debug_object_describes_the_value() {
+    let server = Server::start(&[]);
+    let mut client = server.client();
+    client.cmd(&["SET", "int", "12345"]);
+    client.cmd(&["RPUSH", "list", "a", "b", "c"]);
+
+    for (key, encoding, elements) in [("int", "int", 1), ("list", "listpack", 3)] {
+        let Reply::Simple(object) = client.cmd(&["DEBUG", "OBJECT", key]) else {
+            panic!("DEBUG OBJECT {} failed", key);
+        };
+        assert!(object.starts_with("Value at:"), "{}", object);
+        assert!(
+            object.contains(&format!(" encoding:{} ", encoding)),
+            "{}",
+            object
+        );
+        assert!(object.contains(" serializedlength:"), "{}", object);
+        assert!(
+            object.ends_with(&format!(" elements:{}", elements)),
+            "{}",
+            object
+        );
+    }
+    assert_eq!(
+        client.cmd(&["DEBUG", "OBJECT", "missing"]),
+        Reply::Error("ERR no such key".to_string())
+    );
+    assert_eq!(
+        client.cmd(&["DEBUG", "QUICKLIST-PACKED-THRESHOLD", "1"]),
+        Reply::ok()
+    );
+    assert!(client.cmd(&["DEBUG", "NOPE"]).is_error());
+}
+
+#[test]
+fn object_reports_encoding_refcount_and_idle_time() {
+    let server = Server::start(&[]);
+    let mut client = server.client();
+    assert_eq!(client.cmd(&["SET", "k", "123"]), Reply::ok());
+    assert_eq!(client.cmd(&["SET", "text", "hello"]), Reply::ok());
+
+    assert_eq!(client.cmd(&["OBJECT", "ENCODING", "k"]), Reply::bulk("int"));
+    assert_eq!(
+        client.cmd(&["OBJECT", "encoding", "text"]),
+        Reply::bulk("embstr")
+    );
+    assert_eq!(client.cmd(&["OBJECT", "REFCOUNT", "k"]), Reply::Integer(1));
+    assert_eq!(client.cmd(&["OBJECT", "ENCODING", "missing"]), Reply::Nil);
+
+    // Asking doesn't count as an access, a GET does
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(client.cmd(&["OBJECT", "IDLETIME", "k"]), Reply::Integer(1));
+    assert_eq!(client.cmd(&["OBJECT", "IDLETIME", "k"]), Reply::Integer(1));
+    client.cmd(&["GET", "k"]);
+    assert_eq!(client.cmd(&["OBJECT", "IDLETIME", "k"]), Reply::Integer(0));
+
+    assert!(client.cmd(&["OBJECT", "FREQ", "k"]).is_error());
+    assert!(client.cmd(&["OBJECT", "ENCODING"]).is_error());
+}
+
+#[test]
+fn debug_set_active_expire_toggles_the_cleanup() {
+    let server = Server::start(&[]);
+    let mut client = server.client();
+    assert_eq!(
+        client.cmd(&["DEBUG", "SET-ACTIVE-EXPIRE", "0"]),
+        Reply::ok()
+    );
+    client.cmd(&["SET", "k", "v", "PX", "50"]);
+    thread::sleep(Duration::from_millis(300));
+    // Nothing read the key, and nothing else removed it
+    assert_eq!(client.info_field("stats", "expired_keys"), 0);
+
+    assert_eq!(
+        client.cmd(&["DEBUG", "SET-ACTIVE-EXPIRE", "1"]),
+        Reply::ok()
+    );
+    assert!(wait_until(Duration::from_secs(5), || {
+        client.info_field("stats", "expired_keys") == 1
+    }));
+}
+
+#[test]
+fn pipelined_commands_never_spill_into_each_other() {
+    let server = Server::start(&[]);
+    let mut client = server.client();
+
+    // Each array is one command, however its handler fares with the arguments
+    let mut pipeline = Vec::new();
+    for args in [
+        &["GEOADD", "geo", "13.361389", "38.115556", "Palermo"][..],
+        &["ZADD", "zset", "notafloat", "m"],
+        &["ZADD", "zset", "1", "m", "2"],
+        &["GET", "geo", "PING"],
+        &["SET", "k", "v", "GET", "k"],
+        &["SET", "k", "v", "NOPE"],
+        &["ECHO"],
+        &["ZCARD", "zset"],
+        &["GET", "k"],
+        &["PING"],
+    ] {
+        let args: Vec<&[u8]> = args.iter().map(|arg| arg.as_bytes()).collect();
+        pipeline.extend(common::encode(&args));
+    }
+    client.send_raw(&pipeline);
+
+    assert_eq!(client.read(), Reply::Integer(1));
+    assert_eq!(
+        client.read(),
+        Reply::Error("ERR value is not a valid float".to_string())
+    );
+    assert_eq!(client.read(), Reply::Error("ERR syntax error".to_string()));
+    assert_eq!(
+        client.read(),
+        Reply::Error("ERR wrong number of arguments for 'get' command".to_string())
+    );
+    assert_eq!(client.read(), Reply::Error("ERR syntax error".to_string()));
+    assert_eq!(client.read(), Reply::Error("ERR syntax error".to_string()));
+    assert_eq!(
+        client.read(),
+        Reply::Error("ERR wrong number of arguments for 'echo' command".to_string())
+    );
+    assert_eq!(client.read(), Reply::Integer(0));
+    assert_eq!(client.read(), Reply::Nil);
+    assert_eq!(client.read(), Reply::Simple("PONG".to_string()));
+}
+
+#[test]
+fn get_stays_fast_while_keys_walks_half_a_million_keys() {
+    const KEYS: usize = 500_000;
+    let server = Server::start(&[]);
+    let mut client = server.client();
+    for batch in (0..KEYS).collect::<Vec<_>>().chunks(10_000) {
+        let mut pipeline = Vec::new();
+        for n in batch {
+            let key = format!("key:{}", n);
+            pipeline.extend(common::encode(&[b"SET", key.as_bytes(), b"v"]));
+        }
+        client.send_raw(&pipeline);
+        for _ in batch {
+            assert_eq!(client.read(), Reply::ok());
+        }
+    }
+
+    let mut walker = server.client();
+    let started = Instant::now();
+    walker.send(&["KEYS", "*"]);
+    let walking = thread::spawn(move || {
+        let Reply::Array(keys) = walker.read() else {
+            panic!("KEYS didn't return an array");
+        };
+        (keys.len(), started.elapsed())
+    });
+
+    let mut latencies = Vec::new();
+    while !walking.is_finished() {
+        let sent = Instant::now();
+        assert_eq!(client.cmd(&["GET", "key:123"]), Reply::bulk("v"));
+        latencies.push(sent.elapsed());
+    }
+    let (count, walk) = walking.join().unwrap();
+    assert_eq!(count, KEYS);
+    latencies.sort();
+    let p99 = latencies[latencies.len() * 99 / 100];
+    let max = *latencies.last().unwrap();
+    // Holding every key's lock for the walk would stall GET for all of it
+    assert!(
+        p99 < Duration::from_millis(10) && max < walk / 10,
+        "GETs took up to {:?} (99% under {:?}) during a {:?} KEYS",
+        max,
+        p99,
+        walk
+    );
+}
@@ -0,0 +1,335 @@
+//! Runs the server binary on a free port and talks RESP to it.
+
+#![allow(dead_code)]
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// A server process, killed when dropped. It keeps its RDB and AOF files in
+/// a directory of its own.
+pub struct Server {
+    child: Child,
+    pub port: u16,
+    pub dir: PathBuf,
+}
+
+impl Server {
+    pub fn start(args: &[&str]) -> Server {
+        Server::start_on(free_port(), args)
+    }
+
+    /// Starts a server on `port` and waits until it accepts connections.
+    pub fn start_on(port: u16, args: &[&str]) -> Server {
+        let dir = std::env::temp_dir().join(format!(
+            "redis-test-{}-{}",
+            std::process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut server = Server {
+            child: spawn(port, &dir, args),
+            port,
+            dir,
+        };
+        server.wait_until_listening();
+        server
+    }
+
+    /// Kills the process and starts a new one on the same port and
+    /// directory, which still holds whatever the old one saved.
+    pub fn restart(&mut self, args: &[&str]) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        self.child = spawn(self.port, &self.dir, args);
+        self.wait_until_listening();
+    }
+
+    fn wait_until_listening(&mut self) {
+        let started = Instant::now();
+        while TcpStream::connect(("127.0.0.1", self.port)).is_err() {
+            assert!(started.elapsed() < START_TIMEOUT, "server didn't start");
+            assert!(
+                !matches!(self.child.try_wait(), Ok(Some(_))),
+                "server exited on start"
+            );
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    pub fn client(&self) -> Client {
+        Client::connect(self.port)
+    }
+
+    /// Whether the process ends by itself within `timeout`.
+    pub fn exited_within(&mut self, timeout: Duration) -> bool {
+        wait_until(timeout, || matches!(self.child.try_wait(), Ok(Some(_))))
+    }
+
+    /// Threads the process runs, as Linux reports them.
+    pub fn thread_count(&self) -> u64 {
+        self.proc_field("status", "Threads")
+    }
+
+    /// The process's virtual memory in bytes, which counts allocations
+    /// whether or not anything touched them yet.
+    pub fn virtual_memory(&self) -> u64 {
+        self.proc_field("status", "VmSize") * 1024
+    }
+
+    /// Write syscalls the process has made so far.
+    pub fn write_syscalls(&self) -> u64 {
+        self.proc_field("io", "syscw")
+    }
+
+    /// A numeric field of /proc/<pid>/`file`, without its unit.
+    fn proc_field(&self, file: &str, name: &str) -> u64 {
+        let fields = fs::read_to_string(format!("/proc/{}/{}", self.child.id(), file)).unwrap();
+        let prefix = format!("{}:", name);
+        fields
+            .lines()
+            .find_map(|line| line.strip_prefix(&prefix))
+            .unwrap_or_else(|| panic!("no {} in /proc/<pid>/{}", name, file))
+            .split_whitespace()
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    /// The `--replicaof` argument for following this server.
+    pub fn address(&self) -> String {
+        format!("127.0.0.1 {}", self.port)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Starts the server binary without waiting for it to listen.
+pub fn spawn(port: u16, dir: &Path, args: &[&str]) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_codecrafters-redis"))
+        .args(["--port", &port.to_string(), "--dir", dir.to_str().unwrap()])
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("failed to start the server")
+}
+
+/// A port nothing listens on right now.
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Polls `condition` until it holds or `timeout` passes, returning whether
+/// it held.
+pub fn wait_until(timeout: Duration, mut condition: impl FnMut() -> bool) -> bool {
+    let started = Instant::now();
+    loop {
+        if condition() {
+            return true;
+        }
+        if started.elapsed() >= timeout {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Array(Vec<Reply>),
+    Nil,
+}
+
+impl Reply {
+    pub fn bulk(value: &str) -> Reply {
+        Reply::Bulk(value.as_bytes().to_vec())
+    }
+
+    pub fn ok() -> Reply {
+        Reply::Simple("OK".to_string())
+    }
+
+    /// An array of bulk strings.
+    pub fn bulks(values: &[&str]) -> Reply {
+        Reply::Array(values.iter().map(|value| Reply::bulk(value)).collect())
+    }
+
+    pub fn is_error(&self) -> bool {
+        matches!(self, Reply::Error(_))
+    }
+}
+
+/// A connection speaking RESP2, or RESP3 after HELLO 3.
+pub struct Client {
+    pub stream: TcpStream,
+    buffer: Vec<u8>,
+}
+
+impl Client {
+    pub fn connect(port: u16) -> Client {
+        Client::from_stream(TcpStream::connect(("127.0.0.1", port)).unwrap())
+    }
+
+    /// Wraps an accepted connection, to play the server's side of it.
+    pub fn from_stream(stream: TcpStream) -> Client {
+        stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+        stream.set_nodelay(true).unwrap();
+        Client {
+            stream,
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn cmd(&mut self, args: &[&str]) -> Reply {
+        self.send(args);
+        self.read()
+    }
+
+    pub fn cmd_bytes(&mut self, args: &[&[u8]]) -> Reply {
+        self.send_bytes(args);
+        self.read()
+    }
+
+    pub fn send(&mut self, args: &[&str]) {
+        let args: Vec<&[u8]> = args.iter().map(|arg| arg.as_bytes()).collect();
+        self.send_bytes(&args);
+    }
+
+    pub fn send_bytes(&mut self, args: &[&[u8]]) {
+        self.send_raw(&encode(args));
+    }
+
+    pub fn send_raw(&mut self, bytes: &[u8]) {
+        self.stream.write_all(bytes).unwrap();
+    }
+
+    pub fn read(&mut self) -> Reply {
+        let line = self.read_line();
+        let (kind, rest) = line.split_at(1);
+        let rest = String::from_utf8(rest.to_vec()).unwrap();
+        match kind {
+            b"+" => Reply::Simple(rest),
+            b"-" => Reply::Error(rest),
+            b":" => Reply::Integer(rest.parse().unwrap()),
+            b"$" => match rest.parse::<i64>().unwrap() {
+                -1 => Reply::Nil,
+                len => {
+                    let mut bulk = self.read_exact(len as usize + 2);
+                    bulk.truncate(len as usize);
+                    Reply::Bulk(bulk)
+                }
+            },
+            // RESP3 pushes come back as arrays and maps as flat arrays of
+            // their keys and values
+            b"*" | b">" => match rest.parse::<i64>().unwrap() {
+                -1 => Reply::Nil,
+                len => Reply::Array((0..len).map(|_| self.read()).collect()),
+            },
+            b"%" => {
+                let len = rest.parse::<usize>().unwrap() * 2;
+                Reply::Array((0..len).map(|_| self.read()).collect())
+            }
+            b"_" => Reply::Nil,
+            _ => panic!("unexpected reply line {:?}", String::from_utf8_lossy(&line)),
+        }
+    }
+
+    /// A numeric field of INFO `section`.
+    pub fn info_field(&mut self, section: &str, name: &str) -> u64 {
+        let Reply::Bulk(info) = self.cmd(&["INFO", section]) else {
+            panic!("INFO didn't return a bulk string");
+        };
+        let info = String::from_utf8(info).unwrap();
+        let prefix = format!("{}:", name);
+        info.lines()
+            .find_map(|line| line.strip_prefix(&prefix))
+            .unwrap_or_else(|| panic!("INFO {} has no {}", section, name))
+            .parse()
+            .unwrap()
+    }
+
+    /// Reads and drops whatever arrives until the other side closes the
+    /// connection, returning false if it is still open after `timeout`.
+    pub fn closed_within(&mut self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut chunk = [0u8; 16 * 1024];
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            self.stream
+                .set_read_timeout(Some(left.max(Duration::from_millis(1))))
+                .unwrap();
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return true,
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+        false
+    }
+
+    fn read_line(&mut self) -> Vec<u8> {
+        loop {
+            if let Some(end) = self.buffer.windows(2).position(|pair| pair == b"\r\n") {
+                let line = self.buffer[..end].to_vec();
+                self.buffer.drain(..end + 2);
+                return line;
+            }
+            self.fill();
+        }
+    }
+
+    fn read_exact(&mut self, len: usize) -> Vec<u8> {
+        while self.buffer.len() < len {
+            self.fill();
+        }
+        self.buffer.drain(..len).collect()
+    }
+
+    fn fill(&mut self) {
+        let mut chunk = [0u8; 16 * 1024];
+        let n = self
+            .stream
+            .read(&mut chunk)
+            .expect("no reply from the server");
+        assert!(n > 0, "server closed the connection");
+        self.buffer.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// A command as a RESP array of bulk strings.
+pub fn encode(args: &[&[u8]]) -> Vec<u8> {
+    let mut frame = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        frame.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        frame.extend_from_slice(arg);
+        frame.extend_from_slice(b"\r\n");
+    }
+    frame
+}
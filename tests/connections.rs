@@ -0,0 +1,157 @@
+mod common;
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{wait_until, Client, Reply, Server};
+
+#[test]
+fn panicking_command_still_unregisters_its_client() {
+    let server = Server::start(&[]);
+    let mut victim = server.client();
+    assert!(!victim.cmd(&["HELLO", "3"]).is_error());
+    assert_eq!(victim.cmd(&["CLIENT", "SETNAME", "victim"]), Reply::ok());
+    victim.cmd(&["SUBSCRIBE", "news"]);
+    assert_eq!(victim.cmd(&["WATCH", "k"]), Reply::ok());
+
+    victim.send(&["DEBUG", "PANIC"]);
+    assert!(victim.closed_within(Duration::from_secs(5)));
+
+    let mut observer = server.client();
+    assert_eq!(
+        observer.cmd(&["PUBSUB", "NUMSUB", "news"]),
+        Reply::Array(vec![Reply::bulk("news"), Reply::Integer(0)])
+    );
+    let Reply::Bulk(list) = observer.cmd(&["CLIENT", "LIST"]) else {
+        panic!("CLIENT LIST didn't return a bulk string");
+    };
+    assert!(!String::from_utf8(list).unwrap().contains("name=victim"));
+}
+
+#[test]
+fn shutdown_stops_the_server_with_clients_still_connected() {
+    let mut server = Server::start(&[]);
+    let _idle = server.client();
+    server.client().send(&["SHUTDOWN", "NOSAVE"]);
+    assert!(server.exited_within(Duration::from_secs(5)));
+}
+
+#[test]
+fn ten_thousand_idle_connections() {
+    let server = Server::start(&["--maxclients", "20000"]);
+    let mut idle: Vec<TcpStream> = (0..10_000)
+        .map(|_| TcpStream::connect(("127.0.0.1", server.port)).unwrap())
+        .collect();
+    let mut client = server.client();
+    assert!(wait_until(Duration::from_secs(30), || {
+        client.info_field("clients", "connected_clients") == 10_001
+    }));
+    // Idle connections don't each hold a thread
+    assert!(
+        server.thread_count() < 100,
+        "{} threads",
+        server.thread_count()
+    );
+
+    let started = Instant::now();
+    assert_eq!(client.cmd(&["SET", "k", "v"]), Reply::ok());
+    assert_eq!(client.cmd(&["GET", "k"]), Reply::bulk("v"));
+    assert!(started.elapsed() < Duration::from_millis(100));
+
+    // Any of them can still talk
+    for stream in idle.iter_mut().step_by(1000) {
+        let mut idle_client = Client::from_stream(stream.try_clone().unwrap());
+        assert_eq!(
+            idle_client.cmd(&["PING"]),
+            Reply::Simple("PONG".to_string())
+        );
+    }
+}
+
+#[test]
+fn serves_clients_on_a_unix_socket() {
+    let path = std::env::temp_dir().join(format!("redis-test-{}.sock", common::free_port()));
+    let path_arg = path.to_str().unwrap();
+    let server = Server::start(&["--unixsocket", path_arg, "--unixsocketperm", "700"]);
+    assert!(wait_until(Duration::from_secs(5), || path.exists()));
+    assert_eq!(
+        fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+        0o700
+    );
+
+    let mut unix = UnixStream::connect(&path).unwrap();
+    unix.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut request = common::encode(&[b"SET", b"k", b"over unix"]);
+    request.extend(common::encode(&[b"CLIENT", b"LIST"]));
+    unix.write_all(&request).unwrap();
+
+    let mut replies = Vec::new();
+    let mut chunk = [0u8; 4096];
+    while !replies.ends_with(b"\n\r\n") {
+        let n = unix.read(&mut chunk).unwrap();
+        assert!(n > 0, "server closed the unix connection");
+        replies.extend_from_slice(&chunk[..n]);
+    }
+    let replies = String::from_utf8(replies).unwrap();
+    assert!(replies.starts_with("+OK\r\n"), "{}", replies);
+    // Unix clients show the socket's path as their address
+    assert!(
+        replies.contains(&format!(" addr={}:0 ", path_arg)),
+        "{}",
+        replies
+    );
+
+    assert_eq!(server.client().cmd(&["GET", "k"]), Reply::bulk("over unix"));
+    drop(server);
+    let _ = fs::remove_file(&path);
+}
+
+/// Runs `ops` SET/GET pairs on keys only this client touches.
+fn set_get_disjoint(server: &Server, client_id: usize, ops: usize) {
+    let mut client = server.client();
+    for n in 0..ops {
+        let key = format!("client{}:key{}", client_id, n % 100);
+        let value = n.to_string();
+        assert_eq!(client.cmd(&["SET", &key, &value]), Reply::ok());
+        assert_eq!(client.cmd(&["GET", &key]), Reply::bulk(&value));
+    }
+}
+
+#[test]
+fn clients_on_disjoint_keys_scale_with_the_cores() {
+    const OPS: usize = 2000;
+    let server = Server::start(&[]);
+    set_get_disjoint(&server, 0, OPS);
+
+    let started = Instant::now();
+    set_get_disjoint(&server, 0, OPS);
+    let alone = started.elapsed();
+
+    let started = Instant::now();
+    thread::scope(|scope| {
+        for client_id in 1..=8 {
+            let server = &server;
+            scope.spawn(move || set_get_disjoint(server, client_id, OPS));
+        }
+    });
+    let together = started.elapsed();
+
+    // Eight times the work should take eight times as long on one core and
+    // about as long as one client's on eight, with room for the clients'
+    // own threads competing for the same cores
+    let cores = thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(8) as u32;
+    assert!(
+        together < alone * 8 / cores * 2,
+        "8 clients took {:?}, one took {:?}, on {} cores",
+        together,
+        alone,
+        cores
+    );
+}
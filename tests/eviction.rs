@@ -0,0 +1,62 @@
+mod common;
+
+use common::{Reply, Server};
+
+const MAXMEMORY: u64 = 100 * 1024;
+
+#[test]
+fn allkeys_lru_evicts_the_oldest_keys_to_stay_under_maxmemory() {
+    let server = Server::start(&["--maxmemory", "100kb", "--maxmemory-policy", "allkeys-lru"]);
+    let mut client = server.client();
+    let value = "v".repeat(1000);
+
+    // Twenty times what fits
+    let written = 2000;
+    for i in 0..written {
+        assert_eq!(
+            client.cmd(&["SET", &format!("key:{}", i), &value]),
+            Reply::ok()
+        );
+    }
+
+    let Reply::Array(keys) = client.cmd(&["KEYS", "*"]) else {
+        panic!("KEYS didn't return an array");
+    };
+    let kept = keys.len() as i64;
+    assert!(kept > 0 && kept < written, "{} keys kept", kept);
+    assert_eq!(
+        client.info_field("stats", "evicted_keys"),
+        (written - kept) as u64
+    );
+    // The key written last was added after the last eviction pass
+    assert_eq!(
+        client.cmd(&["GET", &format!("key:{}", written - 1)]),
+        Reply::bulk(&value)
+    );
+    assert_eq!(client.cmd(&["GET", "key:0"]), Reply::Nil);
+
+    // One write's worth over the limit at most
+    let used = client.info_field("memory", "used_memory");
+    assert!(used <= MAXMEMORY + 2000, "used_memory is {}", used);
+}
+
+#[test]
+fn used_memory_follows_writes_and_deletes() {
+    let server = Server::start(&[]);
+    let mut client = server.client();
+    assert_eq!(client.info_field("memory", "used_memory"), 0);
+
+    for i in 0..100 {
+        client.cmd(&["RPUSH", "list", &"x".repeat(100), &i.to_string()]);
+    }
+    client.cmd(&["SET", "string", &"y".repeat(10_000)]);
+    let used = client.info_field("memory", "used_memory");
+    assert!(used > 20_000, "used_memory is {}", used);
+
+    client.cmd(&["DEL", "string"]);
+    let after_del = client.info_field("memory", "used_memory");
+    assert!(used - after_del >= 10_000, "DEL freed {}", used - after_del);
+
+    client.cmd(&["DEL", "list"]);
+    assert_eq!(client.info_field("memory", "used_memory"), 0);
+}
@@ -0,0 +1,197 @@
+mod common;
+
+use common::{Client, Reply, Server};
+
+// The examples from the GEOADD documentation, and how Redis reports them
+const SICILY: [&str; 6] = [
+    "13.361389",
+    "38.115556",
+    "Palermo",
+    "15.087269",
+    "37.502669",
+    "Catania",
+];
+const PALERMO: [&str; 2] = ["13.36138933897018433", "38.11555639549629859"];
+const CATANIA: [&str; 2] = ["15.08726745843887329", "37.50266842333162032"];
+
+fn add_sicily(client: &mut Client) {
+    let mut args = vec!["GEOADD", "Sicily"];
+    args.extend(SICILY);
+    assert_eq!(client.cmd(&args), Reply::Integer(2));
+}
+
+#[test]
+fn geopos_round_trips_palermo_and_catania() {
+    let server = Server::start(&[]);
+    let mut client = server.client();
+    add_sicily(&mut client);
+
+    assert_eq!(
+        client.cmd(&["GEOPOS", "Sicily", "Palermo", "Catania", "NonExisting"]),
+        Reply::Array(vec![
+            Reply::bulks(&PALERMO),
+            Reply::bulks(&CATANIA),
+            Reply::Nil,
+        ])
+    );
+    for (added, member) in [(&SICILY[..2], "Palermo"), (&SICILY[3..5], "Catania")] {
+        let Reply::Array(position) = client.cmd(&["GEOPOS", "Sicily", member]) else {
+            panic!("GEOPOS didn't return an array");
+        };
+        let Reply::Array(coordinates) = &position[0] else {
+            panic!("no position for {}", member);
+        };
+        for (coordinate, added) in coordinates.iter().zip(added) {
+            let Reply::Bulk(coordinate) = coordinate else {
+                panic!("coordinate isn't a bulk string");
+            };
+            let coordinate: f64 = String::from_utf8_lossy(coordinate).parse().unwrap();
+            assert!((coordinate - added.parse::<f64>().unwrap()).abs() < 1e-5);
+        }
+    }
+}
+
+#[test]
+fn georadius_matches_geosearch_for_every_with_option() {
+    let server = Server::start(&[]);
+    let mut client = server.client();
+    add_sicily(&mut client);
+
+    let options: [&[&str]; 8] = [
+        &[],
+        &["WITHDIST"],
+        &["WITHCOORD"],
+        &["WITHHASH"],
+        &["WITHDIST", "WITHCOORD"],
+        &["WITHDIST", "WITHHASH"],
+        &["WITHCOORD", "WITHHASH"],
+        &["WITHCOORD", "WITHDIST", "WITHHASH"],
+    ];
+    for with in options {
+        let mut radius = vec!["GEORADIUS", "Sicily", "15", "37", "200", "km", "ASC"];
+        radius.extend(with);
+        let mut search = vec![
+            "GEOSEARCH",
+            "Sicily",
+            "FROMLONLAT",
+            "15",
+            "37",
+            "BYRADIUS",
+            "200",
+            "km",
+            "ASC",
+        ];
+        search.extend(with);
+        let by_radius = client.cmd(&radius);
+        assert!(!by_radius.is_error(), "{:?}: {:?}", with, by_radius);
+        assert_eq!(by_radius, client.cmd(&search), "{:?}", with);
+
+        let mut by_member = vec!["GEORADIUSBYMEMBER", "Sicily", "Palermo", "200", "km", "ASC"];
+        by_member.extend(with);
+        let mut search = vec![
+            "GEOSEARCH",
+            "Sicily",
+            "FROMMEMBER",
+            "Palermo",
+            "BYRADIUS",
+            "200",
+            "km",
+            "ASC",
+        ];
+        search.extend(with);
+        assert_eq!(client.cmd(&by_member), client.cmd(&search), "{:?}", with);
+    }
+
+    // Each match lists its name, distance, hash and coordinates, in that order
+    assert_eq!(
+        client.cmd(&[
+            "GEORADIUS",
+            "Sicily",
+            "15",
+            "37",
+            "200",
+            "km",
+            "WITHCOORD",
+            "WITHDIST",
+            "WITHHASH",
+            "ASC",
+        ]),
+        Reply::Array(vec![
+            Reply::Array(vec![
+                Reply::bulk("Catania"),
+                Reply::bulk("56.4413"),
+                Reply::Integer(3479447370796909),
+                Reply::bulks(&CATANIA),
+            ]),
+            Reply::Array(vec![
+                Reply::bulk("Palermo"),
+                Reply::bulk("190.4424"),
+                Reply::Integer(3479099956230698),
+                Reply::bulks(&PALERMO),
+            ]),
+        ])
+    );
+}
+
+#[test]
+fn georadius_store_writes_the_matches() {
+    let server = Server::start(&[]);
+    let mut client = server.client();
+    add_sicily(&mut client);
+
+    assert_eq!(
+        client.cmd(&[
+            "GEORADIUS",
+            "Sicily",
+            "15",
+            "37",
+            "100",
+            "km",
+            "STORE",
+            "near"
+        ]),
+        Reply::Integer(1)
+    );
+    assert_eq!(
+        client.cmd(&["GEOPOS", "near", "Catania"]),
+        Reply::Array(vec![Reply::bulks(&CATANIA)])
+    );
+
+    assert_eq!(
+        client.cmd(&[
+            "GEORADIUS",
+            "Sicily",
+            "15",
+            "37",
+            "200",
+            "km",
+            "STOREDIST",
+            "dists"
+        ]),
+        Reply::Integer(2)
+    );
+    assert_eq!(
+        client.cmd(&["ZRANGE", "dists", "0", "-1", "WITHSCORES"]),
+        Reply::bulks(&[
+            "Catania",
+            "56.441257870158054",
+            "Palermo",
+            "190.44242984775798"
+        ])
+    );
+
+    let Reply::Error(error) = client.cmd(&[
+        "GEORADIUS",
+        "Sicily",
+        "15",
+        "37",
+        "200",
+        "km",
+        "WITHDIST",
+        "STORE",
+        "x",
+    ]) else {
+        panic!("STORE with WITHDIST succeeded");
+    };
+    assert!(error.starts_with("ERR"));
+}
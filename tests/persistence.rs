@@ -0,0 +1,407 @@
+mod common;
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use common::{free_port, spawn, wait_until, Client, Reply, Server};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// Writes a key of every type the commands can make, two of them with TTLs.
+fn populate(client: &mut Client) {
+    assert_eq!(client.cmd(&["SET", "string", "hello world"]), Reply::ok());
+    assert_eq!(
+        client.cmd_bytes(&[b"SET", b"binary", b"\x00\xff\r\n"]),
+        Reply::ok()
+    );
+    assert_eq!(client.cmd(&["INCR", "counter"]), Reply::Integer(1));
+    assert_eq!(
+        client.cmd(&["SET", "expiring", "v", "EX", "1000"]),
+        Reply::ok()
+    );
+    assert_eq!(
+        client.cmd(&["SET", "short", "v", "PX", "1500"]),
+        Reply::ok()
+    );
+    assert_eq!(
+        client.cmd(&["RPUSH", "list", "a", "b", "c"]),
+        Reply::Integer(3)
+    );
+    assert_eq!(
+        client.cmd(&["ZADD", "zset", "1.5", "one", "-inf", "low"]),
+        Reply::Integer(2)
+    );
+    assert_eq!(
+        client.cmd(&["GEOADD", "geo", "13.361389", "38.115556", "Palermo"]),
+        Reply::Integer(1)
+    );
+    assert!(!client.cmd(&["XADD", "stream", "1-1", "f", "v"]).is_error());
+    assert!(!client
+        .cmd(&["XADD", "stream", "2-0", "a", "b", "c", "d"])
+        .is_error());
+    assert_eq!(
+        client.cmd(&["XGROUP", "CREATE", "stream", "group", "1-1"]),
+        Reply::ok()
+    );
+    // Leaves 2-0 pending for consumer c
+    assert!(!client
+        .cmd(&[
+            "XREADGROUP",
+            "GROUP",
+            "group",
+            "c",
+            "STREAMS",
+            "stream",
+            ">"
+        ])
+        .is_error());
+}
+
+/// Every read that shows what `populate` wrote.
+const READS: [&[&str]; 11] = [
+    &["GET", "string"],
+    &["GET", "binary"],
+    &["GET", "counter"],
+    &["GET", "expiring"],
+    &["LRANGE", "list", "0", "-1"],
+    &["ZRANGE", "zset", "0", "-1", "WITHSCORES"],
+    &["GEOPOS", "geo", "Palermo"],
+    &["XRANGE", "stream", "-", "+"],
+    &["XPENDING", "stream", "group"],
+    &[
+        "XREADGROUP",
+        "GROUP",
+        "group",
+        "c",
+        "STREAMS",
+        "stream",
+        "0",
+    ],
+    &["INFO", "keyspace"],
+];
+
+fn snapshot(client: &mut Client) -> Vec<Reply> {
+    READS.iter().map(|read| client.cmd(read)).collect()
+}
+
+#[test]
+fn save_and_restart_keep_every_type_and_ttl() {
+    let mut server = Server::start(&[]);
+    let mut client = server.client();
+    populate(&mut client);
+    let before = snapshot(&mut client);
+    assert!(before.iter().all(|reply| !reply.is_error()), "{:?}", before);
+    assert_eq!(client.cmd(&["SAVE"]), Reply::ok());
+
+    server.restart(&[]);
+    let mut client = server.client();
+    assert_eq!(snapshot(&mut client), before);
+
+    // The short TTL survived as a deadline, not just as a flag
+    assert_eq!(client.cmd(&["GET", "short"]), Reply::bulk("v"));
+    thread::sleep(Duration::from_millis(1600));
+    assert_eq!(client.cmd(&["GET", "short"]), Reply::Nil);
+}
+
+#[test]
+fn debug_reload_keeps_every_type_and_ttl() {
+    let server = Server::start(&[]);
+    let mut client = server.client();
+    populate(&mut client);
+    let before = snapshot(&mut client);
+
+    assert_eq!(client.cmd(&["DEBUG", "RELOAD"]), Reply::ok());
+    assert_eq!(snapshot(&mut client), before);
+    assert!(server.dir.join("dump.rdb").exists());
+
+    // The reloaded keys are as writable as before
+    assert_eq!(client.cmd(&["INCR", "counter"]), Reply::Integer(2));
+    assert_eq!(client.cmd(&["RPUSH", "list", "d"]), Reply::Integer(4));
+}
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name)
+}
+
+/// Starts a server on a copy of `tests/fixtures/<name>`. Both fixtures hold
+/// the same dataset, in the encodings Redis 6.2 and 7.2 write for small
+/// values: strings (one int-encoded), a list, an intset, a set, a hash, a
+/// zset and a stream with a consumer group, plus a key with a TTL far in the
+/// future and one that already expired.
+fn start_on_fixture(name: &str) -> Server {
+    let mut server = Server::start(&[]);
+    fs::copy(fixture(name), server.dir.join("dump.rdb")).unwrap();
+    server.restart(&[]);
+    server
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// Checks a set or hash by its type and DEBUG OBJECT's element count, as
+/// there are no commands to read them back.
+fn assert_collection(client: &mut Client, key: &str, kind: &str, elements: usize) {
+    assert_eq!(client.cmd(&["TYPE", key]), Reply::Simple(kind.to_string()));
+    let Reply::Simple(object) = client.cmd(&["DEBUG", "OBJECT", key]) else {
+        panic!("DEBUG OBJECT {} failed", key);
+    };
+    assert!(
+        object.ends_with(&format!(" elements:{}", elements)),
+        "{}",
+        object
+    );
+}
+
+fn assert_fixture_dataset(server: &Server) {
+    let mut client = server.client();
+    assert_eq!(client.cmd(&["GET", "string"]), Reply::bulk("hello"));
+    assert_eq!(client.cmd(&["GET", "int"]), Reply::bulk("12345"));
+    assert_eq!(client.cmd(&["INCR", "int"]), Reply::Integer(12346));
+    assert_eq!(client.cmd(&["GET", "expiring"]), Reply::bulk("v"));
+    assert_eq!(client.cmd(&["GET", "expired"]), Reply::Nil);
+    assert_eq!(
+        client.cmd(&["LRANGE", "list", "0", "-1"]),
+        Reply::bulks(&["a", "b", "1", "hello world"])
+    );
+    assert_eq!(
+        client.cmd(&["ZRANGE", "zset", "0", "-1", "WITHSCORES"]),
+        Reply::bulks(&["a", "1", "b", "2.5"])
+    );
+    for (key, kind, elements) in [("intset", "set", 3), ("set", "set", 2), ("hash", "hash", 2)] {
+        assert_collection(&mut client, key, kind, elements);
+    }
+
+    assert_eq!(
+        client.cmd(&["XRANGE", "stream", "-", "+"]),
+        Reply::Array(vec![
+            Reply::Array(vec![Reply::bulk("1-1"), Reply::bulks(&["f", "v"])]),
+            Reply::Array(vec![Reply::bulk("1-2"), Reply::bulks(&["f", "w"])]),
+            Reply::Array(vec![
+                Reply::bulk("2-0"),
+                Reply::bulks(&["a", "1", "b", "2"])
+            ]),
+        ])
+    );
+    // 1-2 is pending for consumer c; the group last delivered it
+    assert_eq!(
+        client.cmd(&["XPENDING", "stream", "g"]),
+        Reply::Array(vec![
+            Reply::Integer(1),
+            Reply::bulk("1-2"),
+            Reply::bulk("1-2"),
+            Reply::Array(vec![Reply::bulks(&["c", "1"])]),
+        ])
+    );
+    assert_eq!(
+        client.cmd(&["XREADGROUP", "GROUP", "g", "c", "STREAMS", "stream", ">"]),
+        Reply::Array(vec![Reply::Array(vec![
+            Reply::bulk("stream"),
+            Reply::Array(vec![Reply::Array(vec![
+                Reply::bulk("2-0"),
+                Reply::bulks(&["a", "1", "b", "2"]),
+            ])]),
+        ])])
+    );
+}
+
+#[test]
+fn loads_an_rdb_in_redis_7_encodings() {
+    let server = start_on_fixture("redis-7.2.rdb");
+    assert_fixture_dataset(&server);
+}
+
+#[test]
+fn loads_an_rdb_in_redis_6_encodings() {
+    let server = start_on_fixture("redis-6.2.rdb");
+    assert_fixture_dataset(&server);
+}
+
+#[test]
+fn aof_replay_after_a_kill_reproduces_the_dataset() {
+    let mut server = Server::start(&["--appendonly", "yes"]);
+    let mut client = server.client();
+    populate(&mut client);
+    assert_eq!(client.cmd(&["DEL", "string"]), Reply::Integer(1));
+    assert_eq!(client.cmd(&["LPUSH", "list", "z"]), Reply::Integer(4));
+    assert_eq!(
+        client.cmd(&["SADD", "set", "a", "1", "a"]),
+        Reply::Integer(2)
+    );
+    assert_eq!(
+        client.cmd(&["HSET", "hash", "f", "v", "g", "2"]),
+        Reply::Integer(2)
+    );
+    let deadline = (now_ms() + 2500).to_string();
+    assert_eq!(
+        client.cmd(&["PEXPIREAT", "zset", &deadline]),
+        Reply::Integer(1)
+    );
+    let before = snapshot(&mut client);
+
+    // A command cut short by the kill is dropped with a warning
+    let aof = server.dir.join("appendonly.aof");
+    let mut file = fs::OpenOptions::new().append(true).open(&aof).unwrap();
+    file.write_all(b"*3\r\n$3\r\nSET\r\n$4\r\nhalf").unwrap();
+
+    server.restart(&["--appendonly", "yes"]);
+    let mut client = server.client();
+    assert_eq!(snapshot(&mut client), before);
+    assert_eq!(client.cmd(&["GET", "half"]), Reply::Nil);
+    assert_collection(&mut client, "set", "set", 2);
+    assert_collection(&mut client, "hash", "hash", 2);
+
+    // The zset kept its deadline through the replay
+    thread::sleep(Duration::from_millis(2600));
+    assert_eq!(client.cmd(&["TYPE", "zset"]), Reply::Simple("none".into()));
+}
+
+#[test]
+fn bgrewriteaof_replays_sets_hashes_and_ttls_without_the_rdb() {
+    let mut server = start_on_fixture("redis-7.2.rdb");
+    server.restart(&["--appendonly", "yes"]);
+    let mut client = server.client();
+    let deadline = (now_ms() + 3000).to_string();
+    assert_eq!(
+        client.cmd(&["PEXPIREAT", "list", &deadline]),
+        Reply::Integer(1)
+    );
+
+    assert!(!client.cmd(&["BGREWRITEAOF"]).is_error());
+    assert!(wait_until(Duration::from_secs(10), || {
+        client.info_field("persistence", "aof_rewrite_in_progress") == 0
+    }));
+    fs::remove_file(server.dir.join("dump.rdb")).unwrap();
+    server.restart(&["--appendonly", "yes"]);
+    assert_fixture_dataset(&server);
+
+    let mut client = server.client();
+    thread::sleep(Duration::from_millis(3100));
+    assert_eq!(client.cmd(&["TYPE", "list"]), Reply::Simple("none".into()));
+}
+
+#[test]
+fn bgrewriteaof_shrinks_an_aof_of_incrs() {
+    let mut server = Server::start(&["--appendonly", "yes"]);
+    let mut client = server.client();
+    for n in 1..=1000 {
+        assert_eq!(client.cmd(&["INCR", "counter"]), Reply::Integer(n));
+    }
+    let aof = server.dir.join("appendonly.aof");
+    let grown = fs::metadata(&aof).unwrap().len();
+
+    assert!(!client.cmd(&["BGREWRITEAOF"]).is_error());
+    assert!(wait_until(Duration::from_secs(10), || {
+        client.info_field("persistence", "aof_rewrite_in_progress") == 0
+    }));
+    let rewritten = fs::metadata(&aof).unwrap().len();
+    assert!(
+        rewritten * 100 < grown,
+        "{} bytes, {} before",
+        rewritten,
+        grown
+    );
+
+    // Writes after the rewrite still land in the new file
+    assert_eq!(client.cmd(&["INCR", "counter"]), Reply::Integer(1001));
+    server.restart(&["--appendonly", "yes"]);
+    assert_eq!(
+        server.client().cmd(&["GET", "counter"]),
+        Reply::bulk("1001")
+    );
+}
+
+/// Starts a server on `rdb` and returns how it exited, or None once it
+/// answers a PING, meaning the file loaded.
+fn load_outcome(dir: &Path, rdb: &[u8], args: &[&str]) -> Option<ExitStatus> {
+    fs::write(dir.join("dump.rdb"), rdb).unwrap();
+    let port = free_port();
+    let mut child = spawn(port, dir, args);
+    let started = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            return Some(status);
+        }
+        // It listens before loading, so only a reply means the file loaded
+        if let Ok(mut stream) = TcpStream::connect(("127.0.0.1", port)) {
+            stream
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .unwrap();
+            let mut reply = [0; 7];
+            if stream.write_all(b"PING\r\n").is_ok() && stream.read_exact(&mut reply).is_ok() {
+                assert_eq!(&reply, b"+PONG\r\n");
+                let _ = child.kill();
+                let _ = child.wait();
+                return None;
+            }
+        }
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "the server hung loading {} bytes",
+            rdb.len()
+        );
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+#[test]
+fn truncated_or_corrupt_rdbs_never_panic_the_loader() {
+    // The trailer checksum would turn every case away before parsing
+    let args = ["--skip-rdb-checksum"];
+    let server = Server::start(&[]);
+    let dir = &server.dir;
+    // Whether the file loaded
+    let check = |rdb: &[u8], what: &str| match load_outcome(dir, rdb, &args) {
+        // A panic exits with 101; a reported load failure with 1
+        Some(status) => {
+            assert_eq!(status.code(), Some(1), "{}", what);
+            false
+        }
+        None => true,
+    };
+
+    let valid = fs::read(fixture("redis-7.2.rdb")).unwrap();
+    // The EOF marker, followed by the 8-byte checksum left unchecked
+    let eof = valid.len() - 9;
+    assert_eq!(valid[eof], 0xFF);
+    for len in 0..=valid.len() {
+        let what = format!("the first {} bytes", len);
+        assert_eq!(check(&valid[..len], &what), len > eof, "{}", what);
+    }
+
+    let mut rng = StdRng::seed_from_u64(1960);
+    let valid = fs::read(fixture("redis-6.2.rdb")).unwrap();
+    for _ in 0..200 {
+        let mut corrupt = valid.clone();
+        for _ in 0..rng.random_range(1..4) {
+            let at = rng.random_range(0..corrupt.len());
+            corrupt[at] = rng.random();
+        }
+        check(&corrupt, &format!("{:?}", corrupt));
+    }
+}
+
+#[test]
+fn ignore_rdb_errors_starts_empty_on_a_truncated_rdb() {
+    let valid = fs::read(fixture("redis-7.2.rdb")).unwrap();
+    let mut server = Server::start(&[]);
+    assert_eq!(
+        load_outcome(&server.dir, &valid[..valid.len() / 2], &[]).and_then(|status| status.code()),
+        Some(1)
+    );
+
+    server.restart(&["--ignore-rdb-errors"]);
+    let mut client = server.client();
+    assert_eq!(client.cmd(&["KEYS", "*"]), Reply::Array(vec![]));
+    assert_eq!(client.cmd(&["SET", "k", "v"]), Reply::ok());
+}
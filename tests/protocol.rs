@@ -0,0 +1,260 @@
+mod common;
+
+use std::io::Read;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{encode, Client, Reply, Server};
+
+#[test]
+fn malformed_requests_get_a_protocol_error_and_a_disconnect() {
+    let server = Server::start(&[]);
+    for (request, error) in [
+        (
+            &b"*abc\r\n"[..],
+            "ERR Protocol error: invalid multibulk length",
+        ),
+        (b"*1\r\n$-5\r\n", "ERR Protocol error: invalid bulk length"),
+        (b"*1\r\n$abc\r\n", "ERR Protocol error: invalid bulk length"),
+        (
+            b"*2\r\n$4\r\nECHO\r\n*1\r\n",
+            "ERR Protocol error: expected '$', got '*'",
+        ),
+        (
+            b"*1\r\n$4\r\nPINGxx\r\n",
+            "ERR Protocol error: invalid bulk length",
+        ),
+        (
+            b"SET \"k v\r\n",
+            "ERR Protocol error: unbalanced quotes in request",
+        ),
+    ] {
+        let mut client = server.client();
+        client.send_raw(request);
+        assert_eq!(
+            client.read(),
+            Reply::Error(error.to_string()),
+            "{:?}",
+            request
+        );
+        assert!(
+            client.closed_within(Duration::from_secs(5)),
+            "{:?} left the connection open",
+            request
+        );
+    }
+
+    // Requests before the malformed one in the same read are still served
+    let mut client = server.client();
+    client.send_raw(b"*1\r\n$4\r\nPING\r\n*x\r\n");
+    assert_eq!(client.read(), Reply::Simple("PONG".to_string()));
+    assert_eq!(
+        client.read(),
+        Reply::Error("ERR Protocol error: invalid multibulk length".to_string())
+    );
+    assert!(client.closed_within(Duration::from_secs(5)));
+}
+
+/// How long a SET of `len` bytes takes, from the first byte sent to the reply.
+fn time_set(client: &mut Client, len: usize) -> Duration {
+    let value = vec![b'x'; len];
+    let started = Instant::now();
+    assert_eq!(client.cmd_bytes(&[b"SET", b"big", &value]), Reply::ok());
+    started.elapsed()
+}
+
+#[test]
+fn a_16mb_value_takes_roughly_linear_time() {
+    let server = Server::start(&[]);
+    let mut client = server.client();
+    // Warm up the allocator and the connection's buffer first
+    time_set(&mut client, 1 << 20);
+    let one_mb = (0..3)
+        .map(|_| time_set(&mut client, 1 << 20))
+        .min()
+        .unwrap();
+    let sixteen_mb = time_set(&mut client, 16 << 20);
+    // Quadratic re-parsing would take 16 times longer again
+    assert!(
+        sixteen_mb < one_mb * 16 * 4 + Duration::from_millis(500),
+        "1MB in {:?}, 16MB in {:?}",
+        one_mb,
+        sixteen_mb
+    );
+
+    let Reply::Bulk(value) = client.cmd(&["GET", "big"]) else {
+        panic!("GET didn't return a bulk string");
+    };
+    assert_eq!(value.len(), 16 << 20);
+    assert!(value.iter().all(|&byte| byte == b'x'));
+}
+
+#[test]
+fn huge_declared_lengths_do_not_allocate_up_front() {
+    let server = Server::start(&[]);
+    // Let every worker thread set up its allocator arena first
+    let mut clients: Vec<Client> = (0..32).map(|_| server.client()).collect();
+    for client in &mut clients {
+        client.send(&["SET", "warm", "up"]);
+    }
+    for client in &mut clients {
+        assert_eq!(client.read(), Reply::ok());
+    }
+    let baseline = server.virtual_memory();
+
+    // Within the limits, so the server waits for the rest of each request
+    clients[0].send_raw(b"*1000000\r\n$3\r\nSET\r\n");
+    clients[1].send_raw(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$536870000\r\nabc");
+    clients[2].send_raw(b"*1\r\n$536870000\r\n");
+    clients[3].send_raw(b"*2\r\n$4\r\nECHO\r\n$400000000\r\nxyz");
+    thread::sleep(Duration::from_millis(200));
+    let mut client = server.client();
+    assert_eq!(client.cmd(&["PING"]), Reply::Simple("PONG".to_string()));
+    let grown = server.virtual_memory().saturating_sub(baseline);
+    assert!(grown < 64 << 20, "grew by {} bytes", grown);
+
+    // Past them, the request is refused outright
+    for request in [
+        &b"*1048577\r\n"[..],
+        b"*1\r\n$536870913\r\n",
+        b"*1\r\n$2147483647\r\n",
+    ] {
+        let mut client = server.client();
+        client.send_raw(request);
+        assert!(client.read().is_error(), "{:?}", request);
+        assert!(client.closed_within(Duration::from_secs(5)));
+    }
+}
+
+#[test]
+fn ten_thousand_pipelined_incrs_come_back_in_order_and_fast() {
+    let server = Server::start(&[]);
+    let mut client = server.client();
+    let incr = encode(&[b"INCR", b"counter"]);
+
+    let started = Instant::now();
+    for n in 1..=10_000 {
+        assert_eq!(client.cmd(&["INCR", "serial"]), Reply::Integer(n));
+    }
+    let serial = started.elapsed();
+
+    let started = Instant::now();
+    client.send_raw(&incr.repeat(10_000));
+    for n in 1..=10_000 {
+        assert_eq!(client.read(), Reply::Integer(n));
+    }
+    let pipelined = started.elapsed();
+    // Without the round trips, and with one write per batch
+    assert!(
+        pipelined * 2 < serial,
+        "10000 pipelined in {:?}, one at a time in {:?}",
+        pipelined,
+        serial
+    );
+}
+
+#[test]
+fn common_errors_are_worded_like_redis() {
+    let server = Server::start(&[]);
+    let mut client = server.client();
+    client.cmd(&["SET", "string", "abc"]);
+    client.cmd(&["RPUSH", "list", "a"]);
+
+    for (request, reply) in [
+        (
+            &["GET"][..],
+            "ERR wrong number of arguments for 'get' command",
+        ),
+        (
+            &["INCR", "string"],
+            "ERR value is not an integer or out of range",
+        ),
+        (
+            &["SET", "k", "v", "EX", "soon"],
+            "ERR value is not an integer or out of range",
+        ),
+        (
+            &["ZADD", "zset", "x", "m"],
+            "ERR value is not a valid float",
+        ),
+        (
+            &["GET", "list"],
+            "WRONGTYPE Operation against a key holding the wrong kind of value",
+        ),
+        (
+            &["LPUSH", "string", "a"],
+            "WRONGTYPE Operation against a key holding the wrong kind of value",
+        ),
+        (
+            &["ZADD", "list", "1", "m"],
+            "WRONGTYPE Operation against a key holding the wrong kind of value",
+        ),
+        (
+            &["XADD", "list", "*", "f", "v"],
+            "WRONGTYPE Operation against a key holding the wrong kind of value",
+        ),
+        (&["SET", "k", "v", "XX", "NX"], "ERR syntax error"),
+    ] {
+        // The whole line after the '-', so no code gains an ERR in front
+        assert_eq!(
+            client.cmd(request),
+            Reply::Error(reply.to_string()),
+            "{:?}",
+            request
+        );
+    }
+    // Writes of the wrong type leave the key alone
+    assert_eq!(client.cmd(&["GET", "string"]), Reply::bulk("abc"));
+    assert_eq!(
+        client.cmd(&["LRANGE", "list", "0", "-1"]),
+        Reply::bulks(&["a"])
+    );
+}
+
+/// Sends `args` and reads exactly as many bytes as `reply` holds, so the
+/// null bulk, null array and empty array can be told apart.
+fn raw_reply(client: &mut Client, args: &[&str], reply: &[u8]) -> String {
+    client.send(args);
+    let mut received = vec![0; reply.len()];
+    client.stream.read_exact(&mut received).unwrap();
+    String::from_utf8_lossy(&received).into_owned()
+}
+
+#[test]
+fn missing_values_use_the_reply_type_redis_does() {
+    let server = Server::start(&[]);
+    let mut client = server.client();
+    client.cmd(&["ZADD", "zset", "1", "m"]);
+    client.cmd(&["RPUSH", "list", "a"]);
+
+    let table: [(&[&str], &[u8]); 12] = [
+        (&["GET", "missing"], b"$-1\r\n"),
+        (&["ZSCORE", "zset", "missing"], b"$-1\r\n"),
+        (&["ZSCORE", "missing", "m"], b"$-1\r\n"),
+        (&["LPOP", "missing"], b"$-1\r\n"),
+        (&["LPOP", "missing", "2"], b"*-1\r\n"),
+        (&["LRANGE", "missing", "0", "-1"], b"*0\r\n"),
+        (&["ZRANGE", "missing", "0", "-1"], b"*0\r\n"),
+        (&["XRANGE", "missing", "-", "+"], b"*0\r\n"),
+        (&["XREAD", "STREAMS", "missing", "0"], b"*-1\r\n"),
+        (&["BLPOP", "missing", "0.01"], b"*-1\r\n"),
+        (
+            &["ECHO"],
+            b"-ERR wrong number of arguments for 'echo' command\r\n",
+        ),
+        (
+            &["GET", "list"],
+            b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n",
+        ),
+    ];
+    for (args, reply) in table {
+        assert_eq!(
+            raw_reply(&mut client, args, reply),
+            String::from_utf8_lossy(reply),
+            "{:?}",
+            args
+        );
+    }
+    // Nothing extra followed any of them
+    assert_eq!(raw_reply(&mut client, &["PING"], b"+PONG\r\n"), "+PONG\r\n");
+}
@@ -0,0 +1,569 @@
+mod common;
+
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{free_port, wait_until, Client, Reply, Server};
+
+const SYNC_TIMEOUT: Duration = Duration::from_secs(15);
+// Replication ID the fake masters below hand out
+const FAKE_REPLID: &str = "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb";
+
+#[test]
+fn replica_started_before_its_master_syncs_once_it_is_up() {
+    let master_port = free_port();
+    let replica = Server::start(&["--replicaof", &format!("127.0.0.1 {}", master_port)]);
+    let mut replica_client = replica.client();
+    assert_eq!(
+        replica_client.cmd(&["PING"]),
+        Reply::Simple("PONG".to_string())
+    );
+
+    let master = Server::start_on(master_port, &[]);
+    assert_eq!(master.client().cmd(&["SET", "k", "v"]), Reply::ok());
+    assert!(wait_until(SYNC_TIMEOUT, || {
+        replica_client.cmd(&["GET", "k"]) == Reply::bulk("v")
+    }));
+}
+
+/// Plays a master's side of the handshake up to PSYNC and returns the PSYNC
+/// arguments.
+fn accept_replica(client: &mut Client) -> Vec<Reply> {
+    for reply in ["+PONG\r\n", "+OK\r\n", "+OK\r\n"] {
+        client.read();
+        client.send_raw(reply.as_bytes());
+    }
+    match client.read() {
+        Reply::Array(psync) => psync,
+        other => panic!("expected PSYNC, got {other:?}"),
+    }
+}
+
+/// Starts a replica of a master the test plays itself, returning the
+/// replica, the master's listener and its end of the link just after a full
+/// resync with an empty RDB.
+fn replica_of_fake_master() -> (Server, TcpListener, Client) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let master_port = listener.local_addr().unwrap().port();
+    // The replica syncs before it starts listening, so the master answers
+    // from another thread
+    let handshake = thread::spawn({
+        let listener = listener.try_clone().unwrap();
+        move || {
+            let mut link = Client::from_stream(listener.accept().unwrap().0);
+            accept_replica(&mut link);
+            // An empty RDB; the zero trailer stands for no checksum
+            let rdb = b"REDIS0011\xff\0\0\0\0\0\0\0\0";
+            link.send_raw(
+                format!("+FULLRESYNC {} 0\r\n${}\r\n", FAKE_REPLID, rdb.len()).as_bytes(),
+            );
+            link.send_raw(rdb);
+            link
+        }
+    });
+    let replica = Server::start(&["--replicaof", &format!("127.0.0.1 {}", master_port)]);
+    let link = handshake.join().unwrap();
+    (replica, listener, link)
+}
+
+#[test]
+fn protocol_error_from_master_drops_the_link_and_resyncs() {
+    let (replica, listener, mut link) = replica_of_fake_master();
+    link.send(&["SET", "k", "v"]);
+    let mut replica_client = replica.client();
+    assert!(wait_until(SYNC_TIMEOUT, || {
+        replica_client.cmd(&["GET", "k"]) == Reply::bulk("v")
+    }));
+
+    link.send_raw(b"*1\r\n$x\r\n");
+    // The replica hangs up, after whatever ACKs it had queued, and asks for
+    // a full resync on a new link
+    assert!(link.closed_within(SYNC_TIMEOUT));
+    let mut relink = Client::from_stream(listener.accept().unwrap().0);
+    let psync = accept_replica(&mut relink);
+    assert_eq!(psync[0], Reply::bulk("PSYNC"));
+    assert_ne!(psync[1], Reply::bulk(FAKE_REPLID));
+}
+
+#[test]
+fn binary_values_replicate_intact() {
+    let value: &[u8] = b"a\x00b\xff\r\nc\xfe";
+    let master = Server::start(&[]);
+    let mut master_client = master.client();
+    let replica = Server::start(&["--replicaof", &master.address()]);
+    let mut replica_client = replica.client();
+
+    assert_eq!(
+        master_client.cmd_bytes(&[b"SET", b"bin", value]),
+        Reply::ok()
+    );
+    assert_eq!(
+        master_client.cmd_bytes(&[b"RPUSH", b"list", value, b"\xff"]),
+        Reply::Integer(2)
+    );
+    assert_eq!(
+        master_client.cmd(&["GET", "bin"]),
+        Reply::Bulk(value.to_vec())
+    );
+
+    let list = Reply::Array(vec![
+        Reply::Bulk(value.to_vec()),
+        Reply::Bulk(b"\xff".to_vec()),
+    ]);
+    assert!(wait_until(SYNC_TIMEOUT, || {
+        replica_client.cmd(&["LRANGE", "list", "0", "-1"]) == list
+    }));
+    assert_eq!(
+        replica_client.cmd(&["GET", "bin"]),
+        Reply::Bulk(value.to_vec())
+    );
+
+    // A replica that joins later gets them through the RDB instead
+    let late_replica = Server::start(&["--replicaof", &master.address()]);
+    let mut late_client = late_replica.client();
+    assert!(wait_until(SYNC_TIMEOUT, || {
+        late_client.cmd(&["GET", "bin"]) == Reply::Bulk(value.to_vec())
+    }));
+    assert_eq!(late_client.cmd(&["LRANGE", "list", "0", "-1"]), list);
+}
+
+#[test]
+fn lpush_keeps_the_same_order_on_the_replica() {
+    let master = Server::start(&[]);
+    let mut master_client = master.client();
+    let replica = Server::start(&["--replicaof", &master.address()]);
+    let mut replica_client = replica.client();
+
+    assert_eq!(
+        master_client.cmd(&["LPUSH", "l", "a", "b", "c"]),
+        Reply::Integer(3)
+    );
+    assert_eq!(
+        master_client.cmd(&["LPUSH", "l", "d", "e"]),
+        Reply::Integer(5)
+    );
+    let expected = Reply::bulks(&["e", "d", "c", "b", "a"]);
+    assert_eq!(master_client.cmd(&["LRANGE", "l", "0", "-1"]), expected);
+    assert!(wait_until(SYNC_TIMEOUT, || {
+        replica_client.cmd(&["LRANGE", "l", "0", "-1"]) == expected
+    }));
+}
+
+#[test]
+fn propagated_set_options_do_not_spill_into_the_next_command() {
+    let (replica, _listener, mut link) = replica_of_fake_master();
+    let mut buffer = common::encode(&[b"SET", b"k", b"v", b"EX", b"100"]);
+    buffer.extend(common::encode(&[b"SET", b"k2", b"v2"]));
+    link.send_raw(&buffer);
+
+    let mut replica_client = replica.client();
+    assert!(wait_until(SYNC_TIMEOUT, || {
+        replica_client.cmd(&["GET", "k2"]) == Reply::bulk("v2")
+    }));
+    assert_eq!(replica_client.cmd(&["GET", "k"]), Reply::bulk("v"));
+    // Only k has a TTL
+    let Reply::Bulk(keyspace) = replica_client.cmd(&["INFO", "keyspace"]) else {
+        panic!("INFO didn't return a bulk string");
+    };
+    assert!(String::from_utf8(keyspace)
+        .unwrap()
+        .contains("db0:keys=2,expires=1,"));
+}
+
+#[test]
+fn replica_that_never_reads_does_not_slow_down_clients() {
+    let master = Server::start(&[]);
+    let mut stuck = Client::connect(master.port);
+    for args in [
+        &["PING"][..],
+        &["REPLCONF", "listening-port", "1"],
+        &["REPLCONF", "capa", "psync2"],
+        &["PSYNC", "?", "-1"],
+    ] {
+        stuck.send(args);
+    }
+    let mut client = master.client();
+    // Keep it attached however far behind it falls
+    client.cmd(&[
+        "CONFIG",
+        "SET",
+        "client-output-buffer-limit",
+        "replica 0 0 0",
+    ]);
+    assert!(wait_until(SYNC_TIMEOUT, || {
+        client.info_field("replication", "connected_slaves") == 1
+    }));
+
+    // Far more than the socket buffers hold, sent across a few heartbeats
+    let value = "v".repeat(16 * 1024);
+    let started = Instant::now();
+    let mut slowest = Duration::ZERO;
+    let mut i = 0;
+    while started.elapsed() < Duration::from_secs(3) {
+        let sent = Instant::now();
+        assert_eq!(
+            client.cmd(&["SET", &format!("k{}", i % 100), &value]),
+            Reply::ok()
+        );
+        assert!(!client.cmd(&["INFO", "replication"]).is_error());
+        slowest = slowest.max(sent.elapsed());
+        i += 1;
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(client.info_field("replication", "connected_slaves"), 1);
+    assert!(i > 100, "only {} writes went through", i);
+    assert!(
+        slowest < Duration::from_millis(500),
+        "slowest write took {:?}",
+        slowest
+    );
+}
+
+#[test]
+fn values_with_spaces_replicate_as_one_argument() {
+    let master = Server::start(&[]);
+    let mut master_client = master.client();
+    let replica = Server::start(&["--replicaof", &master.address()]);
+    let mut replica_client = replica.client();
+
+    master_client.cmd(&["RPUSH", "l", "hello world", "a\r\nb", " "]);
+    master_client.cmd(&["SET", "k", "two words"]);
+    let expected = Reply::bulks(&["hello world", "a\r\nb", " "]);
+    assert!(wait_until(SYNC_TIMEOUT, || {
+        replica_client.cmd(&["GET", "k"]) == Reply::bulk("two words")
+    }));
+    assert_eq!(replica_client.cmd(&["LRANGE", "l", "0", "-1"]), expected);
+}
+
+#[test]
+fn consumer_groups_reach_the_replica() {
+    let master = Server::start(&[]);
+    let mut master_client = master.client();
+    let replica = Server::start(&[
+        "--replicaof",
+        &master.address(),
+        "--replica-read-only",
+        "no",
+    ]);
+    let mut replica_client = replica.client();
+
+    assert_eq!(
+        master_client.cmd(&["XGROUP", "CREATE", "s", "g", "$", "MKSTREAM"]),
+        Reply::ok()
+    );
+    let read_group = ["XREADGROUP", "GROUP", "g", "c", "STREAMS", "s", ">"];
+    assert!(wait_until(SYNC_TIMEOUT, || {
+        !replica_client.cmd(&read_group).is_error()
+    }));
+
+    assert_eq!(
+        master_client.cmd(&["XGROUP", "DESTROY", "s", "g"]),
+        Reply::Integer(1)
+    );
+    assert!(wait_until(SYNC_TIMEOUT, || {
+        replica_client.cmd(&read_group).is_error()
+    }));
+}
+
+#[test]
+fn geoadd_gives_the_replica_the_same_positions() {
+    let master = Server::start(&[]);
+    let mut master_client = master.client();
+    let replica = Server::start(&["--replicaof", &master.address()]);
+    let mut replica_client = replica.client();
+
+    assert_eq!(
+        master_client.cmd(&[
+            "GEOADD",
+            "Sicily",
+            "13.361389",
+            "38.115556",
+            "Palermo",
+            "15.087269",
+            "37.502669",
+            "Catania",
+        ]),
+        Reply::Integer(2)
+    );
+    let geopos = ["GEOPOS", "Sicily", "Palermo", "Catania"];
+    let positions = master_client.cmd(&geopos);
+    assert!(wait_until(SYNC_TIMEOUT, || replica_client.cmd(&geopos) == positions));
+    let geodist = ["GEODIST", "Sicily", "Palermo", "Catania"];
+    assert_eq!(replica_client.cmd(&geodist), master_client.cmd(&geodist));
+}
+
+#[test]
+fn publish_reaches_subscribers_on_the_replica() {
+    let master = Server::start(&[]);
+    let mut master_client = master.client();
+    let replica = Server::start(&["--replicaof", &master.address()]);
+    let mut replica_client = replica.client();
+    assert!(wait_until(SYNC_TIMEOUT, || {
+        master_client.info_field("replication", "connected_slaves") == 1
+    }));
+
+    let mut subscriber = replica.client();
+    assert_eq!(
+        subscriber.cmd(&["SUBSCRIBE", "news"]),
+        Reply::Array(vec![
+            Reply::bulk("subscribe"),
+            Reply::bulk("news"),
+            Reply::Integer(1)
+        ])
+    );
+    // Only the master's own subscribers count towards the reply
+    assert_eq!(
+        master_client.cmd(&["PUBLISH", "news", "hello"]),
+        Reply::Integer(0)
+    );
+    assert_eq!(
+        subscriber.read(),
+        Reply::Array(vec![
+            Reply::bulk("message"),
+            Reply::bulk("news"),
+            Reply::bulk("hello")
+        ])
+    );
+    // A message isn't data: nothing got written on the replica
+    assert_eq!(replica_client.cmd(&["KEYS", "*"]), Reply::Array(vec![]));
+}
+
+#[test]
+fn auto_generated_stream_ids_match_on_the_replica() {
+    let master = Server::start(&[]);
+    let mut master_client = master.client();
+    let replica = Server::start(&["--replicaof", &master.address()]);
+    let mut replica_client = replica.client();
+
+    for n in 0..20 {
+        let n = n.to_string();
+        assert!(!master_client.cmd(&["XADD", "s", "*", "n", &n]).is_error());
+    }
+    assert!(!master_client
+        .cmd(&["XADD", "s", "9999999999999-*", "n", "last"])
+        .is_error());
+    let xrange = ["XRANGE", "s", "-", "+"];
+    let entries = master_client.cmd(&xrange);
+    assert!(wait_until(SYNC_TIMEOUT, || replica_client.cmd(&xrange) == entries));
+}
+
+/// The offset the master last heard its first replica acknowledge.
+fn acked_offset(master_client: &mut Client) -> Option<u64> {
+    let Reply::Bulk(info) = master_client.cmd(&["INFO", "replication"]) else {
+        panic!("INFO didn't return a bulk string");
+    };
+    let info = String::from_utf8(info).unwrap();
+    let replica = info.lines().find_map(|line| line.strip_prefix("slave0:"))?;
+    replica
+        .split(',')
+        .find_map(|field| field.strip_prefix("offset="))
+        .map(|offset| offset.parse().unwrap())
+}
+
+#[test]
+fn offset_counts_every_propagated_byte_and_the_replica_acks_it() {
+    let master = Server::start(&["--repl-ping-replica-period", "3600"]);
+    let mut master_client = master.client();
+    let _replica = Server::start(&["--replicaof", &master.address()]);
+    assert!(wait_until(SYNC_TIMEOUT, || acked_offset(
+        &mut master_client
+    )
+    .is_some()));
+
+    let mut expected = master_client.info_field("replication", "master_repl_offset");
+    for n in 0..50 {
+        let key = format!("key:{}", n);
+        let value = "v".repeat(n);
+        assert_eq!(master_client.cmd(&["SET", &key, &value]), Reply::ok());
+        expected += common::encode(&[b"SET", key.as_bytes(), value.as_bytes()]).len() as u64;
+    }
+    assert_eq!(
+        master_client.info_field("replication", "master_repl_offset"),
+        expected
+    );
+    assert!(wait_until(SYNC_TIMEOUT, || {
+        acked_offset(&mut master_client) == Some(expected)
+    }));
+}
+
+#[test]
+fn expired_key_reads_as_missing_on_the_replica_before_the_master_deletes_it() {
+    let (replica, _listener, mut link) = replica_of_fake_master();
+    // The fake master never sends the DEL a real one would on expiry
+    link.send(&["SET", "k", "v", "PX", "100"]);
+    link.send(&["SET", "marker", "v"]);
+    let mut replica_client = replica.client();
+    assert!(wait_until(SYNC_TIMEOUT, || {
+        replica_client.cmd(&["GET", "marker"]) == Reply::bulk("v")
+    }));
+
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(replica_client.cmd(&["GET", "k"]), Reply::Nil);
+    assert_eq!(
+        replica_client.cmd(&["KEYS", "*"]),
+        Reply::bulks(&["marker"])
+    );
+}
+
+#[test]
+fn keys_written_before_the_replica_connects_arrive_with_the_snapshot() {
+    let master = Server::start(&[]);
+    let mut master_client = master.client();
+    assert_eq!(master_client.cmd(&["SET", "string", "v"]), Reply::ok());
+    assert_eq!(
+        master_client.cmd(&["SET", "expiring", "v", "EX", "1000"]),
+        Reply::ok()
+    );
+    assert_eq!(master_client.cmd(&["INCR", "counter"]), Reply::Integer(1));
+    assert_eq!(
+        master_client.cmd(&["RPUSH", "list", "a", "b"]),
+        Reply::Integer(2)
+    );
+    assert_eq!(
+        master_client.cmd(&["ZADD", "zset", "1.5", "m"]),
+        Reply::Integer(1)
+    );
+    assert!(!master_client
+        .cmd(&["XADD", "stream", "1-1", "f", "v"])
+        .is_error());
+
+    let replica = Server::start(&["--replicaof", &master.address()]);
+    let mut replica_client = replica.client();
+    assert!(wait_until(SYNC_TIMEOUT, || {
+        replica_client.cmd(&["GET", "string"]) == Reply::bulk("v")
+    }));
+    for read in [
+        &["GET", "expiring"][..],
+        &["GET", "counter"],
+        &["LRANGE", "list", "0", "-1"],
+        &["ZSCORE", "zset", "m"],
+        &["XRANGE", "stream", "-", "+"],
+        &["INFO", "keyspace"],
+    ] {
+        assert_eq!(
+            replica_client.cmd(read),
+            master_client.cmd(read),
+            "{:?}",
+            read
+        );
+    }
+}
+
+#[test]
+fn writes_during_a_large_sync_arrive_once_after_the_snapshot() {
+    let master = Server::start(&[]);
+    let mut master_client = master.client();
+    // Several MB, so the snapshot goes out in many chunks
+    let value = "x".repeat(64 * 1024);
+    for _ in 0..8 {
+        let values = vec![value.as_str(); 16];
+        let mut command = vec!["RPUSH", "big"];
+        command.extend(values);
+        assert!(!master_client.cmd(&command).is_error());
+    }
+
+    // Writes keep coming before, while and after the replica syncs
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = thread::spawn({
+        let stop = Arc::clone(&stop);
+        move || {
+            let mut count = 0;
+            while !stop.load(Ordering::Relaxed) {
+                count += 1;
+                assert_eq!(
+                    master_client.cmd(&["INCR", "counter"]),
+                    Reply::Integer(count)
+                );
+            }
+            count
+        }
+    });
+    let replica = Server::start(&["--replicaof", &master.address()]);
+    let mut replica_client = replica.client();
+    thread::sleep(Duration::from_millis(100));
+    stop.store(true, Ordering::Relaxed);
+    let count = writer.join().unwrap();
+
+    assert!(wait_until(SYNC_TIMEOUT, || {
+        replica_client.cmd(&["GET", "counter"]) == Reply::bulk(&count.to_string())
+    }));
+    let Reply::Simple(object) = replica_client.cmd(&["DEBUG", "OBJECT", "big"]) else {
+        panic!("DEBUG OBJECT big failed");
+    };
+    assert!(object.ends_with(" elements:128"), "{}", object);
+}
+
+#[test]
+fn replica_writes_nothing_but_acks_on_the_master_link() {
+    let (replica, _listener, mut link) = replica_of_fake_master();
+    let mut burst = Vec::new();
+    for n in 0..200 {
+        let key = format!("key:{}", n);
+        burst.extend(common::encode(&[b"SET", key.as_bytes(), b"v"]));
+    }
+    burst.extend(common::encode(&[b"DEL", b"key:0"]));
+    burst.extend(common::encode(&[b"INCR", b"counter"]));
+    link.send_raw(&burst);
+    let offset = burst.len().to_string();
+    link.send(&["REPLCONF", "GETACK", "*"]);
+
+    // Periodic ACKs may come first; anything else is a reply that leaked
+    loop {
+        let Reply::Array(frame) = link.read() else {
+            panic!("replica wrote something other than an array");
+        };
+        assert_eq!(frame.len(), 3, "{:?}", frame);
+        assert_eq!(frame[0], Reply::bulk("REPLCONF"));
+        assert_eq!(frame[1], Reply::bulk("ACK"));
+        if frame[2] == Reply::bulk(&offset) {
+            break;
+        }
+    }
+    assert_eq!(replica.client().cmd(&["GET", "counter"]), Reply::bulk("1"));
+}
+
+#[test]
+fn debug_from_the_master_is_ignored() {
+    let (replica, _listener, mut link) = replica_of_fake_master();
+    let started = Instant::now();
+    link.send(&["DEBUG", "SLEEP", "5"]);
+    link.send(&["SET", "marker", "v"]);
+
+    let mut replica_client = replica.client();
+    assert!(wait_until(SYNC_TIMEOUT, || {
+        replica_client.cmd(&["GET", "marker"]) == Reply::bulk("v")
+    }));
+    assert!(started.elapsed() < Duration::from_secs(2));
+}
+
+#[test]
+fn writes_before_a_panic_inside_exec_still_reach_the_replica() {
+    let master = Server::start(&[]);
+    let replica = Server::start(&["--replicaof", &master.address()]);
+    let mut replica_client = replica.client();
+    // Synced first, so x can only come from the EXEC
+    let mut client = master.client();
+    assert_eq!(client.cmd(&["SET", "synced", "1"]), Reply::ok());
+    assert!(wait_until(SYNC_TIMEOUT, || {
+        replica_client.cmd(&["GET", "synced"]) == Reply::bulk("1")
+    }));
+
+    assert_eq!(client.cmd(&["MULTI"]), Reply::ok());
+    for command in [&["SET", "x", "1"][..], &["DEBUG", "PANIC"]] {
+        assert_eq!(client.cmd(command), Reply::Simple("QUEUED".to_string()));
+    }
+    client.send(&["EXEC"]);
+    assert!(client.closed_within(Duration::from_secs(5)));
+
+    // Later writes aren't left buffered on the thread that ran the EXEC
+    let mut client = master.client();
+    assert_eq!(client.cmd(&["GET", "x"]), Reply::bulk("1"));
+    assert_eq!(client.cmd(&["SET", "y", "2"]), Reply::ok());
+    assert!(wait_until(SYNC_TIMEOUT, || {
+        replica_client.cmd(&["GET", "y"]) == Reply::bulk("2")
+    }));
+    assert_eq!(replica_client.cmd(&["GET", "x"]), Reply::bulk("1"));
+}
@@ -0,0 +1,198 @@
+mod common;
+
+use std::io::Read;
+use std::time::Duration;
+
+use common::{encode, wait_until, Reply, Server};
+
+/// An entry as XRANGE and XREAD return it.
+fn entry(id: &str, fields: &[&str]) -> Reply {
+    Reply::Array(vec![Reply::bulk(id), Reply::bulks(fields)])
+}
+
+#[test]
+fn xrange_exclusive_bounds_skip_the_matching_entry() {
+    let server = Server::start(&[]);
+    let mut client = server.client();
+    for id in ["1-1", "1-2", "2-0"] {
+        assert_eq!(client.cmd(&["XADD", "s", id, "n", id]), Reply::bulk(id));
+    }
+
+    assert_eq!(
+        client.cmd(&["XRANGE", "s", "(1-1", "+"]),
+        Reply::Array(vec![
+            entry("1-2", &["n", "1-2"]),
+            entry("2-0", &["n", "2-0"])
+        ])
+    );
+    assert_eq!(
+        client.cmd(&["XRANGE", "s", "-", "(2-0"]),
+        Reply::Array(vec![
+            entry("1-1", &["n", "1-1"]),
+            entry("1-2", &["n", "1-2"])
+        ])
+    );
+    assert_eq!(
+        client.cmd(&["XRANGE", "s", "(1-1", "(2-0"]),
+        Reply::Array(vec![entry("1-2", &["n", "1-2"])])
+    );
+    assert_eq!(
+        client.cmd(&["XRANGE", "s", "(1-2", "(2-0"]),
+        Reply::Array(vec![])
+    );
+    assert_eq!(
+        client.cmd(&["XRANGE", "s", "-", "+", "COUNT", "1"]),
+        Reply::Array(vec![entry("1-1", &["n", "1-1"])])
+    );
+}
+
+#[test]
+fn xgroup_create_and_destroy_report_redis_errors() {
+    let server = Server::start(&[]);
+    let mut client = server.client();
+    let Reply::Error(missing) = client.cmd(&["XGROUP", "CREATE", "s", "g", "$"]) else {
+        panic!("XGROUP CREATE on a missing key succeeded");
+    };
+    assert!(missing.starts_with("ERR The XGROUP subcommand requires the key to exist"));
+
+    assert_eq!(
+        client.cmd(&["XGROUP", "CREATE", "s", "g", "$", "MKSTREAM"]),
+        Reply::ok()
+    );
+    assert_eq!(
+        client.cmd(&["XGROUP", "CREATE", "s", "g", "0"]),
+        Reply::Error("BUSYGROUP Consumer Group name already exists".to_string())
+    );
+    assert_eq!(
+        client.cmd(&["XGROUP", "CREATECONSUMER", "s", "nope", "c"]),
+        Reply::Error("NOGROUP No such consumer group 'nope' for key name 's'".to_string())
+    );
+    assert_eq!(
+        client.cmd(&["XGROUP", "CREATECONSUMER", "s", "g", "c"]),
+        Reply::Integer(1)
+    );
+    assert_eq!(
+        client.cmd(&["XGROUP", "DESTROY", "s", "g"]),
+        Reply::Integer(1)
+    );
+    assert_eq!(
+        client.cmd(&["XGROUP", "DESTROY", "s", "g"]),
+        Reply::Integer(0)
+    );
+
+    client.cmd(&["SET", "str", "v"]);
+    assert_eq!(
+        client.cmd(&["XGROUP", "CREATE", "str", "g", "$"]),
+        Reply::Error(
+            "WRONGTYPE Operation against a key holding the wrong kind of value".to_string()
+        )
+    );
+}
+
+#[test]
+fn xread_block_zero_wakes_on_the_second_stream() {
+    let server = Server::start(&[]);
+    let mut client = server.client();
+    client.cmd(&["XADD", "first", "1-0", "n", "old"]);
+    client.cmd(&["XADD", "second", "1-0", "n", "old"]);
+
+    let mut reader = server.client();
+    reader.send(&[
+        "XREAD", "BLOCK", "0", "STREAMS", "first", "second", "$", "$",
+    ]);
+    assert!(wait_until(Duration::from_secs(5), || {
+        client.info_field("clients", "blocked_clients") == 1
+    }));
+    assert_eq!(
+        client.cmd(&["XADD", "second", "2-0", "n", "new"]),
+        Reply::bulk("2-0")
+    );
+
+    assert_eq!(
+        reader.read(),
+        Reply::Array(vec![Reply::Array(vec![
+            Reply::bulk("second"),
+            Reply::Array(vec![entry("2-0", &["n", "new"])]),
+        ])])
+    );
+}
+
+#[test]
+fn xread_skips_missing_streams_and_honors_count() {
+    let server = Server::start(&[]);
+    let mut client = server.client();
+    for id in ["1-0", "2-0", "3-0"] {
+        client.cmd(&["XADD", "s", id, "n", id]);
+    }
+
+    assert_eq!(
+        client.cmd(&["XREAD", "COUNT", "2", "STREAMS", "missing", "s", "0", "0"]),
+        Reply::Array(vec![Reply::Array(vec![
+            Reply::bulk("s"),
+            Reply::Array(vec![
+                entry("1-0", &["n", "1-0"]),
+                entry("2-0", &["n", "2-0"])
+            ]),
+        ])])
+    );
+    assert_eq!(
+        client.cmd(&["XREAD", "BLOCK", "10", "STREAMS", "s", "$"]),
+        Reply::Nil
+    );
+}
+
+/// The RESP bytes of `entries` as an array, built field by field the way
+/// the reply was written before entries were serialized in one go.
+fn encode_entries(entries: &[(String, String)]) -> Vec<u8> {
+    let bulk = |value: &str| format!("${}\r\n{}\r\n", value.len(), value);
+    let mut reply = format!("*{}\r\n", entries.len());
+    for (id, value) in entries {
+        reply.push_str("*2\r\n");
+        reply.push_str(&bulk(id));
+        reply.push_str("*2\r\n");
+        reply.push_str(&bulk("n"));
+        reply.push_str(&bulk(value));
+    }
+    reply.into_bytes()
+}
+
+#[test]
+fn xrange_of_ten_thousand_entries_is_one_reply_in_few_writes() {
+    let server = Server::start(&[]);
+    let mut client = server.client();
+    let entries: Vec<(String, String)> = (1..=10_000)
+        .map(|n| (format!("{}-{}", n / 7, n % 7), "v".repeat(n % 50)))
+        .collect();
+    let mut pipeline = Vec::new();
+    for (id, value) in &entries {
+        pipeline.extend(encode(&[
+            b"XADD",
+            b"s",
+            id.as_bytes(),
+            b"n",
+            value.as_bytes(),
+        ]));
+    }
+    client.send_raw(&pipeline);
+    for (id, _) in &entries {
+        assert_eq!(client.read(), Reply::bulk(id));
+    }
+
+    let mut read_stream = |args: &[&str], expected: Vec<u8>| {
+        let writes = server.write_syscalls();
+        client.send(args);
+        let mut received = vec![0; expected.len()];
+        client.stream.read_exact(&mut received).unwrap();
+        assert!(received == expected, "{:?} differs", args);
+        // A write per entry would be tens of thousands
+        let writes = server.write_syscalls() - writes;
+        assert!(writes < 100, "{:?} took {} writes", args, writes);
+    };
+    read_stream(&["XRANGE", "s", "-", "+"], encode_entries(&entries));
+    let mut xread = b"*1\r\n*2\r\n$1\r\ns\r\n".to_vec();
+    xread.extend(encode_entries(&entries));
+    read_stream(&["XREAD", "STREAMS", "s", "0"], xread);
+
+    // Nothing followed the replies
+    assert_eq!(client.cmd(&["PING"]), Reply::Simple("PONG".to_string()));
+}
@@ -0,0 +1,91 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::{Reply, Server};
+
+fn queued() -> Reply {
+    Reply::Simple("QUEUED".to_string())
+}
+
+#[test]
+fn exec_runs_list_and_sorted_set_commands_in_order() {
+    let server = Server::start(&[]);
+    let mut client = server.client();
+    assert_eq!(client.cmd(&["MULTI"]), Reply::ok());
+    assert_eq!(client.cmd(&["RPUSH", "list", "a", "b"]), queued());
+    assert_eq!(client.cmd(&["LRANGE", "list", "0", "-1"]), queued());
+    assert_eq!(client.cmd(&["ZADD", "zset", "1", "one"]), queued());
+    assert_eq!(
+        client.cmd(&["EXEC"]),
+        Reply::Array(vec![
+            Reply::Integer(2),
+            Reply::bulks(&["a", "b"]),
+            Reply::Integer(1),
+        ])
+    );
+    assert_eq!(client.cmd(&["ZSCORE", "zset", "one"]), Reply::bulk("1"));
+}
+
+#[test]
+fn exec_keeps_values_with_spaces_and_crlf_whole() {
+    let server = Server::start(&[]);
+    let mut client = server.client();
+    let value = "hello world\r\n*1\r\n$4\r\nPING\r\n";
+    assert_eq!(client.cmd(&["MULTI"]), Reply::ok());
+    assert_eq!(client.cmd(&["SET", "greeting", value]), queued());
+    assert_eq!(client.cmd(&["GET", "greeting"]), queued());
+    assert_eq!(
+        client.cmd(&["EXEC"]),
+        Reply::Array(vec![Reply::ok(), Reply::bulk(value)])
+    );
+}
+
+#[test]
+fn blocking_commands_inside_exec_do_not_block() {
+    let server = Server::start(&[]);
+    let mut client = server.client();
+    assert_eq!(
+        client.cmd(&["XADD", "stream", "1-1", "f", "v"]),
+        Reply::bulk("1-1")
+    );
+    assert_eq!(
+        client.cmd(&["XGROUP", "CREATE", "stream", "group", "$"]),
+        Reply::ok()
+    );
+
+    let started = Instant::now();
+    assert_eq!(client.cmd(&["MULTI"]), Reply::ok());
+    assert_eq!(client.cmd(&["BLPOP", "empty", "0"]), queued());
+    assert_eq!(
+        client.cmd(&["XREAD", "BLOCK", "0", "STREAMS", "stream", "$"]),
+        queued()
+    );
+    assert_eq!(
+        client.cmd(&[
+            "XREADGROUP",
+            "GROUP",
+            "group",
+            "consumer",
+            "BLOCK",
+            "0",
+            "STREAMS",
+            "stream",
+            ">",
+        ]),
+        queued()
+    );
+    assert_eq!(client.cmd(&["WAIT", "1", "0"]), queued());
+    assert_eq!(client.cmd(&["SET", "after", "1"]), queued());
+    assert_eq!(
+        client.cmd(&["EXEC"]),
+        Reply::Array(vec![
+            Reply::Nil,
+            Reply::Nil,
+            Reply::Nil,
+            Reply::Integer(0),
+            Reply::ok(),
+        ])
+    );
+    assert!(started.elapsed() < Duration::from_secs(2));
+}